@@ -0,0 +1,351 @@
+use crate::file;
+use crate::metadata::{DiskChange, FileMetadata};
+use crate::piece_table::PieceTable;
+use crate::swap::SwapFile;
+use std::fs;
+use std::io;
+use std::time::SystemTime;
+
+// Commands about buffers, which the key handler leaves for the editor to carry out
+#[derive(PartialEq, Clone, Debug)]
+pub enum BufferCommand {
+    // `:e file` for a file other than the current one
+    Edit(String),
+    // `:bn` and `:bp` with a count
+    Next(usize),
+    Previous(usize),
+    // `:b N` or `:b name`
+    Go(String),
+    List,
+    // `:bd[!] [N]`
+    Delete { buffer: String, bang: bool },
+    // `:q[!]`, which closes the window when there are others, and `:qa[!]`.
+    // Neither leaves changes behind without `!`
+    Quit { bang: bool, all: bool },
+}
+
+// What a buffer that hasn't been given a file yet is called
+pub const NO_NAME: &str = "[No Name]";
+
+// A file being edited, and everything about it that stays put while another buffer is shown
+pub struct Buffer {
+    pub number: usize,
+    pub piece_table: PieceTable,
+    pub metadata: FileMetadata,
+    // Where the cursor and the top of the screen were when it was last shown
+    pub cursor: (usize, usize),
+    pub scroll_y: usize,
+    // Where changes are journaled, unless the file was opened read-only
+    pub swap: Option<SwapFile>,
+    // When the file had last been written as of the swap file starting again
+    pub swap_written: Option<SystemTime>,
+    // Whether a swap file has been looked for since the buffer was opened
+    pub swap_checked: bool,
+    // The last change to the file on disk that was warned about
+    pub disk_warned: Option<DiskChange>,
+}
+
+impl Buffer {
+    pub fn new(text: &str, file_path: String) -> Self {
+        let mut metadata = FileMetadata::new(file_path);
+        metadata.update();
+        Self {
+            number: 0,
+            piece_table: PieceTable::new(text),
+            metadata,
+            cursor: (0, 0),
+            scroll_y: 0,
+            swap: None,
+            swap_written: None,
+            swap_checked: false,
+            disk_warned: None,
+        }
+    }
+
+    // Reads the file at `file_path`, or starts it empty if there isn't one yet
+    pub fn open(file_path: String) -> io::Result<Self> {
+        let Some(text) = file::load_file_if_exists(&file_path)? else {
+            let mut buffer = Self::new("", file_path);
+            buffer.metadata.new_file = true;
+            return Ok(buffer);
+        };
+        Ok(Self::new(&text, file_path))
+    }
+
+    // The name `:ls` and `:b` go by
+    pub fn name(&self) -> &str {
+        if self.metadata.file_path.is_empty() {
+            NO_NAME
+        } else {
+            &self.metadata.file_path
+        }
+    }
+}
+
+// The open buffers in the order they were opened, numbered from 1 like in vim
+pub struct BufferList {
+    buffers: Vec<Buffer>,
+    current: usize,
+    // The number of the buffer shown before the current one
+    alternate: Option<usize>,
+    last_number: usize,
+}
+
+impl BufferList {
+    pub fn new(first: Buffer) -> Self {
+        let mut list = Self {
+            buffers: Vec::new(),
+            current: 0,
+            alternate: None,
+            last_number: 0,
+        };
+        list.add(first);
+        list
+    }
+
+    pub fn current(&self) -> &Buffer {
+        &self.buffers[self.current]
+    }
+
+    pub fn current_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.current]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Buffer> {
+        self.buffers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Buffer> {
+        self.buffers.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    // Adds a buffer after the others, giving it the next number
+    pub fn add(&mut self, mut buffer: Buffer) -> usize {
+        self.last_number += 1;
+        buffer.number = self.last_number;
+        self.buffers.push(buffer);
+        self.last_number
+    }
+
+    pub fn get(&self, number: usize) -> Option<&Buffer> {
+        self.buffers.iter().find(|buffer| buffer.number == number)
+    }
+
+    pub fn get_mut(&mut self, number: usize) -> Option<&mut Buffer> {
+        self.buffers
+            .iter_mut()
+            .find(|buffer| buffer.number == number)
+    }
+
+    // Makes buffer `number` the current one, if there is one
+    pub fn switch_to(&mut self, number: usize) -> bool {
+        let Some(index) = self.index(number) else {
+            return false;
+        };
+        if index != self.current {
+            self.alternate = Some(self.current().number);
+            self.current = index;
+        }
+        true
+    }
+
+    // The buffer `count` after or before the current one, going round from the end to the start
+    pub fn next_number(&self, count: usize, forward: bool) -> usize {
+        let len = self.buffers.len();
+        let step = count % len;
+        let index = if forward {
+            (self.current + step) % len
+        } else {
+            (self.current + len - step) % len
+        };
+        self.buffers[index].number
+    }
+
+    // The buffer editing `file_path`, however the path is written
+    pub fn find_file(&self, file_path: &str) -> Option<usize> {
+        let canonical = fs::canonicalize(file_path).ok();
+        self.buffers
+            .iter()
+            .find(|buffer| {
+                let name = &buffer.metadata.file_path;
+                !name.is_empty()
+                    && (name == file_path
+                        || canonical.is_some() && fs::canonicalize(name).ok() == canonical)
+            })
+            .map(|buffer| buffer.number)
+    }
+
+    // The buffer a `:b` or `:bd` argument means: a number, `%` or `#`, or part of a name that
+    // only one buffer has. Nothing means the current buffer
+    pub fn find(&self, argument: &str) -> Result<usize, BufferError> {
+        let argument = argument.trim();
+        match argument {
+            "" | "%" => return Ok(self.current().number),
+            "#" => return self.alternate.ok_or(BufferError::NoAlternate),
+            _ => {}
+        }
+        if let Ok(number) = argument.parse() {
+            return self
+                .index(number)
+                .map(|_| number)
+                .ok_or(BufferError::NoSuchBuffer(number));
+        }
+        let matching: Vec<&Buffer> = self
+            .buffers
+            .iter()
+            .filter(|buffer| buffer.name().contains(argument))
+            .collect();
+        match matching[..] {
+            [buffer] => Ok(buffer.number),
+            [] => Err(BufferError::NoMatch(argument.to_string())),
+            _ => Err(BufferError::MoreThanOneMatch(argument.to_string())),
+        }
+    }
+
+    // Takes buffer `number` out of the list. The alternate buffer, or else the next one,
+    // becomes current in place of the current buffer
+    pub fn remove(&mut self, number: usize) -> Result<Buffer, BufferError> {
+        let index = self
+            .index(number)
+            .ok_or(BufferError::NoSuchBuffer(number))?;
+        if self.buffers.len() == 1 {
+            return Err(BufferError::LastBuffer);
+        }
+        if index == self.current {
+            let replacement = self
+                .alternate
+                .filter(|&alternate| alternate != number)
+                .unwrap_or_else(|| self.next_number(1, true));
+            self.switch_to(replacement);
+        }
+        let current_number = self.current().number;
+        let buffer = self.buffers.remove(index);
+        self.current = self.index(current_number).unwrap_or(0);
+        if self.alternate == Some(number) {
+            self.alternate = None;
+        }
+        Ok(buffer)
+    }
+
+    // A line for each buffer for `:ls`, marking the current one with `%`, the alternate one
+    // with `#` and ones with changes with `+`. The current buffer's cursor has to be saved first
+    pub fn list(&self) -> Vec<String> {
+        self.buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| {
+                let flag = if index == self.current {
+                    "%a"
+                } else if self.alternate == Some(buffer.number) {
+                    "# "
+                } else {
+                    "  "
+                };
+                let modified = if buffer.piece_table.is_modified() {
+                    '+'
+                } else {
+                    ' '
+                };
+                let name = format!("\"{}\"", buffer.name());
+                format!(
+                    "{:3} {} {} {:<30} line {}",
+                    buffer.number,
+                    flag,
+                    modified,
+                    name,
+                    buffer.cursor.1 + 1
+                )
+            })
+            .collect()
+    }
+
+    // Names of the buffers, for completing `:b` and `:bd`
+    pub fn names(&self) -> Vec<String> {
+        self.buffers
+            .iter()
+            .map(|buffer| buffer.name().to_string())
+            .collect()
+    }
+
+    fn index(&self, number: usize) -> Option<usize> {
+        self.buffers
+            .iter()
+            .position(|buffer| buffer.number == number)
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum BufferError {
+    NoSuchBuffer(usize),
+    NoAlternate,
+    NoMatch(String),
+    MoreThanOneMatch(String),
+    LastBuffer,
+}
+
+impl std::fmt::Display for BufferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BufferError::NoSuchBuffer(number) => {
+                write!(f, "E86: Buffer {} does not exist", number)
+            }
+            BufferError::NoAlternate => write!(f, "E23: No alternate file"),
+            BufferError::NoMatch(name) => write!(f, "E94: No matching buffer for {}", name),
+            BufferError::MoreThanOneMatch(name) => {
+                write!(f, "E93: More than one match for {}", name)
+            }
+            BufferError::LastBuffer => write!(f, "E90: Cannot unload last buffer"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer_list(names: &[&str]) -> BufferList {
+        let mut list = BufferList::new(Buffer::new("", names[0].to_string()));
+        for name in &names[1..] {
+            list.add(Buffer::new("", name.to_string()));
+        }
+        list
+    }
+
+    #[test]
+    fn test_buffer_list() {
+        let mut list = buffer_list(&["src/main.rs", "src/lib.rs", "README.md"]);
+        assert_eq!(list.next_number(1, false), 3);
+        assert_eq!(list.next_number(4, true), 2);
+        assert_eq!(list.find("lib"), Ok(2));
+        assert_eq!(
+            list.find("src"),
+            Err(BufferError::MoreThanOneMatch("src".to_string()))
+        );
+        assert_eq!(list.find("4"), Err(BufferError::NoSuchBuffer(4)));
+        assert_eq!(list.find("#"), Err(BufferError::NoAlternate));
+
+        assert!(list.switch_to(3));
+        assert_eq!(list.find("#"), Ok(1));
+        assert_eq!(list.find_file("README.md"), Some(3));
+        // The alternate buffer takes the current one's place
+        assert_eq!(list.remove(3).map(|buffer| buffer.number), Ok(3));
+        assert_eq!(list.current().number, 1);
+        assert_eq!(list.add(Buffer::new("", "new.txt".to_string())), 4);
+        assert_eq!(list.remove(1).map(|buffer| buffer.number), Ok(1));
+        assert_eq!(list.current().number, 2);
+        assert!(list.remove(4).is_ok());
+        assert_eq!(list.remove(2).err(), Some(BufferError::LastBuffer));
+        assert_eq!(
+            list.list(),
+            ["  2 %a   \"src/lib.rs\"                   line 1"]
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use crate::regex::is_keyword;
+use crate::utils::display_column;
+use log::info;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// How many command lines the history keeps
+const HISTORY_SIZE: usize = 100;
+
+// The text typed after `:` and where the cursor is in it
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct CommandLine {
+    pub text: String,
+    // Byte offset of the cursor in `text`
+    pub cursor: usize,
+    // Set by Ctrl-r until the register name is typed
+    pub register_pending: bool,
+    // Which history entry Up and Down have got to, and what was typed before they were used
+    browsing: Option<(usize, String)>,
+    pub completion: Option<Completion>,
+}
+
+// The choices Tab is cycling through
+#[derive(PartialEq, Clone, Debug)]
+pub struct Completion {
+    // Where the word being completed starts in the text
+    start: usize,
+    pub candidates: Vec<String>,
+    // None while the word is back to what was typed
+    pub selected: Option<usize>,
+    typed: String,
+}
+
+impl CommandLine {
+    pub fn new(text: String) -> Self {
+        Self {
+            cursor: text.len(),
+            text,
+            ..Self::default()
+        }
+    }
+
+    // The text as it is shown, with a carriage return from `Ctrl-r` as `^M`, and the cursor's column
+    pub fn display(&self) -> (String, usize) {
+        let shown = |text: &str| text.replace('\r', "^M");
+        let column = display_column(&shown(&self.text[..self.cursor]), usize::MAX);
+        (shown(&self.text), column)
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        self.edited();
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(ch) = self.text[..self.cursor].chars().next_back() {
+            self.edited();
+            self.cursor -= ch.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.edited();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(ch) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= ch.len_utf8();
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(ch) = self.text[self.cursor..].chars().next() {
+            self.cursor += ch.len_utf8();
+        }
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    // Shift or Ctrl with Left goes back to the start of a WORD
+    pub fn word_left(&mut self) {
+        let before = self.text[..self.cursor].trim_end();
+        self.cursor = before.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    }
+
+    pub fn word_right(&mut self) {
+        let after = &self.text[self.cursor..];
+        let word_end = after.find(char::is_whitespace).unwrap_or(after.len());
+        let next = after[word_end..]
+            .find(|ch: char| !ch.is_whitespace())
+            .unwrap_or(after.len() - word_end);
+        self.cursor += word_end + next;
+    }
+
+    // `Ctrl-w` deletes the word before the cursor, and any spaces after it
+    pub fn delete_word(&mut self) {
+        let before = self.text[..self.cursor].trim_end();
+        let keyword = before.chars().next_back().is_some_and(is_keyword);
+        let start = before
+            .char_indices()
+            .rev()
+            .take_while(|&(_, ch)| !ch.is_whitespace() && is_keyword(ch) == keyword)
+            .last()
+            .map_or(before.len(), |(i, _)| i);
+        self.edited();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    // `Ctrl-u` deletes everything before the cursor
+    pub fn delete_to_start(&mut self) {
+        self.edited();
+        self.text.replace_range(..self.cursor, "");
+        self.cursor = 0;
+    }
+
+    // Up and Down go to older and newer entries that start with what was typed,
+    // or any entry with `filtered` off. Going past the newest brings back what was typed
+    pub fn browse_history(&mut self, history: &CommandHistory, older: bool, filtered: bool) {
+        let entries = history.entries();
+        let (position, typed) = self
+            .browsing
+            .take()
+            .unwrap_or((entries.len(), self.text.clone()));
+        let matches = |i: &usize| !filtered || entries[*i].starts_with(&typed);
+        let found = if older {
+            (0..position).rev().find(matches)
+        } else {
+            (position + 1..entries.len()).find(matches)
+        };
+        match found {
+            Some(i) => {
+                self.text = entries[i].clone();
+                self.browsing = Some((i, typed));
+            }
+            None if older => self.browsing = Some((position, typed)),
+            None => self.text = typed,
+        }
+        self.cursor = self.text.len();
+        self.completion = None;
+    }
+
+    // Shows the next or previous choice for the word before the cursor. `find` works out
+    // where the word starts and what it could be, the first time round
+    pub fn complete(
+        &mut self,
+        forward: bool,
+        find: impl FnOnce(&str) -> Option<(usize, Vec<String>)>,
+    ) {
+        if self.completion.is_none() {
+            let Some((start, candidates)) = find(&self.text[..self.cursor]) else {
+                return;
+            };
+            self.completion = Some(Completion {
+                start,
+                typed: self.text[start..self.cursor].to_string(),
+                candidates,
+                selected: None,
+            });
+        }
+        let Some(completion) = self.completion.as_mut() else {
+            return;
+        };
+        let count = completion.candidates.len();
+        if count == 0 {
+            self.completion = None;
+            return;
+        }
+        // Cycling goes through the word as it was typed between the last choice and the first
+        completion.selected = match (completion.selected, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(count - 1),
+            (Some(i), true) if i + 1 < count => Some(i + 1),
+            (Some(i), false) if i > 0 => Some(i - 1),
+            _ => None,
+        };
+        let word = match completion.selected {
+            Some(i) => &completion.candidates[i],
+            None => &completion.typed,
+        };
+        self.text.replace_range(completion.start..self.cursor, word);
+        self.cursor = completion.start + word.len();
+    }
+
+    fn edited(&mut self) {
+        self.browsing = None;
+        self.completion = None;
+    }
+}
+
+// Command lines that have been run, oldest first, saved to a file if there is one
+#[derive(Default)]
+pub struct CommandHistory {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl CommandHistory {
+    // The history saved at `path`, which it is saved back to as it changes
+    pub fn load(path: PathBuf) -> Self {
+        let entries = fs::read_to_string(&path)
+            .map(|text| text.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Self {
+            entries,
+            path: Some(path),
+        }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    // Running a command again moves it to the end
+    pub fn add(&mut self, entry: &str) {
+        if entry.is_empty() {
+            return;
+        }
+        self.entries.retain(|existing| existing != entry);
+        self.entries.push(entry.to_string());
+        let excess = self.entries.len().saturating_sub(HISTORY_SIZE);
+        self.entries.drain(..excess);
+        self.save();
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut text = self.entries.join("\n");
+        text.push('\n');
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, text));
+        if let Err(error) = result {
+            info!("Couldn't save the command history: {}", error);
+        }
+    }
+}
+
+// Where the command history is kept between sessions
+pub fn history_path() -> Option<PathBuf> {
+    let state = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+    Some(state.join("text-editor").join("command_history"))
+}
+
+// Files and directories whose paths start with `word`. Directories end in `/`,
+// and hidden ones only show up once a `.` is typed
+pub fn complete_path(word: &str) -> Vec<String> {
+    let (directory, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..=i], &word[i + 1..]),
+        None => ("", word),
+    };
+    let Ok(entries) = fs::read_dir(if directory.is_empty() { "." } else { directory }) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", directory, name, slash))
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line() {
+        let mut line = CommandLine::new("s/foo bar/baz".to_string());
+        line.delete_word();
+        assert_eq!(line.text, "s/foo bar/");
+        line.delete_word();
+        assert_eq!(line.text, "s/foo bar");
+        line.word_left();
+        line.move_left();
+        line.insert("d");
+        assert_eq!((line.text.as_str(), line.cursor), ("s/food bar", 6));
+        line.delete_to_start();
+        line.move_end();
+        line.backspace();
+        assert_eq!((line.text.as_str(), line.cursor), (" ba", 3));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut history = CommandHistory::default();
+        for entry in ["w", "s/a/b/", "set", "s/c/d/", "w"] {
+            history.add(entry);
+        }
+        assert_eq!(history.entries(), ["s/a/b/", "set", "s/c/d/", "w"]);
+
+        let mut line = CommandLine::new("s".to_string());
+        line.browse_history(&history, true, true);
+        assert_eq!(line.text, "s/c/d/");
+        line.browse_history(&history, true, true);
+        line.browse_history(&history, true, true);
+        assert_eq!(line.text, "s/a/b/");
+        line.browse_history(&history, false, false);
+        assert_eq!(line.text, "set");
+        line.browse_history(&history, false, true);
+        line.browse_history(&history, false, true);
+        assert_eq!(line.text, "s");
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut line = CommandLine::new("1,2wr".to_string());
+        let find = |text: &str| {
+            assert_eq!(text, "1,2wr");
+            Some((3, vec!["write".to_string(), "wq".to_string()]))
+        };
+        line.complete(true, find);
+        assert_eq!(line.text, "1,2write");
+        line.complete(true, |_| None);
+        line.complete(true, |_| None);
+        assert_eq!(line.text, "1,2wr");
+        line.complete(false, |_| None);
+        assert_eq!(line.text, "1,2wq");
+        line.insert(" ");
+        assert_eq!(line.completion, None);
+    }
+}
@@ -1,16 +1,36 @@
-use crate::metadata::FileMetadata;
+use crate::buffer::{Buffer, BufferCommand, BufferList, NO_NAME};
+use crate::command_line::{CommandHistory, CommandLine, Completion};
+use crate::ex::CommandError;
+use crate::key_handler::{replace_text, BlockInsert, Change};
+use crate::metadata::{DiskChange, FileMetadata};
+use crate::normal_command::WINDOW_KEY;
+use crate::operator::{RangeKind, TextRange};
 use crate::piece_table::PieceTable;
+use crate::register::Registers;
+use crate::search::{LastSearch, Pattern};
+use crate::substitute::Substitution;
+use crate::swap::{self, is_running, swap_owner, swap_path, SwapFile, SYNC_IDLE_TIME};
+use crate::utils::{display_column, display_graphemes, grapheme_count};
+use crate::window::{Layout, Rect, WindowCommand};
 use crossterm::event::*;
+use crossterm::style::Stylize;
 use crossterm::terminal::ClearType;
 use crossterm::{cursor, event, execute, queue, terminal};
 use log::{error, info};
+use std::collections::VecDeque;
 use std::io;
 use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use unicode_width::UnicodeWidthStr;
 
 pub struct CleanUp;
 
 impl Drop for CleanUp {
     fn drop(&mut self) {
+        if let Err(e) = execute!(stdout(), DisableFocusChange) {
+            error!("Could not stop focus change events: {}", e);
+        }
         if let Err(e) = terminal::disable_raw_mode() {
             error!("Could not turn off raw mode: {}", e);
         }
@@ -21,26 +41,89 @@ impl Drop for CleanUp {
 }
 
 pub enum TextType {
-    PieceTable(PieceTable),
+    PieceTable(Box<PieceTable>),
     String(String),
 }
 
 #[derive(PartialEq, Clone)]
 pub enum BarMode {
     Write,
+    Message(String),
 }
 
 #[derive(PartialEq, Clone)]
 pub enum Mode {
     Normal(Option<BarMode>),
     Insert,
-    Command { previous_chars: String },
-    // TODO - Visual Mode
-    // TODO - Replace Mode
+    Command(CommandLine),
+    Visual {
+        kind: RangeKind,
+        anchor: (usize, usize),
+    },
+    Replace,
+    // Typing a pattern after `/` or `?`, from the cursor position `origin`
+    Search {
+        forward: bool,
+        previous_chars: String,
+        origin: (usize, usize),
+        count: usize,
+    },
+    // Asking whether to make a substitution, for `:s` with the `c` flag
+    Confirm {
+        prompt: String,
+    },
 }
 
 pub struct KeyHandler {
     mode: Mode,
+    pub registers: Registers,
+    pub block_insert: Option<BlockInsert>,
+    // Keys typed so far of an unfinished normal mode command
+    pub pending: String,
+    // What each key typed in replace mode overwrote, for Backspace to restore
+    pub replaced: Vec<Option<String>>,
+    // How many times the text typed in insert or replace mode goes in, from a count like `3i`
+    pub insert_count: usize,
+    // Text typed since entering insert or replace mode
+    pub inserted: String,
+    // The last change and the text typed in insert mode after it, for `.`
+    pub last_change: Option<(Change, String)>,
+    // A change that is waiting for insert mode to finish
+    pub unfinished_change: Option<Change>,
+    // The register a macro is being recorded into and the keys typed so far
+    pub recording: Option<(char, Vec<KeyEvent>)>,
+    // Keys from a macro that are still to be played
+    pub queued_keys: VecDeque<KeyEvent>,
+    // The register last played with `@`, for `@@`
+    pub last_macro: Option<char>,
+    // Macros started since the last typed key
+    pub macro_plays: usize,
+    // The last pattern searched for, for `n` and `N`
+    pub last_search: Option<LastSearch>,
+    // Whether matches of the last search are highlighted, until `:nohlsearch`
+    pub highlight_search: bool,
+    // The first and last lines of the last visual selection, for `'<` and `'>`
+    pub visual_lines: Option<(usize, usize)>,
+    // Whether the selection was last widened with `$`, so a block goes to the end of every line
+    pub visual_to_line_end: bool,
+    // The last pattern and replacement `:s` used, for `:s` on its own and `~`
+    pub last_substitute: Option<(String, String)>,
+    // A `:s` waiting for an answer about a match
+    pub substitution: Option<Substitution>,
+    // Whether a `:g` is running, which can't start another
+    pub global_busy: bool,
+    // Command lines run before, for Up and Down on the command line and `q:`
+    pub command_history: CommandHistory,
+    // Set by `q:` for the editor to open the command-line window
+    pub open_command_window: bool,
+    // Set by `:recover` for the editor to read the file's swap file
+    pub recover: bool,
+    // A command about buffers for the editor to carry out
+    pub buffer_command: Option<BufferCommand>,
+    // The names of the buffers, for completing `:b`
+    pub buffer_names: Vec<String>,
+    // A command about windows for the editor to carry out
+    pub window_command: Option<WindowCommand>,
 }
 
 impl Default for KeyHandler {
@@ -53,6 +136,31 @@ impl KeyHandler {
     pub fn new() -> Self {
         KeyHandler {
             mode: Mode::Normal(None),
+            registers: Registers::new(),
+            block_insert: None,
+            pending: String::new(),
+            replaced: Vec::new(),
+            insert_count: 1,
+            inserted: String::new(),
+            last_change: None,
+            unfinished_change: None,
+            recording: None,
+            queued_keys: VecDeque::new(),
+            last_macro: None,
+            macro_plays: 0,
+            last_search: None,
+            highlight_search: false,
+            visual_lines: None,
+            visual_to_line_end: false,
+            last_substitute: None,
+            substitution: None,
+            global_busy: false,
+            command_history: CommandHistory::default(),
+            open_command_window: false,
+            recover: false,
+            buffer_command: None,
+            buffer_names: Vec::new(),
+            window_command: None,
         }
     }
 
@@ -67,8 +175,33 @@ impl KeyHandler {
     pub fn get_mode_mut(&mut self) -> &mut Mode {
         &mut self.mode
     }
+
+    // What to highlight matches of: the pattern being typed, or else the last one searched for
+    fn highlight(&self) -> Option<Pattern> {
+        match &self.mode {
+            Mode::Search { previous_chars, .. } if !previous_chars.is_empty() => {
+                Pattern::new(previous_chars).ok()
+            }
+            Mode::Confirm { .. } => self
+                .substitution
+                .as_ref()
+                .map(|substitution| substitution.pattern.clone()),
+            _ if self.highlight_search => {
+                self.last_search.as_ref().map(|last| last.pattern.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Adds a typed key to the macro being recorded
+    fn record_key(&mut self, key_event: KeyEvent) {
+        if let Some((_, keys)) = &mut self.recording {
+            keys.push(key_event);
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct CursorController {
     cursor_x: usize,
     desired_cursor_x: usize,
@@ -76,6 +209,7 @@ pub struct CursorController {
 
     relative_y: usize,
 
+    // The size of the text in the window
     screen_columns: usize,
     screen_rows: usize,
 }
@@ -100,6 +234,13 @@ impl CursorController {
         self.cursor_y
     }
 
+    // Screen column of the cursor, which differs from `cursor_x` for wide characters and tabs
+    pub fn display_x(&self, piece_table: &PieceTable) -> usize {
+        piece_table
+            .line(self.cursor_y)
+            .map_or(0, |line| display_column(&line, self.cursor_x))
+    }
+
     pub fn screen_size(&self) -> (usize, usize) {
         (self.screen_columns, self.screen_rows)
     }
@@ -107,7 +248,8 @@ impl CursorController {
     // Doesn't let you go past the end of the line
     pub fn set_cursor_x_normal_mode(&mut self, x: usize, line_length: usize) {
         if x >= line_length {
-            self.cursor_x = line_length - 1;
+            // An empty line still has a column for the cursor
+            self.cursor_x = line_length.saturating_sub(1);
         } else {
             self.cursor_x = x;
         }
@@ -133,29 +275,43 @@ impl CursorController {
 
     pub fn set_cursor_y(&mut self, y: usize, num_lines: usize) {
         if y >= num_lines {
-            self.cursor_y = num_lines - 1;
+            self.cursor_y = num_lines.saturating_sub(1);
         } else {
             self.cursor_y = y;
         }
     }
 
+    pub fn restore_position(&mut self, (x, y): (usize, usize), piece_table: &PieceTable) {
+        let num_lines = piece_table.line_count();
+        if num_lines == 0 {
+            self.cursor_y = 0;
+            self.set_cursor_x_no_checks(0);
+            return;
+        }
+        self.set_cursor_y(y, num_lines);
+        let line_length = piece_table.line_grapheme_len(self.cursor_y);
+        self.set_cursor_x_no_checks(x.min(line_length.saturating_sub(1)));
+    }
+
     pub fn update_desired_x(&mut self) {
         self.desired_cursor_x = self.cursor_x;
     }
 
-    pub fn update_desired_x_if_needed(&mut self, lines: &[String]) {
-        self.cursor_x = self
-            .desired_cursor_x
-            .min(lines[self.cursor_y].len().saturating_sub(1));
+    pub fn update_desired_x_if_needed(&mut self, line_length: usize) {
+        self.cursor_x = self.desired_cursor_x.min(line_length.saturating_sub(1));
     }
 }
 
+#[derive(Clone)]
 pub struct EditorView {
     cursor_controller: CursorController,
     scroll_y: usize,
 }
 
 impl EditorView {
+    // Rows at the bottom of a window for its status line
+    const STATUS_BAR_ROWS: usize = 1;
+
     fn new(window_size: (usize, usize)) -> Self {
         Self {
             cursor_controller: CursorController::new(window_size),
@@ -163,6 +319,12 @@ impl EditorView {
         }
     }
 
+    // Fits the view to a window at `rect`, leaving out the status line
+    fn resize(&mut self, rect: Rect) {
+        self.cursor_controller.screen_columns = rect.width;
+        self.cursor_controller.screen_rows = rect.height.saturating_sub(Self::STATUS_BAR_ROWS);
+    }
+
     fn update_scroll(&mut self) {
         self.scroll_y = self.adjust_scroll();
         self.cursor_controller.relative_y = self
@@ -172,7 +334,7 @@ impl EditorView {
     }
 
     fn adjust_scroll(&self) -> usize {
-        let content_rows = self.cursor_controller.screen_rows - Output::STATUS_BAR_ROWS;
+        let content_rows = self.cursor_controller.screen_rows.max(1);
         let scroll_bottom = self.scroll_y + content_rows - 1;
 
         if self.cursor_controller.cursor_y > scroll_bottom {
@@ -230,17 +392,26 @@ impl Write for EditorContents {
 
 struct Output {
     editor_contents: EditorContents,
-    editor_view: EditorView,
+    screen_size: (usize, usize),
 }
 
 impl Output {
-    const STATUS_BAR_ROWS: usize = 2;
+    // The command line goes below the windows, and the tabline above them
+    // when there is more than one tab page
+    const COMMAND_LINE_ROWS: usize = 1;
+    const TABLINE_ROWS: usize = 1;
     const INSERT_MODE_LABEL: &'static str = "-- INSERT --";
+    const REPLACE_MODE_LABEL: &'static str = "-- REPLACE --";
+    const VISUAL_MODE_LABEL: &'static str = "-- VISUAL --";
+    const VISUAL_LINE_MODE_LABEL: &'static str = "-- VISUAL LINE --";
+    const VISUAL_BLOCK_MODE_LABEL: &'static str = "-- VISUAL BLOCK --";
+    // Keys of an unfinished command are shown this many columns from the right
+    const SHOWCMD_COLUMNS: usize = 11;
     const DEFAULT_WINDOW_SIZE: (usize, usize) = (80, 24);
     const COMMAND_CURSOR_Y_OFFSET: usize = 1;
 
     fn new() -> Self {
-        let window_size = terminal::size()
+        let screen_size = terminal::size()
             .map(|(x, y)| (x as usize, y as usize))
             .unwrap_or({
                 info!("Could not get window size, using default");
@@ -248,20 +419,54 @@ impl Output {
             });
         Self {
             editor_contents: EditorContents::new(),
-            editor_view: EditorView::new(window_size),
+            screen_size,
         }
     }
 
+    // Where the windows go: all of the screen above the command line,
+    // and below the tabline if there is one
+    fn window_area(&self, tabline: bool) -> Rect {
+        let top = if tabline { Self::TABLINE_ROWS } else { 0 };
+        Rect {
+            x: 0,
+            y: top,
+            width: self.screen_size.0,
+            height: self
+                .screen_size
+                .1
+                .saturating_sub(Self::COMMAND_LINE_ROWS + top),
+        }
+    }
+
+    // The tab pages along the top, with the current one in bold and the others in reverse video
+    fn draw_tabline(&mut self, labels: &[(String, bool)]) -> io::Result<()> {
+        let columns = self.screen_size.0;
+        let mut used = 0;
+        let mut tabline = String::new();
+        for (label, current) in labels {
+            let label: String = label.chars().take(columns.saturating_sub(used)).collect();
+            used += label.chars().count();
+            if *current {
+                tabline.push_str(&label.bold().to_string());
+            } else {
+                tabline.push_str(&label.reverse().to_string());
+            }
+        }
+        let fill = " ".repeat(columns.saturating_sub(used));
+        tabline.push_str(&fill.reverse().to_string());
+        self.draw_at(0, 0, &tabline)
+    }
+
     fn clear_screen() -> io::Result<()> {
         execute!(stdout(), terminal::Clear(ClearType::All))?;
         execute!(stdout(), cursor::MoveTo(0, 0))
     }
 
-    fn calculate_line_percent(lines: &[String], cursor_y: usize) -> String {
-        if lines.is_empty() || lines.len() == 1 {
+    fn calculate_line_percent(num_lines: usize, cursor_y: usize) -> String {
+        if num_lines <= 1 {
             "Top".to_string()
         } else {
-            match 100 * cursor_y / (lines.len() - 1) {
+            match 100 * cursor_y / (num_lines - 1) {
                 100 => "Bot".to_string(),
                 0 => "Top".to_string(),
                 percent => format!("{}%", percent),
@@ -269,88 +474,316 @@ impl Output {
         }
     }
 
-    fn draw_rows(&mut self, content: &[String]) {
-        self.editor_contents.push_str(&content.join("\n"));
+    // Draws `text` starting at screen row `y` and column `x`
+    fn draw_at(&mut self, x: usize, y: usize, text: &str) -> io::Result<()> {
+        queue!(self.editor_contents, cursor::MoveTo(x as u16, y as u16))?;
+        self.editor_contents.push_str(text);
+        Ok(())
     }
 
-    fn draw_content(&mut self, piece_table: &PieceTable) {
-        self.editor_view.update_scroll();
+    // Draws a line with the grapheme columns `start..end` of the selection in reverse video
+    // and search matches highlighted, cut off at `width` columns.
+    // A selection past the end of the line is shown as one selected space
+    fn render_line(
+        line: &str,
+        selection: Option<(usize, usize)>,
+        matches: &[(usize, usize)],
+        width: usize,
+    ) -> String {
+        let mut graphemes = display_graphemes(line);
+        let mut used = 0;
+        let visible = graphemes
+            .iter()
+            .take_while(|grapheme| {
+                used += grapheme.width();
+                used <= width
+            })
+            .count();
+        let cut_off = visible < graphemes.len();
+        graphemes.truncate(visible);
+        if selection.is_none() && matches.is_empty() {
+            return graphemes.concat();
+        }
 
-        let lines = piece_table.lines();
-        let start = self.editor_view.scroll_y;
+        let within = |(start, end): (usize, usize), i: usize| start <= i && i < end;
+        let mut rendered: String = graphemes
+            .into_iter()
+            .enumerate()
+            .map(|(i, grapheme)| {
+                if selection.is_some_and(|range| within(range, i)) {
+                    grapheme.reverse().to_string()
+                } else if matches.iter().any(|&range| within(range, i)) {
+                    grapheme.black().on_yellow().to_string()
+                } else {
+                    grapheme
+                }
+            })
+            .collect();
+        if !cut_off && used < width && selection.is_some_and(|(_, end)| end > grapheme_count(line))
+        {
+            rendered.push_str(&" ".reverse().to_string());
+        }
+        rendered
+    }
+
+    // Draws the text a window shows, with its status line below it
+    fn draw_window(
+        &mut self,
+        window: &WindowDraw,
+        mode: &Mode,
+        highlight: Option<&Pattern>,
+        several: bool,
+    ) -> io::Result<()> {
+        let WindowDraw {
+            rect,
+            view,
+            buffer,
+            current,
+        } = *window;
+        let piece_table = &buffer.piece_table;
+        let cursor_controller = &view.cursor_controller;
+        // Only the window being typed in shows the selection
+        let selection = match mode {
+            Mode::Visual { kind, anchor } if current => Some(TextRange::new(
+                *kind,
+                *anchor,
+                (cursor_controller.cursor_x, cursor_controller.cursor_y),
+            )),
+            _ => None,
+        };
+
+        let start = view.scroll_y;
         let end = std::cmp::min(
-            lines.len(),
-            start + self.editor_view.cursor_controller.screen_rows - 2,
+            piece_table.line_count(),
+            start + cursor_controller.screen_rows,
         );
+        for (row, y) in (start..end).enumerate() {
+            let Some(line) = piece_table.line(y) else {
+                break;
+            };
+            let columns = selection.and_then(|range| {
+                let (first, last) = range.line_columns(piece_table, y)?;
+                if line.is_empty() && range.kind != RangeKind::Blockwise {
+                    Some((0, 1))
+                } else {
+                    Some((first, last))
+                }
+            });
+            let matches = highlight.map_or_else(Vec::new, |pattern| pattern.line_matches(&line));
+            let rendered = Self::render_line(&line, columns, &matches, rect.width);
+            self.draw_at(rect.x, rect.y + row, &rendered)?;
+        }
 
-        self.draw_rows(&lines[start..end]);
-        self.fill_screen(start + self.editor_view.cursor_controller.screen_rows - end - 1);
+        let line_percent =
+            Self::calculate_line_percent(piece_table.line_count(), cursor_controller.cursor_y);
+        let display_x = cursor_controller.display_x(piece_table);
+        let status_bar = Self::format_status_bar(
+            cursor_controller,
+            &buffer.metadata,
+            &line_percent,
+            display_x,
+        );
+        // With more than one window, status lines are in reverse video to set the windows
+        // apart, and the one being typed in is in bold as well
+        let status_bar = match (several, current) {
+            (false, _) => status_bar,
+            (true, false) => status_bar.reverse().to_string(),
+            (true, true) => status_bar.reverse().bold().to_string(),
+        };
+        self.draw_at(rect.x, rect.y + rect.height.saturating_sub(1), &status_bar)
     }
 
-    fn fill_screen(&mut self, empty_lines: usize) {
-        let lines = vec!["".to_string(); empty_lines];
-        self.draw_rows(&lines);
+    // A column of `|` to the right of each window with another beside it
+    fn draw_separators(&mut self, windows: &[WindowDraw]) -> io::Result<()> {
+        let right_edge = self.screen_size.0;
+        for window in windows {
+            let Rect {
+                x,
+                y,
+                width,
+                height,
+            } = window.rect;
+            if x + width >= right_edge {
+                continue;
+            }
+            for row in y..y + height {
+                self.draw_at(x + width, row, "|")?;
+            }
+        }
+        Ok(())
     }
 
     fn format_status_bar(
         cursor_controller: &CursorController,
         metadata: &FileMetadata,
         line_position: &str,
+        display_x: usize,
     ) -> String {
+        let column = if cursor_controller.cursor_x == display_x {
+            format!("{}", cursor_controller.cursor_x + 1)
+        } else {
+            format!("{}-{}", cursor_controller.cursor_x + 1, display_x + 1)
+        };
         let right_part = format!(
             "{},{}        {}",
             cursor_controller.cursor_y + 1,
-            cursor_controller.cursor_x + 1,
+            column,
             line_position
         );
 
-        let remaining_space =
-            cursor_controller.screen_columns - metadata.file_path.len() - right_part.len();
-
-        format!(
-            "\n{}{}{}",
-            metadata.file_path,
-            " ".repeat(remaining_space),
-            right_part
-        )
+        let mut name = if metadata.file_path.is_empty() {
+            NO_NAME.to_string()
+        } else {
+            metadata.file_path.clone()
+        };
+        if metadata.new_file {
+            name.push_str(" [New]");
+        }
+        if metadata.read_only {
+            name.push_str(" [RO]");
+        }
+        let columns = cursor_controller.screen_columns;
+        let remaining_space = columns.saturating_sub(name.chars().count() + right_part.len());
+
+        // A narrow window only has room for the start of it
+        format!("{}{}{}", name, " ".repeat(remaining_space), right_part)
+            .chars()
+            .take(columns)
+            .collect()
     }
 
-    fn draw_status_bar(&mut self, piece_table: &PieceTable, mode: &Mode, metadata: &FileMetadata) {
-        let lines = &piece_table.lines();
-
-        let line_percent =
-            Self::calculate_line_percent(lines, self.editor_view.cursor_controller.cursor_y);
-
-        let status_bar =
-            Self::format_status_bar(&self.editor_view.cursor_controller, metadata, &line_percent);
+    // The choices Tab is cycling through, with the one showing in reverse video. When they don't
+    // all fit, the ones before the selected one are left off
+    fn wildmenu(completion: &Completion, columns: usize) -> String {
+        let width = |candidates: &[String]| {
+            candidates
+                .iter()
+                .map(|candidate| display_column(candidate, usize::MAX) + 2)
+                .sum::<usize>()
+        };
+        let selected = completion.selected.unwrap_or(0);
+        let candidates = &completion.candidates;
+        let first = (0..selected)
+            .find(|&first| width(&candidates[first..=selected]) <= columns)
+            .unwrap_or(selected);
+        let mut used = 0;
+        let mut menu = String::new();
+        for (i, candidate) in candidates.iter().enumerate().skip(first) {
+            used += display_column(candidate, usize::MAX) + 2;
+            if used > columns && i > selected {
+                break;
+            }
+            if Some(i) == completion.selected {
+                menu.push_str(&candidate.clone().reverse().to_string());
+            } else {
+                menu.push_str(candidate);
+            }
+            menu.push_str("  ");
+        }
+        menu
+    }
 
-        self.editor_contents.push_str(&status_bar);
+    // Draws the mode, message or command being typed on the bottom line. A message longer
+    // than a line, like the one from `:registers`, covers the windows above it, and so does
+    // the wildmenu
+    fn draw_command_line(
+        &mut self,
+        piece_table: &PieceTable,
+        mode: &Mode,
+        pending: &str,
+        recording: Option<char>,
+        metadata: &FileMetadata,
+    ) -> io::Result<()> {
+        let (columns, rows) = self.screen_size;
+        let bottom = rows.saturating_sub(1);
+        if let Mode::Command(CommandLine {
+            completion: Some(completion),
+            ..
+        }) = mode
+        {
+            let menu = Self::wildmenu(completion, columns);
+            queue!(
+                self.editor_contents,
+                cursor::MoveTo(0, bottom.saturating_sub(1) as u16),
+                terminal::Clear(ClearType::CurrentLine)
+            )?;
+            self.editor_contents.push_str(&menu);
+        }
 
+        let num_lines = piece_table.line_count();
         let mode_label = match mode {
             Mode::Insert => Self::INSERT_MODE_LABEL.to_string(),
-            Mode::Command { previous_chars } => {
-                format!(":{}", previous_chars)
+            Mode::Replace => Self::REPLACE_MODE_LABEL.to_string(),
+            Mode::Visual { kind, .. } => match kind {
+                RangeKind::Charwise => Self::VISUAL_MODE_LABEL.to_string(),
+                RangeKind::Linewise => Self::VISUAL_LINE_MODE_LABEL.to_string(),
+                RangeKind::Blockwise => Self::VISUAL_BLOCK_MODE_LABEL.to_string(),
+            },
+            Mode::Command(line) => format!(":{}", line.display().0),
+            Mode::Search {
+                forward,
+                previous_chars,
+                ..
+            } => {
+                let direction = if *forward { '/' } else { '?' };
+                format!("{}{}", direction, previous_chars)
             }
             Mode::Normal(Some(BarMode::Write)) => format!(
                 "\"{}\" {}L, {}B written",
                 metadata.file_path,
-                lines.len(),
+                num_lines,
                 metadata.file_size.unwrap_or({
                     info!("File size not found");
                     0
                 })
             ),
+            Mode::Normal(Some(BarMode::Message(message))) => message.clone(),
+            Mode::Confirm { prompt } => prompt.clone(),
             _ => "".to_string(),
         };
+        let mode_label = match (recording, mode) {
+            (
+                Some(name),
+                Mode::Insert | Mode::Replace | Mode::Visual { .. } | Mode::Normal(None),
+            ) => {
+                format!("{}recording @{}", mode_label, name)
+            }
+            _ => mode_label,
+        };
 
-        self.editor_contents.push_str(&format!("\n{mode_label}"));
+        let showcmd = if pending.is_empty() {
+            String::new()
+        } else {
+            let column = columns.saturating_sub(Self::SHOWCMD_COLUMNS);
+            let last_line = mode_label.lines().last().unwrap_or_default();
+            let padding = column.saturating_sub(display_column(last_line, usize::MAX));
+            // `Ctrl-w` is shown the way vim does
+            let pending = pending.replace(WINDOW_KEY, "^W");
+            format!("{}{}", " ".repeat(padding), pending)
+        };
+
+        let text = format!("{mode_label}{showcmd}");
+        let lines: Vec<&str> = text.lines().collect();
+        let top = (bottom + 1).saturating_sub(lines.len().max(1));
+        for (row, line) in lines.iter().enumerate() {
+            queue!(
+                self.editor_contents,
+                cursor::MoveTo(0, (top + row) as u16),
+                terminal::Clear(ClearType::CurrentLine)
+            )?;
+            self.editor_contents.push_str(line);
+        }
+        Ok(())
     }
 
     fn refresh_screen(
         &mut self,
-        piece_table: &PieceTable,
+        tab_labels: &[(String, bool)],
+        windows: &[WindowDraw],
         mode: &Mode,
-        metadata: &FileMetadata,
+        pending: &str,
+        recording: Option<char>,
+        highlight: Option<&Pattern>,
     ) -> io::Result<()> {
         queue!(
             self.editor_contents,
@@ -359,35 +792,44 @@ impl Output {
             cursor::MoveTo(0, 0)
         )?;
 
-        self.draw_content(piece_table);
-        self.draw_status_bar(piece_table, mode, metadata);
+        if !tab_labels.is_empty() {
+            self.draw_tabline(tab_labels)?;
+        }
+        let several = windows.len() > 1;
+        for window in windows {
+            self.draw_window(window, mode, highlight, several)?;
+        }
+        self.draw_separators(windows)?;
+        let Some(current) = windows.iter().find(|window| window.current) else {
+            return self.editor_contents.flush();
+        };
+        let buffer = current.buffer;
+        self.draw_command_line(
+            &buffer.piece_table,
+            mode,
+            pending,
+            recording,
+            &buffer.metadata,
+        )?;
 
+        let bottom = self.screen_size.1.saturating_sub(1);
+        let cursor_controller = &current.view.cursor_controller;
         let (cursor_x, cursor_y) = match mode {
-            Mode::Insert => (
-                self.editor_view.cursor_controller.cursor_x,
-                self.editor_view
-                    .cursor_controller
-                    .cursor_y
-                    .saturating_sub(self.editor_view.scroll_y),
-            ),
-            Mode::Command { previous_chars } => {
-                let cursor_y = self.editor_view.cursor_controller.screen_rows - 1;
-                let cursor_x = previous_chars.len() + Output::COMMAND_CURSOR_Y_OFFSET;
-                (cursor_x, cursor_y)
-            }
-            Mode::Normal(Some(BarMode::Write)) => (
-                self.editor_view.cursor_controller.cursor_x,
-                self.editor_view
-                    .cursor_controller
-                    .cursor_y
-                    .saturating_sub(self.editor_view.scroll_y),
-            ),
+            Mode::Command(line) => {
+                let (_, column) = line.display();
+                (column + Output::COMMAND_CURSOR_Y_OFFSET, bottom)
+            }
+            Mode::Search { previous_chars, .. } => {
+                let cursor_x =
+                    display_column(previous_chars, usize::MAX) + Output::COMMAND_CURSOR_Y_OFFSET;
+                (cursor_x, bottom)
+            }
             _ => (
-                self.editor_view.cursor_controller.cursor_x,
-                self.editor_view
-                    .cursor_controller
-                    .cursor_y
-                    .saturating_sub(self.editor_view.scroll_y),
+                current.rect.x + cursor_controller.display_x(&buffer.piece_table),
+                current.rect.y
+                    + cursor_controller
+                        .cursor_y
+                        .saturating_sub(current.view.scroll_y),
             ),
         };
 
@@ -400,110 +842,942 @@ impl Output {
     }
 }
 
+// What `Output` needs to draw a window
+#[derive(Clone, Copy)]
+struct WindowDraw<'a> {
+    rect: Rect,
+    view: &'a EditorView,
+    buffer: &'a Buffer,
+    // Whether it's the window being typed in
+    current: bool,
+}
+
 struct Reader;
 
 impl Reader {
-    fn read_key(&self) -> io::Result<KeyEvent> {
+    // The next key typed or the terminal coming back into focus,
+    // or None if neither happens within `timeout`
+    fn read_event(&self, timeout: Duration) -> io::Result<Option<Event>> {
+        let start = Instant::now();
         loop {
-            if let Event::Key(event) = event::read()? {
-                return Ok(event);
+            if !event::poll(timeout.saturating_sub(start.elapsed()))? {
+                return Ok(None);
+            }
+            if let event @ (Event::Key(_) | Event::FocusGained) = event::read()? {
+                return Ok(Some(event));
             }
         }
     }
 }
 
+// The file `q:` put aside while the command-line window is open
+struct CommandWindow {
+    piece_table: PieceTable,
+    metadata: FileMetadata,
+    cursor: (usize, usize),
+}
+
+// A window onto a buffer, with its own cursor and scroll position
+struct Window {
+    id: usize,
+    // The number of the buffer it shows
+    buffer: usize,
+    view: EditorView,
+}
+
+// The windows of a tab page, put aside while another tab page is shown
+struct TabPage {
+    windows: Vec<Window>,
+    layout: Layout,
+    current_window: usize,
+}
+
 pub struct Editor {
     reader: Reader,
     output: Output,
-    piece_table: PieceTable,
+    buffers: BufferList,
+    windows: Vec<Window>,
+    layout: Layout,
+    // Which of `windows` is being typed in. Its buffer is always the current buffer
+    current_window: usize,
+    last_window_id: usize,
+    // The other tab pages in order. The current one goes at `current_tab` among them
+    tab_pages: Vec<TabPage>,
+    current_tab: usize,
     key_handler: KeyHandler,
-    metadata: FileMetadata,
+    command_window: Option<CommandWindow>,
+    // Whether buffers get swap files, which is left off for tests
+    swap_files: bool,
+    // A swap file found on opening the current buffer, until the question about it is answered
+    found_swap: Option<PathBuf>,
 }
 
 impl Default for Editor {
     fn default() -> Self {
-        Self {
-            reader: Reader,
-            output: Output::new(),
-            piece_table: PieceTable::default(),
-            key_handler: KeyHandler::new(),
-            metadata: FileMetadata::new(String::new()),
-        }
+        Self::new("", String::new())
     }
 }
 
 impl Editor {
     pub fn new(original_text: &str, file_path: String) -> Self {
-        Self {
+        Self::with_buffer(Buffer::new(original_text, file_path))
+    }
+
+    // Starts with the file at `file_path`, which is a new file if there's nothing there yet
+    pub fn open(file_path: String) -> io::Result<Self> {
+        Ok(Self::with_buffer(Buffer::open(file_path)?))
+    }
+
+    fn with_buffer(buffer: Buffer) -> Self {
+        let output = Output::new();
+        let buffers = BufferList::new(buffer);
+        let window = Window {
+            id: 0,
+            buffer: buffers.current().number,
+            view: EditorView::new(output.screen_size),
+        };
+        let mut editor = Self {
             reader: Reader,
-            output: Output::new(),
-            piece_table: PieceTable::new(original_text),
+            output,
+            buffers,
+            windows: vec![window],
+            layout: Layout::Window(0),
+            current_window: 0,
+            last_window_id: 0,
+            tab_pages: Vec::new(),
+            current_tab: 0,
             key_handler: KeyHandler::new(),
-            metadata: FileMetadata::new(file_path),
+            command_window: None,
+            swap_files: false,
+            found_swap: None,
+        };
+        editor.key_handler.buffer_names = editor.buffers.names();
+        editor
+    }
+
+    // Opens another file in a buffer after the others, like the rest of the files given to `main`
+    pub fn add_file(&mut self, file_path: String) -> io::Result<()> {
+        self.buffers.add(Buffer::open(file_path)?);
+        self.key_handler.buffer_names = self.buffers.names();
+        Ok(())
+    }
+
+    // Keeps the command history in `path` between sessions
+    pub fn load_command_history(&mut self, path: PathBuf) {
+        self.key_handler.command_history = CommandHistory::load(path);
+    }
+
+    // Gives each buffer a swap file from when it is first shown, starting with the current one
+    pub fn open_swap(&mut self) {
+        self.swap_files = true;
+        self.check_swap();
+    }
+
+    // Where the windows go, below the tabline if there is one
+    fn window_area(&self) -> Rect {
+        self.output.window_area(!self.tab_pages.is_empty())
+    }
+
+    fn view(&self) -> &EditorView {
+        &self.windows[self.current_window].view
+    }
+
+    // Starts a swap file for the current buffer, or asks what to do with the one already there
+    fn check_swap(&mut self) {
+        let buffer = self.buffers.current_mut();
+        // A buffer without a file has nowhere to put one until it is written
+        if !self.swap_files
+            || buffer.metadata.file_path.is_empty()
+            || std::mem::replace(&mut buffer.swap_checked, true)
+        {
+            return;
         }
+        let path = swap_path(&buffer.metadata.file_path);
+        if !path.exists() {
+            self.start_swap(path);
+            return;
+        }
+        let owner = match swap_owner(&path) {
+            Ok(pid) if is_running(pid) => format!(" by process {} (still running)", pid),
+            Ok(pid) => format!(" by process {}", pid),
+            Err(_) => String::new(),
+        };
+        let prompt = format!(
+            "Found a swap file \"{}\"{}: [R]ecover, [D]elete it, [O]pen read-only, [Q]uit",
+            path.display(),
+            owner
+        );
+        self.found_swap = Some(path);
+        self.key_handler.mode = Mode::Confirm { prompt };
     }
 
-    fn process_keypress(&mut self) -> io::Result<bool> {
-        let key_event = self.reader.read_key()?;
-        let lines = self.piece_table.lines();
-
-        match self.key_handler.mode {
-            Mode::Normal(_) => self.key_handler.normal_keypress(
-                key_event,
-                lines,
-                self.metadata.file_path.clone(),
-                &mut self.piece_table,
-                &mut self.output.editor_view.cursor_controller,
-            ),
-            Mode::Insert => self.key_handler.insert_keypress(
-                key_event,
-                lines,
-                &mut self.piece_table,
-                &mut self.output.editor_view.cursor_controller,
-            ),
-            Mode::Command { .. } => self.key_handler.command_keypress(
-                key_event,
-                &mut self.piece_table,
-                &mut self.metadata,
+    fn start_swap(&mut self, path: PathBuf) {
+        let buffer = self.buffers.current_mut();
+        match SwapFile::create(path, &mut buffer.piece_table, &buffer.metadata) {
+            Ok(swap) => {
+                buffer.swap = Some(swap);
+                buffer.swap_written = buffer.metadata.last_write_time;
+            }
+            // Editing goes on without one, like in a directory that can't be written to
+            Err(error) => info!("Couldn't create a swap file: {}", error),
+        }
+    }
+
+    // Keeps the swap files up to date, starting one again whenever its file is written
+    fn sync_swap(&mut self, idle: bool) {
+        // The command-line window's text isn't the file's
+        if self.command_window.is_some() {
+            return;
+        }
+        for buffer in self.buffers.iter_mut() {
+            let Some(swap) = &mut buffer.swap else {
+                continue;
+            };
+            let result = if buffer.metadata.last_write_time != buffer.swap_written {
+                buffer.swap_written = buffer.metadata.last_write_time;
+                swap.restart(&mut buffer.piece_table, &buffer.metadata)
+            } else if idle || swap.is_due(&buffer.piece_table) {
+                swap.sync(&mut buffer.piece_table)
+            } else {
+                Ok(())
+            };
+            if let Err(error) = result {
+                // Trying again each time would only fail again
+                error!("Couldn't write to {:?}: {}", swap.path(), error);
+                buffer.swap = None;
+                self.key_handler.mode = Mode::Normal(Some(BarMode::Message(format!(
+                    "E297: Write error in swap file: {}",
+                    error
+                ))));
+            }
+        }
+    }
+
+    fn remove_swap(buffer: &mut Buffer) {
+        if let Some(swap) = buffer.swap.take() {
+            if let Err(error) = swap.remove() {
+                error!("Couldn't remove the swap file: {}", error);
+            }
+        }
+    }
+
+    // Answers the question asked by `check_swap`
+    fn swap_keypress(&mut self, key_event: KeyEvent, path: PathBuf) -> io::Result<bool> {
+        let answer = match key_event.code {
+            KeyCode::Char(ch) => ch.to_ascii_lowercase(),
+            _ => ' ',
+        };
+        match answer {
+            'r' => {
+                if self.recover_from(&path) {
+                    self.start_swap(path);
+                } else {
+                    self.buffers.current_mut().metadata.read_only = true;
+                }
+            }
+            // Starting a new swap file writes over the old one
+            'd' => {
+                self.key_handler.mode = Mode::Normal(None);
+                self.start_swap(path);
+            }
+            'o' => {
+                self.key_handler.mode = Mode::Normal(None);
+                self.buffers.current_mut().metadata.read_only = true;
+            }
+            'q' => return Ok(false),
+            _ => self.found_swap = Some(path),
+        }
+        Ok(true)
+    }
+
+    // `:recover` reads the file's swap file, unless it is this editor's own
+    fn recover_swap(&mut self) {
+        let buffer = self.buffers.current();
+        let path = swap_path(&buffer.metadata.file_path);
+        let own = buffer.swap.as_ref().is_some_and(|swap| swap.path() == path);
+        if own || !path.exists() {
+            let error = CommandError::NoSwapFile(buffer.metadata.file_path.clone());
+            self.show_message(error.to_string());
+            return;
+        }
+        self.recover_from(&path);
+    }
+
+    // Replaces the text with what the swap file at `path` held, as one change that `u` undoes
+    fn recover_from(&mut self, path: &Path) -> bool {
+        let text = match swap::recover(path) {
+            Ok(text) => text,
+            Err(error) => {
+                self.show_message(format!(
+                    "E306: Cannot open \"{}\": {}",
+                    path.display(),
+                    error
+                ));
+                return false;
+            }
+        };
+        replace_text(
+            &text,
+            &mut self.buffers.current_mut().piece_table,
+            &mut self.windows[self.current_window].view.cursor_controller,
+        );
+        self.show_message(format!(
+            "Recovered from \"{}\". Check the text, then write it",
+            path.display()
+        ));
+        true
+    }
+
+    // Warns once about each change something else makes to the current buffer's file
+    fn check_disk(&mut self) -> bool {
+        // Not in the middle of a command
+        if !matches!(self.key_handler.mode, Mode::Normal(_)) || !self.key_handler.pending.is_empty()
+        {
+            return false;
+        }
+        let buffer = self.buffers.current_mut();
+        let change = buffer.metadata.disk_change();
+        if change == buffer.disk_warned {
+            return false;
+        }
+        buffer.disk_warned = change.clone();
+        let path = &buffer.metadata.file_path;
+        let message = match change {
+            None => return false,
+            Some(DiskChange::Deleted) => format!("E211: File \"{}\" no longer available", path),
+            Some(DiskChange::Modified { .. }) => format!(
+                "W11: Warning: File \"{}\" has changed since editing started. \
+                 :e! loads it, :w! writes over it",
+                path
             ),
+        };
+        self.show_message(message);
+        true
+    }
+
+    fn show_message(&mut self, message: String) {
+        self.key_handler.mode = Mode::Normal(Some(BarMode::Message(message)));
+    }
+
+    // Carries out a command the key handler left, returning false to quit
+    fn buffer_command(&mut self, command: BufferCommand) -> bool {
+        // The command-line window stands in for the current buffer
+        if self.command_window.is_some() {
+            if let BufferCommand::Quit { .. } = command {
+                self.close_command_window();
+            } else {
+                self.show_message(COMMAND_WINDOW_ERROR.to_string());
+            }
+            return true;
+        }
+        let result = match command {
+            // Buffers stay in the list, so closing one of several windows loses nothing
+            BufferCommand::Quit { all: false, .. }
+                if self.windows.len() > 1 || !self.tab_pages.is_empty() =>
+            {
+                self.close_window()
+            }
+            BufferCommand::Quit { bang, .. } => {
+                let current = self.buffers.current();
+                let modified = self
+                    .buffers
+                    .iter()
+                    .find(|buffer| buffer.piece_table.is_modified());
+                match modified {
+                    Some(_) if bang => return false,
+                    Some(buffer) if buffer.number == current.number => {
+                        Err(CommandError::NotSaved.to_string())
+                    }
+                    Some(buffer) => Err(format!(
+                        "E162: No write since last change for buffer \"{}\"",
+                        buffer.name()
+                    )),
+                    None => return false,
+                }
+            }
+            BufferCommand::Edit(file_path) => self.edit_file(file_path),
+            BufferCommand::Next(count) => {
+                self.go_to_buffer(self.buffers.next_number(count, true));
+                Ok(())
+            }
+            BufferCommand::Previous(count) => {
+                self.go_to_buffer(self.buffers.next_number(count, false));
+                Ok(())
+            }
+            BufferCommand::Go(argument) => self
+                .buffers
+                .find(&argument)
+                .map(|number| self.go_to_buffer(number))
+                .map_err(|error| error.to_string()),
+            BufferCommand::List => {
+                self.save_view();
+                self.show_message(self.buffers.list().join("\n"));
+                Ok(())
+            }
+            BufferCommand::Delete { buffer, bang } => self.delete_buffer(&buffer, bang),
+        };
+        if let Err(message) = result {
+            self.show_message(message);
+        }
+        self.key_handler.buffer_names = self.buffers.names();
+        true
+    }
+
+    // `:e file` shows the buffer for the file, opening it first if there isn't one
+    fn edit_file(&mut self, file_path: String) -> Result<(), String> {
+        let number = match self.buffers.find_file(&file_path) {
+            Some(number) => number,
+            None => {
+                let buffer = Buffer::open(file_path.clone())
+                    .map_err(|error| format!("\"{}\" {}", file_path, error))?;
+                self.buffers.add(buffer)
+            }
+        };
+        self.go_to_buffer(number);
+        Ok(())
+    }
+
+    // `:bd` takes a buffer out of the list, which needs `!` if it has changes.
+    // Windows showing it show the buffer that takes its place instead
+    fn delete_buffer(&mut self, argument: &str, bang: bool) -> Result<(), String> {
+        let number = self
+            .buffers
+            .find(argument)
+            .map_err(|error| error.to_string())?;
+        let modified = self
+            .buffers
+            .iter()
+            .any(|buffer| buffer.number == number && buffer.piece_table.is_modified());
+        if modified && !bang {
+            return Err(format!(
+                "E89: No write since last change for buffer {} (add ! to override)",
+                number
+            ));
+        }
+        self.save_view();
+        let mut buffer = self
+            .buffers
+            .remove(number)
+            .map_err(|error| error.to_string())?;
+        Self::remove_swap(&mut buffer);
+        let replacement = self.buffers.current();
+        let tab_windows = self.tab_pages.iter_mut().flat_map(|tab| &mut tab.windows);
+        for window in self.windows.iter_mut().chain(tab_windows) {
+            if window.buffer == number {
+                window.buffer = replacement.number;
+                window
+                    .view
+                    .cursor_controller
+                    .restore_position(replacement.cursor, &replacement.piece_table);
+                window.view.scroll_y = replacement.scroll_y;
+            }
+        }
+        if self.windows[self.current_window].buffer != replacement.number {
+            let buffer = self.windows[self.current_window].buffer;
+            self.buffers.switch_to(buffer);
+        }
+        self.show_buffer();
+        Ok(())
+    }
+
+    // Keeps where the cursor and the view are in the current buffer, for when it is shown again
+    fn save_view(&mut self) {
+        let view = &self.windows[self.current_window].view;
+        let buffer = self.buffers.current_mut();
+        buffer.cursor = (
+            view.cursor_controller.cursor_x(),
+            view.cursor_controller.cursor_y(),
+        );
+        buffer.scroll_y = view.scroll_y;
+    }
+
+    // Shows buffer `number` in the current window, the way it was left
+    fn go_to_buffer(&mut self, number: usize) {
+        self.save_view();
+        self.buffers.switch_to(number);
+        let buffer = self.buffers.current();
+        let view = &mut self.windows[self.current_window].view;
+        view.cursor_controller
+            .restore_position(buffer.cursor, &buffer.piece_table);
+        view.scroll_y = buffer.scroll_y;
+        self.windows[self.current_window].buffer = number;
+        self.show_buffer();
+    }
+
+    // Shows the current buffer's name and size below
+    fn show_buffer(&mut self) {
+        let buffer = self.buffers.current();
+        let modified = if buffer.piece_table.is_modified() {
+            " [Modified]"
+        } else {
+            ""
+        };
+        let message = if buffer.metadata.new_file && !buffer.piece_table.is_modified() {
+            format!("\"{}\" [New]", buffer.name())
+        } else {
+            format!(
+                "\"{}\"{} {}L, {}B",
+                buffer.name(),
+                modified,
+                buffer.piece_table.line_count(),
+                buffer.piece_table.len()
+            )
+        };
+        self.show_message(message);
+        self.check_swap();
+    }
+
+    // Carries out a command about windows from the key handler
+    fn window_command(&mut self, command: WindowCommand) {
+        if self.command_window.is_some() {
+            self.show_message(COMMAND_WINDOW_ERROR.to_string());
+            return;
+        }
+        let area = self.window_area();
+        let id = self.windows[self.current_window].id;
+        let result = match command {
+            WindowCommand::Split {
+                vertical,
+                file_path,
+            } => self.split_window(vertical, file_path),
+            WindowCommand::Focus(direction) => {
+                if let Some(neighbour) = self.layout.neighbour(id, direction, area) {
+                    self.go_to_window(neighbour);
+                }
+                Ok(())
+            }
+            WindowCommand::Next => {
+                let order = self.layout.rects(area);
+                let index = order.iter().position(|&(window, _)| window == id);
+                let next = index.map_or(0, |index| (index + 1) % order.len());
+                self.go_to_window(order[next].0);
+                Ok(())
+            }
+            WindowCommand::Equalize => {
+                self.layout.equalize(area);
+                Ok(())
+            }
+            WindowCommand::Only => {
+                self.layout = Layout::Window(id);
+                self.windows.retain(|window| window.id == id);
+                self.current_window = 0;
+                Ok(())
+            }
+            WindowCommand::Close => self.close_window(),
+            WindowCommand::TabNew(file_path) => self.new_tab(file_path),
+            WindowCommand::TabNext(None) => {
+                self.go_to_tab((self.current_tab + 1) % self.tab_count());
+                Ok(())
+            }
+            WindowCommand::TabNext(Some(number)) => {
+                if number == 0 || number > self.tab_count() {
+                    Err(format!("E475: Invalid argument: {}", number))
+                } else {
+                    self.go_to_tab(number - 1);
+                    Ok(())
+                }
+            }
+            WindowCommand::TabPrevious(count) => {
+                let tab_count = self.tab_count();
+                let back = count % tab_count;
+                self.go_to_tab((self.current_tab + tab_count - back) % tab_count);
+                Ok(())
+            }
+            WindowCommand::TabClose => self.close_tab(),
+        };
+        if let Err(message) = result {
+            self.show_message(message);
+        }
+        self.key_handler.buffer_names = self.buffers.names();
+    }
+
+    // Puts a new window above the current one, or to the left of it, showing the same buffer
+    // or else `file_path`
+    fn split_window(&mut self, vertical: bool, file_path: String) -> Result<(), String> {
+        let area = self.window_area();
+        let id = self.last_window_id + 1;
+        let current = &self.windows[self.current_window];
+        self.layout
+            .split(current.id, id, vertical, area)
+            .map_err(|error| error.to_string())?;
+        self.last_window_id = id;
+        let window = Window {
+            id,
+            buffer: current.buffer,
+            view: current.view.clone(),
+        };
+        self.windows.push(window);
+        self.current_window = self.windows.len() - 1;
+        if file_path.is_empty() {
+            Ok(())
+        } else {
+            self.edit_file(file_path)
+        }
+    }
+
+    // Closes the current window, going to the one before it.
+    // Closing the last window of a tab page closes the tab page
+    fn close_window(&mut self) -> Result<(), String> {
+        if self.windows.len() == 1 && !self.tab_pages.is_empty() {
+            return self.close_tab();
         }
+        let id = self.windows[self.current_window].id;
+        let order: Vec<usize> = self
+            .layout
+            .rects(self.window_area())
+            .iter()
+            .map(|&(window, _)| window)
+            .filter(|&window| window != id)
+            .collect();
+        let index = self
+            .layout
+            .rects(self.window_area())
+            .iter()
+            .position(|&(window, _)| window == id)
+            .unwrap_or(0);
+        self.layout.close(id).map_err(|error| error.to_string())?;
+        self.save_view();
+        self.windows.remove(self.current_window);
+        self.enter_window(order[index.saturating_sub(1)]);
+        Ok(())
+    }
+
+    fn go_to_window(&mut self, id: usize) {
+        self.save_view();
+        self.enter_window(id);
+    }
+
+    // Makes window `id` the one being typed in, and its buffer the current one
+    fn enter_window(&mut self, id: usize) {
+        let Some(index) = self.windows.iter().position(|window| window.id == id) else {
+            return;
+        };
+        self.current_window = index;
+        let window = &mut self.windows[index];
+        self.buffers.switch_to(window.buffer);
+        // Changes made in another window may have left the cursor past the end
+        let cursor = (
+            window.view.cursor_controller.cursor_x(),
+            window.view.cursor_controller.cursor_y(),
+        );
+        let piece_table = &self.buffers.current().piece_table;
+        window
+            .view
+            .cursor_controller
+            .restore_position(cursor, piece_table);
+        self.check_swap();
+    }
+
+    fn tab_count(&self) -> usize {
+        self.tab_pages.len() + 1
+    }
+
+    // Puts the windows of the current tab page aside for those of another
+    fn swap_tab(&mut self, tab: TabPage) -> TabPage {
+        TabPage {
+            windows: std::mem::replace(&mut self.windows, tab.windows),
+            layout: std::mem::replace(&mut self.layout, tab.layout),
+            current_window: std::mem::replace(&mut self.current_window, tab.current_window),
+        }
+    }
+
+    // Shows tab page `index`, counting from 0
+    fn go_to_tab(&mut self, index: usize) {
+        if index == self.current_tab {
+            return;
+        }
+        self.save_view();
+        // The current tab page goes back among the others first, so `index` counts all of them
+        let placeholder = TabPage {
+            windows: Vec::new(),
+            layout: Layout::Window(0),
+            current_window: 0,
+        };
+        let current = self.swap_tab(placeholder);
+        self.tab_pages.insert(self.current_tab, current);
+        let tab = self.tab_pages.remove(index);
+        self.swap_tab(tab);
+        self.current_tab = index;
+        self.enter_window(self.windows[self.current_window].id);
+    }
+
+    // `:tabnew` opens a tab page after the current one, with a window onto the current buffer
+    // or else `file_path`
+    fn new_tab(&mut self, file_path: String) -> Result<(), String> {
+        self.save_view();
+        let id = self.last_window_id + 1;
+        self.last_window_id = id;
+        let current = &self.windows[self.current_window];
+        let tab = TabPage {
+            windows: vec![Window {
+                id,
+                buffer: current.buffer,
+                view: current.view.clone(),
+            }],
+            layout: Layout::Window(id),
+            current_window: 0,
+        };
+        let current = self.swap_tab(tab);
+        self.tab_pages.insert(self.current_tab, current);
+        self.current_tab += 1;
+        if file_path.is_empty() {
+            Ok(())
+        } else {
+            self.edit_file(file_path)
+        }
+    }
+
+    // `:tabclose` goes to the tab page after the current one, or the one before the last
+    fn close_tab(&mut self) -> Result<(), String> {
+        if self.tab_pages.is_empty() {
+            return Err("E784: Cannot close last tab page".to_string());
+        }
+        self.save_view();
+        let index = self.current_tab.min(self.tab_pages.len() - 1);
+        let tab = self.tab_pages.remove(index);
+        self.swap_tab(tab);
+        self.current_tab = index;
+        self.enter_window(self.windows[self.current_window].id);
+        Ok(())
+    }
+
+    // A label for each tab page: its number, a `+` if any of its windows show changes and
+    // the name of the file in the window being typed in. The current one is marked as well
+    fn tab_labels(&self) -> Vec<(String, bool)> {
+        if self.tab_pages.is_empty() {
+            return Vec::new();
+        }
+        let (before, after) = self.tab_pages.split_at(self.current_tab);
+        let current = (self.windows.as_slice(), self.current_window);
+        let mut tabs: Vec<(&[Window], usize)> = before
+            .iter()
+            .map(|tab| (tab.windows.as_slice(), tab.current_window))
+            .collect();
+        tabs.push(current);
+        tabs.extend(
+            after
+                .iter()
+                .map(|tab| (tab.windows.as_slice(), tab.current_window)),
+        );
+        tabs.iter()
+            .enumerate()
+            .map(|(index, &(windows, current_window))| {
+                let modified = windows.iter().any(|window| {
+                    self.buffers
+                        .get(window.buffer)
+                        .is_some_and(|buffer| buffer.piece_table.is_modified())
+                });
+                let name = self.buffers.get(windows[current_window].buffer).map_or(
+                    String::new(),
+                    |buffer| {
+                        let path = Path::new(buffer.name());
+                        path.file_name()
+                            .unwrap_or(path.as_os_str())
+                            .to_string_lossy()
+                            .to_string()
+                    },
+                );
+                let modified = if modified { "+ " } else { "" };
+                let label = format!(" {} {}{} ", index + 1, modified, name);
+                (label, index == self.current_tab)
+            })
+            .collect()
+    }
+
+    fn process_keypress(&mut self) -> io::Result<bool> {
+        loop {
+            match self.reader.read_event(SYNC_IDLE_TIME)? {
+                Some(Event::Key(key_event)) => return self.handle_key(key_event),
+                // The file may have been changed while the terminal was in the background
+                Some(_) => {}
+                // Changes go to the swap file whenever typing stops for a while
+                None => self.sync_swap(true),
+            }
+            if self.check_disk() {
+                return Ok(true);
+            }
+        }
+    }
+
+    // Runs a typed key, then the keys of any macro it started
+    fn handle_key(&mut self, key_event: KeyEvent) -> io::Result<bool> {
+        if let Some(path) = self.found_swap.take() {
+            return self.swap_keypress(key_event, path);
+        }
+        self.key_handler.record_key(key_event);
+        let mut running = self.window_keypress(key_event)?;
+        while running {
+            let Some(key_event) = self.key_handler.queued_keys.pop_front() else {
+                break;
+            };
+            running = self.window_keypress(key_event)?;
+        }
+        self.key_handler.macro_plays = 0;
+        self.sync_swap(false);
+        Ok(running)
+    }
+
+    // Handles the keys that open, close and run lines from the command-line window,
+    // and the commands the editor carries out itself
+    fn window_keypress(&mut self, key_event: KeyEvent) -> io::Result<bool> {
+        let in_window = self.command_window.is_some();
+        let running = if in_window
+            && matches!(self.key_handler.mode, Mode::Normal(_))
+            && key_event.code == KeyCode::Enter
+        {
+            let y = self.view().cursor_controller.cursor_y();
+            let buffer = self.buffers.current();
+            let line = buffer.piece_table.line(y).unwrap_or_default();
+            self.close_command_window();
+            let buffer = self.buffers.current_mut();
+            self.key_handler.execute_command_line(
+                line,
+                &mut buffer.piece_table,
+                &mut buffer.metadata,
+                &mut self.windows[self.current_window].view.cursor_controller,
+            )?
+        } else {
+            self.test_process_keypress(key_event)?
+        };
+
+        if std::mem::take(&mut self.key_handler.open_command_window) && !in_window {
+            self.open_command_window();
+        }
+        if std::mem::take(&mut self.key_handler.recover) {
+            self.recover_swap();
+        }
+        // A buffer without a file may have just been written to one
+        if !self.buffers.current().swap_checked {
+            self.key_handler.buffer_names = self.buffers.names();
+            self.check_swap();
+        }
+        if let Some(command) = self.key_handler.window_command.take() {
+            self.window_command(command);
+        }
+        let command = self.key_handler.buffer_command.take();
+        // `:q` in the command-line window only closes it
+        if !running && self.command_window.is_some() {
+            self.close_command_window();
+            return Ok(true);
+        }
+        Ok(match command {
+            Some(command) => self.buffer_command(command),
+            None => running,
+        })
+    }
+
+    // Swaps the file for the command history, with an empty line at the end to type a new command
+    fn open_command_window(&mut self) {
+        let mut history: String = self
+            .key_handler
+            .command_history
+            .entries()
+            .iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect();
+        history.push('\n');
+        let cursor_controller = &mut self.windows[self.current_window].view.cursor_controller;
+        let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+        let buffer = self.buffers.current_mut();
+        let piece_table = std::mem::replace(&mut buffer.piece_table, PieceTable::new(&history));
+        let metadata = std::mem::replace(
+            &mut buffer.metadata,
+            FileMetadata::new("[Command Line]".to_string()),
+        );
+        self.command_window = Some(CommandWindow {
+            piece_table,
+            metadata,
+            cursor,
+        });
+        let last = buffer.piece_table.line_count().saturating_sub(1);
+        cursor_controller.restore_position((0, last), &buffer.piece_table);
+        self.key_handler.mode = Mode::Normal(None);
+    }
+
+    fn close_command_window(&mut self) {
+        let Some(window) = self.command_window.take() else {
+            return;
+        };
+        let buffer = self.buffers.current_mut();
+        buffer.piece_table = window.piece_table;
+        buffer.metadata = window.metadata;
+        self.windows[self.current_window]
+            .view
+            .cursor_controller
+            .restore_position(window.cursor, &buffer.piece_table);
+        self.key_handler.mode = Mode::Normal(None);
     }
 
     fn test_process_keypress(&mut self, key_event: KeyEvent) -> io::Result<bool> {
-        let lines = self.piece_table.lines();
-
-        match self.key_handler.mode {
-            Mode::Normal(_) => self.key_handler.normal_keypress(
-                key_event,
-                lines,
-                self.metadata.file_path.clone(),
-                &mut self.piece_table,
-                &mut self.output.editor_view.cursor_controller,
-            ),
-            Mode::Insert => self.key_handler.insert_keypress(
-                key_event,
-                lines,
-                &mut self.piece_table,
-                &mut self.output.editor_view.cursor_controller,
-            ),
-            Mode::Command { .. } => self.key_handler.command_keypress(
-                key_event,
-                &mut self.piece_table,
-                &mut self.metadata,
-            ),
+        let buffer = self.buffers.current_mut();
+        self.key_handler.process_key(
+            key_event,
+            &mut buffer.piece_table,
+            &mut buffer.metadata,
+            &mut self.windows[self.current_window].view.cursor_controller,
+        )
+    }
+
+    fn refresh_screen(&mut self) -> io::Result<()> {
+        let rects = self.layout.rects(self.window_area());
+        for (index, window) in self.windows.iter_mut().enumerate() {
+            let Some(&(_, rect)) = rects.iter().find(|&&(id, _)| id == window.id) else {
+                continue;
+            };
+            window.view.resize(rect);
+            // Another window showing the same buffer may have deleted the lines under the cursor
+            if index != self.current_window {
+                if let Some(buffer) = self.buffers.get(window.buffer) {
+                    let cursor_controller = &mut window.view.cursor_controller;
+                    let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+                    cursor_controller.restore_position(cursor, &buffer.piece_table);
+                }
+            }
+            window.view.update_scroll();
         }
+
+        let windows: Vec<WindowDraw> = rects
+            .iter()
+            .filter_map(|&(id, rect)| {
+                let (index, window) = self
+                    .windows
+                    .iter()
+                    .enumerate()
+                    .find(|(_, window)| window.id == id)?;
+                Some(WindowDraw {
+                    rect,
+                    view: &window.view,
+                    buffer: self.buffers.get(window.buffer)?,
+                    current: index == self.current_window,
+                })
+            })
+            .collect();
+        self.output.refresh_screen(
+            &self.tab_labels(),
+            &windows,
+            &self.key_handler.mode,
+            &self.key_handler.pending,
+            self.key_handler.recording.as_ref().map(|(name, _)| *name),
+            self.key_handler.highlight().as_ref(),
+        )
     }
 
     pub fn run(&mut self) -> io::Result<bool> {
-        self.output
-            .refresh_screen(&self.piece_table, &self.key_handler.mode, &self.metadata)?;
-        self.piece_table.merge();
-        self.process_keypress()
+        self.refresh_screen()?;
+        let running = self.process_keypress()?;
+        if !running {
+            for buffer in self.buffers.iter_mut() {
+                Self::remove_swap(buffer);
+            }
+        }
+        Ok(running)
     }
 
     pub fn test_run(&mut self, key_event: KeyEvent) -> io::Result<bool> {
-        self.output
-            .refresh_screen(&self.piece_table, &self.key_handler.mode, &self.metadata)?;
-        self.test_process_keypress(key_event)
+        self.refresh_screen()?;
+        self.handle_key(key_event)
     }
 }
+
+const COMMAND_WINDOW_ERROR: &str =
+    "E11: Invalid in command-line window; <CR> executes, CTRL-C quits";
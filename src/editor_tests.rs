@@ -2,11 +2,33 @@
 mod tests {
     use crate::editor::Editor;
     use crate::file;
+    use crate::metadata::FileMetadata;
+    use crate::piece_table::PieceTable;
+    use crate::swap::{swap_path, SwapFile};
     use crate::utils::{control_key_event, create_key_event, string_to_key_events};
     use crossterm::event::{KeyCode, KeyEvent};
     use std::fs;
     use std::io::Write;
-    use tempfile::NamedTempFile;
+    use tempfile::{tempdir, NamedTempFile};
+
+    // Opens `contents` in an editor, types `key_events` followed by Ctrl-s and returns the saved file
+    fn edit_file(
+        contents: &str,
+        key_events: Vec<KeyEvent>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut temp_file = NamedTempFile::new().expect("Failed to create temp file");
+        write!(temp_file, "{}", contents).expect("Failed to write to temp file");
+        let file_path = temp_file.path().to_str().unwrap();
+
+        let original_text = file::load_file(file_path)?;
+        let mut editor = Editor::new(&original_text, file_path.to_string());
+        for key_event in key_events {
+            editor.test_run(key_event)?;
+        }
+        editor.test_run(control_key_event(KeyCode::Char('s')))?;
+
+        Ok(fs::read_to_string(file_path)?)
+    }
 
     #[test]
     fn test_editor_flow() -> Result<(), Box<dyn std::error::Error>> {
@@ -38,7 +60,7 @@ mod tests {
         add_repeated_keys(&mut key_events, KeyCode::Delete, 11);
         key_events.push(create_key_event(KeyCode::Esc));
 
-        key_events.push(control_key_event(KeyCode::Char('w')));
+        key_events.push(control_key_event(KeyCode::Char('s')));
         key_events.push(control_key_event(KeyCode::Char('q')));
 
         for key_event in key_events {
@@ -52,4 +74,536 @@ mod tests {
         assert_eq!(saved_content, "Hello, world!\nThis is an edited test file.");
         Ok(())
     }
+
+    #[test]
+    fn test_undo_insert_session() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = vec![create_key_event(KeyCode::Char('i'))];
+        key_events.extend(string_to_key_events(String::from("the ")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.push(create_key_event(KeyCode::Char('j')));
+        key_events.push(create_key_event(KeyCode::Char('a')));
+        key_events.extend(string_to_key_events(String::from("typo")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.push(create_key_event(KeyCode::Char('u')));
+        key_events.push(create_key_event(KeyCode::Char('u')));
+        key_events.push(control_key_event(KeyCode::Char('r')));
+
+        let saved_content = edit_file("first line\nsecond line", key_events)?;
+        assert_eq!(saved_content, "the first line\nsecond line");
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_multibyte_text() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = vec![create_key_event(KeyCode::Char('l'))];
+        key_events.push(create_key_event(KeyCode::Char('i')));
+        key_events.push(create_key_event(KeyCode::Delete));
+        key_events.extend(string_to_key_events(String::from("é")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.push(create_key_event(KeyCode::Char('j')));
+        key_events.push(create_key_event(KeyCode::Char('a')));
+        key_events.extend(string_to_key_events(String::from("語")));
+        key_events.push(create_key_event(KeyCode::Backspace));
+        key_events.extend(string_to_key_events(String::from("人")));
+        key_events.push(create_key_event(KeyCode::Esc));
+
+        let saved_content = edit_file("über naïve\n日本", key_events)?;
+        assert_eq!(saved_content, "üéer naïve\n日本人");
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_by_grapheme() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = vec![create_key_event(KeyCode::Char('l'))];
+        key_events.push(create_key_event(KeyCode::Char('i')));
+        key_events.push(create_key_event(KeyCode::Backspace));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.push(create_key_event(KeyCode::Char('l')));
+        key_events.push(create_key_event(KeyCode::Char('i')));
+        key_events.push(create_key_event(KeyCode::Delete));
+        key_events.extend(string_to_key_events(String::from("u\u{308}")));
+        key_events.push(create_key_event(KeyCode::Char('x')));
+        key_events.push(create_key_event(KeyCode::Esc));
+
+        let saved_content = edit_file("e\u{301}t👍\u{1f3fd}de", key_events)?;
+        assert_eq!(saved_content, "tu\u{308}xde");
+        Ok(())
+    }
+
+    #[test]
+    fn test_visual_modes() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = vec![control_key_event(KeyCode::Char('v'))];
+        key_events.extend(string_to_key_events(String::from("jjI// ")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.extend(string_to_key_events(String::from("Vj>")));
+        key_events.extend(string_to_key_events(String::from("jjjhvlU")));
+        key_events.extend(string_to_key_events(String::from("jVd")));
+        let saved_content = edit_file("one\ntwo\nthree\nfour\nfive", key_events)?;
+
+        assert_eq!(saved_content, "\t// one\n\t// two\n// three\nFOur");
+
+        // After `$`, `A` adds to the end of each line rather than padding the short ones
+        let mut key_events = vec![control_key_event(KeyCode::Char('v'))];
+        key_events.extend(string_to_key_events(String::from("$jjA;")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        let saved_content = edit_file("ab\nabcd\na\nz", key_events)?;
+        assert_eq!(saved_content, "ab;\nabcd;\na;\nz");
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_mode() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from("3rxjR12345"));
+        for _ in 0..5 {
+            key_events.push(create_key_event(KeyCode::Backspace));
+        }
+        key_events.extend(string_to_key_events(String::from("3")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.extend(string_to_key_events(String::from("krZ9ry")));
+        let saved_content = edit_file("abcdef\nxyz", key_events)?;
+
+        assert_eq!(saved_content, "xxZdef\nxy3");
+        Ok(())
+    }
+
+    #[test]
+    fn test_operators_with_motions() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from("dwjf(ci(x"));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.extend(string_to_key_events(String::from("jditj=jGgUiwggde")));
+        let saved_content = edit_file(
+            "foo bar baz\ncall(one, \"two\")\n<p>text</p>\n  if x {\na();\n  }\nlast line\n",
+            key_events,
+        )?;
+
+        assert_eq!(
+            saved_content,
+            " baz\ncall(x)\n<p></p>\nif x {\n\ta();\n  }\nLAST line\n"
+        );
+
+        // The blank line next to a one-line paragraph is where `}` and `{` stop
+        let saved_content = edit_file("one\n\ntwo\nthree", string_to_key_events("d}".into()))?;
+        assert_eq!(saved_content, "\ntwo\nthree");
+        let saved_content = edit_file("one\n\ntwo\n\nthree", string_to_key_events("jjd{".into()))?;
+        assert_eq!(saved_content, "one\ntwo\n\nthree");
+        Ok(())
+    }
+
+    #[test]
+    fn test_counts() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from("2d2w3ix"));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.extend(string_to_key_events(String::from("j2dd2u")));
+        key_events.push(control_key_event(KeyCode::Char('r')));
+        key_events.extend(string_to_key_events(String::from("3Gd2jgg2Rz")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        let saved_content = edit_file("a b c d e f\nline2\nline3\nline4\nline5", key_events)?;
+
+        assert_eq!(saved_content, "zzxe f\nline2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_registers_and_put() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from("yyjp\"ayiwG\"apddgg\"_ddp"));
+        key_events.extend(string_to_key_events(String::from("jwviw\"0pP:reg")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        let saved_content = edit_file("one two\nthree\nfour", key_events)?;
+
+        assert_eq!(saved_content, "three\nfoneour\none \ntwoone two\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dot_repeat() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from("dw.2.cwXY"));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.extend(string_to_key_events(String::from("w.j03i-")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.extend(string_to_key_events(String::from("j0.jVd.u")));
+        let saved_content = edit_file("one two three four five six\nx\ny\nz\nw", key_events)?;
+
+        assert_eq!(saved_content, "XY XY\n---x\n---y\nw");
+        Ok(())
+    }
+
+    #[test]
+    fn test_visual_repeat() -> Result<(), Box<dyn std::error::Error>> {
+        let saved_content = edit_file("abcdefgh", string_to_key_events("vld.".into()))?;
+        assert_eq!(saved_content, "efgh");
+        let saved_content = edit_file("abcdefgh", string_to_key_events("vld3.".into()))?;
+        assert_eq!(saved_content, "fgh");
+
+        let key_events = string_to_key_events("Vj>.jj3.".into());
+        let saved_content = edit_file("a\nb\nc\nd\ne", key_events)?;
+        assert_eq!(saved_content, "\t\ta\n\t\tb\n\tc\n\td\n\te");
+        Ok(())
+    }
+
+    #[test]
+    fn test_macros() -> Result<(), Box<dyn std::error::Error>> {
+        let key_events = string_to_key_events(String::from("qa0f,r;jq2@aqbqqb0f,r:j@bq@b@@"));
+        let saved_content = edit_file("a,1\nb,2\nc,3\nd,4\ne,5\nf,6", key_events)?;
+
+        assert_eq!(saved_content, "a;1\nb;2\nc;3\nd:4\ne:5\nf:6");
+        Ok(())
+    }
+
+    #[test]
+    fn test_search() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from("/foo"));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from("dlnrXgg*rY?ba")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from("rZNdl/baz")));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.extend(string_to_key_events(String::from("dl:noh")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        let saved_content = edit_file("foo bar\nbaz foo\nfoobar foo\n", key_events)?;
+
+        assert_eq!(saved_content, "foo r\nbaz oo\nXooZar Yoo\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_search() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from("/\\cbar"));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from("rXgg/\\v\\d{2}\\ze\\s")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from("rY")));
+        let saved_content = edit_file("x1 ab12\nfoo99 Bar\n", key_events)?;
+
+        assert_eq!(saved_content, "x1 ab12\nfooY9 Xar\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_substitute() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from(":%s/\\(o\\)ne/\\u&-\\1/g"));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from("gg:s/o/0/gc")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from("nyqjVj:s/o\\+/~")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        let saved_content = edit_file("one two one\nthree one\nfoo\n", key_events)?;
+
+        assert_eq!(saved_content, "One-o tw0 One-o\nthree One-0\nf0\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_ex_commands() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = Vec::new();
+        for command in [":2,3d | $", "rX:1", ":/foo/s/o/0/g", ":bogus", ":y a|wri"] {
+            key_events.extend(string_to_key_events(String::from(command)));
+            key_events.push(create_key_event(KeyCode::Enter));
+        }
+        key_events.extend(string_to_key_events(String::from("\"ap")));
+        let saved_content = edit_file("a\nb\nc\nfoo\nd\n", key_events)?;
+
+        assert_eq!(saved_content, "a\nf00\nf00\nX\n");
+
+        // `'<` and `'>` are remembered however visual mode ends
+        let mut key_events = string_to_key_events(String::from("jVjyG:'<,'>d"));
+        key_events.push(create_key_event(KeyCode::Enter));
+        let saved_content = edit_file("a\nb\nc\nd\n", key_events)?;
+        assert_eq!(saved_content, "a\nd\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_global() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = Vec::new();
+        for command in [
+            ":g/a/normal $ax",
+            ":g/a/normal yyp",
+            ":v/x/d",
+            ":g/2/.,+1d",
+            "u:g/1/s/x/y",
+        ] {
+            key_events.extend(string_to_key_events(String::from(command)));
+            key_events.push(create_key_event(KeyCode::Enter));
+        }
+        let saved_content = edit_file("a1\nb\na2\na3\nc\n", key_events)?;
+
+        assert_eq!(saved_content, "a1y\na1y\na2x\na2x\na3x\na3x\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_line_editing() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = string_to_key_events(String::from(":s/b/Y"));
+        key_events.push(create_key_event(KeyCode::Enter));
+        // The last command again, from the history
+        key_events.extend(string_to_key_events(String::from("j:s")));
+        key_events.push(create_key_event(KeyCode::Up));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from("j:sc/Z")));
+        key_events.push(create_key_event(KeyCode::Home));
+        key_events.push(create_key_event(KeyCode::Right));
+        key_events.extend(string_to_key_events(String::from("/")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        // A register typed in with Ctrl-r, after Ctrl-w takes off a word
+        key_events.extend(string_to_key_events(String::from("\"ayiw:s/foo")));
+        key_events.push(control_key_event(KeyCode::Char('w')));
+        key_events.push(control_key_event(KeyCode::Char('r')));
+        key_events.extend(string_to_key_events(String::from("a/W")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from(":subs")));
+        key_events.push(create_key_event(KeyCode::Tab));
+        key_events.extend(string_to_key_events(String::from("/W/V")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        let saved_content = edit_file("abc\nabc\nabc\n", key_events)?;
+
+        assert_eq!(saved_content, "aYc\naYc\nV\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_window() -> Result<(), Box<dyn std::error::Error>> {
+        let mut key_events = Vec::new();
+        // Runs the command above the empty line again, then `:q` closes the window
+        for keys in [":s/a/X", "q:k", "q::q"] {
+            key_events.extend(string_to_key_events(String::from(keys)));
+            key_events.push(create_key_event(KeyCode::Enter));
+        }
+        key_events.extend(string_to_key_events(String::from("rZ")));
+        let saved_content = edit_file("aa\n", key_events)?;
+
+        assert_eq!(saved_content, "ZX\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap_recovery() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempdir()?;
+        let path = directory.path().join("file.txt");
+        let file_path = path.to_str().unwrap();
+        fs::write(&path, "one\n")?;
+        // Changes made by an editor that died before writing them
+        let mut piece_table = PieceTable::new("one\n");
+        let mut metadata = FileMetadata::new(file_path.to_string());
+        metadata.update();
+        let mut swap = SwapFile::create(swap_path(file_path), &mut piece_table, &metadata)?;
+        piece_table.append("two\n");
+        swap.sync(&mut piece_table)?;
+
+        let mut editor = Editor::new("one\n", file_path.to_string());
+        editor.open_swap();
+        let mut key_events = string_to_key_events(String::from("oGdd"));
+        // Opened read-only, so only `:w!` writes
+        key_events.push(control_key_event(KeyCode::Char('s')));
+        key_events.extend(string_to_key_events(String::from(":recover")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        key_events.extend(string_to_key_events(String::from(":w")));
+        key_events.push(create_key_event(KeyCode::Enter));
+        for key_event in key_events {
+            editor.test_run(key_event)?;
+        }
+        assert_eq!(fs::read_to_string(&path)?, "one\n");
+        for key_event in string_to_key_events(String::from(":w!")) {
+            editor.test_run(key_event)?;
+        }
+        editor.test_run(create_key_event(KeyCode::Enter))?;
+        assert_eq!(fs::read_to_string(&path)?, "one\ntwo\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_change() -> Result<(), Box<dyn std::error::Error>> {
+        let mut temp_file = NamedTempFile::new()?;
+        writeln!(temp_file, "one")?;
+        let file_path = temp_file.path().to_str().unwrap();
+        let mut editor = Editor::new("one\n", file_path.to_string());
+        for key_event in string_to_key_events(String::from("dl")) {
+            editor.test_run(key_event)?;
+        }
+        fs::write(file_path, "three\n")?;
+
+        // `:w` won't write over the change and `:e` won't throw away the deletion
+        let mut key_events = Vec::new();
+        for keys in [":w", ":e", ":e!"] {
+            key_events.extend(string_to_key_events(String::from(keys)));
+            key_events.push(create_key_event(KeyCode::Enter));
+        }
+        key_events.extend(string_to_key_events(String::from("dl")));
+        key_events.push(control_key_event(KeyCode::Char('s')));
+        for key_event in key_events {
+            editor.test_run(key_event)?;
+        }
+        assert_eq!(fs::read_to_string(file_path)?, "hree\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffers() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempdir()?;
+        let paths: Vec<String> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| directory.path().join(name).to_str().unwrap().to_string())
+            .collect();
+        for (path, text) in paths.iter().zip(["one\n", "two\n", "three\n"]) {
+            fs::write(path, text)?;
+        }
+        let mut editor = Editor::new("one\n", paths[0].clone());
+        editor.add_file(paths[1].clone())?;
+
+        // Each buffer keeps its own changes, and `:q` won't leave any of them behind
+        let mut running = true;
+        for keys in ["dl", ":bn\n", ":q\n", "dl\x13", ":b 1\n", "\x13"] {
+            running = run_keys(&mut editor, keys)?;
+            assert!(running);
+        }
+        assert_eq!(fs::read_to_string(&paths[0])?, "ne\n");
+        assert_eq!(fs::read_to_string(&paths[1])?, "wo\n");
+
+        let command = format!(":e {}\n", paths[2]);
+        for keys in [command.as_str(), "dl", ":bd\n", ":bd!\n", ":q\n"] {
+            running = run_keys(&mut editor, keys)?;
+        }
+        assert!(!running);
+        assert_eq!(fs::read_to_string(&paths[2])?, "three\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_windows() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempdir()?;
+        let a = directory.path().join("a.txt");
+        let b = directory.path().join("b.txt");
+        fs::write(&a, "one\ntwo\n")?;
+        fs::write(&b, "three\n")?;
+        let mut editor = Editor::new("one\ntwo\n", a.to_str().unwrap().to_string());
+
+        // Both windows show a.txt. The bottom one deletes the line the top one's cursor was
+        // below, leaving that cursor past the end
+        let split_b = format!(":vs {}\n", b.display());
+        for keys in [
+            ":sp\n",
+            "j\x17j",
+            "dd\x17k",
+            "dd\x13",
+            split_b.as_str(),
+            "dl\x13",
+            ":q\n",
+            ":only\n",
+            ":close\n",
+        ] {
+            assert!(run_keys(&mut editor, keys)?);
+        }
+        assert_eq!(fs::read_to_string(&a)?, "");
+        assert_eq!(fs::read_to_string(&b)?, "hree\n");
+        assert!(!run_keys(&mut editor, ":q\n")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tab_pages() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempdir()?;
+        let a = directory.path().join("a.txt");
+        let b = directory.path().join("b.txt");
+        fs::write(&a, "one\n")?;
+        fs::write(&b, "two\n")?;
+        let mut editor = Editor::new("one\n", a.to_str().unwrap().to_string());
+
+        // The last change to b.txt is left in a closed tab page, which `:q` won't leave behind
+        let open_b = format!(":tabnew {}\n", b.display());
+        for keys in [
+            open_b.as_str(),
+            "dl\x13",
+            "gtdl\x13",
+            "2gtgT:tabn\ndl",
+            ":tabclose\n",
+            ":q\n",
+            ":b 2\n\x13",
+            ":tabnew\n",
+            ":q\n",
+            ":tabclose\n",
+        ] {
+            assert!(run_keys(&mut editor, keys)?);
+        }
+        assert_eq!(fs::read_to_string(&a)?, "ne\n");
+        assert_eq!(fs::read_to_string(&b)?, "o\n");
+        assert!(!run_keys(&mut editor, ":q\n")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_files() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempdir()?;
+        let scratch = directory.path().join("scratch.txt");
+        let new = directory.path().join("new.txt");
+        let empty = directory.path().join("empty.txt");
+        fs::write(&empty, "")?;
+
+        // Nothing is written until the buffer is given a name, which it then keeps
+        let mut editor = Editor::default();
+        let write_scratch = format!(":w {}\n", scratch.display());
+        for keys in ["ione\x1b\x13", write_scratch.as_str(), "dl\x13"] {
+            assert!(run_keys(&mut editor, keys)?);
+        }
+        assert_eq!(fs::read_to_string(&scratch)?, "on");
+
+        // A file that isn't there yet is made on the first write, and an empty one has
+        // nothing for the cursor to land on
+        let mut editor = Editor::open(new.to_str().unwrap().to_string())?;
+        editor.add_file(empty.to_str().unwrap().to_string())?;
+        assert!(!new.exists());
+        for keys in [
+            "Gdl$ddp",
+            "itwo\x1b\x13",
+            ":bn\n",
+            "jdd0dluGp$",
+            ":e!\n",
+            "\x13",
+        ] {
+            assert!(run_keys(&mut editor, keys)?);
+        }
+        assert_eq!(fs::read_to_string(&new)?, "two\n");
+        assert_eq!(fs::read_to_string(&empty)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell_commands() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempdir()?;
+        let path = directory.path().join("file.txt");
+        let sorted = directory.path().join("sorted.txt");
+        fs::write(&path, "b\na\n")?;
+        let mut editor = Editor::new("b\na\n", path.to_str().unwrap().to_string());
+
+        // The output goes below the cursor line, and the lines going to `sort` come back
+        // in order below the last line. `:0r` puts it above the first
+        let sort = format!(":2,3w !sort > {}\n", sorted.display());
+        let read_sorted = format!(":$r {}\n", sorted.display());
+        for keys in [
+            ":r !printf 'd\\nc'\n",
+            sort.as_str(),
+            read_sorted.as_str(),
+            ":0r !echo x\n",
+            "\x13",
+        ] {
+            assert!(run_keys(&mut editor, keys)?);
+        }
+        assert_eq!(fs::read_to_string(&sorted)?, "c\nd\n");
+        assert_eq!(fs::read_to_string(&path)?, "x\nb\nd\nc\na\nc\nd\n");
+        Ok(())
+    }
+
+    // Runs `keys`, with `\n` for Enter, `\x1b` for Escape, `\x13` for Ctrl-s and `\x17` for Ctrl-w
+    fn run_keys(editor: &mut Editor, keys: &str) -> std::io::Result<bool> {
+        let mut running = true;
+        for ch in keys.chars() {
+            let key_event = match ch {
+                '\n' => create_key_event(KeyCode::Enter),
+                '\x1b' => create_key_event(KeyCode::Esc),
+                '\x13' => control_key_event(KeyCode::Char('s')),
+                '\x17' => control_key_event(KeyCode::Char('w')),
+                _ => create_key_event(KeyCode::Char(ch)),
+            };
+            running = editor.test_run(key_event)?;
+        }
+        Ok(running)
+    }
 }
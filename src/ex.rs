@@ -0,0 +1,825 @@
+use crate::command_line::complete_path;
+use crate::piece_table::PieceTable;
+use crate::search::Pattern;
+use crate::substitute::split_at_delimiter;
+use std::fmt;
+
+// Ex command lines, the text typed after `:`
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CommandError {
+    NotEditorCommand(String),
+    NoBang,
+    NoRange,
+    InvalidRange,
+    MarkNotSet,
+    FileExists,
+    PartialWrite,
+    ReadOnly,
+    ChangedOnDisk,
+    NotSaved,
+    NoFileName,
+    Write(String),
+    NoSwapFile(String),
+    GlobalRecursive,
+    BadDelimiter,
+    PatternEverywhere(String),
+    Io(String),
+    NoPreviousPattern,
+    NoPreviousSubstitute,
+    PatternNotFound(String),
+    TrailingCharacters(String),
+    Regex(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::NotEditorCommand(command) => {
+                write!(f, "E492: Not an editor command: {}", command)
+            }
+            CommandError::NoBang => write!(f, "E477: No ! allowed"),
+            CommandError::NoRange => write!(f, "E481: No range allowed"),
+            CommandError::InvalidRange => write!(f, "E16: Invalid range"),
+            CommandError::MarkNotSet => write!(f, "E20: Mark not set"),
+            CommandError::FileExists => write!(f, "E13: File exists (add ! to override)"),
+            CommandError::PartialWrite => write!(f, "E140: Use ! to write partial buffer"),
+            CommandError::ReadOnly => {
+                write!(f, "E45: 'readonly' option is set (add ! to override)")
+            }
+            CommandError::ChangedOnDisk => write!(
+                f,
+                "WARNING: The file has been changed since reading it (add ! to override)"
+            ),
+            CommandError::NotSaved => {
+                write!(f, "E37: No write since last change (add ! to override)")
+            }
+            CommandError::NoFileName => write!(f, "E32: No file name"),
+            CommandError::Write(message) => write!(f, "E514: Write error: {}", message),
+            CommandError::NoSwapFile(file) => write!(f, "E305: No swap file found for {}", file),
+            CommandError::GlobalRecursive => write!(f, "E147: Cannot do :global recursive"),
+            CommandError::BadDelimiter => {
+                write!(f, "E146: Regular expressions can't be delimited by letters")
+            }
+            CommandError::PatternEverywhere(pattern) => {
+                write!(f, "Pattern found in every line: {}", pattern)
+            }
+            CommandError::Io(message) => write!(f, "{}", message),
+            CommandError::NoPreviousPattern => write!(f, "E35: No previous regular expression"),
+            CommandError::NoPreviousSubstitute => {
+                write!(f, "E35: No previous substitute regular expression")
+            }
+            CommandError::PatternNotFound(pattern) => {
+                write!(f, "E486: Pattern not found: {}", pattern)
+            }
+            CommandError::TrailingCharacters(text) => {
+                write!(f, "E488: Trailing characters: {}", text)
+            }
+            CommandError::Regex(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// Lines `start..=end`, counted from 0
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl LineRange {
+    pub fn line(y: usize) -> Self {
+        Self { start: y, end: y }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum CommandName {
+    Buffer,
+    BufferDelete,
+    BufferNext,
+    BufferPrevious,
+    Buffers,
+    Close,
+    Delete,
+    Edit,
+    Exit,
+    Global,
+    NoHlSearch,
+    Normal,
+    Only,
+    Quit,
+    QuitAll,
+    Read,
+    Recover,
+    Redo,
+    Registers,
+    Split,
+    Substitute,
+    TabClose,
+    TabNew,
+    TabNext,
+    TabPrevious,
+    Undo,
+    VGlobal,
+    VSplit,
+    Write,
+    WriteQuit,
+    Yank,
+}
+
+// How a command can be typed and what can follow it
+struct Definition {
+    full: &'static str,
+    shortest: &'static str,
+    name: CommandName,
+    range: bool,
+    bang: bool,
+    args: bool,
+}
+
+const fn define(
+    full: &'static str,
+    shortest: &'static str,
+    name: CommandName,
+    (range, bang, args): (bool, bool, bool),
+) -> Definition {
+    Definition {
+        full,
+        shortest,
+        name,
+        range,
+        bang,
+        args,
+    }
+}
+
+const COMMANDS: [Definition; 37] = [
+    define(
+        "bNext",
+        "bN",
+        CommandName::BufferPrevious,
+        (false, false, true),
+    ),
+    define(
+        "bdelete",
+        "bd",
+        CommandName::BufferDelete,
+        (false, true, true),
+    ),
+    define("bnext", "bn", CommandName::BufferNext, (false, false, true)),
+    define(
+        "bprevious",
+        "bp",
+        CommandName::BufferPrevious,
+        (false, false, true),
+    ),
+    define("buffer", "b", CommandName::Buffer, (false, false, true)),
+    define(
+        "buffers",
+        "buffers",
+        CommandName::Buffers,
+        (false, false, false),
+    ),
+    define("close", "clo", CommandName::Close, (false, true, false)),
+    define("delete", "d", CommandName::Delete, (true, false, true)),
+    define(
+        "display",
+        "di",
+        CommandName::Registers,
+        (false, false, true),
+    ),
+    define("edit", "e", CommandName::Edit, (false, true, true)),
+    define("exit", "exi", CommandName::Exit, (true, true, true)),
+    define(
+        "files",
+        "files",
+        CommandName::Buffers,
+        (false, false, false),
+    ),
+    define("global", "g", CommandName::Global, (true, true, true)),
+    define("ls", "ls", CommandName::Buffers, (false, false, false)),
+    define(
+        "nohlsearch",
+        "noh",
+        CommandName::NoHlSearch,
+        (false, false, false),
+    ),
+    define("normal", "norm", CommandName::Normal, (true, true, true)),
+    define("only", "on", CommandName::Only, (false, true, false)),
+    define("qall", "qa", CommandName::QuitAll, (false, true, false)),
+    define("quit", "q", CommandName::Quit, (false, true, false)),
+    define("read", "r", CommandName::Read, (true, false, true)),
+    define(
+        "recover",
+        "rec",
+        CommandName::Recover,
+        (false, false, false),
+    ),
+    define("redo", "red", CommandName::Redo, (false, false, false)),
+    define(
+        "registers",
+        "reg",
+        CommandName::Registers,
+        (false, false, true),
+    ),
+    define("split", "sp", CommandName::Split, (false, false, true)),
+    define(
+        "substitute",
+        "s",
+        CommandName::Substitute,
+        (true, false, true),
+    ),
+    define(
+        "tabNext",
+        "tabN",
+        CommandName::TabPrevious,
+        (false, false, true),
+    ),
+    define(
+        "tabclose",
+        "tabc",
+        CommandName::TabClose,
+        (false, true, false),
+    ),
+    define(
+        "tabnew",
+        "tabnew",
+        CommandName::TabNew,
+        (false, false, true),
+    ),
+    define(
+        "tabnext",
+        "tabn",
+        CommandName::TabNext,
+        (false, false, true),
+    ),
+    define(
+        "tabprevious",
+        "tabp",
+        CommandName::TabPrevious,
+        (false, false, true),
+    ),
+    define("undo", "u", CommandName::Undo, (false, false, true)),
+    define("vglobal", "v", CommandName::VGlobal, (true, false, true)),
+    define("vsplit", "vs", CommandName::VSplit, (false, false, true)),
+    define("write", "w", CommandName::Write, (true, true, true)),
+    define("wq", "wq", CommandName::WriteQuit, (true, true, true)),
+    define("xit", "x", CommandName::Exit, (true, true, true)),
+    define("yank", "y", CommandName::Yank, (true, false, true)),
+];
+
+// One command from a command line, like `1,5d` or `w! other.txt`
+#[derive(PartialEq, Clone, Debug)]
+pub struct ExCommand {
+    pub range: Option<LineRange>,
+    // None for a range on its own, like `:10`
+    pub name: Option<CommandName>,
+    pub bang: bool,
+    pub args: String,
+    // Whether the range ends at line 0, above the first line, which is where `:0r` reads text in
+    pub line_zero: bool,
+}
+
+// What the addresses in a range are worked out from
+#[derive(Clone, Copy)]
+pub struct RangeContext<'a> {
+    pub current: usize,
+    pub piece_table: &'a PieceTable,
+    // The first and last lines of the last visual selection, for `'<` and `'>`
+    pub visual: Option<(usize, usize)>,
+    // What `//` and `??` search for
+    pub last_pattern: Option<&'a Pattern>,
+    // What `:b` and `:bd` complete to
+    pub buffer_names: &'a [String],
+}
+
+// Parses the first command on a command line, returning it and the text after a `|` if there is one
+pub fn parse_command<'a>(
+    line: &'a str,
+    context: &RangeContext,
+) -> Result<(ExCommand, Option<&'a str>), CommandError> {
+    let (range, rest) = parse_range(line, context)?;
+    let line_zero = ends_at_line_zero(&line[..line.len() - rest.len()]);
+    let rest = rest.trim_start();
+    let name_length = rest
+        .find(|ch: char| !ch.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    if name_length == 0 {
+        let (args, next) = split_at_bar(rest, false);
+        if !args.trim().is_empty() {
+            return Err(CommandError::NotEditorCommand(line.trim().to_string()));
+        }
+        let command = ExCommand {
+            range,
+            name: None,
+            bang: false,
+            args,
+            line_zero,
+        };
+        return Ok((command, next));
+    }
+
+    let definition = lookup(&rest[..name_length])
+        .ok_or_else(|| CommandError::NotEditorCommand(line.trim().to_string()))?;
+    let rest = &rest[name_length..];
+    let (bang, rest) = match rest.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    if bang && !definition.bang {
+        return Err(CommandError::NoBang);
+    }
+    if range.is_some() && !definition.range {
+        return Err(CommandError::NoRange);
+    }
+
+    // `:g` and `:normal` take the rest of the line, `|` and all, and so do `:w !cmd` and
+    // `:r !cmd`, which give it to the shell
+    let shell = matches!(definition.name, CommandName::Write | CommandName::Read)
+        && rest.trim_start().starts_with('!');
+    if shell
+        || matches!(
+            definition.name,
+            CommandName::Global | CommandName::VGlobal | CommandName::Normal
+        )
+    {
+        let command = ExCommand {
+            range,
+            name: Some(definition.name),
+            bang,
+            args: rest.trim_start().to_string(),
+            line_zero,
+        };
+        return Ok((command, None));
+    }
+
+    // The pattern and replacement of `:s` can have a `|` in them
+    let (args, next) = split_at_bar(rest, definition.name == CommandName::Substitute);
+    let args = if definition.name == CommandName::Substitute {
+        args
+    } else {
+        args.trim().to_string()
+    };
+    if !definition.args && !args.is_empty() {
+        return Err(CommandError::TrailingCharacters(args));
+    }
+    let command = ExCommand {
+        range,
+        name: Some(definition.name),
+        bang,
+        args,
+        line_zero,
+    };
+    Ok((command, next))
+}
+
+fn lookup(name: &str) -> Option<&'static Definition> {
+    COMMANDS
+        .iter()
+        .find(|definition| is_abbreviation(name, definition.shortest, definition.full))
+}
+
+// What Tab can complete at the end of `line`: where the word being completed starts and
+// what it could be. That is a command name, a file name after a command that reads or writes
+// one, or a buffer name after `:b`
+pub fn completions(line: &str, context: &RangeContext) -> Option<(usize, Vec<String>)> {
+    let (_, rest) = parse_range(line, context).ok()?;
+    let name_start = line.len() - rest.len();
+    let name_length = rest
+        .find(|ch: char| !ch.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    if name_length == rest.len() {
+        let names = COMMANDS
+            .iter()
+            .map(|definition| definition.full.to_string())
+            .filter(|full| full.starts_with(rest))
+            .collect();
+        return Some((name_start, names));
+    }
+
+    let definition = lookup(&rest[..name_length])?;
+    let args = &rest[name_length..];
+    let args = args.strip_prefix('!').unwrap_or(args);
+    if !args.starts_with(' ') {
+        return None;
+    }
+    let word_start = line.rfind(' ').map_or(0, |i| i + 1);
+    let word = &line[word_start..];
+    match definition.name {
+        CommandName::Write
+        | CommandName::WriteQuit
+        | CommandName::Exit
+        | CommandName::Read
+        | CommandName::Edit
+        | CommandName::Split
+        | CommandName::VSplit
+        | CommandName::TabNew => Some((word_start, complete_path(word))),
+        // Any buffer with the word somewhere in its name
+        CommandName::Buffer | CommandName::BufferDelete => {
+            let names = context
+                .buffer_names
+                .iter()
+                .filter(|name| name.contains(word))
+                .cloned()
+                .collect();
+            Some((word_start, names))
+        }
+        _ => None,
+    }
+}
+
+// Splits the arguments of `:g/pattern/command` into the pattern and the command
+pub fn parse_global(args: &str) -> Result<(String, &str), CommandError> {
+    let delimiter = args.chars().next().ok_or(CommandError::NoPreviousPattern)?;
+    if delimiter.is_alphanumeric() || matches!(delimiter, '\\' | '"' | '|') {
+        return Err(CommandError::BadDelimiter);
+    }
+    let (pattern, command) = split_at_delimiter(&args[delimiter.len_utf8()..], delimiter);
+    Ok((pattern, command.unwrap_or_default()))
+}
+
+// Splits `text` at the first `|` that isn't escaped with a backslash, which is then removed.
+// With `delimited`, two parts between delimiters like `/pattern/replacement/` are skipped first
+fn split_at_bar(text: &str, delimited: bool) -> (String, Option<&str>) {
+    let mut args = String::new();
+    let mut chars = text.char_indices();
+    if delimited {
+        let trimmed = text.len() - text.trim_start().len();
+        let delimiter = text[trimmed..]
+            .chars()
+            .next()
+            .filter(|ch| !ch.is_alphanumeric() && *ch != '\\' && *ch != '"' && *ch != '|');
+        if let Some(delimiter) = delimiter {
+            let mut parts = 0;
+            while let Some((_, ch)) = chars.next() {
+                args.push(ch);
+                if ch == '\\' {
+                    args.extend(chars.next().map(|(_, ch)| ch));
+                } else if ch == delimiter {
+                    parts += 1;
+                    if parts == 3 {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '|' => return (args, Some(&text[i + 1..])),
+            '\\' if text[i + 1..].starts_with('|') => {
+                args.push('|');
+                chars.next();
+            }
+            _ => args.push(ch),
+        }
+    }
+    (args, None)
+}
+
+// Whether `name` is `full` shortened to no less than `shortest`
+pub fn is_abbreviation(name: &str, shortest: &str, full: &str) -> bool {
+    name.len() >= shortest.len() && full.starts_with(name)
+}
+
+// Splits the range off the start of `command`, like `%`, `.,$` or `'<,'>`.
+// Returns `None` for the range if there isn't one
+pub fn parse_range<'a>(
+    command: &'a str,
+    context: &RangeContext,
+) -> Result<(Option<LineRange>, &'a str), CommandError> {
+    let last = context.piece_table.line_count().saturating_sub(1);
+    let command = command.trim_start_matches([' ', ':']);
+    if let Some(rest) = command.strip_prefix('%') {
+        return Ok((
+            Some(LineRange {
+                start: 0,
+                end: last,
+            }),
+            rest,
+        ));
+    }
+
+    let (start, rest) = parse_address(command, context)?;
+    let (end, rest) = match rest.chars().next() {
+        Some(separator @ (',' | ';')) => {
+            // After `;` the second address is worked out from the first
+            let context = match (separator, start) {
+                (';', Some(start)) => RangeContext {
+                    current: start,
+                    ..*context
+                },
+                _ => *context,
+            };
+            let (end, rest) = parse_address(&rest[1..], &context)?;
+            (Some(end.unwrap_or(context.current)), rest)
+        }
+        _ => (start, rest),
+    };
+    let range = match (start, end) {
+        (None, None) => return Ok((None, rest)),
+        (start, end) => {
+            let start = start.unwrap_or(context.current);
+            let end = end.unwrap_or(start);
+            // `:5,3` means the same as `:3,5`
+            LineRange {
+                start: start.min(end),
+                end: start.max(end),
+            }
+        }
+    };
+    if range.end > last {
+        return Err(CommandError::InvalidRange);
+    }
+    Ok((Some(range), rest))
+}
+
+// Whether the last address in the text of a range is `0`, which `parse_range` takes as line 1
+fn ends_at_line_zero(range: &str) -> bool {
+    let last = range.rsplit([',', ';']).next().unwrap_or_default();
+    let last = last.trim_matches([' ', ':']);
+    !last.is_empty() && last.chars().all(|ch| ch == '0')
+}
+
+// One end of a range: a line number, `.`, `$`, a mark or a pattern,
+// followed by any number of `+N` and `-N`
+fn parse_address<'a>(
+    text: &'a str,
+    context: &RangeContext,
+) -> Result<(Option<usize>, &'a str), CommandError> {
+    let last = context.piece_table.line_count().saturating_sub(1);
+    let digits = text
+        .find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (base, mut rest) = if digits > 0 {
+        let number: usize = text[..digits]
+            .parse()
+            .map_err(|_| CommandError::InvalidRange)?;
+        (Some(number.saturating_sub(1)), &text[digits..])
+    } else if let Some(rest) = text.strip_prefix('.') {
+        (Some(context.current), rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (Some(last), rest)
+    } else if let Some(rest) = text.strip_prefix("'<") {
+        let (first, _) = context.visual.ok_or(CommandError::MarkNotSet)?;
+        (Some(first), rest)
+    } else if let Some(rest) = text.strip_prefix("'>") {
+        let (_, last) = context.visual.ok_or(CommandError::MarkNotSet)?;
+        (Some(last), rest)
+    } else if text.starts_with(['/', '?']) {
+        let (line, rest) = search_address(text, context)?;
+        (Some(line), rest)
+    } else {
+        (None, text)
+    };
+
+    // `+2` on its own is two lines below the cursor
+    let mut line = base;
+    while let Some(sign) = rest.chars().next().filter(|&ch| ch == '+' || ch == '-') {
+        rest = &rest[1..];
+        let digits = rest
+            .find(|ch: char| !ch.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount = if digits == 0 {
+            1
+        } else {
+            rest[..digits]
+                .parse()
+                .map_err(|_| CommandError::InvalidRange)?
+        };
+        rest = &rest[digits..];
+        let from = line.unwrap_or(context.current);
+        line = Some(if sign == '+' {
+            from.saturating_add(amount)
+        } else {
+            from.checked_sub(amount).ok_or(CommandError::InvalidRange)?
+        });
+    }
+    Ok((line, rest))
+}
+
+// `/pattern/` is the next line with a match after the current one, and `?pattern?` the one before
+fn search_address<'a>(
+    text: &'a str,
+    context: &RangeContext,
+) -> Result<(usize, &'a str), CommandError> {
+    let mut chars = text.char_indices();
+    let Some((_, delimiter)) = chars.next() else {
+        return Err(CommandError::InvalidRange);
+    };
+    let mut end = text.len();
+    while let Some((i, ch)) = chars.next() {
+        if ch == '\\' {
+            chars.next();
+        } else if ch == delimiter {
+            end = i;
+            break;
+        }
+    }
+    let source = &text[1..end];
+    let rest = text.get(end + 1..).unwrap_or_default();
+
+    let compiled;
+    let pattern = if source.is_empty() {
+        context
+            .last_pattern
+            .ok_or(CommandError::NoPreviousPattern)?
+    } else {
+        compiled = Pattern::new(source).map_err(|error| CommandError::Regex(error.to_string()))?;
+        &compiled
+    };
+    let piece_table = context.piece_table;
+    let found = if delimiter == '/' {
+        let from = piece_table
+            .line_start(context.current + 1)
+            .unwrap_or(piece_table.len());
+        pattern.find_forward(piece_table, from)
+    } else {
+        let from = piece_table.line_start(context.current).unwrap_or(0);
+        pattern.find_backward(piece_table, from)
+    };
+    let found = found.ok_or_else(|| CommandError::PatternNotFound(pattern.source().to_string()))?;
+    let (_, line) = piece_table.coordinates(found.start);
+    Ok((line.min(piece_table.line_count().saturating_sub(1)), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let piece_table = PieceTable::new("0\n1\n2\nfoo\n4\n5\n6\nfoo\n8\n9\n");
+        let context = RangeContext {
+            current: 4,
+            piece_table: &piece_table,
+            visual: Some((1, 2)),
+            last_pattern: None,
+            buffer_names: &[],
+        };
+        let range = |start, end| Some(LineRange { start, end });
+        assert_eq!(parse_range("s/a/b/", &context), Ok((None, "s/a/b/")));
+        assert_eq!(parse_range("%s", &context), Ok((range(0, 9), "s")));
+        assert_eq!(parse_range(".,.+2d", &context), Ok((range(4, 6), "d")));
+        assert_eq!(parse_range("3,$", &context), Ok((range(2, 9), "")));
+        assert_eq!(parse_range("'<,'>s", &context), Ok((range(1, 2), "s")));
+        assert_eq!(parse_range("-,+", &context), Ok((range(3, 5), "")));
+        assert_eq!(parse_range("7,2", &context), Ok((range(1, 6), "")));
+        assert_eq!(parse_range("11", &context), Err(CommandError::InvalidRange));
+        assert_eq!(parse_range("/foo/d", &context), Ok((range(7, 7), "d")));
+        assert_eq!(parse_range("?foo?+1", &context), Ok((range(4, 4), "")));
+        assert_eq!(parse_range("2;+3", &context), Ok((range(1, 4), "")));
+        assert_eq!(
+            parse_range("/bar/", &context),
+            Err(CommandError::PatternNotFound("bar".to_string()))
+        );
+
+        let context = RangeContext {
+            visual: None,
+            ..context
+        };
+        assert_eq!(parse_range("'<", &context), Err(CommandError::MarkNotSet));
+        assert_eq!(
+            parse_range("//", &context),
+            Err(CommandError::NoPreviousPattern)
+        );
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let piece_table = PieceTable::new("a\nb\nc\n");
+        let context = RangeContext {
+            current: 0,
+            piece_table: &piece_table,
+            visual: None,
+            last_pattern: None,
+            buffer_names: &[],
+        };
+        let command = |range, name, bang, args: &str| ExCommand {
+            range,
+            name,
+            bang,
+            args: args.to_string(),
+            line_zero: false,
+        };
+        assert_eq!(
+            parse_command("wri! other.txt", &context),
+            Ok((
+                command(None, Some(CommandName::Write), true, "other.txt"),
+                None
+            ))
+        );
+        assert_eq!(
+            parse_command("2", &context),
+            Ok((command(Some(LineRange::line(1)), None, false, ""), None))
+        );
+        assert_eq!(
+            parse_command("1,2d | s/a\\|b/|/g|q", &context),
+            Ok((
+                command(
+                    Some(LineRange { start: 0, end: 1 }),
+                    Some(CommandName::Delete),
+                    false,
+                    ""
+                ),
+                Some(" s/a\\|b/|/g|q")
+            ))
+        );
+        assert_eq!(
+            parse_command("s/a\\|b/|/g|q", &context),
+            Ok((
+                command(None, Some(CommandName::Substitute), false, "/a\\|b/|/g"),
+                Some("q")
+            ))
+        );
+        assert_eq!(
+            parse_command("0r !echo x", &context),
+            Ok((
+                ExCommand {
+                    line_zero: true,
+                    ..command(
+                        Some(LineRange::line(0)),
+                        Some(CommandName::Read),
+                        false,
+                        "!echo x"
+                    )
+                },
+                None
+            ))
+        );
+        assert_eq!(
+            parse_command("foo", &context),
+            Err(CommandError::NotEditorCommand("foo".to_string()))
+        );
+        assert_eq!(
+            parse_command("g/a|b/s/x/y/|d", &context),
+            Ok((
+                command(None, Some(CommandName::Global), false, "/a|b/s/x/y/|d"),
+                None
+            ))
+        );
+        assert_eq!(
+            parse_command("2,3w !sort | uniq", &context),
+            Ok((
+                command(
+                    Some(LineRange { start: 1, end: 2 }),
+                    Some(CommandName::Write),
+                    false,
+                    "!sort | uniq"
+                ),
+                None
+            ))
+        );
+        assert_eq!(
+            parse_command("r !date|q", &context),
+            Ok((
+                command(None, Some(CommandName::Read), false, "!date|q"),
+                None
+            ))
+        );
+        assert_eq!(
+            parse_global("/a\\/b/normal dd"),
+            Ok(("a/b".to_string(), "normal dd"))
+        );
+        assert_eq!(parse_global("xax"), Err(CommandError::BadDelimiter));
+        assert_eq!(
+            completions("2,3wr", &context),
+            Some((3, vec!["write".to_string()]))
+        );
+        assert_eq!(completions("noh ", &context), None);
+        assert_eq!(parse_command("noh!", &context), Err(CommandError::NoBang));
+        assert_eq!(parse_command("1q", &context), Err(CommandError::NoRange));
+        assert_eq!(
+            parse_command("redo 2", &context),
+            Err(CommandError::TrailingCharacters("2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_completions() {
+        let piece_table = PieceTable::new("a\n");
+        let names = ["src/main.rs".to_string(), "src/lib.rs".to_string()];
+        let context = RangeContext {
+            current: 0,
+            piece_table: &piece_table,
+            visual: None,
+            last_pattern: None,
+            buffer_names: &names,
+        };
+        assert_eq!(
+            completions("%bu", &context),
+            Some((1, vec!["buffer".to_string(), "buffers".to_string()]))
+        );
+        assert_eq!(
+            completions("b lib", &context),
+            Some((2, vec!["src/lib.rs".to_string()]))
+        );
+        assert_eq!(
+            completions("bd! src", &context).map(|(_, names)| names.len()),
+            Some(2)
+        );
+        assert_eq!(completions("bnext 1", &context), None);
+    }
+}
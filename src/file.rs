@@ -1,12 +1,189 @@
-use std::fs;
-use std::io;
+use log::info;
+use rustix::fs::XattrFlags;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{fchown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
 
 pub fn load_file(path: &str) -> io::Result<String> {
     let contents = fs::read_to_string(path)?;
     Ok(contents)
 }
 
-pub fn save_file(path: &str, content: String) -> io::Result<()> {
-    fs::write(path, content)?;
+// Like `load_file`, but nothing there yet is fine: it's a new file, to be made on the first write
+pub fn load_file_if_exists(path: &str) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Reads everything piped in, for `cmd | text-editor -`. Keys still come from the terminal,
+// so stdin is pointed back at it after
+pub fn load_stdin() -> io::Result<String> {
+    let mut contents = Vec::new();
+    io::stdin().read_to_end(&mut contents)?;
+    let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    rustix::stdio::dup2_stdin(&tty)?;
+    Ok(String::from_utf8_lossy(&contents).into_owned())
+}
+
+// Saves without ever leaving the file half written: the text goes to a new file next to it,
+// which gets the old file's permissions, owner and extended attributes and is synced to disk
+// before being renamed over the old one. Returns false if the new file couldn't be given the old
+// one's owner, which only root can do for someone else's file
+pub fn save_file(path: &str, content: String) -> io::Result<bool> {
+    // Saving through a symlink replaces the file it points to, not the link
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let original = fs::metadata(&path).ok();
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let (temp_path, mut temp) = create_temp_file(&path, directory)?;
+    let result = (|| -> io::Result<bool> {
+        let mut kept_owner = true;
+        if let Some(original) = &original {
+            temp.set_permissions(original.permissions())?;
+            if let Err(error) = fchown(&temp, Some(original.uid()), Some(original.gid())) {
+                info!("Couldn't keep the owner of {:?}: {}", path, error);
+                kept_owner = false;
+            }
+            copy_xattrs(&path, &temp)?;
+        }
+        temp.write_all(content.as_bytes())?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        // The rename has to reach the disk as well
+        File::open(directory)?.sync_all()?;
+        Ok(kept_owner)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// A new file in `directory` named after `path`, which nothing else is using
+fn create_temp_file(path: &Path, directory: &Path) -> io::Result<(PathBuf, File)> {
+    let name = path
+        .file_name()
+        .map_or("file".into(), |name| name.to_string_lossy());
+    let mut attempt = 0;
+    loop {
+        let temp_path = directory.join(format!(".{}.{}-{}.tmp", name, process::id(), attempt));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+        {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => {
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// Copies the extended attributes of the file at `path` onto `temp`. A file system without
+// them has nothing to copy
+fn copy_xattrs(path: &Path, temp: &File) -> io::Result<()> {
+    let unsupported = |error: &rustix::io::Errno| {
+        matches!(*error, rustix::io::Errno::NOTSUP | rustix::io::Errno::PERM)
+    };
+    let original = File::open(path)?;
+    let names = match read_xattr(|names| rustix::fs::flistxattr(&original, names)) {
+        Ok(names) => names,
+        Err(error) if unsupported(&error) => return Ok(()),
+        Err(error) => return Err(error.into()),
+    };
+    for name in names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let value = read_xattr(|value| rustix::fs::fgetxattr(&original, name, value))?;
+        match rustix::fs::fsetxattr(temp, name, &value, XattrFlags::empty()) {
+            // Some, like security ones, can only be set by root
+            Err(error) if unsupported(&error) => {
+                info!(
+                    "Couldn't copy an extended attribute of {:?}: {}",
+                    path, error
+                )
+            }
+            result => result?,
+        }
+    }
     Ok(())
 }
+
+// Reads an attribute list or value with `read`, which is asked how big it is first. It can
+// grow before the second call, which fails with ERANGE, so then it's asked again
+fn read_xattr<T: Copy + Default>(
+    read: impl Fn(&mut [T]) -> rustix::io::Result<usize>,
+) -> rustix::io::Result<Vec<T>> {
+    loop {
+        let mut buffer = vec![T::default(); read(&mut [])?];
+        match read(&mut buffer) {
+            Ok(length) => {
+                buffer.truncate(length);
+                return Ok(buffer);
+            }
+            Err(rustix::io::Errno::RANGE) => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_save_file() -> io::Result<()> {
+        let directory = tempdir()?;
+        let path = directory.path().join("file.txt");
+        let path_name = path.to_str().unwrap();
+        // Our own file keeps its owner
+        assert!(save_file(path_name, "new\n".to_string())?);
+        assert_eq!(load_file(path_name)?, "new\n");
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640))?;
+        let link = directory.path().join("link.txt");
+        symlink(&path, &link)?;
+        save_file(link.to_str().unwrap(), "changed\n".to_string())?;
+        assert_eq!(load_file(path_name)?, "changed\n");
+        assert!(fs::symlink_metadata(&link)?.file_type().is_symlink());
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o640);
+        // Only the file is left, without any temp files
+        assert_eq!(fs::read_dir(directory.path())?.count(), 2);
+
+        let missing = directory.path().join("missing").join("file.txt");
+        assert!(save_file(missing.to_str().unwrap(), String::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_file_xattrs() -> io::Result<()> {
+        let directory = tempdir()?;
+        let path = directory.path().join("file.txt");
+        fs::write(&path, "old\n")?;
+        let value = vec![b'x'; 3000];
+        match rustix::fs::setxattr(&path, "user.editor", &value, XattrFlags::empty()) {
+            // Nothing to test on a file system without them
+            Err(rustix::io::Errno::NOTSUP) => return Ok(()),
+            result => result?,
+        }
+
+        save_file(path.to_str().unwrap(), "new\n".to_string())?;
+        let copied = read_xattr(|buffer| rustix::fs::getxattr(&path, "user.editor", buffer))?;
+        assert_eq!(copied, value);
+        Ok(())
+    }
+}
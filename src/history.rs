@@ -0,0 +1,226 @@
+#[derive(Debug)]
+pub enum HistoryError {
+    AtOldest,
+    AtNewest,
+    NoSuchChange(usize),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    Insert { position: usize, text: String },
+    Delete { position: usize, text: String },
+}
+
+impl Change {
+    pub fn inverse(&self) -> Change {
+        match self {
+            Change::Insert { position, text } => Change::Delete {
+                position: *position,
+                text: text.clone(),
+            },
+            Change::Delete { position, text } => Change::Insert {
+                position: *position,
+                text: text.clone(),
+            },
+        }
+    }
+}
+
+pub struct UndoStep {
+    pub changes: Vec<Change>,
+    pub cursor: Option<(usize, usize)>,
+}
+
+// One node per undoable step. Node 0 is the unmodified document
+struct UndoNode {
+    parent: usize,
+    changes: Vec<Change>,
+    cursor: Option<(usize, usize)>,
+    // The branch that redo follows
+    last_child: Option<usize>,
+}
+
+struct PendingGroup {
+    changes: Vec<Change>,
+    cursor: Option<(usize, usize)>,
+}
+
+pub struct History {
+    nodes: Vec<UndoNode>,
+    current: usize,
+    pending: Option<PendingGroup>,
+    // While held, groups that begin and end are all part of the one already open
+    held: usize,
+    // The node the file was last read or written at
+    saved: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![UndoNode {
+                parent: 0,
+                changes: vec![],
+                cursor: None,
+                last_child: None,
+            }],
+            current: 0,
+            pending: None,
+            held: 0,
+            saved: 0,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn begin_group(&mut self, cursor: (usize, usize)) {
+        if self.held > 0 {
+            self.pending.get_or_insert(PendingGroup {
+                changes: vec![],
+                cursor: Some(cursor),
+            });
+            return;
+        }
+        self.end_group();
+        self.pending = Some(PendingGroup {
+            changes: vec![],
+            cursor: Some(cursor),
+        });
+    }
+
+    // The text now matches the file
+    pub fn mark_saved(&mut self) {
+        self.end_group();
+        self.saved = self.current;
+    }
+
+    // Whether there are changes since `mark_saved`, which undoing back to it takes away
+    pub fn is_modified(&self) -> bool {
+        self.current != self.saved
+            || self
+                .pending
+                .as_ref()
+                .is_some_and(|group| !group.changes.is_empty())
+    }
+
+    pub fn hold_group(&mut self) {
+        self.held += 1;
+    }
+
+    pub fn release_group(&mut self) {
+        self.held = self.held.saturating_sub(1);
+    }
+
+    pub fn end_group(&mut self) {
+        if self.held > 0 {
+            return;
+        }
+        let Some(group) = self.pending.take() else {
+            return;
+        };
+        if group.changes.is_empty() {
+            return;
+        }
+
+        let new_node = self.nodes.len();
+        self.nodes.push(UndoNode {
+            parent: self.current,
+            changes: group.changes,
+            cursor: group.cursor,
+            last_child: None,
+        });
+        self.nodes[self.current].last_child = Some(new_node);
+        self.current = new_node;
+    }
+
+    pub fn record(&mut self, change: Change) {
+        self.pending
+            .get_or_insert(PendingGroup {
+                changes: vec![],
+                cursor: None,
+            })
+            .changes
+            .push(change);
+    }
+
+    // Returns the changes that have to be applied, in order, to undo the current node
+    pub fn undo(&mut self) -> Result<UndoStep, HistoryError> {
+        self.end_group();
+        if self.current == 0 {
+            return Err(HistoryError::AtOldest);
+        }
+
+        let node = &self.nodes[self.current];
+        let changes = node.changes.iter().rev().map(Change::inverse).collect();
+        let cursor = node.cursor;
+        self.current = node.parent;
+        Ok(UndoStep { changes, cursor })
+    }
+
+    pub fn redo(&mut self) -> Result<UndoStep, HistoryError> {
+        self.end_group();
+        let child = self.nodes[self.current]
+            .last_child
+            .ok_or(HistoryError::AtNewest)?;
+
+        let node = &self.nodes[child];
+        let changes = node.changes.clone();
+        let cursor = node.cursor;
+        self.current = child;
+        Ok(UndoStep { changes, cursor })
+    }
+
+    // Walks the tree from the current node to `target`, possibly switching branches
+    pub fn undo_to(&mut self, target: usize) -> Result<UndoStep, HistoryError> {
+        self.end_group();
+        if target >= self.nodes.len() {
+            return Err(HistoryError::NoSuchChange(target));
+        }
+
+        let current_path = self.path_to_root(self.current);
+        let target_path = self.path_to_root(target);
+        let common = *current_path
+            .iter()
+            .find(|node| target_path.contains(node))
+            .unwrap_or(&0);
+
+        let mut changes = Vec::new();
+        let mut cursor = None;
+        for &node in current_path.iter().take_while(|&&node| node != common) {
+            changes.extend(self.nodes[node].changes.iter().rev().map(Change::inverse));
+            cursor = self.nodes[node].cursor.or(cursor);
+        }
+
+        let redo_path: Vec<usize> = target_path
+            .iter()
+            .take_while(|&&node| node != common)
+            .copied()
+            .collect();
+        for &node in redo_path.iter().rev() {
+            let parent = self.nodes[node].parent;
+            self.nodes[parent].last_child = Some(node);
+            changes.extend(self.nodes[node].changes.iter().cloned());
+            cursor = self.nodes[node].cursor.or(cursor);
+        }
+
+        self.current = target;
+        Ok(UndoStep { changes, cursor })
+    }
+
+    fn path_to_root(&self, mut node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(node);
+        }
+        path
+    }
+}
@@ -1,46 +1,228 @@
+use crate::buffer::BufferCommand;
+use crate::command_line::CommandLine;
 use crate::editor::{BarMode, CursorController, KeyHandler, Mode};
+use crate::ex::{
+    completions, parse_command, parse_global, CommandError, CommandName, ExCommand, LineRange,
+    RangeContext,
+};
 use crate::file;
-use crate::metadata::FileMetadata;
+use crate::history::HistoryError;
+use crate::metadata::{DiskChange, FileMetadata};
+use crate::motion::Motion;
+use crate::normal_command::{
+    parse_normal_command, parse_visual_target, split_prefix, NormalCommand, Parsed, Prefix, Target,
+    WINDOW_KEY,
+};
+use crate::operator::{apply_operator, first_non_blank, pad_line, Operator, RangeKind, TextRange};
 use crate::piece_table::PieceTable;
-use crate::utils::find_index;
+use crate::register::{is_register_name, put, Register};
+use crate::search::{word_at, LastSearch, Pattern};
+use crate::shell::{self, ShellOutput};
+use crate::substitute::{expand_tilde, parse_substitute, Replacement, Substitution};
+use crate::utils::{
+    display_column, grapheme_at_column, grapheme_count, grapheme_to_byte, keys_to_text,
+    text_to_keys,
+};
+use crate::window::{Direction, WindowCommand};
 use crossterm::event;
 use crossterm::event::*;
 use log::info;
-use std::fs::File;
 use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+// How many times macros can start playing from one typed key, so one that calls itself
+// without ever failing can't hang the editor
+const MAX_MACRO_PLAYS: usize = 100_000;
+
+#[derive(PartialEq, Clone, Copy)]
+enum BlockInsertKind {
+    // `I` skips lines that end before the block
+    Insert,
+    // `c` skips lines that end before the block but not ones it emptied
+    Change,
+    // `A` pads lines that are too short with spaces
+    Append,
+    // `A` after `$` adds to the end of each line instead
+    AppendToLineEnd,
+}
+
+// The keys that go into insert or replace mode
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum InsertKey {
+    Insert,
+    Append,
+    Replace,
+}
+
+// A change that `.` can repeat
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Change {
+    Command(Prefix, NormalCommand),
+    // `i`, `a` or `R` with a count
+    Insert(InsertKey, usize),
+    // An operator applied to a selection, which is repeated on as much text from the cursor.
+    // `columns` is the last column for a selection over several lines
+    Visual {
+        operator: Operator,
+        kind: RangeKind,
+        lines: usize,
+        columns: usize,
+        register: Option<char>,
+    },
+}
+
+// Text typed on the top line of a visual block, to be repeated on the lines below it
+pub struct BlockInsert {
+    kind: BlockInsertKind,
+    top: usize,
+    bottom: usize,
+    column: usize,
+    offset: usize,
+    line_length: usize,
+}
 
 impl KeyHandler {
+    // Hands a key to whatever the current mode does with it, returning false to quit
+    pub fn process_key(
+        &mut self,
+        key_event: KeyEvent,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> io::Result<bool> {
+        match self.mode() {
+            Mode::Normal(_) => {
+                self.normal_keypress(key_event, metadata, piece_table, cursor_controller)
+            }
+            Mode::Insert => self.insert_keypress(key_event, piece_table, cursor_controller),
+            Mode::Replace => self.replace_keypress(key_event, piece_table, cursor_controller),
+            Mode::Visual { .. } => self.visual_keypress(
+                key_event,
+                metadata.file_path.clone(),
+                piece_table,
+                cursor_controller,
+            ),
+            Mode::Command(_) => {
+                self.command_keypress(key_event, piece_table, metadata, cursor_controller)
+            }
+            Mode::Confirm { .. } => {
+                self.confirm_keypress(key_event, piece_table, cursor_controller)
+            }
+            Mode::Search { .. } => self.search_keypress(key_event, piece_table, cursor_controller),
+        }
+    }
+
     pub fn insert_keypress(
         &mut self,
         key_event: KeyEvent,
-        lines: Vec<String>,
         piece_table: &mut PieceTable,
         cursor_controller: &mut CursorController,
     ) -> io::Result<bool> {
         match key_event {
             KeyEvent {
                 code: KeyCode::Esc, ..
-            } => handle_escape_key(cursor_controller, self.get_mode_mut()),
+            } => {
+                if let Some(block_insert) = self.block_insert.take() {
+                    finish_block_insert(block_insert, piece_table);
+                } else {
+                    // `3ix<Esc>` types the x twice more
+                    let text = self.inserted.repeat(self.insert_count - 1);
+                    insert_text(piece_table, cursor_controller, &text);
+                }
+                self.finish_insert();
+                handle_escape_key(piece_table, cursor_controller, self.get_mode_mut())
+            }
 
             KeyEvent {
                 code: KeyCode::Char(ch),
                 ..
-            } => type_char(&lines, piece_table, cursor_controller, ch),
+            } => {
+                type_char(piece_table, cursor_controller, ch);
+                self.inserted.push(ch);
+            }
 
             KeyEvent {
                 code: KeyCode::Enter,
                 ..
-            } => enter(&lines, piece_table, cursor_controller),
+            } => {
+                enter(piece_table, cursor_controller);
+                self.inserted.push('\n');
+            }
 
             KeyEvent {
                 code: KeyCode::Backspace,
                 ..
-            } => backspace(&lines, piece_table, cursor_controller),
+            } => {
+                backspace(piece_table, cursor_controller);
+                self.inserted.pop();
+            }
 
             KeyEvent {
                 code: KeyCode::Delete,
                 ..
-            } => delete(&lines, piece_table, cursor_controller),
+            } => delete(piece_table, cursor_controller),
+            _ => {}
+        }
+
+        cursor_controller.update_desired_x();
+        Ok(true)
+    }
+
+    pub fn replace_keypress(
+        &mut self,
+        key_event: KeyEvent,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) -> io::Result<bool> {
+        match key_event {
+            KeyEvent {
+                code: KeyCode::Esc, ..
+            } => {
+                self.replaced.clear();
+                // `3Rab<Esc>` overwrites the next four graphemes with abab as well
+                for _ in 1..self.insert_count {
+                    for ch in self.inserted.chars() {
+                        if ch == '\n' {
+                            enter(piece_table, cursor_controller);
+                        } else {
+                            overtype_char(piece_table, cursor_controller, ch);
+                        }
+                    }
+                }
+                self.finish_insert();
+                handle_escape_key(piece_table, cursor_controller, self.get_mode_mut())
+            }
+
+            KeyEvent {
+                code: KeyCode::Char(ch),
+                ..
+            } => {
+                let replaced = overtype_char(piece_table, cursor_controller, ch);
+                self.replaced.push(replaced);
+                self.inserted.push(ch);
+            }
+
+            KeyEvent {
+                code: KeyCode::Enter,
+                ..
+            } => {
+                enter(piece_table, cursor_controller);
+                self.replaced.push(None);
+                self.inserted.push('\n');
+            }
+
+            KeyEvent {
+                code: KeyCode::Backspace,
+                ..
+            } => {
+                match self.replaced.pop() {
+                    Some(replaced) => restore_replaced(piece_table, cursor_controller, replaced),
+                    None => move_left(cursor_controller),
+                }
+                self.inserted.pop();
+            }
+
             _ => {}
         }
 
@@ -48,75 +230,214 @@ impl KeyHandler {
         Ok(true)
     }
 
+    // Consumes the count and register typed before a command
+    fn take_prefix(&mut self) -> Prefix {
+        let prefix = match split_prefix(&self.pending) {
+            Parsed::Complete((prefix, _)) => prefix,
+            _ => Prefix::default(),
+        };
+        self.pending.clear();
+        prefix
+    }
+
+    // Whether keys other than a count and register have been typed towards a command
+    fn has_pending_command(&self) -> bool {
+        match split_prefix(&self.pending) {
+            Parsed::Complete((_, keys)) => !keys.is_empty(),
+            _ => true,
+        }
+    }
+
+    fn finish_insert(&mut self) {
+        if let Some(change) = self.unfinished_change.take() {
+            self.last_change = Some((change, self.inserted.clone()));
+        }
+        self.registers.last_inserted = std::mem::take(&mut self.inserted);
+        self.insert_count = 1;
+    }
+
     pub fn normal_keypress(
         &mut self,
         key_event: KeyEvent,
-        lines: Vec<String>,
-        file_path: String,
+        metadata: &mut FileMetadata,
         piece_table: &mut PieceTable,
         cursor_controller: &mut CursorController,
     ) -> io::Result<bool> {
+        let file_path = metadata.file_path.clone();
+        // `q` finishes recording a macro
+        if self.recording.is_some()
+            && !self.has_pending_command()
+            && matches!(
+                key_event,
+                KeyEvent {
+                    code: KeyCode::Char('q'),
+                    modifiers: KeyModifiers::NONE,
+                    ..
+                }
+            )
+        {
+            self.pending.clear();
+            self.stop_recording();
+            return Ok(true);
+        }
+
+        // A message over several lines, like the one from `:registers`, goes at the next key
+        if let Mode::Normal(Some(BarMode::Message(message))) = self.get_mode_mut() {
+            if message.contains('\n') {
+                switch_mode(Mode::Normal(None), self.get_mode_mut());
+            }
+        }
+
+        match command_char(key_event) {
+            Some(ch) if self.has_pending_command() || starts_normal_command(ch) => {
+                self.pending.push(ch);
+                match parse_normal_command(&self.pending) {
+                    Parsed::Incomplete => {}
+                    Parsed::Invalid => self.pending.clear(),
+                    Parsed::Complete((prefix, command)) => {
+                        self.pending.clear();
+                        self.execute_normal_command(
+                            command,
+                            prefix,
+                            &file_path,
+                            piece_table,
+                            cursor_controller,
+                        );
+                        if is_change(command) {
+                            self.record_change(Change::Command(prefix, command));
+                        }
+                    }
+                }
+                return Ok(true);
+            }
+            // Any other key, like Esc, abandons a half typed command
+            _ if self.has_pending_command() => {
+                self.pending.clear();
+                return Ok(true);
+            }
+            _ => {}
+        }
+
+        // A count on its own goes to the key after it
+        let prefix = self.take_prefix();
+        let count = prefix.count.unwrap_or(1);
         match key_event {
+            KeyEvent {
+                code: KeyCode::Char('R'),
+                ..
+            } => self.start_insert(InsertKey::Replace, count, piece_table, cursor_controller),
+
+            KeyEvent {
+                code: KeyCode::Char('.'),
+                ..
+            } => self.repeat_change(prefix.count, &file_path, piece_table, cursor_controller),
+
             KeyEvent {
                 code: KeyCode::Char('q'),
                 modifiers: event::KeyModifiers::CONTROL,
                 ..
             } => return quit(),
 
+            // Writing the file was on `Ctrl-w` until that started window commands
             KeyEvent {
-                code: KeyCode::Char('w'),
+                code: KeyCode::Char('s'),
                 modifiers: event::KeyModifiers::CONTROL,
                 ..
-            } => {
-                write_file(piece_table, file_path);
-            }
+            } => match write_lines(None, false, "", piece_table, metadata) {
+                Ok(message) => switch_mode(Mode::Normal(Some(message)), self.get_mode_mut()),
+                Err(error) => self.command_error(error),
+            },
 
             KeyEvent {
-                code: KeyCode::Char('h'),
+                code: KeyCode::Char('i'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => move_left(cursor_controller),
+            } => self.start_insert(InsertKey::Insert, count, piece_table, cursor_controller),
 
             KeyEvent {
-                code: KeyCode::Char('j'),
+                code: KeyCode::Char('a'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => move_down(cursor_controller, &lines),
+            } => self.start_insert(InsertKey::Append, count, piece_table, cursor_controller),
 
             KeyEvent {
-                code: KeyCode::Char('k'),
+                code: KeyCode::Char('u'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => move_up(cursor_controller, &lines),
+            } => {
+                let result = step_history(piece_table, count, PieceTable::undo);
+                restore_from_history(result, piece_table, cursor_controller, self.get_mode_mut());
+            }
 
             KeyEvent {
-                code: KeyCode::Char('l'),
-                modifiers: KeyModifiers::NONE,
+                code: KeyCode::Char('r'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => {
+                let result = step_history(piece_table, count, PieceTable::redo);
+                restore_from_history(result, piece_table, cursor_controller, self.get_mode_mut());
+            }
+
+            KeyEvent {
+                code: KeyCode::Char('v'),
+                modifiers: KeyModifiers::CONTROL,
                 ..
-            } => move_right(cursor_controller, &lines),
+            } => start_visual(RangeKind::Blockwise, cursor_controller, self.get_mode_mut()),
 
             KeyEvent {
-                code: KeyCode::Char('i'),
+                code: KeyCode::Char('v'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => handle_insert_key(cursor_controller, self.get_mode_mut(), false, &lines),
+            } => start_visual(RangeKind::Charwise, cursor_controller, self.get_mode_mut()),
 
             KeyEvent {
-                code: KeyCode::Char('a'),
-                modifiers: KeyModifiers::NONE,
+                code: KeyCode::Char('V'),
                 ..
-            } => handle_insert_key(cursor_controller, self.get_mode_mut(), true, &lines),
+            } => start_visual(RangeKind::Linewise, cursor_controller, self.get_mode_mut()),
 
             KeyEvent {
                 code: KeyCode::Char(':'),
                 modifiers: KeyModifiers::NONE,
                 ..
-            } => switch_mode(
-                Mode::Command {
-                    previous_chars: String::new(),
-                },
-                self.get_mode_mut(),
-            ),
+            } => {
+                // `3:` starts the command with a range covering this line and the two below
+                let range = match count {
+                    1 => String::new(),
+                    count => format!(".,.+{}", count - 1),
+                };
+                switch_mode(Mode::Command(CommandLine::new(range)), self.get_mode_mut());
+            }
+
+            KeyEvent {
+                code: KeyCode::Char('/'),
+                ..
+            } => start_search(true, count, cursor_controller, self.get_mode_mut()),
+
+            KeyEvent {
+                code: KeyCode::Char('?'),
+                ..
+            } => start_search(false, count, cursor_controller, self.get_mode_mut()),
+
+            KeyEvent {
+                code: KeyCode::Char('n'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.repeat_search(false, count, piece_table, cursor_controller),
+
+            KeyEvent {
+                code: KeyCode::Char('N'),
+                ..
+            } => self.repeat_search(true, count, piece_table, cursor_controller),
+
+            KeyEvent {
+                code: KeyCode::Char('*'),
+                ..
+            } => self.search_word(true, count, piece_table, cursor_controller),
+
+            KeyEvent {
+                code: KeyCode::Char('#'),
+                ..
+            } => self.search_word(false, count, piece_table, cursor_controller),
 
             _ => {}
         }
@@ -124,190 +445,2234 @@ impl KeyHandler {
         Ok(true)
     }
 
-    pub fn command_keypress(
+    // Moves to the `count`th match of `pattern` from the byte offset `start`,
+    // saying so if that went round an end of the file. Returns whether there was a match
+    fn search(
+        &mut self,
+        pattern: &Pattern,
+        forward: bool,
+        start: usize,
+        count: usize,
+        piece_table: &PieceTable,
+        cursor_controller: &mut CursorController,
+    ) -> bool {
+        self.highlight_search = true;
+        let mut position = start;
+        let mut wrapped = false;
+        for _ in 0..count {
+            let Some(found) = pattern.find_from(piece_table, position, forward) else {
+                let message = format!("Pattern not found: {}", pattern.source());
+                switch_mode(
+                    Mode::Normal(Some(BarMode::Message(message))),
+                    self.get_mode_mut(),
+                );
+                self.fail();
+                return false;
+            };
+            position = found.start;
+            wrapped |= found.wrapped;
+        }
+        cursor_controller.restore_position(piece_table.coordinates(position), piece_table);
+
+        let message = match (wrapped, forward) {
+            (true, true) => "search hit BOTTOM, continuing at TOP".to_string(),
+            (true, false) => "search hit TOP, continuing at BOTTOM".to_string(),
+            (false, true) => format!("/{}", pattern.source()),
+            (false, false) => format!("?{}", pattern.source()),
+        };
+        switch_mode(
+            Mode::Normal(Some(BarMode::Message(message))),
+            self.get_mode_mut(),
+        );
+        true
+    }
+
+    // `n`, or `N` which goes the other way
+    fn repeat_search(
+        &mut self,
+        reverse: bool,
+        count: usize,
+        piece_table: &PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let Some(LastSearch { pattern, forward }) = self.last_search.clone() else {
+            self.search_error("No previous regular expression");
+            return;
+        };
+        let start = cursor_offset(piece_table, cursor_controller);
+        self.search(
+            &pattern,
+            forward != reverse,
+            start,
+            count,
+            piece_table,
+            cursor_controller,
+        );
+    }
+
+    // `*` and `#`, which look for the word under the cursor
+    fn search_word(
+        &mut self,
+        forward: bool,
+        count: usize,
+        piece_table: &PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+        let Some((start, word)) = word_at(piece_table, cursor) else {
+            self.search_error("No string under cursor");
+            return;
+        };
+        let pattern = match Pattern::word(&word) {
+            Ok(pattern) => pattern,
+            Err(error) => {
+                self.search_error(&error.to_string());
+                return;
+            }
+        };
+        // Searching from the start of the word means `#` doesn't stop on the word it started on
+        self.search(
+            &pattern,
+            forward,
+            start,
+            count,
+            piece_table,
+            cursor_controller,
+        );
+        self.last_search = Some(LastSearch { pattern, forward });
+    }
+
+    // Shows why a search couldn't happen, which stops any macro that is playing
+    fn search_error(&mut self, message: &str) {
+        switch_mode(
+            Mode::Normal(Some(BarMode::Message(message.to_string()))),
+            self.get_mode_mut(),
+        );
+        self.fail();
+    }
+
+    pub fn search_keypress(
         &mut self,
         key_event: KeyEvent,
-        piece_table: &mut PieceTable,
-        metadata: &mut FileMetadata,
+        piece_table: &PieceTable,
+        cursor_controller: &mut CursorController,
     ) -> io::Result<bool> {
+        let Mode::Search {
+            forward,
+            mut previous_chars,
+            origin,
+            count,
+        } = self.mode()
+        else {
+            return Ok(true);
+        };
+        cursor_controller.restore_position(origin, piece_table);
+        let start = cursor_offset(piece_table, cursor_controller);
+
         match key_event {
             KeyEvent {
                 code: KeyCode::Char(ch),
                 ..
-            } => type_command(self.get_mode_mut(), ch),
+            } => previous_chars.push(ch),
 
             KeyEvent {
-                code: KeyCode::Esc, ..
-            } => switch_mode(Mode::Normal(None), self.get_mode_mut()),
+                code: KeyCode::Backspace,
+                ..
+            } if !previous_chars.is_empty() => {
+                previous_chars.pop();
+            }
 
             KeyEvent {
                 code: KeyCode::Enter,
                 ..
-            } => return execute_command(self.get_mode_mut(), piece_table, metadata),
+            } => {
+                switch_mode(Mode::Normal(None), self.get_mode_mut());
+                // `/` on its own looks for the last pattern again
+                let pattern = if previous_chars.is_empty() {
+                    match &self.last_search {
+                        Some(last) => last.pattern.clone(),
+                        None => {
+                            self.search_error("No previous regular expression");
+                            return Ok(true);
+                        }
+                    }
+                } else {
+                    match Pattern::new(&previous_chars) {
+                        Ok(pattern) => pattern,
+                        Err(error) => {
+                            self.search_error(&error.to_string());
+                            return Ok(true);
+                        }
+                    }
+                };
+                self.search(
+                    &pattern,
+                    forward,
+                    start,
+                    count,
+                    piece_table,
+                    cursor_controller,
+                );
+                self.last_search = Some(LastSearch { pattern, forward });
+                return Ok(true);
+            }
 
+            // Esc, or Backspace with nothing left to delete, goes back to where the search started
             KeyEvent {
-                code: KeyCode::Backspace,
+                code: KeyCode::Esc | KeyCode::Backspace,
                 ..
-            } => command_backspace(self.get_mode_mut()),
+            } => {
+                switch_mode(Mode::Normal(None), self.get_mode_mut());
+                return Ok(true);
+            }
 
             _ => {}
         }
 
+        // The cursor jumps to the first match as the pattern is typed
+        let found = Pattern::new(&previous_chars)
+            .ok()
+            .and_then(|pattern| pattern.find_from(piece_table, start, forward));
+        if let Some(found) = found {
+            cursor_controller.restore_position(piece_table.coordinates(found.start), piece_table);
+        }
+        switch_mode(
+            Mode::Search {
+                forward,
+                previous_chars,
+                origin,
+                count,
+            },
+            self.get_mode_mut(),
+        );
         Ok(true)
     }
-}
-
-fn quit() -> io::Result<bool> {
-    Ok(false)
-}
 
-fn command_backspace(mode: &mut Mode) {
-    if let Mode::Command { previous_chars } = mode {
-        if !previous_chars.is_empty() {
-            previous_chars.pop();
-        } else {
-            switch_mode(Mode::Normal(None), mode);
+    // `i`, `a` and `R`. The text typed goes in `count` times
+    fn start_insert(
+        &mut self,
+        key: InsertKey,
+        count: usize,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        self.insert_count = count;
+        let mode = self.get_mode_mut();
+        match key {
+            InsertKey::Insert => handle_insert_key(piece_table, cursor_controller, mode, false),
+            InsertKey::Append => handle_insert_key(piece_table, cursor_controller, mode, true),
+            InsertKey::Replace => {
+                piece_table
+                    .begin_undo_group((cursor_controller.cursor_x(), cursor_controller.cursor_y()));
+                switch_mode(Mode::Replace, mode);
+            }
         }
+        self.unfinished_change = Some(Change::Insert(key, count));
     }
-}
-fn execute_command(
-    mode: &mut Mode,
-    piece_table: &mut PieceTable,
-    metadata: &mut FileMetadata,
-) -> io::Result<bool> {
-    if let Mode::Command {
-        previous_chars: chars,
-    } = mode
-    {
-        let target_mode = match chars.as_str() {
-            "q" => return quit(),
-            "w" => {
-                write_file(piece_table, metadata.file_path.clone());
-                let file = File::open(metadata.file_path.clone())?;
-                let file_size = file.metadata()?.len();
-                metadata.update(file_size as usize);
-                Mode::Normal(Some(BarMode::Write))
-            }
-            "wq" => {
-                write_file(piece_table, metadata.file_path.clone());
-                return quit();
-            }
-            _ => Mode::Normal(None),
-        };
-        switch_mode(target_mode, mode);
-    }
-    Ok(true)
-}
 
-fn type_command(mode: &mut Mode, ch: char) {
-    if let Mode::Command { previous_chars } = mode {
-        previous_chars.push(ch);
+    // Remembers `change` for `.`, once the text typed after it is known
+    fn record_change(&mut self, change: Change) {
+        match self.mode() {
+            Mode::Insert | Mode::Replace => self.unfinished_change = Some(change),
+            _ => self.last_change = Some((change, String::new())),
+        }
     }
-}
 
-fn write_file(piece_table: &mut PieceTable, file_path: String) {
-    let _ = file::save_file(&file_path, piece_table.to_string());
-}
+    // `.`: makes the last change again at the cursor, `count` times instead of as many as before if given
+    fn repeat_change(
+        &mut self,
+        count: Option<usize>,
+        file_path: &str,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let Some((change, text)) = self.last_change.clone() else {
+            return;
+        };
+        match change {
+            Change::Command(prefix, command) => {
+                // `"1p` followed by `.` puts `"2`, then `"3` and so on
+                let register = match (command, prefix.register) {
+                    (NormalCommand::Put { .. }, Some(name @ '1'..='8')) => {
+                        char::from_u32(name as u32 + 1)
+                    }
+                    (_, register) => register,
+                };
+                let prefix = Prefix {
+                    count: count.or(prefix.count),
+                    register,
+                };
+                self.execute_normal_command(
+                    command,
+                    prefix,
+                    file_path,
+                    piece_table,
+                    cursor_controller,
+                );
+                self.record_change(Change::Command(prefix, command));
+            }
+            Change::Insert(key, original_count) => {
+                let count = count.unwrap_or(original_count);
+                self.start_insert(key, count, piece_table, cursor_controller);
+            }
+            Change::Visual {
+                operator,
+                kind,
+                lines,
+                columns,
+                register,
+            } => {
+                // A count is how many lines to work on, or characters for part of one line
+                let (lines, columns) = match count {
+                    Some(count) if kind == RangeKind::Charwise && lines == 0 => {
+                        (0, count.saturating_sub(1))
+                    }
+                    Some(count) => (count.saturating_sub(1), columns),
+                    None => (lines, columns),
+                };
+                let range = repeated_range(kind, lines, columns, piece_table, cursor_controller);
+                self.visual_operate(operator, range, register, piece_table, cursor_controller);
+            }
+        }
 
-fn move_left(cursor_controller: &mut CursorController) {
-    cursor_controller.set_cursor_x_no_checks(cursor_controller.cursor_x().saturating_sub(1));
-    cursor_controller.update_desired_x();
-}
+        // Types the text from last time and leaves insert mode
+        let keys = text
+            .chars()
+            .map(|ch| match ch {
+                '\n' => KeyCode::Enter,
+                _ => KeyCode::Char(ch),
+            })
+            .chain([KeyCode::Esc]);
+        for code in keys {
+            let key_event = KeyEvent::new(code, KeyModifiers::NONE);
+            let _ = match self.mode() {
+                Mode::Insert => self.insert_keypress(key_event, piece_table, cursor_controller),
+                Mode::Replace => self.replace_keypress(key_event, piece_table, cursor_controller),
+                _ => break,
+            };
+        }
+    }
 
-fn move_down(cursor_controller: &mut CursorController, lines: &[String]) {
-    let cursor_y = cursor_controller.cursor_y();
-    let num_lines = lines.len();
-    if cursor_y < num_lines - 1 {
-        cursor_controller.set_cursor_y(cursor_y + 1, num_lines);
-        cursor_controller.update_desired_x_if_needed(lines);
+    // `Ctrl-w` and the key after it, which the editor carries out
+    fn window_key(&mut self, ch: char) {
+        let command = match ch {
+            'h' => WindowCommand::Focus(Direction::Left),
+            'j' => WindowCommand::Focus(Direction::Down),
+            'k' => WindowCommand::Focus(Direction::Up),
+            'l' => WindowCommand::Focus(Direction::Right),
+            's' | 'v' => WindowCommand::Split {
+                vertical: ch == 'v',
+                file_path: String::new(),
+            },
+            'o' => WindowCommand::Only,
+            'c' => WindowCommand::Close,
+            '=' => WindowCommand::Equalize,
+            // Like `:q`
+            'q' => {
+                self.buffer_command = Some(BufferCommand::Quit {
+                    bang: false,
+                    all: false,
+                });
+                return;
+            }
+            _ => WindowCommand::Next,
+        };
+        self.window_command = Some(command);
     }
-}
 
-fn move_up(cursor_controller: &mut CursorController, lines: &[String]) {
-    cursor_controller.set_cursor_y(cursor_controller.cursor_y().saturating_sub(1), lines.len());
-    cursor_controller.update_desired_x_if_needed(lines);
-}
+    // `@{register}`: queues the keys in the register `count` times, ahead of any a running macro still has to play
+    fn play_macro(&mut self, name: char, count: usize, file_path: &str) {
+        self.macro_plays += 1;
+        if self.macro_plays > MAX_MACRO_PLAYS {
+            self.fail();
+            let message = format!("Macro stopped after running {} times", MAX_MACRO_PLAYS);
+            switch_mode(
+                Mode::Normal(Some(BarMode::Message(message))),
+                self.get_mode_mut(),
+            );
+            return;
+        }
 
-fn move_right(cursor_controller: &mut CursorController, lines: &[String]) {
-    cursor_controller.set_cursor_x_normal_mode(
-        cursor_controller.cursor_x() + 1,
-        lines[cursor_controller.cursor_y()].len(),
-    );
-    cursor_controller.update_desired_x();
-}
+        let name = match (name, self.last_macro) {
+            ('@', Some(last)) => last,
+            ('@', None) => {
+                self.fail();
+                return;
+            }
+            _ => name,
+        };
+        // `@:` runs the last command line again
+        let text = if name == ':' {
+            format!(":{}\r", self.registers.last_command)
+        } else {
+            match self.get_register(Some(name), file_path) {
+                Some(register) => register.text,
+                None => return,
+            }
+        };
+        self.last_macro = Some(name);
 
-fn delete(
-    lines: &[String],
-    piece_table: &mut PieceTable,
-    cursor_controller: &mut CursorController,
-) {
-    let cursor_x = cursor_controller.cursor_x();
-    let cursor_y = cursor_controller.cursor_y();
-    if cursor_x != lines[cursor_y].len() {
-        if let Some(position) = find_index(lines, cursor_x, cursor_y) {
-            piece_table.delete(position);
+        let keys = text_to_keys(&text).repeat(count);
+        for key_event in keys.into_iter().rev() {
+            self.queued_keys.push_front(key_event);
         }
-    } else if let Some(position) = find_index(lines, 0, cursor_y + 1) {
-        piece_table.delete(position - 1);
     }
-}
 
-fn backspace(
-    lines: &[String],
-    piece_table: &mut PieceTable,
-    cursor_controller: &mut CursorController,
-) {
-    let cursor_x = cursor_controller.cursor_x();
-    let delete_x = cursor_x.saturating_sub(1);
-    let cursor_y = cursor_controller.cursor_y();
+    // Something went wrong, which stops any macro that is playing
+    fn fail(&mut self) {
+        self.queued_keys.clear();
+    }
 
-    if cursor_x == 0 {
-        if cursor_y == 0 {
-            return;
-        }
-        if let Some(position) = find_index(lines, 0, cursor_y) {
-            piece_table.delete(position - 1);
-            cursor_controller
-                .set_cursor_x_insert_mode(lines[cursor_y - 1].len(), lines[cursor_y - 1].len());
-            cursor_controller.set_cursor_y(cursor_y - 1, lines.len());
+    fn stop_recording(&mut self) {
+        if let Some((name, mut keys)) = self.recording.take() {
+            // The `q` that stopped the recording got recorded as well
+            keys.pop();
+            self.registers.record(name, &keys_to_text(&keys));
         }
-    } else if let Some(position) = find_index(lines, delete_x, cursor_y) {
-        piece_table.delete(position);
-        cursor_controller.set_cursor_x_insert_mode(delete_x, lines[cursor_y].len());
     }
-}
 
-fn enter(lines: &[String], piece_table: &mut PieceTable, cursor_controller: &mut CursorController) {
-    let x = cursor_controller.cursor_x();
-    let y = cursor_controller.cursor_y();
-    if let Some(position) = find_index(lines, x, y) {
-        piece_table.insert(position, "\n");
-        cursor_controller.set_cursor_y(y + 1, piece_table.lines().len());
+    fn execute_normal_command(
+        &mut self,
+        command: NormalCommand,
+        prefix: Prefix,
+        file_path: &str,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let count = prefix.count;
+        let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+        let (operator, target) = match command {
+            NormalCommand::Put { before } => {
+                let Some(register) = self.get_register(prefix.register, file_path) else {
+                    return;
+                };
+                piece_table.begin_undo_group(cursor);
+                let position = put(&register, piece_table, cursor, before, count.unwrap_or(1));
+                piece_table.end_undo_group();
+                cursor_controller.restore_position(position, piece_table);
+                return;
+            }
+            NormalCommand::ReplaceChars(ch) => {
+                if !replace_chars(piece_table, cursor_controller, ch, count.unwrap_or(1)) {
+                    self.fail();
+                }
+                return;
+            }
+            NormalCommand::Move(motion) => {
+                if !move_cursor(motion, count, piece_table, cursor_controller) {
+                    self.fail();
+                }
+                return;
+            }
+            NormalCommand::Record(name) => {
+                self.recording = Some((name, Vec::new()));
+                return;
+            }
+            NormalCommand::Play(name) => {
+                self.play_macro(name, count.unwrap_or(1), file_path);
+                return;
+            }
+            NormalCommand::CommandWindow => {
+                self.open_command_window = true;
+                return;
+            }
+            NormalCommand::Window(ch) => {
+                self.window_key(ch);
+                return;
+            }
+            NormalCommand::TabPage { forward } => {
+                self.window_command = Some(if forward {
+                    WindowCommand::TabNext(count)
+                } else {
+                    WindowCommand::TabPrevious(count.unwrap_or(1))
+                });
+                return;
+            }
+            NormalCommand::Operate(operator, target) => (operator, target),
+        };
+
+        let range = match target {
+            Target::Lines => {
+                let bottom = (cursor.1.saturating_add(count.unwrap_or(1) - 1))
+                    .min(piece_table.line_count().saturating_sub(1));
+                Some(TextRange::new(
+                    RangeKind::Linewise,
+                    cursor,
+                    (cursor.0, bottom),
+                ))
+            }
+            Target::Motion(motion) => motion.operator_range(operator, piece_table, cursor, count),
+            Target::TextObject(object) => {
+                let Some((start, end)) = object.span(piece_table, cursor, count) else {
+                    self.fail();
+                    return;
+                };
+                let range = TextRange::from_span(object.kind(), start, end, piece_table);
+                // Changing an empty object like the inside of `()` types into it
+                if range.is_none() && operator == Operator::Change {
+                    piece_table.begin_undo_group(cursor);
+                    let (x, y) = piece_table.coordinates(start);
+                    cursor_controller.set_cursor_y(y, piece_table.line_count());
+                    cursor_controller.set_cursor_x_insert_mode(x, piece_table.line_grapheme_len(y));
+                    switch_mode(Mode::Insert, self.get_mode_mut());
+                    return;
+                }
+                range
+            }
+        };
+        if range.is_none() {
+            self.fail();
+        }
+        if let Some(range) = range {
+            self.operate(
+                operator,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            );
+        }
+    }
+
+    pub fn visual_keypress(
+        &mut self,
+        key_event: KeyEvent,
+        file_path: String,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) -> io::Result<bool> {
+        let Mode::Visual { kind, anchor } = self.mode() else {
+            return Ok(true);
+        };
+        let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+        let range = TextRange::new(kind, anchor, cursor);
+
+        match command_char(key_event) {
+            Some(ch) if self.has_pending_command() || starts_visual_target(ch) => {
+                self.pending.push(ch);
+                match parse_visual_target(&self.pending) {
+                    Parsed::Incomplete => {}
+                    Parsed::Invalid => self.pending.clear(),
+                    Parsed::Complete((prefix, target)) => {
+                        self.pending.clear();
+                        self.visual_target(target, prefix.count, piece_table, cursor_controller);
+                    }
+                }
+                return Ok(true);
+            }
+            _ if self.has_pending_command() => {
+                self.pending.clear();
+                return Ok(true);
+            }
+            _ => {}
+        }
+        let prefix = self.take_prefix();
+
+        match key_event {
+            KeyEvent {
+                code: KeyCode::Esc, ..
+            } => switch_mode(Mode::Normal(None), self.get_mode_mut()),
+
+            KeyEvent {
+                code: KeyCode::Char('v'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => switch_visual(RangeKind::Blockwise, self.get_mode_mut()),
+
+            KeyEvent {
+                code: KeyCode::Char('v'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => switch_visual(RangeKind::Charwise, self.get_mode_mut()),
+
+            KeyEvent {
+                code: KeyCode::Char('V'),
+                ..
+            } => switch_visual(RangeKind::Linewise, self.get_mode_mut()),
+
+            KeyEvent {
+                code: KeyCode::Char('o'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => {
+                switch_mode(
+                    Mode::Visual {
+                        kind,
+                        anchor: cursor,
+                    },
+                    self.get_mode_mut(),
+                );
+                cursor_controller.restore_position(anchor, piece_table);
+            }
+
+            // `:` starts a command on the selected lines
+            KeyEvent {
+                code: KeyCode::Char(':'),
+                ..
+            } => {
+                let range = "'<,'>".to_string();
+                switch_mode(Mode::Command(CommandLine::new(range)), self.get_mode_mut());
+            }
+
+            KeyEvent {
+                code: KeyCode::Char('d' | 'x'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.visual_operate(
+                Operator::Delete,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('y'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.operate(
+                Operator::Yank,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('c' | 's'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.visual_operate(
+                Operator::Change,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('>'),
+                ..
+            } => self.visual_operate(
+                Operator::Indent,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('<'),
+                ..
+            } => self.visual_operate(
+                Operator::Outdent,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('='),
+                ..
+            } => self.visual_operate(
+                Operator::Format,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('~'),
+                ..
+            } => self.visual_operate(
+                Operator::ToggleCase,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('u'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.visual_operate(
+                Operator::Lowercase,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('U'),
+                ..
+            } => self.visual_operate(
+                Operator::Uppercase,
+                range,
+                prefix.register,
+                piece_table,
+                cursor_controller,
+            ),
+
+            KeyEvent {
+                code: KeyCode::Char('p' | 'P'),
+                ..
+            } => self.visual_put(range, prefix, &file_path, piece_table, cursor_controller),
+
+            KeyEvent {
+                code: KeyCode::Char(ch @ ('I' | 'A')),
+                ..
+            } if kind == RangeKind::Blockwise => {
+                let (first, last) = range.block_columns(piece_table);
+                let (kind, column) = match ch {
+                    'A' if self.visual_to_line_end => {
+                        let line = piece_table.line(range.top()).unwrap_or_default();
+                        let width = display_column(&line, grapheme_count(&line));
+                        (BlockInsertKind::AppendToLineEnd, width)
+                    }
+                    'A' => (BlockInsertKind::Append, last + 1),
+                    _ => (BlockInsertKind::Insert, first),
+                };
+                piece_table.begin_undo_group(cursor);
+                self.start_block_insert(kind, range, column, piece_table, cursor_controller);
+            }
+
+            _ => {}
+        }
+
+        // However visual mode ended, `'<` and `'>` are the lines that were selected
+        if !matches!(self.mode(), Mode::Visual { .. }) {
+            self.visual_lines = Some((range.top(), range.bottom()));
+            self.visual_to_line_end = false;
+        }
+        Ok(true)
+    }
+
+    // Moves the cursor, or selects a text object
+    fn visual_target(
+        &mut self,
+        target: Target,
+        count: Option<usize>,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let Mode::Visual { kind, .. } = self.mode() else {
+            return;
+        };
+        match target {
+            Target::Motion(motion) => {
+                if !move_cursor(motion, count, piece_table, cursor_controller) {
+                    self.fail();
+                    return;
+                }
+                match motion {
+                    Motion::LineEnd => self.visual_to_line_end = true,
+                    Motion::Down | Motion::Up => {}
+                    _ => self.visual_to_line_end = false,
+                }
+            }
+            Target::TextObject(object) => {
+                let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+                let Some(range) =
+                    object
+                        .span(piece_table, cursor, count)
+                        .and_then(|(start, end)| {
+                            TextRange::from_span(object.kind(), start, end, piece_table)
+                        })
+                else {
+                    return;
+                };
+                let kind = match object.kind() {
+                    RangeKind::Linewise => RangeKind::Linewise,
+                    _ => kind,
+                };
+                switch_mode(
+                    Mode::Visual {
+                        kind,
+                        anchor: range.start,
+                    },
+                    self.get_mode_mut(),
+                );
+                cursor_controller.restore_position(range.end, piece_table);
+            }
+            Target::Lines => {}
+        }
+    }
+
+    // Replaces the selection with the contents of a register, which takes the place of
+    // the selected text in the unnamed register
+    fn visual_put(
+        &mut self,
+        range: TextRange,
+        prefix: Prefix,
+        file_path: &str,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let Some(mut register) = self.get_register(prefix.register, file_path) else {
+            return;
+        };
+        piece_table.begin_undo_group(range.start);
+        let (deleted, (x, y)) = apply_operator(Operator::Delete, range, piece_table);
+        if let Some(deleted) = deleted {
+            self.registers.delete(None, deleted);
+        }
+
+        let (cursor, before) = match (range.kind, register.kind) {
+            // Text put in place of lines goes on a line of its own
+            (RangeKind::Linewise, _) => {
+                if register.kind != RangeKind::Linewise {
+                    register.text.push('\n');
+                    register.kind = RangeKind::Linewise;
+                }
+                let line_count = piece_table.line_count();
+                let top = range.top();
+                ((0, top.min(line_count.saturating_sub(1))), top < line_count)
+            }
+            // Lines put in place of part of a line split it
+            (_, RangeKind::Linewise) => {
+                if let Ok(position) = piece_table.find_index(x, y) {
+                    piece_table.insert(position, "\n");
+                }
+                ((0, y), false)
+            }
+            _ => ((x, y), true),
+        };
+        let position = put(
+            &register,
+            piece_table,
+            cursor,
+            before,
+            prefix.count.unwrap_or(1),
+        );
+        piece_table.end_undo_group();
+        cursor_controller.restore_position(position, piece_table);
+        switch_mode(Mode::Normal(None), self.get_mode_mut());
+    }
+
+    // The contents of a register, or a message saying it is empty
+    fn get_register(&mut self, name: Option<char>, file_path: &str) -> Option<Register> {
+        let register = self.registers.get(name, file_path);
+        if register.is_none() {
+            self.fail();
+            let message = format!("Nothing in register {}", name.unwrap_or('"'));
+            switch_mode(
+                Mode::Normal(Some(BarMode::Message(message))),
+                self.get_mode_mut(),
+            );
+        }
+        register
+    }
+
+    // Applies an operator to the selection, remembering how much was selected for `.`
+    fn visual_operate(
+        &mut self,
+        operator: Operator,
+        range: TextRange,
+        register: Option<char>,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let (lines, columns) = match range.kind {
+            RangeKind::Blockwise => {
+                let (first, last) = range.block_columns(piece_table);
+                (range.bottom() - range.top(), last - first)
+            }
+            _ if range.top() == range.bottom() => (0, range.end.0 - range.start.0),
+            _ => (range.bottom() - range.top(), range.end.0),
+        };
+        self.operate(operator, range, register, piece_table, cursor_controller);
+        if operator != Operator::Yank {
+            self.record_change(Change::Visual {
+                operator,
+                kind: range.kind,
+                lines,
+                columns,
+                register,
+            });
+        }
+    }
+
+    // Applies `operator` to `range` as one undoable change, going on to insert mode for a change.
+    // Deleted or yanked text goes to the register `register_name`
+    fn operate(
+        &mut self,
+        operator: Operator,
+        range: TextRange,
+        register_name: Option<char>,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let (first_column, _) = range.block_columns(piece_table);
+        piece_table.begin_undo_group(range.start);
+        let (register, cursor) = apply_operator(operator, range, piece_table);
+        if let Some(register) = register {
+            if operator == Operator::Yank {
+                self.registers.yank(register_name, register);
+            } else {
+                self.registers.delete(register_name, register);
+            }
+        }
+
+        if operator != Operator::Change {
+            piece_table.end_undo_group();
+            cursor_controller.restore_position(cursor, piece_table);
+            switch_mode(Mode::Normal(None), self.get_mode_mut());
+            return;
+        }
+
+        // The undo group stays open until the insert is finished
+        if range.kind == RangeKind::Blockwise {
+            self.start_block_insert(
+                BlockInsertKind::Change,
+                range,
+                first_column,
+                piece_table,
+                cursor_controller,
+            );
+            return;
+        }
+        let (x, y) = cursor;
+        cursor_controller.set_cursor_y(y, piece_table.line_count().max(1));
+        cursor_controller.set_cursor_x_insert_mode(x, piece_table.line_grapheme_len(y));
+        switch_mode(Mode::Insert, self.get_mode_mut());
+    }
+
+    fn start_block_insert(
+        &mut self,
+        kind: BlockInsertKind,
+        range: TextRange,
+        column: usize,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let y = range.top();
+        if kind == BlockInsertKind::Append {
+            pad_line(piece_table, y, column);
+        }
+        let line = piece_table.line(y).unwrap_or_default();
+        let x = grapheme_at_column(&line, column);
+
+        self.block_insert = Some(BlockInsert {
+            kind,
+            top: y,
+            bottom: range.bottom(),
+            column,
+            offset: grapheme_to_byte(&line, x),
+            line_length: line.len(),
+        });
+        cursor_controller.set_cursor_y(y, piece_table.line_count());
+        cursor_controller.set_cursor_x_insert_mode(x, grapheme_count(&line));
+        switch_mode(Mode::Insert, self.get_mode_mut());
+    }
+
+    pub fn command_keypress(
+        &mut self,
+        key_event: KeyEvent,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> io::Result<bool> {
+        let Mode::Command(mut line) = self.mode() else {
+            return Ok(true);
+        };
+        // `Ctrl-r` puts in the register named by the next key
+        if line.register_pending {
+            line.register_pending = false;
+            let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+            if let Some(text) = self.inserted_register(key_event, piece_table, cursor, metadata) {
+                line.insert(&text);
+            }
+            switch_mode(Mode::Command(line), self.get_mode_mut());
+            return Ok(true);
+        }
+
+        let word = key_event
+            .modifiers
+            .intersects(KeyModifiers::SHIFT | KeyModifiers::CONTROL);
+        match key_event {
+            KeyEvent {
+                code: KeyCode::Esc, ..
+            }
+            | KeyEvent {
+                code: KeyCode::Char('c'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => {
+                switch_mode(Mode::Normal(None), self.get_mode_mut());
+                return Ok(true);
+            }
+
+            KeyEvent {
+                code: KeyCode::Enter,
+                ..
+            } => {
+                return self.execute_command_line(
+                    line.text,
+                    piece_table,
+                    metadata,
+                    cursor_controller,
+                );
+            }
+
+            KeyEvent {
+                code: KeyCode::Tab | KeyCode::BackTab,
+                ..
+            } => {
+                let context = RangeContext {
+                    current: cursor_controller.cursor_y(),
+                    piece_table,
+                    visual: self.visual_lines,
+                    last_pattern: None,
+                    buffer_names: &self.buffer_names,
+                };
+                line.complete(key_event.code == KeyCode::Tab, |text| {
+                    completions(text, &context)
+                });
+            }
+
+            KeyEvent {
+                code: KeyCode::Char('r'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => line.register_pending = true,
+
+            KeyEvent {
+                code: KeyCode::Char('w'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => line.delete_word(),
+
+            KeyEvent {
+                code: KeyCode::Char('u'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => line.delete_to_start(),
+
+            KeyEvent {
+                code: KeyCode::Left,
+                ..
+            } if word => line.word_left(),
+
+            KeyEvent {
+                code: KeyCode::Right,
+                ..
+            } if word => line.word_right(),
+
+            KeyEvent {
+                code: KeyCode::Left,
+                ..
+            } => line.move_left(),
+
+            KeyEvent {
+                code: KeyCode::Right,
+                ..
+            } => line.move_right(),
+
+            KeyEvent {
+                code: KeyCode::Home,
+                ..
+            }
+            | KeyEvent {
+                code: KeyCode::Char('b'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => line.move_home(),
+
+            KeyEvent {
+                code: KeyCode::End, ..
+            }
+            | KeyEvent {
+                code: KeyCode::Char('e'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => line.move_end(),
+
+            // Up and Down only go to lines starting with what was typed, Ctrl-p and Ctrl-n go to any
+            KeyEvent {
+                code: KeyCode::Up | KeyCode::Down,
+                ..
+            } => line.browse_history(&self.command_history, key_event.code == KeyCode::Up, true),
+
+            KeyEvent {
+                code: KeyCode::Char(ch @ ('p' | 'n')),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => line.browse_history(&self.command_history, ch == 'p', false),
+
+            KeyEvent {
+                code: KeyCode::Backspace,
+                ..
+            }
+            | KeyEvent {
+                code: KeyCode::Char('h'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => {
+                // Backspace on an empty line leaves the command line
+                if line.text.is_empty() {
+                    switch_mode(Mode::Normal(None), self.get_mode_mut());
+                    return Ok(true);
+                }
+                line.backspace();
+            }
+
+            KeyEvent {
+                code: KeyCode::Delete,
+                ..
+            } => line.delete(),
+
+            KeyEvent {
+                code: KeyCode::Char(ch),
+                modifiers,
+                ..
+            } if !modifiers.contains(KeyModifiers::CONTROL) => line.insert(&ch.to_string()),
+
+            _ => {}
+        }
+
+        switch_mode(Mode::Command(line), self.get_mode_mut());
+        Ok(true)
+    }
+
+    // What `Ctrl-r` followed by `key_event` puts in the command line: the text of a register,
+    // or the word under the cursor for `Ctrl-w`
+    fn inserted_register(
+        &self,
+        key_event: KeyEvent,
+        piece_table: &PieceTable,
+        cursor: (usize, usize),
+        metadata: &FileMetadata,
+    ) -> Option<String> {
+        match key_event {
+            KeyEvent {
+                code: KeyCode::Char('w'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => word_at(piece_table, cursor).map(|(_, word)| word),
+            KeyEvent {
+                code: KeyCode::Char(name),
+                ..
+            } => {
+                let register = self.registers.get(Some(name), &metadata.file_path)?;
+                // Lines are kept apart with carriage returns, like `:normal` would type them
+                Some(register.text.trim_end_matches('\n').replace('\n', "\r"))
+            }
+            _ => None,
+        }
+    }
+
+    // Runs a command line and keeps it for the history and `@:`
+    pub fn execute_command_line(
+        &mut self,
+        line: String,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> io::Result<bool> {
+        let result = self.run_command_line(&line, piece_table, metadata, cursor_controller);
+        if !line.is_empty() {
+            self.command_history.add(&line);
+            self.registers.last_command = line;
+        }
+        result
+    }
+
+    // Runs a command line typed after `:`, returning false to quit
+    fn run_command_line(
+        &mut self,
+        line: &str,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> io::Result<bool> {
+        switch_mode(Mode::Normal(None), self.get_mode_mut());
+        match self.run_commands(line, piece_table, metadata, cursor_controller) {
+            Ok(running) => Ok(running),
+            Err(error) => {
+                self.command_error(error);
+                Ok(true)
+            }
+        }
+    }
+
+    // Runs the commands on a command line one after another, stopping at the first error
+    fn run_commands(
+        &mut self,
+        line: &str,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> Result<bool, CommandError> {
+        let mut rest = Some(line);
+        while let Some(line) = rest {
+            let context = RangeContext {
+                current: cursor_controller.cursor_y(),
+                piece_table,
+                visual: self.visual_lines,
+                last_pattern: self.last_search.as_ref().map(|last| &last.pattern),
+                buffer_names: &self.buffer_names,
+            };
+            let (command, next) = parse_command(line, &context)?;
+            rest = next;
+            if !self.ex_command(command, piece_table, metadata, cursor_controller)? {
+                return Ok(false);
+            }
+            // A `:s` with the `c` flag has to be answered before anything else runs
+            if !matches!(self.mode(), Mode::Normal(_)) {
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    // Runs one command, returning false to quit
+    fn ex_command(
+        &mut self,
+        command: ExCommand,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> Result<bool, CommandError> {
+        let range = command
+            .range
+            .unwrap_or(LineRange::line(cursor_controller.cursor_y()));
+        let Some(name) = command.name else {
+            // `:10` goes to line 10
+            if command.range.is_some() {
+                let y = range.end;
+                cursor_controller
+                    .restore_position((first_non_blank(piece_table, y), y), piece_table);
+            }
+            return Ok(true);
+        };
+        match name {
+            // `:w !cmd` gives the lines, or the whole file, to a shell command and shows what
+            // it prints
+            CommandName::Write if command.args.starts_with('!') => {
+                let text = lines_text(command.range, piece_table);
+                let output = run_shell(&command.args[1..], Some(&text))?;
+                let message = output.message();
+                let bar = (!message.is_empty()).then_some(BarMode::Message(message));
+                switch_mode(Mode::Normal(bar), self.get_mode_mut());
+            }
+            CommandName::Write => {
+                let message = write_lines(
+                    command.range,
+                    command.bang,
+                    &command.args,
+                    piece_table,
+                    metadata,
+                )?;
+                switch_mode(Mode::Normal(Some(message)), self.get_mode_mut());
+            }
+            CommandName::WriteQuit | CommandName::Exit => {
+                write_lines(
+                    command.range,
+                    command.bang,
+                    &command.args,
+                    piece_table,
+                    metadata,
+                )?;
+                self.buffer_command = Some(BufferCommand::Quit {
+                    bang: command.bang,
+                    all: false,
+                });
+                return Ok(false);
+            }
+            // Another file is opened in a buffer of its own
+            CommandName::Edit if !command.args.is_empty() && command.args != metadata.file_path => {
+                self.buffer_command = Some(BufferCommand::Edit(command.args));
+            }
+            CommandName::Edit => {
+                if !command.bang && piece_table.is_modified() {
+                    return Err(CommandError::NotSaved);
+                }
+                let path = metadata.file_path.clone();
+                if path.is_empty() {
+                    return Err(CommandError::NoFileName);
+                }
+                // A new file that still hasn't been written starts over empty
+                let text = file::load_file_if_exists(&path)
+                    .map_err(|error| CommandError::Io(format!("\"{}\" {}", path, error)))?
+                    .unwrap_or_default();
+                replace_text(&text, piece_table, cursor_controller);
+                piece_table.mark_saved();
+                metadata.update();
+                let message = format!(
+                    "\"{}\" {}L, {}B",
+                    path,
+                    piece_table.line_count(),
+                    text.len()
+                );
+                switch_mode(
+                    Mode::Normal(Some(BarMode::Message(message))),
+                    self.get_mode_mut(),
+                );
+            }
+            // The editor checks for changes, since the buffer may be in another window
+            CommandName::Quit | CommandName::QuitAll => {
+                self.buffer_command = Some(BufferCommand::Quit {
+                    bang: command.bang,
+                    all: name == CommandName::QuitAll,
+                });
+                return Ok(false);
+            }
+            CommandName::Split | CommandName::VSplit => {
+                self.window_command = Some(WindowCommand::Split {
+                    vertical: name == CommandName::VSplit,
+                    file_path: command.args,
+                })
+            }
+            CommandName::Only => self.window_command = Some(WindowCommand::Only),
+            CommandName::Close => self.window_command = Some(WindowCommand::Close),
+            CommandName::TabNew => self.window_command = Some(WindowCommand::TabNew(command.args)),
+            CommandName::TabNext => {
+                let number = parse_count(&command.args)?;
+                self.window_command = Some(WindowCommand::TabNext(number));
+            }
+            CommandName::TabPrevious => {
+                let count = parse_count(&command.args)?.unwrap_or(1);
+                self.window_command = Some(WindowCommand::TabPrevious(count));
+            }
+            CommandName::TabClose => self.window_command = Some(WindowCommand::TabClose),
+            CommandName::Buffer => self.buffer_command = Some(BufferCommand::Go(command.args)),
+            CommandName::BufferNext | CommandName::BufferPrevious => {
+                let count = parse_count(&command.args)?.unwrap_or(1);
+                self.buffer_command = Some(if name == CommandName::BufferNext {
+                    BufferCommand::Next(count)
+                } else {
+                    BufferCommand::Previous(count)
+                });
+            }
+            CommandName::Buffers => self.buffer_command = Some(BufferCommand::List),
+            CommandName::BufferDelete => {
+                self.buffer_command = Some(BufferCommand::Delete {
+                    buffer: command.args,
+                    bang: command.bang,
+                })
+            }
+            // `:r file` and `:r !cmd` put the file or what the command prints below the line
+            CommandName::Read => {
+                let text = match command.args.strip_prefix('!') {
+                    Some(shell_command) => run_shell(shell_command, None)?.text,
+                    None => {
+                        let path = if command.args.is_empty() {
+                            &metadata.file_path
+                        } else {
+                            &command.args
+                        };
+                        if path.is_empty() {
+                            return Err(CommandError::NoFileName);
+                        }
+                        file::load_file(path).map_err(|_| {
+                            CommandError::Io(format!("E484: Can't open file {}", path))
+                        })?
+                    }
+                };
+                if text.is_empty() {
+                    return Ok(true);
+                }
+                let text = if text.ends_with('\n') {
+                    text
+                } else {
+                    text + "\n"
+                };
+                let register = Register::new(&text, RangeKind::Linewise);
+                // `:0r` puts it above the first line
+                let (y, before) = if command.line_zero {
+                    (0, true)
+                } else {
+                    (range.end, false)
+                };
+                let position = put(&register, piece_table, (0, y), before, 1);
+                cursor_controller.restore_position(position, piece_table);
+            }
+            CommandName::Recover => self.recover = true,
+            CommandName::Undo => {
+                let result = if command.args.is_empty() {
+                    piece_table.undo()
+                } else {
+                    let change_number = command
+                        .args
+                        .parse()
+                        .map_err(|_| CommandError::TrailingCharacters(command.args.clone()))?;
+                    piece_table.undo_to(change_number)
+                };
+                restore_from_history(result, piece_table, cursor_controller, self.get_mode_mut());
+            }
+            CommandName::Redo => {
+                let result = piece_table.redo();
+                restore_from_history(result, piece_table, cursor_controller, self.get_mode_mut());
+            }
+            CommandName::Registers => {
+                let lines = self.registers.list(&command.args, &metadata.file_path);
+                let message = BarMode::Message(lines.join("\n"));
+                switch_mode(Mode::Normal(Some(message)), self.get_mode_mut());
+            }
+            CommandName::NoHlSearch => self.highlight_search = false,
+            CommandName::Substitute => {
+                self.substitute(range, &command.args, piece_table, cursor_controller)?
+            }
+            CommandName::Global | CommandName::VGlobal => {
+                let whole_file = LineRange {
+                    start: 0,
+                    end: piece_table.line_count().saturating_sub(1),
+                };
+                let invert = name == CommandName::VGlobal || command.bang;
+                return self.global(
+                    command.range.unwrap_or(whole_file),
+                    invert,
+                    &command.args,
+                    piece_table,
+                    metadata,
+                    cursor_controller,
+                );
+            }
+            CommandName::Normal => {
+                let lines = command.range.map(|range| range.start..=range.end);
+                return self.normal(
+                    lines,
+                    &command.args,
+                    piece_table,
+                    metadata,
+                    cursor_controller,
+                );
+            }
+            CommandName::Delete | CommandName::Yank => {
+                let (register_name, count) = register_and_count(&command.args)?;
+                let last = piece_table.line_count().saturating_sub(1);
+                // A count goes on from the end of the range, like it does for `:s`
+                let range = match count {
+                    Some(count) => LineRange {
+                        start: range.end,
+                        end: (range.end + count - 1).min(last),
+                    },
+                    None => range,
+                };
+                if piece_table.line_count() == 0 {
+                    return Ok(true);
+                }
+                let text_range =
+                    TextRange::new(RangeKind::Linewise, (0, range.start), (0, range.end));
+                if name == CommandName::Delete {
+                    self.operate(
+                        Operator::Delete,
+                        text_range,
+                        register_name,
+                        piece_table,
+                        cursor_controller,
+                    );
+                } else {
+                    let (register, _) = apply_operator(Operator::Yank, text_range, piece_table);
+                    if let Some(register) = register {
+                        self.registers.yank(register_name, register);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // `:g/pattern/command` runs the command on each line with a match, and `:v` on each line
+    // without one. The lines are marked first so the command can add and remove lines
+    fn global(
+        &mut self,
+        range: LineRange,
+        invert: bool,
+        args: &str,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> Result<bool, CommandError> {
+        if self.global_busy {
+            return Err(CommandError::GlobalRecursive);
+        }
+        let (source, command) = parse_global(args)?;
+        let pattern = if source.is_empty() {
+            let last = self.last_search.as_ref();
+            last.ok_or(CommandError::NoPreviousPattern)?.pattern.clone()
+        } else {
+            let pattern =
+                Pattern::new(&source).map_err(|error| CommandError::Regex(error.to_string()))?;
+            let forward = self.last_search.as_ref().is_none_or(|last| last.forward);
+            self.last_search = Some(LastSearch {
+                pattern: pattern.clone(),
+                forward,
+            });
+            pattern
+        };
+        self.highlight_search = true;
+
+        let lines: Vec<usize> = (range.start..=range.end)
+            .filter(|&y| {
+                piece_table
+                    .line(y)
+                    .is_some_and(|line| pattern.regex().find_in(&line, 0).is_some() != invert)
+            })
+            .collect();
+        if lines.is_empty() {
+            let source = pattern.source().to_string();
+            return Err(if invert {
+                CommandError::PatternEverywhere(source)
+            } else {
+                CommandError::PatternNotFound(source)
+            });
+        }
+        // With no command the lines are listed
+        if command.trim().is_empty() {
+            let listed: Vec<String> = lines.iter().filter_map(|&y| piece_table.line(y)).collect();
+            let message = BarMode::Message(listed.join("\n"));
+            switch_mode(Mode::Normal(Some(message)), self.get_mode_mut());
+            return Ok(true);
+        }
+
+        piece_table.mark_lines(lines);
+        piece_table.hold_undo_group((cursor_controller.cursor_x(), cursor_controller.cursor_y()));
+        self.global_busy = true;
+        let mut result = Ok(true);
+        while let Some(y) = piece_table.next_marked_line() {
+            cursor_controller.restore_position((0, y), piece_table);
+            result = match self.run_commands(command, piece_table, metadata, cursor_controller) {
+                // Lines without a match for a `:s` aren't a problem
+                Err(CommandError::PatternNotFound(_)) => Ok(true),
+                result => result,
+            };
+            // A `:s` with the `c` flag stops it as well, since it waits for answers
+            if result != Ok(true) || !matches!(self.mode(), Mode::Normal(_)) {
+                break;
+            }
+        }
+        self.global_busy = false;
+        piece_table.clear_line_marks();
+        piece_table.release_undo_group();
+        result
+    }
+
+    // `:normal keys` types the keys in normal mode, on each line of the range if there is one.
+    // Whatever they leave unfinished is cancelled like Esc would
+    fn normal(
+        &mut self,
+        lines: Option<RangeInclusive<usize>>,
+        keys: &str,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> Result<bool, CommandError> {
+        let keys = text_to_keys(keys);
+        let lines: Vec<Option<usize>> = match lines {
+            Some(lines) => lines.map(Some).collect(),
+            None => vec![None],
+        };
+        // Keys of a macro that is playing wait until these are done
+        let outer_keys = std::mem::take(&mut self.queued_keys);
+        let mut running = Ok(true);
+        for y in lines {
+            if let Some(y) = y {
+                if y >= piece_table.line_count() {
+                    break;
+                }
+                cursor_controller.restore_position((0, y), piece_table);
+            }
+            switch_mode(Mode::Normal(None), self.get_mode_mut());
+            self.queued_keys.extend(keys.iter().copied());
+            running = self.play_queued_keys(piece_table, metadata, cursor_controller);
+            if running != Ok(true) {
+                break;
+            }
+            for _ in 0..3 {
+                if matches!(self.mode(), Mode::Normal(_)) && !self.has_pending_command() {
+                    break;
+                }
+                let escape = KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE);
+                self.process_key(escape, piece_table, metadata, cursor_controller)
+                    .map_err(|error| CommandError::Io(error.to_string()))?;
+            }
+        }
+        self.queued_keys = outer_keys;
+        running
+    }
+
+    fn play_queued_keys(
+        &mut self,
+        piece_table: &mut PieceTable,
+        metadata: &mut FileMetadata,
+        cursor_controller: &mut CursorController,
+    ) -> Result<bool, CommandError> {
+        while let Some(key_event) = self.queued_keys.pop_front() {
+            let running = self
+                .process_key(key_event, piece_table, metadata, cursor_controller)
+                .map_err(|error| CommandError::Io(error.to_string()))?;
+            if !running {
+                self.queued_keys.clear();
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn command_error(&mut self, error: CommandError) {
+        switch_mode(
+            Mode::Normal(Some(BarMode::Message(error.to_string()))),
+            self.get_mode_mut(),
+        );
+        self.fail();
+    }
+
+    // `:[range]s[ubstitute]/pattern/replacement/flags count`
+    fn substitute(
+        &mut self,
+        range: LineRange,
+        args: &str,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) -> Result<(), CommandError> {
+        let command = parse_substitute(args)?;
+        let (source, replacement) = match command.replacement {
+            Some(replacement) => {
+                // `:s//new/` uses the last pattern searched for
+                let source = if command.pattern.is_empty() {
+                    let last = self.last_search.as_ref();
+                    let last = last.ok_or(CommandError::NoPreviousPattern)?;
+                    last.pattern.source().to_string()
+                } else {
+                    command.pattern
+                };
+                let previous = self
+                    .last_substitute
+                    .as_ref()
+                    .map(|(_, replacement)| replacement.as_str());
+                (
+                    source,
+                    expand_tilde(&replacement, previous.unwrap_or_default()),
+                )
+            }
+            None => self
+                .last_substitute
+                .clone()
+                .ok_or(CommandError::NoPreviousSubstitute)?,
+        };
+        let flagged = match command.flags.ignore_case {
+            Some(true) => format!("\\c{}", source),
+            Some(false) => format!("\\C{}", source),
+            None => source.clone(),
+        };
+        let pattern =
+            Pattern::new(&flagged).map_err(|error| CommandError::Regex(error.to_string()))?;
+        let forward = self.last_search.as_ref().is_none_or(|last| last.forward);
+        self.last_search = Pattern::new(&source)
+            .ok()
+            .map(|pattern| LastSearch { pattern, forward });
+        self.last_substitute = Some((source.clone(), replacement.clone()));
+        self.highlight_search = true;
+
+        // A count after the flags starts at the end of the range
+        let range = match command.count {
+            Some(count) => LineRange {
+                start: range.end,
+                end: (range.end + count - 1).min(piece_table.line_count().saturating_sub(1)),
+            },
+            None => range,
+        };
+        let mut substitution = Substitution::new(
+            pattern,
+            Replacement::new(&replacement),
+            command.flags,
+            range,
+            piece_table,
+        );
+        if substitution.next_match(piece_table).is_none() {
+            return Err(CommandError::PatternNotFound(source));
+        }
+
+        piece_table.begin_undo_group((cursor_controller.cursor_x(), cursor_controller.cursor_y()));
+        if substitution.confirm() {
+            self.substitution = Some(substitution);
+            self.confirm_next(piece_table, cursor_controller);
+        } else {
+            while let Some(captures) = substitution.next_match(piece_table) {
+                substitution.substitute(&captures, piece_table);
+            }
+            self.finish_substitution(substitution, piece_table, cursor_controller);
+        }
+        Ok(())
+    }
+
+    // Moves to the next match of a `:s` with the `c` flag and asks about it
+    fn confirm_next(
+        &mut self,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        let Some(substitution) = self.substitution.take() else {
+            return;
+        };
+        match substitution.next_match(piece_table) {
+            Some(captures) => {
+                cursor_controller
+                    .restore_position(piece_table.coordinates(captures.start()), piece_table);
+                let replacement = self
+                    .last_substitute
+                    .as_ref()
+                    .map(|(_, replacement)| replacement.as_str())
+                    .unwrap_or_default();
+                let prompt = format!("replace with {} (y/n/a/q/l)?", replacement);
+                switch_mode(Mode::Confirm { prompt }, self.get_mode_mut());
+                self.substitution = Some(substitution);
+            }
+            None => self.finish_substitution(substitution, piece_table, cursor_controller),
+        }
+    }
+
+    pub fn confirm_keypress(
+        &mut self,
+        key_event: KeyEvent,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) -> io::Result<bool> {
+        let Some(mut substitution) = self.substitution.take() else {
+            switch_mode(Mode::Normal(None), self.get_mode_mut());
+            return Ok(true);
+        };
+        let Some(captures) = substitution.next_match(piece_table) else {
+            self.finish_substitution(substitution, piece_table, cursor_controller);
+            return Ok(true);
+        };
+        match key_event {
+            KeyEvent {
+                code: KeyCode::Char('y'),
+                ..
+            } => substitution.substitute(&captures, piece_table),
+            KeyEvent {
+                code: KeyCode::Char('n'),
+                ..
+            } => substitution.skip(&captures, piece_table),
+            // `a` does this one and the rest without asking
+            KeyEvent {
+                code: KeyCode::Char('a'),
+                ..
+            } => {
+                substitution.substitute(&captures, piece_table);
+                while let Some(captures) = substitution.next_match(piece_table) {
+                    substitution.substitute(&captures, piece_table);
+                }
+            }
+            // `l` does this one and stops
+            KeyEvent {
+                code: KeyCode::Char('l'),
+                ..
+            } => {
+                substitution.substitute(&captures, piece_table);
+                self.finish_substitution(substitution, piece_table, cursor_controller);
+                return Ok(true);
+            }
+            KeyEvent {
+                code: KeyCode::Char('q') | KeyCode::Esc,
+                ..
+            } => {
+                self.finish_substitution(substitution, piece_table, cursor_controller);
+                return Ok(true);
+            }
+            _ => {}
+        }
+        self.substitution = Some(substitution);
+        self.confirm_next(piece_table, cursor_controller);
+        Ok(true)
+    }
+
+    // Puts the cursor on the last line changed and says how many substitutions there were
+    fn finish_substitution(
+        &mut self,
+        substitution: Substitution,
+        piece_table: &mut PieceTable,
+        cursor_controller: &mut CursorController,
+    ) {
+        piece_table.end_undo_group();
+        if substitution.substitutions == 0 {
+            switch_mode(Mode::Normal(None), self.get_mode_mut());
+            return;
+        }
+        if let Some(y) = substitution
+            .last_changed()
+            .filter(|_| !substitution.count_only())
+        {
+            let y = y.min(piece_table.line_count().saturating_sub(1));
+            cursor_controller.restore_position((first_non_blank(piece_table, y), y), piece_table);
+        }
+        switch_mode(
+            Mode::Normal(Some(BarMode::Message(substitution.report()))),
+            self.get_mode_mut(),
+        );
+    }
+}
+
+// The character a key adds to a pending command
+fn command_char(key_event: KeyEvent) -> Option<char> {
+    match key_event {
+        KeyEvent {
+            code: KeyCode::Char(ch),
+            modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT,
+            ..
+        } => Some(ch),
+        KeyEvent {
+            code: KeyCode::Char('w'),
+            modifiers: KeyModifiers::CONTROL,
+            ..
+        } => Some(WINDOW_KEY),
+        KeyEvent {
+            code: KeyCode::Enter,
+            ..
+        } => Some('\n'),
+        KeyEvent {
+            code: KeyCode::Tab, ..
+        } => Some('\t'),
+        _ => None,
+    }
+}
+
+// The number after a command like `:bnext` or `:tabnext`, if there is one
+fn parse_count(args: &str) -> Result<Option<usize>, CommandError> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+    args.parse()
+        .map(Some)
+        .map_err(|_| CommandError::TrailingCharacters(args.to_string()))
+}
+
+// Whether `.` repeats a command
+fn is_change(command: NormalCommand) -> bool {
+    !matches!(
+        command,
+        NormalCommand::Move(_)
+            | NormalCommand::Operate(Operator::Yank, _)
+            | NormalCommand::Record(_)
+            | NormalCommand::Play(_)
+            | NormalCommand::Window(_)
+            | NormalCommand::TabPage { .. }
+    )
+}
+
+// The text a visual change covers when `.` repeats it from the cursor
+fn repeated_range(
+    kind: RangeKind,
+    lines: usize,
+    columns: usize,
+    piece_table: &PieceTable,
+    cursor_controller: &CursorController,
+) -> TextRange {
+    let (x, y) = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+    let bottom = (y + lines).min(piece_table.line_count().saturating_sub(1));
+    let end = match kind {
+        RangeKind::Linewise => (x, bottom),
+        RangeKind::Charwise if lines == 0 => (x + columns, y),
+        RangeKind::Charwise => (columns, bottom),
+        RangeKind::Blockwise => {
+            let line = piece_table.line(y).unwrap_or_default();
+            let column = display_column(&line, x) + columns;
+            let bottom_line = piece_table.line(bottom).unwrap_or_default();
+            (grapheme_at_column(&bottom_line, column), bottom)
+        }
+    };
+    TextRange::new(kind, (x, y), end)
+}
+
+fn starts_normal_command(ch: char) -> bool {
+    parse_normal_command(&ch.to_string()) != Parsed::Invalid
+}
+
+fn starts_visual_target(ch: char) -> bool {
+    parse_visual_target(&ch.to_string()) != Parsed::Invalid
+}
+
+// `/` and `?`, which start typing a pattern
+fn start_search(
+    forward: bool,
+    count: usize,
+    cursor_controller: &CursorController,
+    mode: &mut Mode,
+) {
+    let origin = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+    switch_mode(
+        Mode::Search {
+            forward,
+            previous_chars: String::new(),
+            origin,
+            count,
+        },
+        mode,
+    );
+}
+
+// Byte offset of the cursor
+fn cursor_offset(piece_table: &PieceTable, cursor_controller: &CursorController) -> usize {
+    piece_table
+        .find_index(cursor_controller.cursor_x(), cursor_controller.cursor_y())
+        .unwrap_or(0)
+}
+
+fn quit() -> io::Result<bool> {
+    Ok(false)
+}
+
+// Where the cursor goes after undoing or redoing
+type HistoryResult = Result<Option<(usize, usize)>, HistoryError>;
+
+// Undoes or redoes `count` changes, stopping early at the end of the history
+fn step_history(
+    piece_table: &mut PieceTable,
+    count: usize,
+    step: fn(&mut PieceTable) -> HistoryResult,
+) -> HistoryResult {
+    let mut result = step(piece_table);
+    for _ in 1..count {
+        match step(piece_table) {
+            Ok(cursor) => result = Ok(cursor),
+            Err(_) => break,
+        }
+    }
+    result
+}
+
+fn restore_from_history(
+    result: HistoryResult,
+    piece_table: &PieceTable,
+    cursor_controller: &mut CursorController,
+    mode: &mut Mode,
+) {
+    let target_mode = match result {
+        Ok(cursor) => {
+            let position =
+                cursor.unwrap_or((cursor_controller.cursor_x(), cursor_controller.cursor_y()));
+            cursor_controller.restore_position(position, piece_table);
+            Mode::Normal(None)
+        }
+        Err(HistoryError::AtOldest) => Mode::Normal(Some(BarMode::Message(
+            "Already at oldest change".to_string(),
+        ))),
+        Err(HistoryError::AtNewest) => Mode::Normal(Some(BarMode::Message(
+            "Already at newest change".to_string(),
+        ))),
+        Err(HistoryError::NoSuchChange(number)) => Mode::Normal(Some(BarMode::Message(format!(
+            "Undo number {} not found",
+            number
+        )))),
+    };
+    switch_mode(target_mode, mode);
+}
+
+// Swaps all the text for `text` as one change, keeping the cursor where it can
+pub fn replace_text(
+    text: &str,
+    piece_table: &mut PieceTable,
+    cursor_controller: &mut CursorController,
+) {
+    if *text == piece_table.to_string() {
+        return;
+    }
+    let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+    piece_table.begin_undo_group(cursor);
+    piece_table.delete_range(0, piece_table.len());
+    piece_table.insert(0, text);
+    piece_table.end_undo_group();
+    cursor_controller.restore_position(cursor, piece_table);
+}
+
+// `:[range]w[rite][!] [file]`, returning what to show in the status bar.
+// Writing over some other file, only part of this one or a read-only one needs `!`
+fn write_lines(
+    range: Option<LineRange>,
+    bang: bool,
+    file_name: &str,
+    piece_table: &mut PieceTable,
+    metadata: &mut FileMetadata,
+) -> Result<BarMode, CommandError> {
+    let path = if file_name.is_empty() {
+        metadata.file_path.clone()
+    } else {
+        file_name.to_string()
+    };
+    if path.is_empty() {
+        return Err(CommandError::NoFileName);
+    }
+    let other_file = path != metadata.file_path;
+    if !bang && other_file && Path::new(&path).exists() {
+        return Err(CommandError::FileExists);
+    }
+    if !bang && !other_file && metadata.read_only {
+        return Err(CommandError::ReadOnly);
+    }
+    if !bang && !other_file && matches!(metadata.disk_change(), Some(DiskChange::Modified { .. })) {
+        return Err(CommandError::ChangedOnDisk);
+    }
+    if !bang && !other_file && range.is_some() {
+        return Err(CommandError::PartialWrite);
+    }
+
+    let text = lines_text(range, piece_table);
+    let kept_owner = file::save_file(&path, text.clone())
+        .map_err(|error| CommandError::Write(format!("\"{}\" {}", path, error)))?;
+    let mut message = format!(
+        "\"{}\" {}L, {}B written",
+        path,
+        text.lines().count(),
+        text.len()
+    );
+    // The file was replaced by one of our own rather than written over in place
+    if !kept_owner {
+        message.push_str(", but its owner couldn't be kept");
+    }
+    // A buffer without a file takes the name it is first written to
+    if metadata.file_path.is_empty() && range.is_none() {
+        metadata.file_path = path;
+    } else if other_file || range.is_some() {
+        return Ok(BarMode::Message(message));
+    }
+    metadata.update();
+    piece_table.mark_saved();
+    Ok(if kept_owner {
+        BarMode::Write
+    } else {
+        BarMode::Message(message)
+    })
+}
+
+// The text of the lines in `range`, or of the whole file without one
+fn lines_text(range: Option<LineRange>, piece_table: &PieceTable) -> String {
+    match range {
+        Some(range) => {
+            let start = piece_table.line_start(range.start).unwrap_or(0);
+            let end = piece_table
+                .line_start(range.end + 1)
+                .unwrap_or(piece_table.len());
+            piece_table.slice(start, end)
+        }
+        None => piece_table.to_string(),
+    }
+}
+
+// Runs a shell command for `:w !cmd` or `:r !cmd`
+fn run_shell(command: &str, input: Option<&str>) -> Result<ShellOutput, CommandError> {
+    shell::run(command, input)
+        .map_err(|error| CommandError::Io(format!("Cannot run \"{}\": {}", command, error)))
+}
+
+// The register name and count that can follow `:d` and `:y`, like `:d a 3`
+fn register_and_count(args: &str) -> Result<(Option<char>, Option<usize>), CommandError> {
+    let trailing = || CommandError::TrailingCharacters(args.to_string());
+    let (register_name, rest) = match args.chars().next() {
+        Some(name) if !name.is_ascii_digit() => {
+            if !is_register_name(name) {
+                return Err(trailing());
+            }
+            (Some(name), args[name.len_utf8()..].trim_start())
+        }
+        _ => (None, args),
+    };
+    if rest.is_empty() {
+        return Ok((register_name, None));
+    }
+    match rest.parse() {
+        Ok(count) if count > 0 => Ok((register_name, Some(count))),
+        _ => Err(trailing()),
+    }
+}
+
+fn move_left(cursor_controller: &mut CursorController) {
+    cursor_controller.set_cursor_x_no_checks(cursor_controller.cursor_x().saturating_sub(1));
+    cursor_controller.update_desired_x();
+}
+
+fn move_cursor(
+    motion: Motion,
+    count: Option<usize>,
+    piece_table: &PieceTable,
+    cursor_controller: &mut CursorController,
+) -> bool {
+    let cursor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+    let Some((x, y)) = motion.target(piece_table, cursor, count) else {
+        return false;
+    };
+    match motion {
+        // Moving between lines keeps to the column the cursor was last put in
+        Motion::Down | Motion::Up => {
+            cursor_controller.set_cursor_y(y, piece_table.line_count());
+            cursor_controller.update_desired_x_if_needed(piece_table.line_grapheme_len(y));
+        }
+        _ => cursor_controller.restore_position((x, y), piece_table),
+    }
+    true
+}
+
+fn delete(piece_table: &mut PieceTable, cursor_controller: &mut CursorController) {
+    let cursor_x = cursor_controller.cursor_x();
+    let cursor_y = cursor_controller.cursor_y();
+    if cursor_x != piece_table.line_grapheme_len(cursor_y) {
+        if let (Ok(start), Ok(end)) = (
+            piece_table.find_index(cursor_x, cursor_y),
+            piece_table.find_index(cursor_x + 1, cursor_y),
+        ) {
+            piece_table.delete_range(start, end);
+        }
+    } else if let Ok(position) = piece_table.find_index(0, cursor_y + 1) {
+        piece_table.delete(position - 1);
+    }
+}
+
+fn backspace(piece_table: &mut PieceTable, cursor_controller: &mut CursorController) {
+    let cursor_x = cursor_controller.cursor_x();
+    let delete_x = cursor_x.saturating_sub(1);
+    let cursor_y = cursor_controller.cursor_y();
+
+    if cursor_x == 0 {
+        if cursor_y == 0 {
+            return;
+        }
+        if let Ok(position) = piece_table.find_index(0, cursor_y) {
+            let previous_line_len = piece_table.line_grapheme_len(cursor_y - 1);
+            piece_table.delete(position - 1);
+            cursor_controller.set_cursor_x_insert_mode(previous_line_len, previous_line_len);
+            cursor_controller.set_cursor_y(cursor_y - 1, piece_table.line_count());
+        }
+    } else if let (Ok(start), Ok(end)) = (
+        piece_table.find_index(delete_x, cursor_y),
+        piece_table.find_index(cursor_x, cursor_y),
+    ) {
+        piece_table.delete_range(start, end);
         cursor_controller
-            .set_cursor_x_insert_mode(0, lines.get(y + 1).unwrap_or(&"".to_string()).len());
+            .set_cursor_x_insert_mode(delete_x, piece_table.line_grapheme_len(cursor_y));
+    }
+}
+
+fn enter(piece_table: &mut PieceTable, cursor_controller: &mut CursorController) {
+    let x = cursor_controller.cursor_x();
+    let y = cursor_controller.cursor_y();
+    if let Ok(position) = piece_table.find_index(x, y) {
+        piece_table.insert(position, "\n");
+        cursor_controller.set_cursor_y(y + 1, piece_table.line_count());
+        cursor_controller.set_cursor_x_insert_mode(0, piece_table.line_grapheme_len(y + 1));
+    } else {
+        info!("Position {},{} not found", x, y);
+    }
+}
+
+// Types `text` at the cursor, leaving the cursor after it
+fn insert_text(piece_table: &mut PieceTable, cursor_controller: &mut CursorController, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    let x = cursor_controller.cursor_x();
+    let y = cursor_controller.cursor_y();
+    if let Ok(position) = piece_table.find_index(x, y) {
+        piece_table.insert(position, text);
+        let (new_x, new_y) = piece_table.coordinates(position + text.len());
+        cursor_controller.set_cursor_y(new_y, piece_table.line_count());
+        cursor_controller.set_cursor_x_insert_mode(new_x, piece_table.line_grapheme_len(new_y));
+    }
+}
+
+fn type_char(piece_table: &mut PieceTable, cursor_controller: &mut CursorController, ch: char) {
+    let x = cursor_controller.cursor_x();
+    let y = cursor_controller.cursor_y();
+
+    if let Ok(position) = piece_table.find_index(x, y) {
+        piece_table.insert(position, &ch.to_string());
+        // A combining character joins the previous grapheme instead of starting a new one
+        let (new_x, _) = piece_table.coordinates(position + ch.len_utf8());
+        cursor_controller.set_cursor_x_insert_mode(new_x, piece_table.line_grapheme_len(y));
     } else {
         info!("Position {},{} not found", x, y);
     }
 }
 
-fn type_char(
-    lines: &[String],
+// Replaces the grapheme under the cursor with `ch`, or appends it at the end of the line.
+// Returns the text that was replaced
+fn overtype_char(
     piece_table: &mut PieceTable,
     cursor_controller: &mut CursorController,
     ch: char,
+) -> Option<String> {
+    let x = cursor_controller.cursor_x();
+    let y = cursor_controller.cursor_y();
+    let replaced = match (
+        piece_table.find_index(x, y),
+        piece_table.find_index(x + 1, y),
+    ) {
+        (Ok(start), Ok(end)) => {
+            let replaced = piece_table.slice(start, end);
+            piece_table.delete_range(start, end);
+            Some(replaced)
+        }
+        _ => None,
+    };
+    type_char(piece_table, cursor_controller, ch);
+    replaced
+}
+
+// Undoes one `overtype_char` or newline from replace mode, moving the cursor back over it
+fn restore_replaced(
+    piece_table: &mut PieceTable,
+    cursor_controller: &mut CursorController,
+    replaced: Option<String>,
 ) {
+    backspace(piece_table, cursor_controller);
+    if let Some(text) = replaced {
+        let x = cursor_controller.cursor_x();
+        let y = cursor_controller.cursor_y();
+        if let Ok(position) = piece_table.find_index(x, y) {
+            piece_table.insert(position, &text);
+        }
+    }
+}
+
+// `r`: replaces `count` graphemes with `ch`, leaving the cursor on the last one.
+// Does nothing and returns false if the line doesn't have that many graphemes left
+fn replace_chars(
+    piece_table: &mut PieceTable,
+    cursor_controller: &mut CursorController,
+    ch: char,
+    count: usize,
+) -> bool {
     let x = cursor_controller.cursor_x();
     let y = cursor_controller.cursor_y();
+    if x + count > piece_table.line_grapheme_len(y) {
+        return false;
+    }
+    let (Ok(start), Ok(end)) = (
+        piece_table.find_index(x, y),
+        piece_table.find_index(x + count, y),
+    ) else {
+        return false;
+    };
 
-    if let Some(position) = find_index(lines, x, y) {
-        piece_table.insert(position, &ch.to_string());
-        cursor_controller.set_cursor_x_insert_mode(x + 1, lines[y].len() + 1);
+    // A line break replaces all the graphemes with a single newline
+    let text = match ch {
+        '\n' => "\n".to_string(),
+        _ => ch.to_string().repeat(count),
+    };
+    piece_table.begin_undo_group((x, y));
+    piece_table.delete_range(start, end);
+    piece_table.insert(start, &text);
+    piece_table.end_undo_group();
+
+    if ch == '\n' {
+        cursor_controller.set_cursor_y(y + 1, piece_table.line_count());
+        cursor_controller.set_cursor_x_no_checks(0);
     } else {
-        info!("Position {},{} not found", x, y);
+        cursor_controller.set_cursor_x_no_checks(x + count - 1);
     }
+    true
 }
 
-fn handle_escape_key(cursor_controller: &mut CursorController, mode: &mut Mode) {
+fn handle_escape_key(
+    piece_table: &mut PieceTable,
+    cursor_controller: &mut CursorController,
+    mode: &mut Mode,
+) {
+    piece_table.end_undo_group();
     let cursor_x = cursor_controller.cursor_x();
     if cursor_x != 0 {
         cursor_controller.set_cursor_x_no_checks(cursor_x - 1);
@@ -316,18 +2681,78 @@ fn handle_escape_key(cursor_controller: &mut CursorController, mode: &mut Mode)
 }
 
 fn handle_insert_key(
+    piece_table: &mut PieceTable,
     cursor_controller: &mut CursorController,
     mode: &mut Mode,
     shift_right: bool,
-    lines: &[String],
 ) {
+    // Everything typed until Esc is undone as one change
+    piece_table.begin_undo_group((cursor_controller.cursor_x(), cursor_controller.cursor_y()));
     switch_mode(Mode::Insert, mode);
-    let cur_line_len = lines[cursor_controller.cursor_y()].len();
+    let cur_line_len = piece_table.line_grapheme_len(cursor_controller.cursor_y());
     if shift_right && cur_line_len != 0 {
-        cursor_controller.set_cursor_x_insert_mode(
-            cursor_controller.cursor_x() + 1,
-            lines[cursor_controller.cursor_y()].len(),
-        );
+        cursor_controller.set_cursor_x_insert_mode(cursor_controller.cursor_x() + 1, cur_line_len);
+    }
+}
+
+fn start_visual(kind: RangeKind, cursor_controller: &CursorController, mode: &mut Mode) {
+    let anchor = (cursor_controller.cursor_x(), cursor_controller.cursor_y());
+    switch_mode(Mode::Visual { kind, anchor }, mode);
+}
+
+// Pressing the key of the current visual mode leaves it, any other switches to that mode
+fn switch_visual(target_kind: RangeKind, mode: &mut Mode) {
+    if let Mode::Visual { kind, anchor } = mode.clone() {
+        if kind == target_kind {
+            switch_mode(Mode::Normal(None), mode);
+        } else {
+            switch_mode(
+                Mode::Visual {
+                    kind: target_kind,
+                    anchor,
+                },
+                mode,
+            );
+        }
+    }
+}
+
+fn finish_block_insert(block_insert: BlockInsert, piece_table: &mut PieceTable) {
+    let top = block_insert.top;
+    let new_length = piece_table.line_len(top);
+    let Some(line_start) = piece_table.line_start(top) else {
+        return;
+    };
+    if new_length <= block_insert.line_length {
+        return;
+    }
+
+    let start = line_start + block_insert.offset;
+    let text = piece_table.slice(start, start + new_length - block_insert.line_length);
+    if text.contains('\n') {
+        return;
+    }
+
+    for y in top + 1..=block_insert.bottom {
+        let Some(line) = piece_table.line(y) else {
+            break;
+        };
+        let width = display_column(&line, grapheme_count(&line));
+        match block_insert.kind {
+            BlockInsertKind::Append => pad_line(piece_table, y, block_insert.column),
+            BlockInsertKind::Insert if width <= block_insert.column => continue,
+            BlockInsertKind::Change if width < block_insert.column => continue,
+            _ => {}
+        }
+
+        let line = piece_table.line(y).unwrap_or_default();
+        let x = match block_insert.kind {
+            BlockInsertKind::AppendToLineEnd => grapheme_count(&line),
+            _ => grapheme_at_column(&line, block_insert.column),
+        };
+        if let Ok(position) = piece_table.find_index(x, y) {
+            piece_table.insert(position, &text);
+        }
     }
 }
 
@@ -1,7 +1,22 @@
+pub mod buffer;
+pub mod command_line;
 pub mod editor;
 pub mod editor_tests;
+pub mod ex;
 pub mod file;
+pub mod history;
 pub mod key_handler;
+pub mod line_marks;
 pub mod metadata;
+pub mod motion;
+pub mod normal_command;
+pub mod operator;
 pub mod piece_table;
+pub mod regex;
+pub mod register;
+pub mod search;
+pub mod shell;
+pub mod substitute;
+pub mod swap;
 pub mod utils;
+pub mod window;
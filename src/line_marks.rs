@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+// Lines picked out by `:g`, which keep pointing at the same lines as lines around them
+// are added and removed
+#[derive(Default)]
+pub struct LineMarks {
+    // In order, each stored less `shift` so moving every mark down is cheap
+    lines: VecDeque<usize>,
+    shift: isize,
+}
+
+impl LineMarks {
+    pub fn new(lines: impl IntoIterator<Item = usize>) -> Self {
+        let mut lines: Vec<usize> = lines.into_iter().collect();
+        lines.sort_unstable();
+        lines.dedup();
+        Self {
+            lines: lines.into(),
+            shift: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    // Takes the first mark off
+    pub fn pop(&mut self) -> Option<usize> {
+        self.lines.pop_front().map(|line| self.actual(line))
+    }
+
+    // `count` lines were added before line `first`
+    pub fn insert_lines(&mut self, first: usize, count: usize) {
+        let from = self.position(first);
+        self.move_from(from, count as isize);
+    }
+
+    // Lines `first..first + count` were removed, along with their marks
+    pub fn remove_lines(&mut self, first: usize, count: usize) {
+        let from = self.position(first);
+        let to = self.position(first + count);
+        self.lines.drain(from..to);
+        self.move_from(from, -(count as isize));
+    }
+
+    fn actual(&self, line: usize) -> usize {
+        line.saturating_add_signed(self.shift)
+    }
+
+    // Where the first mark on or after `line` is
+    fn position(&self, line: usize) -> usize {
+        self.lines
+            .partition_point(|&stored| self.actual(stored) < line)
+    }
+
+    fn move_from(&mut self, from: usize, amount: isize) {
+        // Edits usually come before every mark that is left
+        if from == 0 {
+            self.shift += amount;
+            return;
+        }
+        for stored in self.lines.iter_mut().skip(from) {
+            *stored = stored.saturating_add_signed(amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_marks() {
+        let mut marks = LineMarks::new([6, 2, 4, 9]);
+        marks.insert_lines(0, 2);
+        marks.remove_lines(4, 1);
+        marks.insert_lines(8, 3);
+        marks.remove_lines(10, 2);
+        assert_eq!(marks.pop(), Some(5));
+        marks.remove_lines(3, 1);
+        assert_eq!(marks.pop(), Some(6));
+        assert_eq!(marks.pop(), Some(10));
+        assert_eq!(marks.pop(), None);
+        assert!(marks.is_empty());
+    }
+}
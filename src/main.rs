@@ -1,7 +1,9 @@
-use crossterm::terminal;
+use crossterm::event::EnableFocusChange;
+use crossterm::{execute, terminal};
 use log::{error, info};
 use std::env;
 use std::io;
+use text_editor::command_line::history_path;
 use text_editor::editor::{CleanUp, Editor};
 use text_editor::file;
 
@@ -9,22 +11,39 @@ fn main() -> io::Result<()> {
     env_logger::init();
     info!("Logging initialized");
 
+    let args: Vec<String> = env::args().collect();
+    // `-` reads the text from stdin, before raw mode so text typed in ends with Ctrl-d
+    let stdin_text = match args.get(1) {
+        Some(arg) if arg == "-" => Some(file::load_stdin()?),
+        _ => None,
+    };
+
     let _clean_up = CleanUp;
     if let Err(e) = terminal::enable_raw_mode() {
         error!("Error enabling raw mode: {}", e);
     };
+    // For checking whether the file changed while the terminal was in the background
+    if let Err(e) = execute!(io::stdout(), EnableFocusChange) {
+        error!("Error enabling focus change events: {}", e);
+    }
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 2 {
-        let file_path = args[1].clone();
-        let original_text = file::load_file(&file_path)?;
-        let mut editor = Editor::new(&original_text, file_path);
-
-        while editor.run()? {}
-    } else {
-        error!("Please provide one argument - The file to read");
-        panic!("Please provide one argument - The file to read");
+    // Without a file to edit, the buffer has no name until it is written with `:w name`.
+    // Files that aren't there yet are opened empty and made on the first write
+    let mut editor = match (stdin_text, args.get(1)) {
+        (Some(text), _) => Editor::new(&text, String::new()),
+        (None, Some(file_path)) => Editor::open(file_path.clone())?,
+        (None, None) => Editor::default(),
+    };
+    // The rest of the files are opened in buffers after the first
+    for file_path in args.iter().skip(2) {
+        editor.add_file(file_path.clone())?;
     }
+    if let Some(path) = history_path() {
+        editor.load_command_history(path);
+    }
+    editor.open_swap();
+
+    while editor.run()? {}
 
     Ok(())
 }
@@ -1,9 +1,28 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::time::SystemTime;
 
+// How the file on disk differs from when it was last read or written
+#[derive(PartialEq, Clone, Debug)]
+pub enum DiskChange {
+    Modified {
+        modified: Option<SystemTime>,
+        size: u64,
+        inode: u64,
+    },
+    Deleted,
+}
+
 pub struct FileMetadata {
+    // What the file on disk looked like when it was last read or written
     pub last_write_time: Option<SystemTime>,
     pub file_size: Option<usize>,
+    pub inode: Option<u64>,
     pub file_path: String,
+    // Set when the file was opened read-only, so only `:w!` writes it
+    pub read_only: bool,
+    // Set when there was no file to read, until it is first written
+    pub new_file: bool,
 }
 
 impl FileMetadata {
@@ -11,12 +30,72 @@ impl FileMetadata {
         Self {
             last_write_time: None,
             file_size: None,
+            inode: None,
             file_path,
+            read_only: false,
+            new_file: false,
         }
     }
 
-    pub fn update(&mut self, file_size: usize) {
-        self.last_write_time = Some(SystemTime::now());
-        self.file_size = Some(file_size);
+    // Notes what the file on disk looks like now, after reading or writing it
+    pub fn update(&mut self) {
+        let Ok(disk) = fs::metadata(&self.file_path) else {
+            return;
+        };
+        self.last_write_time = disk.modified().ok();
+        self.file_size = Some(disk.len() as usize);
+        self.inode = Some(disk.ino());
+        self.new_file = false;
+    }
+
+    // Whether something else has changed the file since `update`. Writing a new file
+    // over it, like most editors do, gives it a new inode
+    pub fn disk_change(&self) -> Option<DiskChange> {
+        let inode = self.inode?;
+        let Ok(disk) = fs::metadata(&self.file_path) else {
+            return Some(DiskChange::Deleted);
+        };
+        let modified = disk.modified().ok();
+        if modified == self.last_write_time
+            && Some(disk.len() as usize) == self.file_size
+            && disk.ino() == inode
+        {
+            return None;
+        }
+        Some(DiskChange::Modified {
+            modified,
+            size: disk.len(),
+            inode: disk.ino(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_disk_change() -> std::io::Result<()> {
+        let directory = tempdir()?;
+        let path = directory.path().join("file.txt");
+        let mut metadata = FileMetadata::new(path.to_str().unwrap().to_string());
+        fs::write(&path, "one\n")?;
+        // Never read, so nothing to compare with
+        assert_eq!(metadata.disk_change(), None);
+
+        metadata.update();
+        assert_eq!(metadata.disk_change(), None);
+        fs::write(&path, "two!\n")?;
+        assert!(matches!(
+            metadata.disk_change(),
+            Some(DiskChange::Modified { size: 5, .. })
+        ));
+        metadata.update();
+        assert_eq!(metadata.disk_change(), None);
+
+        fs::remove_file(&path)?;
+        assert_eq!(metadata.disk_change(), Some(DiskChange::Deleted));
+        Ok(())
     }
 }
@@ -0,0 +1,926 @@
+use crate::operator::{first_non_blank, Operator, RangeKind, TextRange};
+use crate::piece_table::PieceTable;
+use crate::utils::grapheme_to_byte;
+use unicode_segmentation::UnicodeSegmentation;
+
+// How much of the text between the cursor and a motion's target an operator acts on
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum MotionKind {
+    // Up to but not including the target
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Motion {
+    Left,
+    Down,
+    Up,
+    Right,
+    // `big` words (`W`, `B`, `E`) are anything separated by blanks
+    WordForward { big: bool },
+    WordBackward { big: bool },
+    WordEnd { big: bool },
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    // `f`, `t`, `F` and `T`. `till` stops next to the character instead of on it
+    FindChar { ch: char, forward: bool, till: bool },
+    MatchPair,
+    ParagraphForward,
+    ParagraphBackward,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TextObject {
+    Word {
+        big: bool,
+        around: bool,
+    },
+    Quote {
+        quote: char,
+        around: bool,
+    },
+    Block {
+        open: char,
+        close: char,
+        around: bool,
+    },
+    Tag {
+        around: bool,
+    },
+    Paragraph {
+        around: bool,
+    },
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum CharClass {
+    Blank,
+    Punctuation,
+    Word,
+}
+
+fn char_class(ch: char, big: bool) -> CharClass {
+    if ch.is_whitespace() {
+        CharClass::Blank
+    } else if big || ch == '_' || !ch.is_ascii_punctuation() {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+// Byte offset of a position, which may be past the end of its line or of the document
+fn position_offset(piece_table: &PieceTable, (x, y): (usize, usize)) -> usize {
+    let x = x.min(piece_table.line_grapheme_len(y));
+    piece_table.find_index(x, y).unwrap_or(piece_table.len())
+}
+
+// Byte offset just after the char starting at `offset`
+fn char_end(piece_table: &PieceTable, offset: usize) -> usize {
+    offset + piece_table.index(offset).map_or(0, char::len_utf8)
+}
+
+// Offset of the first `wanted` char that isn't paired with a `nested` char before it
+fn find_unmatched(
+    chars: impl Iterator<Item = (usize, char)>,
+    nested: char,
+    wanted: char,
+) -> Option<usize> {
+    let mut depth = 0;
+    for (i, ch) in chars {
+        if ch == nested {
+            depth += 1;
+        } else if ch == wanted {
+            if depth == 0 {
+                return Some(i);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// Applies `step` up to `repeat` times, stopping early once it can't go any further
+fn repeat_step(start: usize, repeat: usize, step: impl Fn(usize) -> Option<usize>) -> usize {
+    let mut current = start;
+    for _ in 0..repeat {
+        match step(current) {
+            Some(next) if next != current => current = next,
+            _ => break,
+        }
+    }
+    current
+}
+
+// Start of the next word, stopping at empty lines, or the end of the document
+fn word_forward(piece_table: &PieceTable, offset: usize, big: bool) -> usize {
+    let mut chars = piece_table.chars_at(offset);
+    let Some((_, first)) = chars.next() else {
+        return offset;
+    };
+    let start_class = char_class(first, big);
+    let mut in_start_word = start_class != CharClass::Blank;
+    let mut previous = first;
+    for (i, ch) in chars {
+        if ch == '\n' && previous == '\n' {
+            return i;
+        }
+        let class = char_class(ch, big);
+        if in_start_word && class == start_class {
+            continue;
+        }
+        in_start_word = false;
+        if class != CharClass::Blank {
+            return i;
+        }
+        previous = ch;
+    }
+    piece_table.len()
+}
+
+// Start of the current or previous word, stopping at empty lines
+fn word_backward(piece_table: &PieceTable, offset: usize, big: bool) -> usize {
+    let mut chars = piece_table.chars_before(offset).peekable();
+    while let Some(&(i, ch)) = chars.peek() {
+        if !ch.is_whitespace() {
+            break;
+        }
+        chars.next();
+        let line_is_empty = chars.peek().is_none_or(|&(_, previous)| previous == '\n');
+        if ch == '\n' && line_is_empty {
+            return i;
+        }
+    }
+
+    let Some((mut start, ch)) = chars.next() else {
+        return 0;
+    };
+    let class = char_class(ch, big);
+    while let Some((i, _)) = chars.next_if(|&(_, previous)| char_class(previous, big) == class) {
+        start = i;
+    }
+    start
+}
+
+// Offset of the last char of the next word. With `stop_here`, a word under the cursor counts too
+fn word_end(piece_table: &PieceTable, offset: usize, big: bool, stop_here: bool) -> Option<usize> {
+    let mut chars = piece_table.chars_at(offset).peekable();
+    if !stop_here {
+        chars.next();
+    }
+    while chars.next_if(|&(_, ch)| ch.is_whitespace()).is_some() {}
+
+    let (mut end, ch) = chars.next()?;
+    let class = char_class(ch, big);
+    while let Some((i, _)) = chars.next_if(|&(_, next)| char_class(next, big) == class) {
+        end = i;
+    }
+    Some(end)
+}
+
+impl Motion {
+    pub fn kind(&self) -> MotionKind {
+        match self {
+            Motion::Down | Motion::Up | Motion::FirstLine | Motion::LastLine => {
+                MotionKind::Linewise
+            }
+            Motion::WordEnd { .. } | Motion::LineEnd | Motion::MatchPair => MotionKind::Inclusive,
+            Motion::FindChar { forward: true, .. } => MotionKind::Inclusive,
+            _ => MotionKind::Exclusive,
+        }
+    }
+
+    // Where the motion takes the cursor, or None if it can't move.
+    // The target can be one past the end of a line, which an operator needs to reach its last character
+    pub fn target(
+        &self,
+        piece_table: &PieceTable,
+        (x, y): (usize, usize),
+        count: Option<usize>,
+    ) -> Option<(usize, usize)> {
+        let repeat = count.unwrap_or(1).max(1);
+        let last_line = piece_table.line_count().saturating_sub(1);
+        let offset = position_offset(piece_table, (x, y));
+
+        match *self {
+            Motion::Left => (x > 0).then(|| (x.saturating_sub(repeat), y)),
+            Motion::Right => {
+                let line_length = piece_table.line_grapheme_len(y);
+                (x < line_length).then(|| (x.saturating_add(repeat).min(line_length), y))
+            }
+            Motion::Down => (y < last_line).then(|| (x, y.saturating_add(repeat).min(last_line))),
+            Motion::Up => (y > 0).then(|| (x, y.saturating_sub(repeat))),
+
+            Motion::WordForward { big } => {
+                let target = repeat_step(offset, repeat, |offset| {
+                    Some(word_forward(piece_table, offset, big))
+                });
+                (target > offset).then(|| piece_table.coordinates(target))
+            }
+            Motion::WordBackward { big } => {
+                let target = repeat_step(offset, repeat, |offset| {
+                    Some(word_backward(piece_table, offset, big))
+                });
+                (target < offset).then(|| piece_table.coordinates(target))
+            }
+            Motion::WordEnd { big } => {
+                let target = repeat_step(offset, repeat, |offset| {
+                    word_end(piece_table, offset, big, false)
+                });
+                (target > offset).then(|| piece_table.coordinates(target))
+            }
+
+            Motion::LineStart => Some((0, y)),
+            Motion::FirstNonBlank => Some((first_non_blank(piece_table, y), y)),
+            Motion::LineEnd => {
+                let y = y.saturating_add(repeat - 1);
+                (y <= last_line).then(|| (piece_table.line_grapheme_len(y).saturating_sub(1), y))
+            }
+
+            Motion::FirstLine | Motion::LastLine => {
+                let default = match self {
+                    Motion::FirstLine => 0,
+                    _ => last_line,
+                };
+                let y = count
+                    .map_or(default, |line| line.saturating_sub(1))
+                    .min(last_line);
+                Some((first_non_blank(piece_table, y), y))
+            }
+
+            Motion::FindChar { ch, forward, till } => {
+                let line = piece_table.line(y)?;
+                let graphemes: Vec<&str> = line.graphemes(true).collect();
+                let matches = |i: &usize| graphemes[*i].starts_with(ch);
+                let found = if forward {
+                    (x + 1..graphemes.len()).filter(matches).nth(repeat - 1)?
+                } else {
+                    (0..x.min(graphemes.len()))
+                        .rev()
+                        .filter(matches)
+                        .nth(repeat - 1)?
+                };
+                match (till, forward) {
+                    (true, true) => Some((found - 1, y)),
+                    (true, false) => Some((found + 1, y)),
+                    _ => Some((found, y)),
+                }
+            }
+
+            Motion::MatchPair => {
+                let line = piece_table.line(y)?;
+                let (index, bracket) =
+                    line.graphemes(true)
+                        .enumerate()
+                        .skip(x)
+                        .find_map(|(i, grapheme)| {
+                            let ch = grapheme.chars().next()?;
+                            "()[]{}".contains(ch).then_some((i, ch))
+                        })?;
+                let offset = position_offset(piece_table, (index, y));
+                let target = match bracket {
+                    '(' => find_unmatched(piece_table.chars_at(offset + 1), '(', ')'),
+                    '[' => find_unmatched(piece_table.chars_at(offset + 1), '[', ']'),
+                    '{' => find_unmatched(piece_table.chars_at(offset + 1), '{', '}'),
+                    ')' => find_unmatched(piece_table.chars_before(offset), ')', '('),
+                    ']' => find_unmatched(piece_table.chars_before(offset), ']', '['),
+                    _ => find_unmatched(piece_table.chars_before(offset), '}', '{'),
+                }?;
+                Some(piece_table.coordinates(target))
+            }
+
+            Motion::ParagraphForward => {
+                let is_empty = |y: usize| piece_table.line_len(y) == 0;
+                if y >= last_line {
+                    return None;
+                }
+                let target = repeat_step(y, repeat, |y| {
+                    if y >= last_line {
+                        return None;
+                    }
+                    // Blank lines are only skipped when starting on one, so the line after a
+                    // one-line paragraph is still its edge
+                    let mut next = y + 1;
+                    while is_empty(y) && next < last_line && is_empty(next) {
+                        next += 1;
+                    }
+                    while next < last_line && !is_empty(next) {
+                        next += 1;
+                    }
+                    Some(next)
+                });
+                if target >= last_line && !is_empty(last_line) {
+                    // Past the last paragraph goes to the end of the document
+                    Some((piece_table.line_grapheme_len(last_line), last_line))
+                } else {
+                    Some((0, target.min(last_line)))
+                }
+            }
+            Motion::ParagraphBackward => {
+                let is_empty = |y: usize| piece_table.line_len(y) == 0;
+                if y == 0 {
+                    return None;
+                }
+                let target = repeat_step(y, repeat, |y| {
+                    let mut next = y.checked_sub(1)?;
+                    while is_empty(y) && next > 0 && is_empty(next) {
+                        next -= 1;
+                    }
+                    while next > 0 && !is_empty(next) {
+                        next -= 1;
+                    }
+                    Some(next)
+                });
+                Some((0, target))
+            }
+        }
+    }
+
+    // The text `operator` acts on when given this motion
+    pub fn operator_range(
+        &self,
+        operator: Operator,
+        piece_table: &PieceTable,
+        cursor: (usize, usize),
+        count: Option<usize>,
+    ) -> Option<TextRange> {
+        let cursor_offset = position_offset(piece_table, cursor);
+
+        // `cw` on a word changes up to the end of it, like `ce`, but never past it
+        if let Motion::WordForward { big } = *self {
+            let on_word = piece_table
+                .index(cursor_offset)
+                .is_some_and(|ch| !ch.is_whitespace());
+            if operator == Operator::Change && on_word {
+                let end = word_end(piece_table, cursor_offset, big, true)?;
+                let end = repeat_step(end, count.unwrap_or(1).saturating_sub(1), |end| {
+                    word_end(piece_table, end, big, false)
+                });
+                let (x, y) = piece_table.coordinates(end);
+                let end = position_offset(piece_table, (x + 1, y));
+                return TextRange::from_span(RangeKind::Charwise, cursor_offset, end, piece_table);
+            }
+        }
+
+        let target = self.target(piece_table, cursor, count)?;
+        if self.kind() == MotionKind::Linewise {
+            return Some(TextRange::new(RangeKind::Linewise, cursor, target));
+        }
+
+        let (start, end) = if (cursor.1, cursor.0) <= (target.1, target.0) {
+            (cursor, target)
+        } else {
+            (target, cursor)
+        };
+        let start_offset = position_offset(piece_table, start);
+        let end_offset = match self.kind() {
+            MotionKind::Inclusive => position_offset(piece_table, (end.0 + 1, end.1)),
+            // `w` ends at the last word moved over rather than on the next line
+            _ if end.1 > start.1 && matches!(self, Motion::WordForward { .. }) => {
+                position_offset(piece_table, (0, end.1)) - 1
+            }
+            // Any other exclusive motion to the start of a line stops at the end of the line before,
+            // and covers whole lines if it started before the first non-blank of its line
+            _ if end.0 == 0 && end.1 > start.1 => {
+                if start.0 <= first_non_blank(piece_table, start.1) {
+                    return Some(TextRange::new(RangeKind::Linewise, start, (0, end.1 - 1)));
+                }
+                position_offset(piece_table, end) - 1
+            }
+            _ => position_offset(piece_table, end),
+        };
+        TextRange::from_span(RangeKind::Charwise, start_offset, end_offset, piece_table)
+    }
+}
+
+impl TextObject {
+    pub fn kind(&self) -> RangeKind {
+        match self {
+            TextObject::Paragraph { .. } => RangeKind::Linewise,
+            _ => RangeKind::Charwise,
+        }
+    }
+
+    // Byte range of the object at the cursor. It can be empty, like the inside of `""`
+    pub fn span(
+        &self,
+        piece_table: &PieceTable,
+        cursor: (usize, usize),
+        count: Option<usize>,
+    ) -> Option<(usize, usize)> {
+        let count = count.unwrap_or(1).max(1);
+        match *self {
+            TextObject::Word { big, around } => word_span(piece_table, cursor, big, around, count),
+            TextObject::Quote { quote, around } => quote_span(piece_table, cursor, quote, around),
+            TextObject::Block {
+                open,
+                close,
+                around,
+            } => block_span(piece_table, cursor, open, close, around, count),
+            TextObject::Tag { around } => tag_span(piece_table, cursor, around, count),
+            TextObject::Paragraph { around } => {
+                paragraph_span(piece_table, cursor.1, around, count)
+            }
+        }
+    }
+}
+
+// `iw` is the run of word characters, punctuation or blanks under the cursor.
+// `aw` adds the blanks after it, or before it if there are none after
+fn word_span(
+    piece_table: &PieceTable,
+    (x, y): (usize, usize),
+    big: bool,
+    around: bool,
+    count: usize,
+) -> Option<(usize, usize)> {
+    let line = piece_table.line(y)?;
+    let line_start = piece_table.line_start(y)?;
+    let graphemes: Vec<(usize, &str)> = line.grapheme_indices(true).collect();
+    if graphemes.is_empty() {
+        return Some((line_start, line_start));
+    }
+
+    let class = |i: usize| {
+        graphemes[i]
+            .1
+            .chars()
+            .next()
+            .map_or(CharClass::Blank, |ch| char_class(ch, big))
+    };
+    let run_end = |mut i: usize| {
+        let run_class = class(i);
+        while i + 1 < graphemes.len() && class(i + 1) == run_class {
+            i += 1;
+        }
+        i
+    };
+
+    let x = x.min(graphemes.len() - 1);
+    let mut first = x;
+    while first > 0 && class(first - 1) == class(x) {
+        first -= 1;
+    }
+    let mut last = run_end(x);
+    for _ in 1..count {
+        if last + 1 >= graphemes.len() {
+            break;
+        }
+        last = run_end(last + 1);
+    }
+
+    if around {
+        let blank_after = last + 1 < graphemes.len() && class(last + 1) == CharClass::Blank;
+        if class(x) == CharClass::Blank || blank_after {
+            if last + 1 < graphemes.len() {
+                last = run_end(last + 1);
+            }
+        } else {
+            while first > 0 && class(first - 1) == CharClass::Blank {
+                first -= 1;
+            }
+        }
+    }
+
+    let end = graphemes.get(last + 1).map_or(line.len(), |&(i, _)| i);
+    Some((line_start + graphemes[first].0, line_start + end))
+}
+
+// The quoted string on the cursor line that the cursor is in, or the next one after it
+fn quote_span(
+    piece_table: &PieceTable,
+    (x, y): (usize, usize),
+    quote: char,
+    around: bool,
+) -> Option<(usize, usize)> {
+    let line = piece_table.line(y)?;
+    let line_start = piece_table.line_start(y)?;
+    let cursor = grapheme_to_byte(&line, x);
+
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (i, ch) in line.char_indices() {
+        if ch == quote && !escaped {
+            quotes.push(i);
+        }
+        escaped = ch == '\\' && !escaped;
+    }
+    let (open, close) = quotes
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| cursor <= close)?;
+
+    let (start, end) = if around {
+        let after = &line[close + 1..];
+        let blanks_after = after.len() - after.trim_start_matches([' ', '\t']).len();
+        let before = &line[..open];
+        let blanks_before = before.len() - before.trim_end_matches([' ', '\t']).len();
+        if blanks_after > 0 {
+            (open, close + 1 + blanks_after)
+        } else {
+            (open - blanks_before, close + 1)
+        }
+    } else {
+        (open + 1, close)
+    };
+    Some((line_start + start, line_start + end))
+}
+
+fn block_span(
+    piece_table: &PieceTable,
+    cursor: (usize, usize),
+    open: char,
+    close: char,
+    around: bool,
+    count: usize,
+) -> Option<(usize, usize)> {
+    let cursor_offset = position_offset(piece_table, cursor);
+    // An opening bracket under the cursor belongs to the block, a closing one already does
+    let mut search_end = match piece_table.index(cursor_offset) {
+        Some(ch) if ch == open => cursor_offset + ch.len_utf8(),
+        _ => cursor_offset,
+    };
+    for _ in 0..count {
+        search_end = find_unmatched(piece_table.chars_before(search_end), close, open)?;
+    }
+    let open_offset = search_end;
+    let close_offset = find_unmatched(
+        piece_table.chars_at(open_offset + open.len_utf8()),
+        open,
+        close,
+    )?;
+    if around {
+        return Some((open_offset, close_offset + close.len_utf8()));
+    }
+
+    // The inside of a block with its brackets on their own lines is the lines between them
+    let mut start = open_offset + open.len_utf8();
+    if piece_table.index(start) == Some('\n') {
+        start += 1;
+    }
+    let mut end = close_offset;
+    let close_line = piece_table.coordinates(close_offset).1;
+    if let Some(close_line_start) = piece_table.line_start(close_line) {
+        if close_line_start > start
+            && piece_table
+                .slice(close_line_start, close_offset)
+                .trim()
+                .is_empty()
+        {
+            end = close_line_start;
+        }
+    }
+    Some((start, end.max(start)))
+}
+
+struct Tag {
+    name: String,
+    closing: bool,
+    end: usize,
+}
+
+// Parses the tag whose `<` is at `offset`. Self-closing tags, comments and declarations don't count
+fn tag_at(piece_table: &PieceTable, offset: usize) -> Option<Tag> {
+    let mut chars = piece_table.chars_at(offset + 1).peekable();
+    let closing = chars.next_if(|&(_, ch)| ch == '/').is_some();
+    let mut name = String::new();
+    while let Some((_, ch)) = chars.next_if(|&(_, ch)| ch.is_alphanumeric() || "-_:.".contains(ch))
+    {
+        name.push(ch);
+    }
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut previous = ' ';
+    for (i, ch) in chars {
+        match ch {
+            '>' if previous == '/' => return None,
+            '>' => {
+                return Some(Tag {
+                    name,
+                    closing,
+                    end: i + 1,
+                })
+            }
+            '<' => return None,
+            _ => previous = ch,
+        }
+    }
+    None
+}
+
+// Start and end of the tag closing `open`
+fn matching_close_tag(piece_table: &PieceTable, open: &Tag) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    for (i, _) in piece_table.chars_at(open.end).filter(|&(_, ch)| ch == '<') {
+        let Some(tag) = tag_at(piece_table, i) else {
+            continue;
+        };
+        if tag.name != open.name {
+            continue;
+        }
+        if !tag.closing {
+            depth += 1;
+        } else if depth == 0 {
+            return Some((i, tag.end));
+        } else {
+            depth -= 1;
+        }
+    }
+    None
+}
+
+fn tag_span(
+    piece_table: &PieceTable,
+    cursor: (usize, usize),
+    around: bool,
+    count: usize,
+) -> Option<(usize, usize)> {
+    let cursor_offset = position_offset(piece_table, cursor);
+    let mut found = 0;
+    let candidates = piece_table
+        .chars_before(char_end(piece_table, cursor_offset))
+        .filter(|&(_, ch)| ch == '<');
+    for (open_start, _) in candidates {
+        let Some(open) = tag_at(piece_table, open_start).filter(|tag| !tag.closing) else {
+            continue;
+        };
+        let Some((close_start, close_end)) = matching_close_tag(piece_table, &open) else {
+            continue;
+        };
+        if close_end <= cursor_offset {
+            continue;
+        }
+        found += 1;
+        if found == count {
+            return Some(if around {
+                (open_start, close_end)
+            } else {
+                (open.end, close_start)
+            });
+        }
+    }
+    None
+}
+
+// `ip` is the run of blank or non-blank lines the cursor is on.
+// `ap` adds the run of the other kind after it, or the blank lines before it at the end of the document
+fn paragraph_span(
+    piece_table: &PieceTable,
+    y: usize,
+    around: bool,
+    count: usize,
+) -> Option<(usize, usize)> {
+    let line_count = piece_table.line_count();
+    if line_count == 0 {
+        return None;
+    }
+    let y = y.min(line_count - 1);
+    let is_blank = |y: usize| {
+        piece_table
+            .line(y)
+            .is_none_or(|line| line.trim().is_empty())
+    };
+    let run_end = |mut y: usize| {
+        let blank = is_blank(y);
+        while y + 1 < line_count && is_blank(y + 1) == blank {
+            y += 1;
+        }
+        y
+    };
+
+    let mut first = y;
+    while first > 0 && is_blank(first - 1) == is_blank(y) {
+        first -= 1;
+    }
+    let mut last = run_end(y);
+    for _ in 1..count {
+        if last + 1 >= line_count {
+            break;
+        }
+        last = run_end(last + 1);
+    }
+
+    if around {
+        if last + 1 < line_count {
+            last = run_end(last + 1);
+        } else if !is_blank(y) {
+            while first > 0 && is_blank(first - 1) {
+                first -= 1;
+            }
+        }
+    }
+
+    let start = piece_table.line_start(first)?;
+    let end = piece_table
+        .line_start(last + 1)
+        .unwrap_or(piece_table.len());
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(text: &str, motion: Motion, cursor: (usize, usize)) -> Option<(usize, usize)> {
+        motion.target(&PieceTable::new(text), cursor, None)
+    }
+
+    fn object_text(text: &str, object: TextObject, cursor: (usize, usize)) -> Option<String> {
+        let piece_table = PieceTable::new(text);
+        let (start, end) = object.span(&piece_table, cursor, None)?;
+        Some(piece_table.slice(start, end))
+    }
+
+    #[test]
+    fn test_word_motions() {
+        let text = "foo.bar baz\n\n  qux";
+        let forward = Motion::WordForward { big: false };
+        assert_eq!(target(text, forward, (0, 0)), Some((3, 0)));
+        assert_eq!(target(text, forward, (8, 0)), Some((0, 1)));
+        assert_eq!(target(text, forward, (0, 1)), Some((2, 2)));
+        assert_eq!(
+            target(text, Motion::WordForward { big: true }, (0, 0)),
+            Some((8, 0))
+        );
+
+        let backward = Motion::WordBackward { big: false };
+        assert_eq!(target(text, backward, (2, 2)), Some((0, 1)));
+        assert_eq!(target(text, backward, (0, 1)), Some((8, 0)));
+        assert_eq!(target(text, backward, (6, 0)), Some((4, 0)));
+        assert_eq!(target(text, backward, (0, 0)), None);
+
+        let end = Motion::WordEnd { big: false };
+        assert_eq!(target(text, end, (0, 0)), Some((2, 0)));
+        assert_eq!(target(text, end, (8, 0)), Some((10, 0)));
+        assert_eq!(target(text, end, (10, 0)), Some((4, 2)));
+    }
+
+    #[test]
+    fn test_line_motions() {
+        let text = "  if (a[0]) { b(); }\nx\n\npara\ngraph\n\nend";
+        let find = |ch, forward, till| Motion::FindChar { ch, forward, till };
+        assert_eq!(target(text, find('(', true, false), (0, 0)), Some((5, 0)));
+        assert_eq!(target(text, find('(', true, true), (0, 0)), Some((4, 0)));
+        assert_eq!(target(text, find('i', false, true), (5, 0)), Some((3, 0)));
+        assert_eq!(target(text, find('z', true, false), (0, 0)), None);
+
+        assert_eq!(target(text, Motion::MatchPair, (0, 0)), Some((10, 0)));
+        assert_eq!(target(text, Motion::MatchPair, (7, 0)), Some((9, 0)));
+        assert_eq!(target(text, Motion::MatchPair, (19, 0)), Some((12, 0)));
+
+        assert_eq!(target(text, Motion::FirstNonBlank, (9, 0)), Some((2, 0)));
+        assert_eq!(target(text, Motion::LineEnd, (0, 0)), Some((19, 0)));
+        assert_eq!(target(text, Motion::LastLine, (0, 0)), Some((0, 6)));
+
+        assert_eq!(target(text, Motion::ParagraphForward, (0, 0)), Some((0, 2)));
+        assert_eq!(target(text, Motion::ParagraphForward, (0, 2)), Some((0, 5)));
+        assert_eq!(target(text, Motion::ParagraphForward, (0, 5)), Some((3, 6)));
+        assert_eq!(
+            target(text, Motion::ParagraphBackward, (2, 4)),
+            Some((0, 2))
+        );
+        assert_eq!(
+            target(text, Motion::ParagraphBackward, (0, 1)),
+            Some((0, 0))
+        );
+
+        // A one-line paragraph stops at the blank line right next to it
+        let text = "one\n\ntwo\n\nthree";
+        assert_eq!(target(text, Motion::ParagraphForward, (0, 0)), Some((0, 1)));
+        assert_eq!(target(text, Motion::ParagraphForward, (0, 1)), Some((0, 3)));
+        assert_eq!(
+            target(text, Motion::ParagraphBackward, (0, 2)),
+            Some((0, 1))
+        );
+        assert_eq!(
+            target(text, Motion::ParagraphBackward, (0, 3)),
+            Some((0, 1))
+        );
+    }
+
+    #[test]
+    fn test_text_objects() {
+        let word = |big, around| TextObject::Word { big, around };
+        let text = "call(foo.bar, \"x y\")  end";
+        assert_eq!(
+            object_text(text, word(false, false), (6, 0)).as_deref(),
+            Some("foo")
+        );
+        assert_eq!(
+            object_text(text, word(true, false), (6, 0)).as_deref(),
+            Some("call(foo.bar,")
+        );
+        assert_eq!(
+            object_text(text, word(false, true), (22, 0)).as_deref(),
+            Some("  end")
+        );
+
+        let quote = |around| TextObject::Quote { quote: '"', around };
+        assert_eq!(
+            object_text(text, quote(false), (0, 0)).as_deref(),
+            Some("x y")
+        );
+        assert_eq!(
+            object_text(text, quote(true), (15, 0)).as_deref(),
+            Some(" \"x y\"")
+        );
+
+        let block = |around| TextObject::Block {
+            open: '(',
+            close: ')',
+            around,
+        };
+        assert_eq!(
+            object_text(text, block(false), (10, 0)).as_deref(),
+            Some("foo.bar, \"x y\"")
+        );
+        assert_eq!(
+            object_text(text, block(true), (19, 0)).as_deref(),
+            Some("(foo.bar, \"x y\")")
+        );
+        assert_eq!(object_text(text, block(false), (23, 0)), None);
+
+        let text = "fn f() {\n    a();\n}\n";
+        let braces = TextObject::Block {
+            open: '{',
+            close: '}',
+            around: false,
+        };
+        assert_eq!(
+            object_text(text, braces, (4, 1)).as_deref(),
+            Some("    a();\n")
+        );
+
+        let text = "<div><p>one <b>two</b></p>\n</div>";
+        let tag = |around| TextObject::Tag { around };
+        assert_eq!(
+            object_text(text, tag(false), (16, 0)).as_deref(),
+            Some("two")
+        );
+        assert_eq!(
+            object_text(text, tag(true), (9, 0)).as_deref(),
+            Some("<p>one <b>two</b></p>")
+        );
+        assert_eq!(
+            object_text(text, tag(false), (1, 1)).as_deref(),
+            Some("<p>one <b>two</b></p>\n")
+        );
+
+        let text = "one\ntwo\n\n\nthree\n";
+        let paragraph = |around| TextObject::Paragraph { around };
+        assert_eq!(
+            object_text(text, paragraph(false), (0, 1)).as_deref(),
+            Some("one\ntwo\n")
+        );
+        assert_eq!(
+            object_text(text, paragraph(true), (0, 0)).as_deref(),
+            Some("one\ntwo\n\n\n")
+        );
+        assert_eq!(
+            object_text(text, paragraph(true), (0, 4)).as_deref(),
+            Some("\n\nthree\n")
+        );
+    }
+
+    #[test]
+    fn test_operator_ranges() {
+        let piece_table = PieceTable::new("foo bar\n  baz qux\nend");
+        let range = |motion: Motion, operator, cursor| {
+            motion
+                .operator_range(operator, &piece_table, cursor, None)
+                .map(|range| range.text(&piece_table))
+        };
+        let word = Motion::WordForward { big: false };
+        assert_eq!(
+            range(word, Operator::Delete, (0, 0)).as_deref(),
+            Some("foo ")
+        );
+        assert_eq!(
+            range(word, Operator::Change, (0, 0)).as_deref(),
+            Some("foo")
+        );
+        assert_eq!(
+            range(word, Operator::Delete, (4, 0)).as_deref(),
+            Some("bar")
+        );
+        assert_eq!(
+            range(Motion::LineEnd, Operator::Delete, (6, 1)).as_deref(),
+            Some("qux")
+        );
+        assert_eq!(
+            range(Motion::ParagraphForward, Operator::Delete, (0, 0)).as_deref(),
+            Some("foo bar\n  baz qux\nend")
+        );
+        assert_eq!(
+            range(Motion::Down, Operator::Delete, (2, 1)).as_deref(),
+            Some("  baz qux\nend\n")
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use crate::motion::{Motion, TextObject};
+use crate::operator::Operator;
+use crate::register::is_register_name;
+
+// What an operator acts on
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Target {
+    Motion(Motion),
+    TextObject(TextObject),
+    // A doubled operator like `dd` or `gUU`
+    Lines,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum NormalCommand {
+    Move(Motion),
+    Operate(Operator, Target),
+    ReplaceChars(char),
+    // `p`, or `P` to put before the cursor
+    Put { before: bool },
+    // `q{register}` starts recording a macro
+    Record(char),
+    // `@{register}` plays one back
+    Play(char),
+    // `q:` opens the command-line window
+    CommandWindow,
+    // `Ctrl-w` and a key for what to do with the windows
+    Window(char),
+    // `gt` goes to the next tab page, or the one numbered by the count, and `gT` back
+    TabPage { forward: bool },
+}
+
+// `Ctrl-w`, which starts window commands
+pub const WINDOW_KEY: char = '\x17';
+
+// The count and register typed before a command, like the `2"a` of `2"ayy`
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct Prefix {
+    pub count: Option<usize>,
+    pub register: Option<char>,
+}
+
+// The result of reading the keys typed so far
+#[derive(PartialEq, Debug)]
+pub enum Parsed<T> {
+    // More keys are needed
+    Incomplete,
+    Invalid,
+    Complete(T),
+}
+
+impl<T> Parsed<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Parsed<U> {
+        match self {
+            Parsed::Incomplete => Parsed::Incomplete,
+            Parsed::Invalid => Parsed::Invalid,
+            Parsed::Complete(value) => Parsed::Complete(f(value)),
+        }
+    }
+
+    fn filter(self, f: impl FnOnce(&T) -> bool) -> Parsed<T> {
+        match self {
+            Parsed::Complete(value) if !f(&value) => Parsed::Invalid,
+            parsed => parsed,
+        }
+    }
+}
+
+// Splits the count typed before a command off the keys. A leading `0` is a motion, not a count
+pub fn split_count(keys: &str) -> (Option<usize>, &str) {
+    if !keys.starts_with(|ch: char| ch.is_ascii_digit() && ch != '0') {
+        return (None, keys);
+    }
+    let digits = keys.len()
+        - keys
+            .trim_start_matches(|ch: char| ch.is_ascii_digit())
+            .len();
+    (keys[..digits].parse().ok(), &keys[digits..])
+}
+
+// Splits the count and register typed before a command off the keys. A count can also go after the register
+pub fn split_prefix(keys: &str) -> Parsed<(Prefix, &str)> {
+    let (count, keys) = split_count(keys);
+    let Some(rest) = keys.strip_prefix('"') else {
+        return Parsed::Complete((
+            Prefix {
+                count,
+                register: None,
+            },
+            keys,
+        ));
+    };
+    let mut chars = rest.chars();
+    match chars.next() {
+        None => Parsed::Incomplete,
+        Some(name) if is_register_name(name) => {
+            let (register_count, keys) = split_count(chars.as_str());
+            Parsed::Complete((
+                Prefix {
+                    count: multiply_counts(count, register_count),
+                    register: Some(name),
+                },
+                keys,
+            ))
+        }
+        Some(_) => Parsed::Invalid,
+    }
+}
+
+// `2d3w` deletes six words
+fn multiply_counts(first: Option<usize>, second: Option<usize>) -> Option<usize> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(first.saturating_mul(second)),
+        _ => first.or(second),
+    }
+}
+
+// Keys that stand for a single char, like `r{char}` and `f{char}`
+fn single_char(keys: &str) -> Parsed<char> {
+    let mut chars = keys.chars();
+    match (chars.next(), chars.next()) {
+        (None, _) => Parsed::Incomplete,
+        (Some(ch), None) => Parsed::Complete(ch),
+        _ => Parsed::Invalid,
+    }
+}
+
+// A command along with the count and register typed before it
+pub fn parse_normal_command(keys: &str) -> Parsed<(Prefix, NormalCommand)> {
+    let (prefix, keys) = match split_prefix(keys) {
+        Parsed::Complete(split) => split,
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+    match keys {
+        "" => return Parsed::Incomplete,
+        "p" | "P" => {
+            let before = keys == "P";
+            return Parsed::Complete((prefix, NormalCommand::Put { before }));
+        }
+        "gt" | "gT" => {
+            let forward = keys == "gt";
+            return Parsed::Complete((prefix, NormalCommand::TabPage { forward }));
+        }
+        _ => {}
+    }
+    if let Some(rest) = keys.strip_prefix('r') {
+        return single_char(rest).map(|ch| (prefix, NormalCommand::ReplaceChars(ch)));
+    }
+    if let Some(rest) = keys.strip_prefix(WINDOW_KEY) {
+        return single_char(rest)
+            .filter(|ch| "hjklwsvocq=\x17".contains(*ch))
+            .map(|ch| (prefix, NormalCommand::Window(ch)));
+    }
+    if keys == "q:" {
+        return Parsed::Complete((prefix, NormalCommand::CommandWindow));
+    }
+    if let Some(rest) = keys.strip_prefix('q') {
+        return single_char(rest)
+            .filter(|name| name.is_ascii_alphanumeric() || *name == '"')
+            .map(|name| (prefix, NormalCommand::Record(name)));
+    }
+    // `@@` plays the last macro again
+    if let Some(rest) = keys.strip_prefix('@') {
+        return single_char(rest)
+            .filter(|&name| is_register_name(name) || name == '@')
+            .map(|name| (prefix, NormalCommand::Play(name)));
+    }
+
+    match parse_operator(keys) {
+        Parsed::Complete((operator, operator_keys, rest)) => {
+            let (target_count, rest) = split_count(rest);
+            parse_operator_target(operator_keys, rest).map(|target| {
+                let prefix = Prefix {
+                    count: multiply_counts(prefix.count, target_count),
+                    ..prefix
+                };
+                (prefix, NormalCommand::Operate(operator, target))
+            })
+        }
+        Parsed::Incomplete => Parsed::Incomplete,
+        Parsed::Invalid => parse_motion(keys).map(|motion| (prefix, NormalCommand::Move(motion))),
+    }
+}
+
+// What the keys typed in visual mode select or move over, along with the count and register typed before it
+pub fn parse_visual_target(keys: &str) -> Parsed<(Prefix, Target)> {
+    let (prefix, keys) = match split_prefix(keys) {
+        Parsed::Complete(split) => split,
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+    if keys.is_empty() {
+        return Parsed::Incomplete;
+    }
+    match parse_text_object(keys) {
+        Parsed::Invalid => parse_motion(keys).map(Target::Motion),
+        parsed => parsed.map(Target::TextObject),
+    }
+    .map(|target| (prefix, target))
+}
+
+// The operator at the start of `keys`, along with the keys it was typed with and the rest
+fn parse_operator(keys: &str) -> Parsed<(Operator, &str, &str)> {
+    let operators = [
+        ("d", Operator::Delete),
+        ("c", Operator::Change),
+        ("y", Operator::Yank),
+        ("=", Operator::Format),
+        (">", Operator::Indent),
+        ("<", Operator::Outdent),
+        ("g~", Operator::ToggleCase),
+        ("gu", Operator::Lowercase),
+        ("gU", Operator::Uppercase),
+    ];
+    for (operator_keys, operator) in operators {
+        if let Some(rest) = keys.strip_prefix(operator_keys) {
+            return Parsed::Complete((operator, operator_keys, rest));
+        }
+    }
+    // `g` on its own could still become `gu` or `gg`
+    if keys == "g" {
+        return Parsed::Incomplete;
+    }
+    Parsed::Invalid
+}
+
+fn parse_operator_target(operator_keys: &str, keys: &str) -> Parsed<Target> {
+    if keys.is_empty() {
+        return Parsed::Incomplete;
+    }
+    // `g~~` as well as `g~g~`
+    if operator_keys.ends_with(keys) {
+        return Parsed::Complete(Target::Lines);
+    }
+    if operator_keys.starts_with(keys) {
+        return Parsed::Incomplete;
+    }
+    match parse_text_object(keys) {
+        Parsed::Invalid => parse_motion(keys).map(Target::Motion),
+        parsed => parsed.map(Target::TextObject),
+    }
+}
+
+pub fn parse_motion(keys: &str) -> Parsed<Motion> {
+    let mut chars = keys.chars();
+    let Some(first) = chars.next() else {
+        return Parsed::Incomplete;
+    };
+    let rest = chars.as_str();
+
+    let motion = match first {
+        'h' => Motion::Left,
+        'j' => Motion::Down,
+        'k' => Motion::Up,
+        'l' => Motion::Right,
+        'w' | 'W' => Motion::WordForward { big: first == 'W' },
+        'b' | 'B' => Motion::WordBackward { big: first == 'B' },
+        'e' | 'E' => Motion::WordEnd { big: first == 'E' },
+        '0' => Motion::LineStart,
+        '^' => Motion::FirstNonBlank,
+        '$' => Motion::LineEnd,
+        'G' => Motion::LastLine,
+        '%' => Motion::MatchPair,
+        '}' => Motion::ParagraphForward,
+        '{' => Motion::ParagraphBackward,
+        'g' => {
+            return match rest {
+                "" => Parsed::Incomplete,
+                "g" => Parsed::Complete(Motion::FirstLine),
+                _ => Parsed::Invalid,
+            }
+        }
+        'f' | 't' | 'F' | 'T' => {
+            return single_char(rest).map(|ch| Motion::FindChar {
+                ch,
+                forward: first.is_lowercase(),
+                till: first.eq_ignore_ascii_case(&'t'),
+            })
+        }
+        _ => return Parsed::Invalid,
+    };
+
+    if rest.is_empty() {
+        Parsed::Complete(motion)
+    } else {
+        Parsed::Invalid
+    }
+}
+
+pub fn parse_text_object(keys: &str) -> Parsed<TextObject> {
+    let mut chars = keys.chars();
+    let around = match chars.next() {
+        Some('i') => false,
+        Some('a') => true,
+        _ => return Parsed::Invalid,
+    };
+    let object = match single_char(chars.as_str()) {
+        Parsed::Complete(object) => object,
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+
+    let block = |open, close| TextObject::Block {
+        open,
+        close,
+        around,
+    };
+    Parsed::Complete(match object {
+        'w' | 'W' => TextObject::Word {
+            big: object == 'W',
+            around,
+        },
+        '"' | '\'' | '`' => TextObject::Quote {
+            quote: object,
+            around,
+        },
+        '(' | ')' | 'b' => block('(', ')'),
+        '[' | ']' => block('[', ']'),
+        '{' | '}' | 'B' => block('{', '}'),
+        '<' | '>' => block('<', '>'),
+        't' => TextObject::Tag { around },
+        'p' => TextObject::Paragraph { around },
+        _ => return Parsed::Invalid,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counted(count: usize) -> Prefix {
+        Prefix {
+            count: Some(count),
+            register: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_normal_command() {
+        let word = Motion::WordForward { big: false };
+        assert_eq!(
+            parse_normal_command("w"),
+            Parsed::Complete((Prefix::default(), NormalCommand::Move(word)))
+        );
+        assert_eq!(
+            parse_normal_command("3dw"),
+            Parsed::Complete((
+                counted(3),
+                NormalCommand::Operate(Operator::Delete, Target::Motion(word))
+            ))
+        );
+        assert_eq!(
+            parse_normal_command("2d3w"),
+            Parsed::Complete((
+                counted(6),
+                NormalCommand::Operate(Operator::Delete, Target::Motion(word))
+            ))
+        );
+        assert_eq!(
+            parse_normal_command("d3d"),
+            Parsed::Complete((
+                counted(3),
+                NormalCommand::Operate(Operator::Delete, Target::Lines)
+            ))
+        );
+        assert_eq!(parse_normal_command("d3"), Parsed::Incomplete);
+        assert_eq!(
+            parse_normal_command("0"),
+            Parsed::Complete((Prefix::default(), NormalCommand::Move(Motion::LineStart)))
+        );
+        assert_eq!(parse_normal_command("10"), Parsed::Incomplete);
+        assert_eq!(parse_normal_command("g"), Parsed::Incomplete);
+        assert_eq!(parse_normal_command("gU"), Parsed::Incomplete);
+        assert_eq!(parse_normal_command("gUg"), Parsed::Incomplete);
+        assert_eq!(
+            parse_normal_command("gUgU"),
+            Parsed::Complete((
+                Prefix::default(),
+                NormalCommand::Operate(Operator::Uppercase, Target::Lines)
+            ))
+        );
+        assert_eq!(
+            parse_normal_command("g~~"),
+            Parsed::Complete((
+                Prefix::default(),
+                NormalCommand::Operate(Operator::ToggleCase, Target::Lines)
+            ))
+        );
+        assert_eq!(
+            parse_normal_command("gugg"),
+            Parsed::Complete((
+                Prefix::default(),
+                NormalCommand::Operate(Operator::Lowercase, Target::Motion(Motion::FirstLine))
+            ))
+        );
+        assert_eq!(parse_normal_command("ci"), Parsed::Incomplete);
+        assert_eq!(
+            parse_normal_command("ci\""),
+            Parsed::Complete((
+                Prefix::default(),
+                NormalCommand::Operate(
+                    Operator::Change,
+                    Target::TextObject(TextObject::Quote {
+                        quote: '"',
+                        around: false
+                    })
+                )
+            ))
+        );
+        assert_eq!(
+            parse_normal_command("dt)"),
+            Parsed::Complete((
+                Prefix::default(),
+                NormalCommand::Operate(
+                    Operator::Delete,
+                    Target::Motion(Motion::FindChar {
+                        ch: ')',
+                        forward: true,
+                        till: true
+                    })
+                )
+            ))
+        );
+        assert_eq!(parse_normal_command("r"), Parsed::Incomplete);
+        assert_eq!(
+            parse_normal_command("rx"),
+            Parsed::Complete((Prefix::default(), NormalCommand::ReplaceChars('x')))
+        );
+        assert_eq!(parse_normal_command("\""), Parsed::Incomplete);
+        assert_eq!(parse_normal_command("\"a"), Parsed::Incomplete);
+        assert_eq!(parse_normal_command("\"!"), Parsed::Invalid);
+        assert_eq!(
+            parse_normal_command("2\"A3P"),
+            Parsed::Complete((
+                Prefix {
+                    count: Some(6),
+                    register: Some('A')
+                },
+                NormalCommand::Put { before: true }
+            ))
+        );
+        assert_eq!(
+            parse_normal_command("3@@"),
+            Parsed::Complete((counted(3), NormalCommand::Play('@')))
+        );
+        assert_eq!(
+            parse_normal_command("q:"),
+            Parsed::Complete((Prefix::default(), NormalCommand::CommandWindow))
+        );
+        assert_eq!(parse_normal_command("q;"), Parsed::Invalid);
+        assert_eq!(parse_normal_command("\x17"), Parsed::Incomplete);
+        assert_eq!(
+            parse_normal_command("\x17l"),
+            Parsed::Complete((Prefix::default(), NormalCommand::Window('l')))
+        );
+        assert_eq!(parse_normal_command("\x17x"), Parsed::Invalid);
+        assert_eq!(
+            parse_normal_command("2gT"),
+            Parsed::Complete((counted(2), NormalCommand::TabPage { forward: false }))
+        );
+        assert_eq!(parse_normal_command("dq"), Parsed::Invalid);
+        assert_eq!(parse_normal_command("i"), Parsed::Invalid);
+        assert_eq!(parse_visual_target("i"), Parsed::Incomplete);
+        assert_eq!(
+            parse_visual_target("2iw"),
+            Parsed::Complete((
+                counted(2),
+                Target::TextObject(TextObject::Word {
+                    big: false,
+                    around: false
+                })
+            ))
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use crate::piece_table::PieceTable;
+use crate::register::Register;
+use crate::utils::{display_column, grapheme_at_column, grapheme_count, TAB_WIDTH};
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Operator {
+    Delete,
+    Yank,
+    Change,
+    Indent,
+    Outdent,
+    ToggleCase,
+    Lowercase,
+    Uppercase,
+    Format,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum RangeKind {
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+// The text between two (grapheme column, line) positions, both inclusive.
+// Blockwise ranges cover the screen columns between the two positions on every line
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct TextRange {
+    pub kind: RangeKind,
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+impl TextRange {
+    pub fn new(kind: RangeKind, a: (usize, usize), b: (usize, usize)) -> Self {
+        let (start, end) = if (a.1, a.0) <= (b.1, b.0) {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        Self { kind, start, end }
+    }
+
+    // The range covering the bytes `start..end`, or None if that is empty
+    pub fn from_span(
+        kind: RangeKind,
+        start: usize,
+        end: usize,
+        piece_table: &PieceTable,
+    ) -> Option<Self> {
+        if start >= end {
+            return None;
+        }
+        let (last, _) = piece_table.chars_before(end).next()?;
+        Some(Self::new(
+            kind,
+            piece_table.coordinates(start),
+            piece_table.coordinates(last),
+        ))
+    }
+
+    pub fn top(&self) -> usize {
+        self.start.1
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.end.1
+    }
+
+    // First and last screen column of a blockwise range
+    pub fn block_columns(&self, piece_table: &PieceTable) -> (usize, usize) {
+        let column_span = |(x, y): (usize, usize)| {
+            let line = piece_table.line(y).unwrap_or_default();
+            let first = display_column(&line, x);
+            let last = display_column(&line, x + 1).max(first + 1) - 1;
+            (first, last)
+        };
+        let (start_first, start_last) = column_span(self.start);
+        let (end_first, end_last) = column_span(self.end);
+        (start_first.min(end_first), start_last.max(end_last))
+    }
+
+    // Grapheme columns `start..end` of line `y` that the range covers
+    pub fn line_columns(&self, piece_table: &PieceTable, y: usize) -> Option<(usize, usize)> {
+        if y < self.top() || y > self.bottom() {
+            return None;
+        }
+        let line_length = piece_table.line_grapheme_len(y);
+        match self.kind {
+            RangeKind::Linewise => Some((0, line_length)),
+            RangeKind::Charwise => {
+                let start = if y == self.top() { self.start.0 } else { 0 };
+                let end = if y == self.bottom() {
+                    (self.end.0 + 1).min(line_length)
+                } else {
+                    line_length
+                };
+                Some((start, end.max(start)))
+            }
+            RangeKind::Blockwise => {
+                let (first, last) = self.block_columns(piece_table);
+                let line = piece_table.line(y).unwrap_or_default();
+                let start = grapheme_at_column(&line, first);
+                let end = grapheme_at_column(&line, last + 1);
+                Some((start, end))
+            }
+        }
+    }
+
+    // Byte ranges covered by the range, in document order
+    pub fn spans(&self, piece_table: &PieceTable) -> Vec<(usize, usize)> {
+        let to_offset = |x: usize, y: usize| piece_table.find_index(x, y).ok();
+        match self.kind {
+            RangeKind::Charwise => {
+                let (x, y) = self.end;
+                let start = to_offset(self.start.0, self.start.1);
+                // Selecting past the end of a line takes its newline with it
+                let end = if x >= piece_table.line_grapheme_len(y) {
+                    piece_table.line_start(y + 1).or(to_offset(x, y))
+                } else {
+                    to_offset(x + 1, y)
+                };
+                match (start, end) {
+                    (Some(start), Some(end)) => vec![(start, end)],
+                    _ => vec![],
+                }
+            }
+            RangeKind::Linewise => match piece_table.line_start(self.top()) {
+                Some(start) => {
+                    let end = piece_table
+                        .line_start(self.bottom() + 1)
+                        .unwrap_or(piece_table.len());
+                    vec![(start, end)]
+                }
+                None => vec![],
+            },
+            RangeKind::Blockwise => (self.top()..=self.bottom())
+                .filter_map(|y| {
+                    let (start, end) = self.line_columns(piece_table, y)?;
+                    Some((to_offset(start, y)?, to_offset(end, y)?))
+                })
+                .filter(|(start, end)| start < end)
+                .collect(),
+        }
+    }
+
+    pub fn text(&self, piece_table: &PieceTable) -> String {
+        let spans = self.spans(piece_table);
+        match self.kind {
+            RangeKind::Blockwise => (self.top()..=self.bottom())
+                .map(|y| {
+                    self.line_columns(piece_table, y)
+                        .and_then(|(start, end)| {
+                            Some(piece_table.slice(
+                                piece_table.find_index(start, y).ok()?,
+                                piece_table.find_index(end, y).ok()?,
+                            ))
+                        })
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            _ => {
+                let mut text: String = spans
+                    .iter()
+                    .map(|&(start, end)| piece_table.slice(start, end))
+                    .collect();
+                if self.kind == RangeKind::Linewise && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text
+            }
+        }
+    }
+}
+
+pub fn first_non_blank(piece_table: &PieceTable, y: usize) -> usize {
+    piece_table.line(y).map_or(0, |line| {
+        grapheme_count(&line) - grapheme_count(line.trim_start_matches([' ', '\t']))
+    })
+}
+
+// Applies `operator` to `range`, returning the text it removed or copied and where the cursor goes.
+// A change leaves the document ready for insert mode at the returned position
+pub fn apply_operator(
+    operator: Operator,
+    range: TextRange,
+    piece_table: &mut PieceTable,
+) -> (Option<Register>, (usize, usize)) {
+    let register = Register {
+        text: range.text(piece_table),
+        kind: range.kind,
+    };
+    let top_left = match range.kind {
+        RangeKind::Blockwise => {
+            let (first, _) = range.block_columns(piece_table);
+            let line = piece_table.line(range.top()).unwrap_or_default();
+            (grapheme_at_column(&line, first), range.top())
+        }
+        _ => range.start,
+    };
+
+    match operator {
+        Operator::Yank => (Some(register), top_left),
+        Operator::Delete => {
+            delete_range(range, piece_table);
+            let cursor = match range.kind {
+                RangeKind::Linewise => {
+                    let y = range.top().min(piece_table.line_count().saturating_sub(1));
+                    (first_non_blank(piece_table, y), y)
+                }
+                _ => top_left,
+            };
+            (Some(register), cursor)
+        }
+        Operator::Change => {
+            match range.kind {
+                // Changing lines keeps an empty line to type into
+                RangeKind::Linewise => {
+                    if let Some(start) = piece_table.line_start(range.top()) {
+                        let end = piece_table.line_start(range.bottom()).unwrap_or(start)
+                            + piece_table.line_len(range.bottom());
+                        piece_table.delete_range(start, end);
+                    }
+                }
+                _ => delete_range(range, piece_table),
+            }
+            let cursor = match range.kind {
+                RangeKind::Linewise => (0, range.top()),
+                _ => top_left,
+            };
+            (Some(register), cursor)
+        }
+        Operator::Indent | Operator::Outdent => {
+            for y in range.top()..=range.bottom() {
+                shift_line(piece_table, y, operator == Operator::Indent);
+            }
+            let y = range.top();
+            (None, (first_non_blank(piece_table, y), y))
+        }
+        Operator::Format => {
+            reindent(piece_table, range.top(), range.bottom());
+            let y = range.top();
+            (None, (first_non_blank(piece_table, y), y))
+        }
+        Operator::ToggleCase | Operator::Lowercase | Operator::Uppercase => {
+            for (start, end) in range.spans(piece_table).into_iter().rev() {
+                let text = piece_table.slice(start, end);
+                let converted = convert_case(&text, operator);
+                if converted != text {
+                    piece_table.delete_range(start, end);
+                    piece_table.insert(start, &converted);
+                }
+            }
+            (None, top_left)
+        }
+    }
+}
+
+fn delete_range(range: TextRange, piece_table: &mut PieceTable) {
+    for (mut start, end) in range.spans(piece_table).into_iter().rev() {
+        // Deleting the last lines also removes the newline before them
+        if range.kind == RangeKind::Linewise
+            && end == piece_table.len()
+            && start > 0
+            && piece_table.index(end.saturating_sub(1)) != Some('\n')
+        {
+            start -= 1;
+        }
+        piece_table.delete_range(start, end);
+    }
+}
+
+// Adds spaces to the end of line `y` until it reaches `column` on screen
+pub fn pad_line(piece_table: &mut PieceTable, y: usize, column: usize) {
+    let Some(line) = piece_table.line(y) else {
+        return;
+    };
+    let width = display_column(&line, grapheme_count(&line));
+    if width < column {
+        let end = piece_table.line_start(y).unwrap_or(0) + line.len();
+        piece_table.insert(end, &" ".repeat(column - width));
+    }
+}
+
+fn shift_line(piece_table: &mut PieceTable, y: usize, right: bool) {
+    let (Some(line), Some(start)) = (piece_table.line(y), piece_table.line_start(y)) else {
+        return;
+    };
+    if right {
+        if !line.is_empty() {
+            piece_table.insert(start, "\t");
+        }
+    } else if line.starts_with('\t') {
+        piece_table.delete_range(start, start + 1);
+    } else {
+        let spaces = line.len() - line.trim_start_matches(' ').len();
+        piece_table.delete_range(start, start + spaces.min(TAB_WIDTH));
+    }
+}
+
+// How many more brackets a line opens than it closes
+fn bracket_depth(line: &str) -> isize {
+    line.chars()
+        .map(|ch| match ch {
+            '(' | '[' | '{' => 1,
+            ')' | ']' | '}' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Indents each line with a tab per bracket it is nested in, counting from the
+// closest non-blank line above. Blank lines lose their indentation
+fn reindent(piece_table: &mut PieceTable, top: usize, bottom: usize) {
+    let above = (0..top)
+        .rev()
+        .find_map(|y| piece_table.line(y).filter(|line| !line.trim().is_empty()));
+    let (base, mut depth) = match above {
+        Some(line) => {
+            let content = line.trim_start_matches([' ', '\t']);
+            let base = line[..line.len() - content.len()].to_string();
+            (base, isize::from(bracket_depth(content) > 0))
+        }
+        None => (String::new(), 0),
+    };
+
+    for y in top..=bottom {
+        let (Some(line), Some(start)) = (piece_table.line(y), piece_table.line_start(y)) else {
+            break;
+        };
+        let content = line.trim_start_matches([' ', '\t']);
+        let line_depth = if content.starts_with([')', ']', '}']) {
+            depth - 1
+        } else {
+            depth
+        };
+        let indent = if content.is_empty() {
+            String::new()
+        } else {
+            base.clone() + &"\t".repeat(line_depth.max(0) as usize)
+        };
+        let indent_length = line.len() - content.len();
+        if line[..indent_length] != indent {
+            piece_table.delete_range(start, start + indent_length);
+            piece_table.insert(start, &indent);
+        }
+        depth = (depth + bracket_depth(content)).max(0);
+    }
+}
+
+fn convert_case(text: &str, operator: Operator) -> String {
+    match operator {
+        Operator::Lowercase => text.to_lowercase(),
+        Operator::Uppercase => text.to_uppercase(),
+        _ => text
+            .chars()
+            .flat_map(|ch| -> Vec<char> {
+                if ch.is_lowercase() {
+                    ch.to_uppercase().collect()
+                } else {
+                    ch.to_lowercase().collect()
+                }
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delete_ranges() {
+        let mut piece_table = PieceTable::new("one two\nthree four\nfive six\n");
+        let range = TextRange::new(RangeKind::Charwise, (4, 1), (4, 0));
+        let (register, cursor) = apply_operator(Operator::Delete, range, &mut piece_table);
+        assert_eq!(register.unwrap().text, "two\nthree");
+        assert_eq!(cursor, (4, 0));
+        assert_eq!(piece_table.to_string(), "one  four\nfive six\n");
+
+        let range = TextRange::new(RangeKind::Linewise, (2, 1), (0, 1));
+        apply_operator(Operator::Delete, range, &mut piece_table);
+        assert_eq!(piece_table.to_string(), "one  four\n");
+
+        let mut piece_table = PieceTable::new("abcd\nef\nghij");
+        let range = TextRange::new(RangeKind::Blockwise, (1, 0), (2, 2));
+        let (register, _) = apply_operator(Operator::Delete, range, &mut piece_table);
+        assert_eq!(register.unwrap().text, "bc\nf\nhi");
+        assert_eq!(piece_table.to_string(), "ad\ne\ngj");
+    }
+
+    #[test]
+    fn test_case_and_shift() {
+        let mut piece_table = PieceTable::new("Hello World\n  indented");
+        let range = TextRange::new(RangeKind::Charwise, (0, 0), (6, 0));
+        apply_operator(Operator::ToggleCase, range, &mut piece_table);
+        assert_eq!(piece_table.line(0).as_deref(), Some("hELLO world"));
+
+        let range = TextRange::new(RangeKind::Linewise, (0, 0), (0, 1));
+        apply_operator(Operator::Indent, range, &mut piece_table);
+        apply_operator(Operator::Outdent, range, &mut piece_table);
+        apply_operator(Operator::Outdent, range, &mut piece_table);
+        assert_eq!(piece_table.to_string(), "hELLO world\nindented");
+    }
+
+    #[test]
+    fn test_reindent() {
+        let mut piece_table = PieceTable::new("fn f() {\n  if x {\ny();\n     }\n  \n}\n");
+        let range = TextRange::new(RangeKind::Linewise, (0, 1), (0, 5));
+        let (_, cursor) = apply_operator(Operator::Format, range, &mut piece_table);
+        assert_eq!(
+            piece_table.to_string(),
+            "fn f() {\n\tif x {\n\t\ty();\n\t}\n\n}\n"
+        );
+        assert_eq!(cursor, (1, 1));
+    }
+}
@@ -1,3 +1,6 @@
+use crate::history::{Change, History, HistoryError};
+use crate::line_marks::LineMarks;
+use crate::utils::{byte_to_grapheme, grapheme_count, grapheme_to_byte};
 use std::fmt;
 
 #[derive(Debug)]
@@ -11,6 +14,21 @@ pub enum BufferType {
     Added,
 }
 
+// A change to the pieces, kept for the swap file while the journal is on
+#[derive(PartialEq, Debug, Clone)]
+pub enum PieceOperation {
+    // `length` bytes from `start` in the added buffer went in at `position`
+    Insert {
+        position: usize,
+        start: usize,
+        length: usize,
+    },
+    Delete {
+        position: usize,
+        length: usize,
+    },
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct Piece {
     pub source: BufferType,
@@ -18,23 +36,107 @@ pub struct Piece {
     pub length: usize,
 }
 
-pub struct PieceTable {
-    pub original: String,
-    pub added: String,
-    pub table: Vec<Piece>,
+// An append-only text buffer along with the byte offset of every newline in it,
+// so the newlines inside any piece can be counted and found with a binary search
+#[derive(Default)]
+struct Buffer {
+    text: String,
+    newlines: Vec<usize>,
+}
+
+impl Buffer {
+    fn new(text: &str) -> Self {
+        let mut buffer = Self::default();
+        buffer.push_str(text);
+        buffer
+    }
+
+    fn push_str(&mut self, text: &str) {
+        let offset = self.text.len();
+        self.newlines.extend(
+            text.bytes()
+                .enumerate()
+                .filter(|(_, byte)| *byte == b'\n')
+                .map(|(i, _)| offset + i),
+        );
+        self.text.push_str(text);
+    }
+
+    fn count_newlines(&self, start: usize, length: usize) -> usize {
+        let first = self.newlines.partition_point(|&i| i < start);
+        let last = self.newlines.partition_point(|&i| i < start + length);
+        last - first
+    }
+
+    // Buffer offset of the nth (starting at 0) newline at or after `start`
+    fn nth_newline(&self, start: usize, n: usize) -> usize {
+        self.newlines[self.newlines.partition_point(|&i| i < start) + n]
+    }
+}
+
+type Tree = Option<Box<Node>>;
+
+// A node of a treap ordered by document position.
+// `length` and `newlines` cover the node's whole subtree
+struct Node {
+    piece: Piece,
+    piece_newlines: usize,
+    priority: u64,
+    length: usize,
+    newlines: usize,
+    left: Tree,
+    right: Tree,
 }
 
-fn substring(s: &str, start: usize, length: usize) -> &str {
-    let mut char_indices = s.char_indices();
-    let start_byte = char_indices
-        .nth(start)
-        .map(|(idx, _)| idx)
-        .unwrap_or(s.len());
-    let end_byte = char_indices
-        .nth(length - 1)
-        .map(|(idx, _)| idx)
-        .unwrap_or(s.len());
-    &s[start_byte..end_byte]
+impl Node {
+    fn update(&mut self) {
+        self.length = tree_length(&self.left) + self.piece.length + tree_length(&self.right);
+        self.newlines =
+            tree_newlines(&self.left) + self.piece_newlines + tree_newlines(&self.right);
+    }
+}
+
+fn tree_length(tree: &Tree) -> usize {
+    tree.as_ref().map_or(0, |node| node.length)
+}
+
+fn tree_newlines(tree: &Tree) -> usize {
+    tree.as_ref().map_or(0, |node| node.newlines)
+}
+
+// Pseudo-random but deterministic, so the same edits always give the same tree
+fn priority(piece: &Piece) -> u64 {
+    let mut x = (piece.start_index as u64) << 1 | (piece.source == BufferType::Added) as u64;
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn merge(left: Tree, right: Tree) -> Tree {
+    match (left, right) {
+        (None, tree) | (tree, None) => tree,
+        (Some(mut left), Some(mut right)) => {
+            if left.priority > right.priority {
+                left.right = merge(left.right.take(), Some(right));
+                left.update();
+                Some(left)
+            } else {
+                right.left = merge(Some(left), right.left.take());
+                right.update();
+                Some(right)
+            }
+        }
+    }
+}
+
+pub struct PieceTable {
+    original: Buffer,
+    added: Buffer,
+    root: Tree,
+    pub history: History,
+    line_marks: LineMarks,
+    journal: Option<Vec<PieceOperation>>,
 }
 
 impl Default for PieceTable {
@@ -45,246 +147,662 @@ impl Default for PieceTable {
 
 impl PieceTable {
     pub fn new(original_contents: &str) -> Self {
-        if original_contents.is_empty() {
-            return Self {
-                original: "".to_string(),
-                added: "".to_string(),
-                table: vec![],
-            };
-        }
-
-        Self {
-            original: original_contents.to_string(),
-            added: "".to_string(),
-            table: vec![Piece {
+        Self::from_pieces(
+            original_contents,
+            "",
+            vec![Piece {
                 source: BufferType::Original,
                 start_index: 0,
                 length: original_contents.len(),
             }],
+        )
+    }
+
+    pub fn from_pieces(original: &str, added: &str, pieces: Vec<Piece>) -> Self {
+        let mut piece_table = Self {
+            original: Buffer::new(original),
+            added: Buffer::new(added),
+            root: None,
+            history: History::new(),
+            line_marks: LineMarks::default(),
+            journal: None,
+        };
+        for piece in pieces {
+            let node = piece_table.new_node(piece);
+            piece_table.root = merge(piece_table.root.take(), node);
         }
+        piece_table
     }
 
-    pub fn merge(&mut self) {
-        let mut entry_no = 1;
-        while entry_no < self.table.len() {
-            let last_entry = &self.table[entry_no - 1];
-            let cur_entry = &self.table[entry_no];
+    pub fn pieces(&self) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        Self::collect_pieces(&self.root, &mut pieces);
+        pieces
+    }
 
-            if last_entry.start_index + last_entry.length == cur_entry.start_index
-                && last_entry.source == cur_entry.source
-            {
-                let new_entry = Piece {
-                    source: last_entry.source.clone(),
-                    start_index: last_entry.start_index,
-                    length: last_entry.length + cur_entry.length,
-                };
-                self.table.insert(entry_no - 1, new_entry);
-                self.table.remove(entry_no);
-                self.table.remove(entry_no);
-            } else {
-                entry_no += 1;
-            }
+    fn collect_pieces(tree: &Tree, pieces: &mut Vec<Piece>) {
+        if let Some(node) = tree {
+            Self::collect_pieces(&node.left, pieces);
+            pieces.push(node.piece.clone());
+            Self::collect_pieces(&node.right, pieces);
+        }
+    }
+
+    fn buffer(&self, source: &BufferType) -> &Buffer {
+        match source {
+            BufferType::Original => &self.original,
+            BufferType::Added => &self.added,
+        }
+    }
+
+    fn new_node(&self, piece: Piece) -> Tree {
+        if piece.length == 0 {
+            return None;
+        }
+        let piece_newlines = self
+            .buffer(&piece.source)
+            .count_newlines(piece.start_index, piece.length);
+        Some(Box::new(Node {
+            priority: priority(&piece),
+            length: piece.length,
+            newlines: piece_newlines,
+            piece,
+            piece_newlines,
+            left: None,
+            right: None,
+        }))
+    }
+
+    // Splits the tree so that the first `offset` bytes are in the left half
+    fn split(&self, tree: Tree, offset: usize) -> (Tree, Tree) {
+        let Some(mut node) = tree else {
+            return (None, None);
+        };
+
+        let left_length = tree_length(&node.left);
+        if offset <= left_length {
+            let (left, right) = self.split(node.left.take(), offset);
+            node.left = right;
+            node.update();
+            (left, Some(node))
+        } else if offset >= left_length + node.piece.length {
+            let (left, right) =
+                self.split(node.right.take(), offset - left_length - node.piece.length);
+            node.right = left;
+            node.update();
+            (Some(node), right)
+        } else {
+            let piece_offset = offset - left_length;
+            let second = self.new_node(Piece {
+                source: node.piece.source.clone(),
+                start_index: node.piece.start_index + piece_offset,
+                length: node.piece.length - piece_offset,
+            });
+            node.piece.length = piece_offset;
+            node.piece_newlines = self
+                .buffer(&node.piece.source)
+                .count_newlines(node.piece.start_index, piece_offset);
+            let right = merge(second, node.right.take());
+            node.update();
+            (Some(node), right)
+        }
+    }
+
+    // Grows the last piece of the tree if `length` more bytes of the added buffer
+    // directly follow it, so that typing doesn't create a piece per character
+    fn extend_last(&self, tree: &mut Tree, added_start: usize, length: usize) -> bool {
+        let Some(node) = tree else {
+            return false;
+        };
+
+        let extended = if node.right.is_some() {
+            self.extend_last(&mut node.right, added_start, length)
+        } else if node.piece.source == BufferType::Added
+            && node.piece.start_index + node.piece.length == added_start
+        {
+            node.piece.length += length;
+            node.piece_newlines = self
+                .added
+                .count_newlines(node.piece.start_index, node.piece.length);
+            true
+        } else {
+            false
+        };
+
+        if extended {
+            node.update();
         }
+        extended
+    }
+
+    pub fn len(&self) -> usize {
+        tree_length(&self.root)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     pub fn append(&mut self, text: &str) {
-        let position = self.table.iter().map(|piece| piece.length).sum::<usize>();
+        self.insert(self.len(), text);
+    }
 
-        self.insert(position, text);
+    // Counts lines the same way as `str::lines`, so a trailing newline doesn't start a new line
+    pub fn line_count(&self) -> usize {
+        let newlines = tree_newlines(&self.root);
+        match self.last_byte() {
+            None => 0,
+            Some(b'\n') => newlines,
+            Some(_) => newlines + 1,
+        }
     }
 
-    pub fn lines(&self) -> Vec<String> {
-        self.to_string()
-            .lines()
-            .map(|line| line.to_string())
-            .collect()
+    fn last_byte(&self) -> Option<u8> {
+        let mut node = self.root.as_ref()?;
+        while let Some(right) = &node.right {
+            node = right;
+        }
+        let piece = &node.piece;
+        Some(self.buffer(&piece.source).text.as_bytes()[piece.start_index + piece.length - 1])
     }
 
-    pub fn find_index(&self, x: usize, y: usize) -> Result<usize, FindIndexError> {
-        let mut cur_index = 0;
-        for (cur_y, line) in self.to_string().lines().enumerate() {
-            if line.is_empty() && cur_y == y {
-                return Ok(cur_index);
-            }
-            for (cur_x, _) in line.chars().enumerate() {
-                if cur_x == x && cur_y == y {
-                    return Ok(cur_index);
-                }
-                cur_index += 1;
-                if cur_x == x && cur_y == y {
-                    return Ok(cur_index);
-                }
+    // Document offset of the nth (starting at 0) newline
+    fn newline_offset(&self, mut n: usize) -> Option<usize> {
+        let mut tree = &self.root;
+        let mut offset = 0;
+        while let Some(node) = tree {
+            let left_newlines = tree_newlines(&node.left);
+            let left_length = tree_length(&node.left);
+            if n < left_newlines {
+                tree = &node.left;
+            } else if n < left_newlines + node.piece_newlines {
+                let piece = &node.piece;
+                let buffer_offset = self
+                    .buffer(&piece.source)
+                    .nth_newline(piece.start_index, n - left_newlines);
+                return Some(offset + left_length + buffer_offset - piece.start_index);
+            } else {
+                n -= left_newlines + node.piece_newlines;
+                offset += left_length + node.piece.length;
+                tree = &node.right;
             }
-            cur_index += 1;
         }
-        Err(FindIndexError::OutOfBounds)
+        None
     }
 
-    pub fn index(&self, i: usize) -> Option<char> {
-        let total_length = self.table.iter().map(|piece| piece.length).sum();
-        if i >= total_length {
+    pub fn line_start(&self, y: usize) -> Option<usize> {
+        if y >= self.line_count() {
             return None;
         }
+        match y {
+            0 => Some(0),
+            _ => self.newline_offset(y - 1).map(|offset| offset + 1),
+        }
+    }
 
-        let mut cur_index = 0;
-        for entry in self.table.iter() {
-            let end_index = cur_index + entry.length;
-            if i >= cur_index && i < end_index {
-                let buffer_index = entry.start_index + i - cur_index;
-                return match &entry.source {
-                    BufferType::Original => self.original.chars().nth(buffer_index),
-                    BufferType::Added => self.added.chars().nth(buffer_index),
-                };
+    fn line_end(&self, y: usize) -> usize {
+        self.newline_offset(y).unwrap_or(self.len())
+    }
+
+    pub fn line(&self, y: usize) -> Option<String> {
+        let start = self.line_start(y)?;
+        Some(self.slice(start, self.line_end(y)))
+    }
+
+    // Length of a line in bytes, not counting the newline
+    pub fn line_len(&self, y: usize) -> usize {
+        self.line_start(y)
+            .map_or(0, |start| self.line_end(y) - start)
+    }
+
+    // Length of a line in grapheme clusters, the unit cursor columns are measured in
+    pub fn line_grapheme_len(&self, y: usize) -> usize {
+        self.line(y).map_or(0, |line| grapheme_count(&line))
+    }
+
+    // Converts a byte offset into a (grapheme column, line) position.
+    // An offset inside a grapheme cluster gives the column of that cluster
+    pub fn coordinates(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.len());
+        let y = self.newlines_before(offset);
+        let start = match y {
+            0 => 0,
+            _ => self.newline_offset(y - 1).map_or(0, |newline| newline + 1),
+        };
+        let line = self.slice(start, self.line_end(y));
+        let x = match offset - start {
+            column if column >= line.len() => grapheme_count(&line),
+            column => byte_to_grapheme(&line, column + 1) - 1,
+        };
+        (x, y)
+    }
+
+    fn newlines_before(&self, mut offset: usize) -> usize {
+        let mut tree = &self.root;
+        let mut newlines = 0;
+        while let Some(node) = tree {
+            let left_length = tree_length(&node.left);
+            if offset < left_length {
+                tree = &node.left;
+            } else if offset < left_length + node.piece.length {
+                let piece = &node.piece;
+                return newlines
+                    + tree_newlines(&node.left)
+                    + self
+                        .buffer(&piece.source)
+                        .count_newlines(piece.start_index, offset - left_length);
+            } else {
+                offset -= left_length + node.piece.length;
+                newlines += tree_newlines(&node.left) + node.piece_newlines;
+                tree = &node.right;
             }
-            cur_index += entry.length;
         }
-        None
+        newlines
     }
 
-    pub fn delete(&mut self, position: usize) {
-        let mut cur_index = 0;
-        let mut split_index = None;
-        for (entry_index, entry) in self.table.iter().enumerate() {
-            let end_index = cur_index + entry.length;
-            if position >= cur_index && position < end_index {
-                split_index = Some((entry_index, position - cur_index));
-            }
-            cur_index += entry.length;
+    pub fn slice(&self, start: usize, end: usize) -> String {
+        let mut text = String::new();
+        self.collect_text(&self.root, 0, start, end, &mut text);
+        text
+    }
+
+    fn collect_text(
+        &self,
+        tree: &Tree,
+        offset: usize,
+        start: usize,
+        end: usize,
+        text: &mut String,
+    ) {
+        let Some(node) = tree else {
+            return;
+        };
+        if offset >= end || offset + node.length <= start {
+            return;
         }
 
-        if let Some((entry_index, entry_position)) = split_index {
-            let original_entry = self.table[entry_index].clone();
-            let original_buffer_type = &original_entry.source;
+        self.collect_text(&node.left, offset, start, end, text);
 
-            if entry_position == 0 {
-                let new_entry = Piece {
-                    source: original_buffer_type.clone(),
-                    start_index: original_entry.start_index + 1,
-                    length: original_entry.length - 1,
-                };
+        let piece = &node.piece;
+        let piece_start = offset + tree_length(&node.left);
+        let from = start.max(piece_start) - piece_start;
+        let to = end
+            .min(piece_start + piece.length)
+            .saturating_sub(piece_start);
+        if from < to {
+            let buffer = &self.buffer(&piece.source).text;
+            text.push_str(&buffer[piece.start_index + from..piece.start_index + to]);
+        }
 
-                self.table.remove(entry_index);
-                self.table.insert(entry_index, new_entry);
-            } else if entry_position == original_entry.length {
-                let new_entry = Piece {
-                    source: original_buffer_type.clone(),
-                    start_index: original_entry.start_index,
-                    length: original_entry.length,
-                };
+        self.collect_text(&node.right, piece_start + piece.length, start, end, text);
+    }
+
+    fn piece_text(&self, piece: &Piece) -> &str {
+        &self.buffer(&piece.source).text[piece.start_index..piece.start_index + piece.length]
+    }
 
-                self.table.remove(entry_index);
-                self.table.insert(entry_index, new_entry);
+    // The text from byte offset `start` to the end, one piece at a time, without copying it
+    pub fn chunks(&self, start: usize) -> Chunks<'_> {
+        let mut stack = Vec::new();
+        let mut tree = &self.root;
+        let mut offset = start;
+        while let Some(node) = tree {
+            let left_length = tree_length(&node.left);
+            if offset < left_length {
+                stack.push((node.as_ref(), 0));
+                tree = &node.left;
+            } else if offset < left_length + node.piece.length {
+                stack.push((node.as_ref(), offset - left_length));
+                break;
             } else {
-                let first_entry = Piece {
-                    source: original_buffer_type.clone(),
-                    start_index: original_entry.start_index,
-                    length: entry_position,
-                };
+                offset -= left_length + node.piece.length;
+                tree = &node.right;
+            }
+        }
+        Chunks {
+            piece_table: self,
+            stack,
+        }
+    }
 
-                let second_length = original_entry.length - entry_position;
-                let second_entry = Piece {
-                    source: original_buffer_type.clone(),
-                    start_index: original_entry.start_index + entry_position + 1,
-                    length: second_length - 1,
-                };
+    // The text before byte offset `end`, one piece at a time, from the last piece to the first
+    pub fn chunks_before(&self, end: usize) -> ChunksBefore<'_> {
+        let mut stack = Vec::new();
+        let mut tree = &self.root;
+        let mut offset = end;
+        while let Some(node) = tree {
+            let left_length = tree_length(&node.left);
+            if offset <= left_length {
+                tree = &node.left;
+            } else if offset <= left_length + node.piece.length {
+                stack.push((node.as_ref(), offset - left_length));
+                break;
+            } else {
+                stack.push((node.as_ref(), node.piece.length));
+                offset -= left_length + node.piece.length;
+                tree = &node.right;
+            }
+        }
+        ChunksBefore {
+            piece_table: self,
+            stack,
+        }
+    }
+
+    // Each char from byte offset `start` to the end along with its offset
+    pub fn chars_at(&self, start: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        let mut offset = start;
+        self.chunks(start).flat_map(move |chunk| {
+            let chunk_start = offset;
+            offset += chunk.len();
+            chunk
+                .char_indices()
+                .map(move |(i, ch)| (chunk_start + i, ch))
+        })
+    }
+
+    // Each char before byte offset `end` along with its offset, going backwards
+    pub fn chars_before(&self, end: usize) -> impl Iterator<Item = (usize, char)> + '_ {
+        let mut offset = end.min(self.len());
+        self.chunks_before(end).flat_map(move |chunk| {
+            offset -= chunk.len();
+            let chunk_start = offset;
+            chunk
+                .char_indices()
+                .rev()
+                .map(move |(i, ch)| (chunk_start + i, ch))
+        })
+    }
 
-                self.table.remove(entry_index);
-                self.table.insert(entry_index, second_entry);
-                self.table.insert(entry_index, first_entry);
+    // Converts a (grapheme column, line) position into a byte offset.
+    // The column may be one past the end of the line
+    pub fn find_index(&self, x: usize, y: usize) -> Result<usize, FindIndexError> {
+        // Empty text still has a line to type on
+        if self.is_empty() && (x, y) == (0, 0) {
+            return Ok(0);
+        }
+        let start = self.line_start(y).ok_or(FindIndexError::OutOfBounds)?;
+        let line = self.slice(start, self.line_end(y));
+        if x > grapheme_count(&line) {
+            return Err(FindIndexError::OutOfBounds);
+        }
+        Ok(start + grapheme_to_byte(&line, x))
+    }
+
+    // The char starting at byte offset `i`
+    pub fn index(&self, i: usize) -> Option<char> {
+        let mut tree = &self.root;
+        let mut i = i;
+        while let Some(node) = tree {
+            let left_length = tree_length(&node.left);
+            if i < left_length {
+                tree = &node.left;
+            } else if i < left_length + node.piece.length {
+                let piece = &node.piece;
+                let buffer = &self.buffer(&piece.source).text;
+                return buffer
+                    .get(piece.start_index + i - left_length..)
+                    .and_then(|text| text.chars().next());
+            } else {
+                i -= left_length + node.piece.length;
+                tree = &node.right;
             }
         }
+        None
+    }
+
+    // Deletes the char starting at byte offset `position`
+    pub fn delete(&mut self, position: usize) {
+        if let Some(ch) = self.index(position) {
+            self.history.record(Change::Delete {
+                position,
+                text: ch.to_string(),
+            });
+            self.delete_unrecorded(position, ch.len_utf8());
+        }
+    }
+
+    // Deletes the bytes in `start..end`
+    pub fn delete_range(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        self.history.record(Change::Delete {
+            position: start,
+            text: self.slice(start, end),
+        });
+        self.delete_unrecorded(start, end - start);
     }
 
     pub fn insert(&mut self, position: usize, text: &str) {
-        let added_start_index = self.added.len();
-        self.added.push_str(text);
-        let mut cur_index = 0;
-        let mut split_index = None;
-        for (entry_index, entry) in self.table.iter_mut().enumerate() {
-            let end_index = cur_index + entry.length;
-            if position >= cur_index && position < end_index {
-                split_index = Some((entry_index, position - cur_index));
-                break;
+        self.history.record(Change::Insert {
+            position,
+            text: text.to_string(),
+        });
+        self.insert_unrecorded(position, text);
+    }
+
+    // Marks lines for `:g`, to be taken back in order with `next_marked_line`
+    pub fn mark_lines(&mut self, lines: impl IntoIterator<Item = usize>) {
+        self.line_marks = LineMarks::new(lines);
+    }
+
+    // The first marked line that is still there, taking its mark off
+    pub fn next_marked_line(&mut self) -> Option<usize> {
+        self.line_marks.pop()
+    }
+
+    pub fn clear_line_marks(&mut self) {
+        self.line_marks = LineMarks::default();
+    }
+
+    // Starts keeping every change to the pieces, for `take_journal`
+    pub fn start_journal(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    // The changes made since the journal was last taken
+    pub fn take_journal(&mut self) -> Vec<PieceOperation> {
+        self.journal
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub fn journal_len(&self) -> usize {
+        self.journal.as_ref().map_or(0, Vec::len)
+    }
+
+    // The added buffer from byte `start` on
+    pub fn added_text(&self, start: usize) -> &str {
+        &self.added.text[start..]
+    }
+
+    // Whether `position` is at the start of a line, or just after the last line
+    fn at_line_start(&self, position: usize) -> bool {
+        self.chars_before(position)
+            .next()
+            .is_none_or(|(_, ch)| ch == '\n')
+    }
+
+    pub fn begin_undo_group(&mut self, cursor: (usize, usize)) {
+        self.history.begin_group(cursor);
+    }
+
+    pub fn end_undo_group(&mut self) {
+        self.history.end_group();
+    }
+
+    // Keeps a group open until `release_undo_group`, so everything `:g` does is undone at once
+    pub fn hold_undo_group(&mut self, cursor: (usize, usize)) {
+        self.history.begin_group(cursor);
+        self.history.hold_group();
+    }
+
+    pub fn release_undo_group(&mut self) {
+        self.history.release_group();
+        self.history.end_group();
+    }
+
+    // The text now matches the file, until it is changed again
+    pub fn mark_saved(&mut self) {
+        self.history.mark_saved();
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.history.is_modified()
+    }
+
+    // Each returns the cursor position to restore, if one was recorded for the change
+    pub fn undo(&mut self) -> Result<Option<(usize, usize)>, HistoryError> {
+        let step = self.history.undo()?;
+        self.apply_changes(&step.changes);
+        Ok(step.cursor)
+    }
+
+    pub fn redo(&mut self) -> Result<Option<(usize, usize)>, HistoryError> {
+        let step = self.history.redo()?;
+        self.apply_changes(&step.changes);
+        Ok(step.cursor)
+    }
+
+    pub fn undo_to(
+        &mut self,
+        change_number: usize,
+    ) -> Result<Option<(usize, usize)>, HistoryError> {
+        let step = self.history.undo_to(change_number)?;
+        self.apply_changes(&step.changes);
+        Ok(step.cursor)
+    }
+
+    fn apply_changes(&mut self, changes: &[Change]) {
+        for change in changes {
+            match change {
+                Change::Insert { position, text } => self.insert_unrecorded(*position, text),
+                Change::Delete { position, text } => self.delete_unrecorded(*position, text.len()),
             }
-            cur_index += entry.length;
         }
-        if let Some((entry_index, entry_position)) = split_index {
-            let original_entry = self.table[entry_index].clone();
+    }
 
-            let original_buffer_type = &original_entry.source;
-            if entry_position == 0 {
-                let added = Piece {
-                    source: BufferType::Added,
-                    start_index: added_start_index,
-                    length: text.len(),
+    fn delete_unrecorded(&mut self, position: usize, length: usize) {
+        if !self.line_marks.is_empty() {
+            let newlines = self
+                .slice(position, position + length)
+                .matches('\n')
+                .count();
+            if newlines > 0 {
+                let (_, y) = self.coordinates(position);
+                // Whole lines go with their marks, otherwise the lines after the first are joined onto it
+                let first = if self.at_line_start(position) && self.at_line_start(position + length)
+                {
+                    y
+                } else {
+                    y + 1
                 };
+                self.line_marks.remove_lines(first, newlines);
+            }
+        }
 
-                self.table.insert(entry_index, added)
-            } else if entry_position == original_entry.length {
-                let added = Piece {
-                    source: BufferType::Added,
-                    start_index: added_start_index,
-                    length: text.len(),
-                };
+        if let Some(journal) = &mut self.journal {
+            journal.push(PieceOperation::Delete { position, length });
+        }
 
-                self.table.insert(entry_index + 1, added);
-            } else {
-                let first_length = entry_position;
-                let first = Piece {
-                    source: original_buffer_type.clone(),
-                    length: first_length,
-                    start_index: original_entry.start_index,
-                };
+        let root = self.root.take();
+        let (left, right) = self.split(root, position);
+        let (_, right) = self.split(right, length);
+        self.root = merge(left, right);
+    }
 
-                let second_length = text.len();
-                let middle = Piece {
-                    source: BufferType::Added,
-                    length: second_length,
-                    start_index: added_start_index,
+    fn insert_unrecorded(&mut self, position: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if !self.line_marks.is_empty() {
+            let newlines = text.matches('\n').count();
+            if newlines > 0 {
+                let (_, y) = self.coordinates(position);
+                // Lines put in above a line move it down, but splitting it leaves it where it is
+                let first = if self.at_line_start(position) && text.ends_with('\n') {
+                    y
+                } else {
+                    y + 1
                 };
+                self.line_marks.insert_lines(first, newlines);
+            }
+        }
 
-                let last_length = original_entry.length - first_length;
-                let last = Piece {
-                    source: original_buffer_type.clone(),
-                    length: last_length,
-                    start_index: first_length + original_entry.start_index,
-                };
+        let added_start_index = self.added.text.len();
+        self.added.push_str(text);
+        if let Some(journal) = &mut self.journal {
+            journal.push(PieceOperation::Insert {
+                position,
+                start: added_start_index,
+                length: text.len(),
+            });
+        }
 
-                self.table.remove(entry_index);
-                self.table.insert(entry_index, last);
-                self.table.insert(entry_index, middle);
-                self.table.insert(entry_index, first);
-            }
-        } else {
-            let added = Piece {
+        let root = self.root.take();
+        let (mut left, right) = self.split(root, position);
+        if !self.extend_last(&mut left, added_start_index, text.len()) {
+            let added = self.new_node(Piece {
                 source: BufferType::Added,
-                length: text.len(),
                 start_index: added_start_index,
-            };
-            self.table.push(added);
+                length: text.len(),
+            });
+            left = merge(left, added);
         }
+        self.root = merge(left, right);
+    }
+}
+
+pub struct Chunks<'a> {
+    piece_table: &'a PieceTable,
+    // Nodes still to visit along with how many bytes of their piece to skip
+    stack: Vec<(&'a Node, usize)>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let (node, skip) = self.stack.pop()?;
+        let mut tree = &node.right;
+        while let Some(right) = tree {
+            self.stack.push((right.as_ref(), 0));
+            tree = &right.left;
+        }
+        Some(&self.piece_table.piece_text(&node.piece)[skip..])
+    }
+}
+
+pub struct ChunksBefore<'a> {
+    piece_table: &'a PieceTable,
+    // Nodes still to visit along with how many bytes of their piece to take
+    stack: Vec<(&'a Node, usize)>,
+}
+
+impl<'a> ChunksBefore<'a> {
+    fn push_right_spine(&mut self, mut tree: &'a Tree) {
+        while let Some(node) = tree {
+            self.stack.push((node.as_ref(), node.piece.length));
+            tree = &node.right;
+        }
+    }
+}
+
+impl<'a> Iterator for ChunksBefore<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let (node, take) = self.stack.pop()?;
+        self.push_right_spine(&node.left);
+        Some(&self.piece_table.piece_text(&node.piece)[..take])
     }
 }
 
 impl fmt::Display for PieceTable {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut string = String::new();
-        for entry in self.table.iter() {
-            if entry.length == 0 {
-                continue;
-            }
-            match entry.source {
-                BufferType::Original => {
-                    let text = substring(&self.original, entry.start_index, entry.length);
-                    string.push_str(text);
-                }
-                BufferType::Added => {
-                    let text = substring(&self.added, entry.start_index, entry.length);
-                    string.push_str(text);
-                }
-            }
-        }
-        write!(f, "{}", string)
+        write!(f, "{}", self.slice(0, self.len()))
     }
 }
 
@@ -302,11 +820,7 @@ mod tests {
             length: 44,
         }];
 
-        let mut piece_table = PieceTable {
-            original,
-            added,
-            table,
-        };
+        let mut piece_table = PieceTable::from_pieces(&original, &added, table);
 
         println!("{}", piece_table);
 
@@ -327,7 +841,7 @@ mod tests {
             },
         ];
 
-        let result = piece_table.table;
+        let result = piece_table.pieces();
         assert_eq!(result, expected);
     }
 
@@ -341,11 +855,7 @@ mod tests {
             length: 44,
         }];
 
-        let mut piece_table = PieceTable {
-            original,
-            added,
-            table,
-        };
+        let mut piece_table = PieceTable::from_pieces(&original, &added, table);
 
         piece_table.insert(20, "went to the park and\n");
 
@@ -369,7 +879,7 @@ mod tests {
 
         println!("{}", piece_table);
 
-        let result = piece_table.table;
+        let result = piece_table.pieces();
         assert_eq!(result, expected);
     }
 
@@ -399,13 +909,197 @@ mod tests {
                 length: 9,
             },
         ];
-        let table = PieceTable {
-            original,
-            added,
-            table,
-        };
+        let table = PieceTable::from_pieces(&original, &added, table);
 
         let result = table.index(15);
         assert_eq!(result, Some('o'));
     }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut piece_table = PieceTable::new("hello world");
+
+        piece_table.begin_undo_group((5, 0));
+        piece_table.insert(5, ",");
+        piece_table.insert(12, "!");
+        piece_table.end_undo_group();
+        piece_table.delete(0);
+        assert_eq!(piece_table.to_string(), "ello, world!");
+
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.to_string(), "hello, world!");
+        assert_eq!(piece_table.undo().unwrap(), Some((5, 0)));
+        assert_eq!(piece_table.to_string(), "hello world");
+        assert!(piece_table.undo().is_err());
+
+        piece_table.redo().unwrap();
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.to_string(), "ello, world!");
+        assert!(piece_table.redo().is_err());
+    }
+
+    #[test]
+    fn test_undo_to_other_branch() {
+        let mut piece_table = PieceTable::new("abc");
+
+        piece_table.insert(3, "d");
+        piece_table.end_undo_group();
+        piece_table.undo().unwrap();
+        piece_table.insert(0, "x");
+        piece_table.end_undo_group();
+        assert_eq!(piece_table.to_string(), "xabc");
+
+        piece_table.undo_to(1).unwrap();
+        assert_eq!(piece_table.to_string(), "abcd");
+        piece_table.undo_to(0).unwrap();
+        assert_eq!(piece_table.to_string(), "abc");
+        piece_table.redo().unwrap();
+        assert_eq!(piece_table.to_string(), "abcd");
+    }
+
+    #[test]
+    fn test_line_lookup_matches_string() {
+        let mut piece_table = PieceTable::new("one\ntwo\nthree\n");
+        let mut expected = String::from("one\ntwo\nthree\n");
+
+        let edits = [
+            (4, "new\nlines\n"),
+            (0, "x"),
+            (9, "\n"),
+            (22, "end"),
+            (3, "\n\n"),
+        ];
+        for (position, text) in edits {
+            piece_table.insert(position, text);
+            expected.insert_str(position, text);
+        }
+        for position in [5, 0, 10] {
+            piece_table.delete(position);
+            expected.remove(position);
+        }
+
+        assert_eq!(piece_table.to_string(), expected);
+        assert_eq!(piece_table.len(), expected.len());
+        assert_eq!(piece_table.line_count(), expected.lines().count());
+        for (y, line) in expected.lines().enumerate() {
+            assert_eq!(piece_table.line(y).as_deref(), Some(line));
+            assert_eq!(piece_table.line_len(y), line.len());
+        }
+        assert_eq!(piece_table.line(expected.lines().count()), None);
+    }
+
+    #[test]
+    fn test_char_iterators() {
+        let mut piece_table = PieceTable::new("ab\ncdé\nfg");
+        piece_table.insert(4, "XY");
+        piece_table.insert(0, "é");
+        piece_table.delete(6);
+        let text = piece_table.to_string();
+
+        for offset in (0..=text.len()).filter(|&i| text.is_char_boundary(i)) {
+            let forward: Vec<(usize, char)> = piece_table.chars_at(offset).collect();
+            let expected: Vec<(usize, char)> = text[offset..]
+                .char_indices()
+                .map(|(i, ch)| (offset + i, ch))
+                .collect();
+            assert_eq!(forward, expected);
+
+            let backward: Vec<(usize, char)> = piece_table.chars_before(offset).collect();
+            let expected: Vec<(usize, char)> = text[..offset].char_indices().rev().collect();
+            assert_eq!(backward, expected);
+        }
+    }
+
+    #[test]
+    fn test_utf8_round_trip() {
+        let original = "naïve café\n日本語\n";
+        let mut piece_table = PieceTable::new(original);
+        assert_eq!(piece_table.to_string(), original);
+        assert_eq!(piece_table.line_grapheme_len(0), 10);
+        assert_eq!(piece_table.line_len(0), 12);
+
+        let position = piece_table.find_index(2, 1).unwrap();
+        assert_eq!(position, 13 + 6);
+        assert_eq!(piece_table.index(position), Some('語'));
+        assert_eq!(piece_table.coordinates(position), (2, 1));
+
+        piece_table.insert(position, "🦀");
+        assert_eq!(piece_table.line(1).as_deref(), Some("日本🦀語"));
+        piece_table.delete(piece_table.find_index(2, 0).unwrap());
+        assert_eq!(piece_table.line(0).as_deref(), Some("nave café"));
+        assert_eq!(piece_table.coordinates(piece_table.len()), (0, 2));
+
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.to_string(), original);
+
+        // Empty text has no lines, but there is still somewhere to type
+        let empty = PieceTable::new("");
+        assert_eq!(empty.line_count(), 0);
+        assert_eq!(empty.find_index(0, 0).ok(), Some(0));
+        assert!(empty.find_index(0, 1).is_err());
+    }
+
+    #[test]
+    fn test_line_marks() {
+        let mut piece_table = PieceTable::new("a\nb\nc\nd\ne\n");
+        piece_table.mark_lines([1, 2, 3, 4]);
+        // A line put in above `b`, `c` deleted and `d` joined onto the line before
+        piece_table.insert(2, "x\n");
+        piece_table.delete_range(6, 8);
+        piece_table.delete(5);
+        // Breaking the line after `e` leaves it where it is
+        piece_table.insert(8, "\n");
+        assert_eq!(piece_table.to_string(), "a\nx\nbd\ne\n\n");
+        assert_eq!(piece_table.next_marked_line(), Some(2));
+        assert_eq!(piece_table.next_marked_line(), Some(3));
+        assert_eq!(piece_table.next_marked_line(), None);
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut piece_table = PieceTable::new("abc");
+        piece_table.insert(1, "x");
+        piece_table.start_journal();
+        piece_table.insert(3, "yz");
+        piece_table.begin_undo_group((0, 0));
+        piece_table.delete_range(0, 2);
+        piece_table.end_undo_group();
+        piece_table.undo().unwrap();
+        assert_eq!(piece_table.journal_len(), 3);
+        assert_eq!(
+            piece_table.take_journal(),
+            vec![
+                PieceOperation::Insert {
+                    position: 3,
+                    start: 1,
+                    length: 2
+                },
+                PieceOperation::Delete {
+                    position: 0,
+                    length: 2
+                },
+                PieceOperation::Insert {
+                    position: 0,
+                    start: 3,
+                    length: 2
+                },
+            ]
+        );
+        assert_eq!(piece_table.added_text(1), "yzax");
+        assert!(piece_table.take_journal().is_empty());
+    }
+
+    #[test]
+    fn test_modified() {
+        let mut piece_table = PieceTable::new("abc");
+        assert!(!piece_table.is_modified());
+        piece_table.insert(0, "x");
+        assert!(piece_table.is_modified());
+        piece_table.mark_saved();
+        assert!(!piece_table.is_modified());
+        piece_table.undo().unwrap();
+        assert!(piece_table.is_modified());
+        piece_table.redo().unwrap();
+        assert!(!piece_table.is_modified());
+    }
 }
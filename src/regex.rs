@@ -0,0 +1,1055 @@
+use crate::piece_table::PieceTable;
+use std::collections::HashSet;
+use std::fmt;
+
+// Vim's regular expressions, as used by `/` and `:s`.
+// Patterns are compiled to a small program that is run with backtracking, remembering
+// which (instruction, offset) pairs already failed so that no pattern takes exponential time.
+// Text is read a char at a time through `Haystack`, so a search never copies the whole file
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RegexError {
+    UnmatchedOpen,
+    UnmatchedClose,
+    FollowsNothing(char),
+    BadCount,
+    BadZ,
+    BackReference,
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegexError::UnmatchedOpen => write!(f, "E54: Unmatched \\("),
+            RegexError::UnmatchedClose => write!(f, "E55: Unmatched \\)"),
+            RegexError::FollowsNothing(ch) => write!(f, "E64: \\{} follows nothing", ch),
+            RegexError::BadCount => write!(f, "E554: Syntax error in \\{{...}}"),
+            RegexError::BadZ => write!(f, "E68: Invalid character after \\z"),
+            RegexError::BackReference => write!(f, "E65: Back references are not supported"),
+        }
+    }
+}
+
+// Text a pattern can be matched against
+pub trait Haystack {
+    // The char starting at byte offset `offset`
+    fn char_at(&self, offset: usize) -> Option<char>;
+    // The char ending at byte offset `offset`
+    fn char_before(&self, offset: usize) -> Option<char>;
+}
+
+impl Haystack for str {
+    fn char_at(&self, offset: usize) -> Option<char> {
+        self.get(offset..)?.chars().next()
+    }
+
+    fn char_before(&self, offset: usize) -> Option<char> {
+        self.get(..offset)?.chars().next_back()
+    }
+}
+
+impl Haystack for PieceTable {
+    fn char_at(&self, offset: usize) -> Option<char> {
+        self.index(offset)
+    }
+
+    fn char_before(&self, offset: usize) -> Option<char> {
+        self.chars_before(offset).next().map(|(_, ch)| ch)
+    }
+}
+
+// Which chars need a backslash to be special, from `\v` (none) to `\V` (all)
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Magic {
+    Very,
+    On,
+    Off,
+    VeryOff,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Named {
+    Space,
+    Digit,
+    Hex,
+    Octal,
+    Word,
+    Head,
+    Alpha,
+    Alnum,
+    Lower,
+    Upper,
+    Punct,
+}
+
+impl Named {
+    fn matches(self, ch: char) -> bool {
+        match self {
+            Named::Space => ch == ' ' || ch == '\t',
+            Named::Digit => ch.is_ascii_digit(),
+            Named::Hex => ch.is_ascii_hexdigit(),
+            Named::Octal => ('0'..='7').contains(&ch),
+            Named::Word => ch.is_ascii_alphanumeric() || ch == '_',
+            Named::Head => ch.is_ascii_alphabetic() || ch == '_',
+            Named::Alpha => ch.is_ascii_alphabetic(),
+            Named::Alnum => ch.is_ascii_alphanumeric(),
+            Named::Lower => ch.is_lowercase(),
+            Named::Upper => ch.is_uppercase(),
+            Named::Punct => ch.is_ascii_punctuation(),
+        }
+    }
+
+    // `[:alpha:]` and friends inside brackets
+    fn from_bracket_name(name: &str) -> Option<Self> {
+        match name {
+            "space" | "blank" => Some(Named::Space),
+            "digit" => Some(Named::Digit),
+            "xdigit" => Some(Named::Hex),
+            "alpha" => Some(Named::Alpha),
+            "alnum" => Some(Named::Alnum),
+            "lower" => Some(Named::Lower),
+            "upper" => Some(Named::Upper),
+            "punct" => Some(Named::Punct),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum ClassItem {
+    Range(char, char),
+    Named(Named),
+}
+
+// `[a-z]`, `[^,]`, `\s`, `\D` and so on. None of them match a newline unless it is listed
+#[derive(PartialEq, Clone, Debug)]
+struct Class {
+    negated: bool,
+    items: Vec<ClassItem>,
+}
+
+impl Class {
+    fn named(named: Named, negated: bool) -> Self {
+        Self {
+            negated,
+            items: vec![ClassItem::Named(named)],
+        }
+    }
+
+    fn matches(&self, ch: char) -> bool {
+        let listed = self.items.iter().any(|item| match *item {
+            ClassItem::Range(first, last) => (first..=last).contains(&ch),
+            ClassItem::Named(named) => named.matches(ch),
+        });
+        if self.negated {
+            !listed && ch != '\n'
+        } else {
+            listed
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Assertion {
+    LineStart,
+    LineEnd,
+    WordStart,
+    WordEnd,
+}
+
+impl Assertion {
+    fn holds<H: Haystack + ?Sized>(self, text: &H, offset: usize) -> bool {
+        let keyword = |ch: Option<char>| ch.is_some_and(is_keyword);
+        match self {
+            Assertion::LineStart => text.char_before(offset).is_none_or(|ch| ch == '\n'),
+            Assertion::LineEnd => text.char_at(offset).is_none_or(|ch| ch == '\n'),
+            Assertion::WordStart => {
+                keyword(text.char_at(offset)) && !keyword(text.char_before(offset))
+            }
+            Assertion::WordEnd => {
+                keyword(text.char_before(offset)) && !keyword(text.char_at(offset))
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Node {
+    Char(char),
+    // `.`, anything but a newline
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    // `\zs` and `\ze` move where the match starts and ends
+    MatchStart,
+    MatchEnd,
+    // A group with the number it is captured as, if it is
+    Group(Option<usize>, Box<Node>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+impl Node {
+    fn can_be_empty(&self) -> bool {
+        match self {
+            Node::Char(_) | Node::Any | Node::Class(_) => false,
+            Node::Assert(_) | Node::MatchStart | Node::MatchEnd => true,
+            Node::Group(_, node) => node.can_be_empty(),
+            Node::Concat(nodes) => nodes.iter().all(Node::can_be_empty),
+            Node::Alternate(nodes) => nodes.iter().any(Node::can_be_empty),
+            Node::Repeat { node, min, .. } => *min == 0 || node.can_be_empty(),
+        }
+    }
+
+    // The chars a match of this node can begin with, if that is a short list
+    fn first_chars(&self) -> Option<Vec<char>> {
+        match self {
+            Node::Char(ch) => Some(vec![*ch]),
+            Node::Group(_, node) => node.first_chars(),
+            Node::Repeat { node, min, .. } if *min > 0 => node.first_chars(),
+            Node::Alternate(nodes) => nodes.iter().try_fold(Vec::new(), |mut chars, node| {
+                chars.extend(node.first_chars()?);
+                Some(chars)
+            }),
+            Node::Concat(nodes) => {
+                let first = nodes.iter().find(|node| !node.is_zero_width())?;
+                if first.can_be_empty() {
+                    None
+                } else {
+                    first.first_chars()
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn is_zero_width(&self) -> bool {
+        matches!(self, Node::Assert(_) | Node::MatchStart | Node::MatchEnd)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum Token {
+    Literal(char),
+    Any,
+    Named(Named, bool),
+    Bracket,
+    Star,
+    Plus,
+    Optional,
+    Brace,
+    Open,
+    OpenNonCapturing,
+    Close,
+    Alternate,
+    Caret,
+    Dollar,
+    WordStart,
+    WordEnd,
+    MatchStart,
+    MatchEnd,
+}
+
+impl Token {
+    fn is_quantifier(self) -> bool {
+        matches!(
+            self,
+            Token::Star | Token::Plus | Token::Optional | Token::Brace
+        )
+    }
+
+    // Whether the token ends a branch, which is where `$` means the end of a line
+    fn ends_branch(token: Option<Token>) -> bool {
+        matches!(token, None | Some(Token::Alternate | Token::Close))
+    }
+}
+
+// A count in `\{n,m}` bigger than this is most likely a mistake and would make a huge program
+const MAX_COUNT: usize = 1000;
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    magic: Magic,
+    ignore_case: bool,
+    match_case: bool,
+    groups: usize,
+}
+
+impl Parser {
+    fn new(pattern: &str) -> Self {
+        Self {
+            chars: pattern.chars().collect(),
+            position: 0,
+            magic: Magic::On,
+            ignore_case: false,
+            match_case: false,
+            groups: 0,
+        }
+    }
+
+    // Whether `ch` is special, given whether it came after a backslash
+    fn is_special(&self, ch: char, escaped: bool) -> bool {
+        let grouping = "()|+?={<>%".contains(ch);
+        let magic = ".*[".contains(ch);
+        let anchor = "^$".contains(ch);
+        match self.magic {
+            Magic::Very => !escaped && (grouping || magic || anchor),
+            Magic::On => {
+                if escaped {
+                    grouping
+                } else {
+                    magic || anchor
+                }
+            }
+            Magic::Off => {
+                if escaped {
+                    grouping || magic
+                } else {
+                    anchor
+                }
+            }
+            Magic::VeryOff => escaped && (grouping || magic || anchor),
+        }
+    }
+
+    // The next token and where the one after it starts. Flags like `\c` and `\v` take
+    // effect as they are passed, wherever they are in the pattern
+    fn peek(&mut self) -> Result<Option<(Token, usize)>, RegexError> {
+        loop {
+            let Some(&ch) = self.chars.get(self.position) else {
+                return Ok(None);
+            };
+            let next = self.position + 1;
+            if ch != '\\' {
+                return Ok(Some(self.special(ch, false, next)));
+            }
+            let Some(&escaped) = self.chars.get(next) else {
+                return Ok(Some((Token::Literal('\\'), next)));
+            };
+            let after = next + 1;
+            let named = |named, negated| Ok(Some((Token::Named(named, negated), after)));
+            match escaped {
+                'c' => self.ignore_case = true,
+                'C' => self.match_case = true,
+                'v' => self.magic = Magic::Very,
+                'm' => self.magic = Magic::On,
+                'M' => self.magic = Magic::Off,
+                'V' => self.magic = Magic::VeryOff,
+                'z' => {
+                    return match self.chars.get(after) {
+                        Some('s') => Ok(Some((Token::MatchStart, after + 1))),
+                        Some('e') => Ok(Some((Token::MatchEnd, after + 1))),
+                        _ => Err(RegexError::BadZ),
+                    }
+                }
+                'n' => return Ok(Some((Token::Literal('\n'), after))),
+                't' => return Ok(Some((Token::Literal('\t'), after))),
+                'e' => return Ok(Some((Token::Literal('\x1b'), after))),
+                'r' => return Ok(Some((Token::Literal('\r'), after))),
+                's' | 'S' => return named(Named::Space, escaped == 'S'),
+                'd' | 'D' => return named(Named::Digit, escaped == 'D'),
+                'x' | 'X' => return named(Named::Hex, escaped == 'X'),
+                'o' | 'O' => return named(Named::Octal, escaped == 'O'),
+                'w' | 'W' => return named(Named::Word, escaped == 'W'),
+                'h' | 'H' => return named(Named::Head, escaped == 'H'),
+                'a' | 'A' => return named(Named::Alpha, escaped == 'A'),
+                'l' | 'L' => return named(Named::Lower, escaped == 'L'),
+                'u' | 'U' => return named(Named::Upper, escaped == 'U'),
+                '1'..='9' => return Err(RegexError::BackReference),
+                _ => return Ok(Some(self.special(escaped, true, after))),
+            }
+            self.position = after;
+        }
+    }
+
+    // What `ch` means, with `next` being where the following token starts
+    fn special(&self, ch: char, escaped: bool, next: usize) -> (Token, usize) {
+        if !self.is_special(ch, escaped) {
+            return (Token::Literal(ch), next);
+        }
+        let token = match ch {
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '|' => Token::Alternate,
+            '+' => Token::Plus,
+            '?' | '=' => Token::Optional,
+            '{' => Token::Brace,
+            '<' => Token::WordStart,
+            '>' => Token::WordEnd,
+            '.' => Token::Any,
+            '*' => Token::Star,
+            '[' => Token::Bracket,
+            '^' => Token::Caret,
+            '$' => Token::Dollar,
+            '%' if self.chars.get(next) == Some(&'(') => {
+                return (Token::OpenNonCapturing, next + 1);
+            }
+            _ => Token::Literal(ch),
+        };
+        (token, next)
+    }
+
+    fn advance(&mut self) -> Result<Option<Token>, RegexError> {
+        let token = self.peek()?;
+        if let Some((_, next)) = token {
+            self.position = next;
+        }
+        Ok(token.map(|(token, _)| token))
+    }
+
+    fn parse_alternation(&mut self) -> Result<Node, RegexError> {
+        let mut branches = vec![self.parse_branch()?];
+        while let Some((Token::Alternate, next)) = self.peek()? {
+            self.position = next;
+            branches.push(self.parse_branch()?);
+        }
+        if branches.len() == 1 {
+            Ok(branches.remove(0))
+        } else {
+            Ok(Node::Alternate(branches))
+        }
+    }
+
+    fn parse_branch(&mut self) -> Result<Node, RegexError> {
+        let mut nodes = Vec::new();
+        while let Some((token, next)) = self.peek()? {
+            if Token::ends_branch(Some(token)) {
+                break;
+            }
+            self.position = next;
+            let node = match token {
+                // `^` only means the start of a line at the start of a branch
+                Token::Caret if nodes.is_empty() => Node::Assert(Assertion::LineStart),
+                Token::Caret => Node::Char('^'),
+                // and `$` the end of one at the end
+                Token::Dollar => {
+                    if Token::ends_branch(self.peek()?.map(|(token, _)| token)) {
+                        Node::Assert(Assertion::LineEnd)
+                    } else {
+                        Node::Char('$')
+                    }
+                }
+                // A `*` with nothing before it is just a star
+                Token::Star if nodes.is_empty() => Node::Char('*'),
+                Token::Plus if nodes.is_empty() => return Err(RegexError::FollowsNothing('+')),
+                Token::Optional if nodes.is_empty() => return Err(RegexError::FollowsNothing('=')),
+                Token::Brace if nodes.is_empty() => return Err(RegexError::FollowsNothing('{')),
+                token if token.is_quantifier() => {
+                    let Some(last) = nodes.pop() else {
+                        break;
+                    };
+                    self.parse_quantifier(token, last)?
+                }
+                token => self.parse_atom(token)?,
+            };
+            nodes.push(node);
+        }
+        if nodes.len() == 1 {
+            Ok(nodes.remove(0))
+        } else {
+            Ok(Node::Concat(nodes))
+        }
+    }
+
+    fn parse_atom(&mut self, token: Token) -> Result<Node, RegexError> {
+        Ok(match token {
+            Token::Literal(ch) => Node::Char(ch),
+            Token::Any => Node::Any,
+            Token::Named(named, negated) => Node::Class(Class::named(named, negated)),
+            Token::Bracket => self.parse_bracket(),
+            Token::WordStart => Node::Assert(Assertion::WordStart),
+            Token::WordEnd => Node::Assert(Assertion::WordEnd),
+            Token::MatchStart => Node::MatchStart,
+            Token::MatchEnd => Node::MatchEnd,
+            Token::Open | Token::OpenNonCapturing => {
+                let number = if token == Token::Open {
+                    self.groups += 1;
+                    Some(self.groups)
+                } else {
+                    None
+                };
+                let node = self.parse_alternation()?;
+                if self.advance()? != Some(Token::Close) {
+                    return Err(RegexError::UnmatchedOpen);
+                }
+                Node::Group(number, Box::new(node))
+            }
+            _ => return Err(RegexError::UnmatchedClose),
+        })
+    }
+
+    fn parse_quantifier(&mut self, token: Token, node: Node) -> Result<Node, RegexError> {
+        let (min, max, greedy) = match token {
+            Token::Star => (0, None, true),
+            Token::Plus => (1, None, true),
+            Token::Optional => (0, Some(1), true),
+            _ => self.parse_count()?,
+        };
+        Ok(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    // The inside of `\{n,m}`, where a leading `-` matches as few as possible
+    fn parse_count(&mut self) -> Result<(usize, Option<usize>, bool), RegexError> {
+        let mut inside = String::new();
+        loop {
+            match self.chars.get(self.position) {
+                Some('}') => break,
+                Some('\\') if self.chars.get(self.position + 1) == Some(&'}') => {
+                    self.position += 1;
+                    break;
+                }
+                Some(&ch) => inside.push(ch),
+                None => return Err(RegexError::BadCount),
+            }
+            self.position += 1;
+        }
+        self.position += 1;
+
+        let (lazy, inside) = match inside.strip_prefix('-') {
+            Some(inside) => (true, inside),
+            None => (false, inside.as_str()),
+        };
+        let number = |text: &str| -> Result<Option<usize>, RegexError> {
+            if text.is_empty() {
+                return Ok(None);
+            }
+            match text.parse::<usize>() {
+                Ok(number) if number <= MAX_COUNT => Ok(Some(number)),
+                _ => Err(RegexError::BadCount),
+            }
+        };
+        let (min, max) = match inside.split_once(',') {
+            Some((min, max)) => (number(min)?.unwrap_or(0), number(max)?),
+            None => match number(inside)? {
+                Some(count) => (count, Some(count)),
+                None => (0, None),
+            },
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(RegexError::BadCount);
+        }
+        Ok((min, max, !lazy))
+    }
+
+    // `[...]`, which is just a `[` if it never closes
+    fn parse_bracket(&mut self) -> Node {
+        let start = self.position;
+        let mut class = Class {
+            negated: false,
+            items: Vec::new(),
+        };
+        if self.chars.get(self.position) == Some(&'^') {
+            class.negated = true;
+            self.position += 1;
+        }
+        let first = self.position;
+        loop {
+            let Some(&ch) = self.chars.get(self.position) else {
+                self.position = start;
+                return Node::Char('[');
+            };
+            self.position += 1;
+            let ch = match ch {
+                ']' if self.position - 1 > first => return Node::Class(class),
+                '[' if self.chars.get(self.position) == Some(&':') => {
+                    let rest: String = self.chars[self.position + 1..].iter().collect();
+                    let named = rest
+                        .split_once(":]")
+                        .and_then(|(name, _)| Some((name.len(), Named::from_bracket_name(name)?)));
+                    if let Some((length, named)) = named {
+                        self.position += length + 3;
+                        class.items.push(ClassItem::Named(named));
+                        continue;
+                    }
+                    '['
+                }
+                '\\' => self.bracket_escape(),
+                ch => ch,
+            };
+            // `a-z`, but a `-` at the end is just a dash
+            let last = match (
+                self.chars.get(self.position),
+                self.chars.get(self.position + 1),
+            ) {
+                (Some('-'), Some(&last)) if last != ']' => {
+                    self.position += 2;
+                    if last == '\\' {
+                        self.bracket_escape()
+                    } else {
+                        last
+                    }
+                }
+                _ => ch,
+            };
+            class.items.push(ClassItem::Range(ch, last));
+        }
+    }
+
+    // A backslash inside brackets, only special before a few chars
+    fn bracket_escape(&mut self) -> char {
+        let escaped = match self.chars.get(self.position) {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('e') => '\x1b',
+            Some('r') => '\r',
+            Some(&ch @ ('\\' | ']' | '^' | '-')) => ch,
+            _ => return '\\',
+        };
+        self.position += 1;
+        escaped
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Instruction {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    // Remembers the offset in a capture slot
+    Save(usize),
+    // Tries the first instruction, then the second if that fails
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+fn compile(node: &Node, program: &mut Vec<Instruction>) {
+    match node {
+        Node::Char(ch) => program.push(Instruction::Char(*ch)),
+        Node::Any => program.push(Instruction::Any),
+        Node::Class(class) => program.push(Instruction::Class(class.clone())),
+        Node::Assert(assertion) => program.push(Instruction::Assert(*assertion)),
+        Node::MatchStart => program.push(Instruction::Save(0)),
+        Node::MatchEnd => program.push(Instruction::Save(1)),
+        Node::Group(number, node) => {
+            if let Some(number) = number {
+                program.push(Instruction::Save(number * 2));
+            }
+            compile(node, program);
+            if let Some(number) = number {
+                program.push(Instruction::Save(number * 2 + 1));
+            }
+        }
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program);
+            }
+        }
+        Node::Alternate(nodes) => {
+            let mut jumps = Vec::new();
+            for (i, node) in nodes.iter().enumerate() {
+                let split = program.len();
+                if i + 1 < nodes.len() {
+                    program.push(Instruction::Split(split + 1, 0));
+                }
+                compile(node, program);
+                if i + 1 < nodes.len() {
+                    jumps.push(program.len());
+                    program.push(Instruction::Jump(0));
+                    program[split] = Instruction::Split(split + 1, program.len());
+                }
+            }
+            for jump in jumps {
+                program[jump] = Instruction::Jump(program.len());
+            }
+        }
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            // Each optional copy is skipped by a split that points past the end, filled in after
+            let split = |program: &mut Vec<Instruction>, at: usize, body: usize, exit: usize| {
+                program[at] = if *greedy {
+                    Instruction::Split(body, exit)
+                } else {
+                    Instruction::Split(exit, body)
+                };
+            };
+            match max {
+                None => {
+                    let start = program.len();
+                    program.push(Instruction::Jump(0));
+                    compile(node, program);
+                    program.push(Instruction::Jump(start));
+                    let exit = program.len();
+                    split(program, start, start + 1, exit);
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Instruction::Jump(0));
+                        compile(node, program);
+                    }
+                    let exit = program.len();
+                    for at in splits {
+                        split(program, at, at + 1, exit);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Where a match and its groups are, as byte offsets
+#[derive(PartialEq, Clone, Debug)]
+pub struct Captures {
+    slots: Vec<Option<usize>>,
+}
+
+impl Captures {
+    pub fn start(&self) -> usize {
+        self.slots[0].unwrap_or(0)
+    }
+
+    pub fn end(&self) -> usize {
+        self.slots[1].unwrap_or(self.start()).max(self.start())
+    }
+
+    // The whole match for 0, otherwise the group with that number if it took part in the match
+    pub fn group(&self, number: usize) -> Option<(usize, usize)> {
+        if number == 0 {
+            return Some((self.start(), self.end()));
+        }
+        let start = (*self.slots.get(number * 2)?)?;
+        let end = (*self.slots.get(number * 2 + 1)?)?;
+        Some((start, end))
+    }
+}
+
+// How many failed states are remembered before starting over, so a long search can't eat memory
+const MAX_VISITED: usize = 1 << 20;
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Regex {
+    program: Vec<Instruction>,
+    slots: usize,
+    ignore_case: bool,
+    // Chars a match can start with, to skip the rest quickly
+    first_chars: Option<Vec<char>>,
+    // Whether the pattern starts with `^`
+    line_start: bool,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self, RegexError> {
+        let mut parser = Parser::new(pattern);
+        let node = parser.parse_alternation()?;
+        if parser.position < parser.chars.len() {
+            return Err(RegexError::UnmatchedClose);
+        }
+        // `\c` wins over `\C`
+        let ignore_case = parser.ignore_case;
+
+        let mut program = vec![Instruction::Save(0)];
+        compile(&node, &mut program);
+        program.push(Instruction::Match);
+
+        let first_chars = node.first_chars().map(|chars| {
+            if ignore_case {
+                chars
+                    .into_iter()
+                    .flat_map(|ch| ch.to_lowercase().chain(ch.to_uppercase()))
+                    .collect()
+            } else {
+                chars
+            }
+        });
+        let line_start = match &node {
+            Node::Assert(Assertion::LineStart) => true,
+            Node::Concat(nodes) => nodes.first() == Some(&Node::Assert(Assertion::LineStart)),
+            _ => false,
+        };
+        Ok(Self {
+            program,
+            slots: (parser.groups + 1) * 2,
+            ignore_case,
+            first_chars,
+            line_start,
+        })
+    }
+
+    // Whether a match could start at `ch`, which comes after `previous`
+    fn could_start(&self, previous: Option<char>, ch: Option<char>) -> bool {
+        (!self.line_start || previous.is_none_or(|previous| previous == '\n'))
+            && self
+                .first_chars
+                .as_ref()
+                .is_none_or(|chars| ch.is_some_and(|ch| chars.contains(&ch)))
+    }
+
+    fn same_char(&self, ch: char, expected: char) -> bool {
+        ch == expected || (self.ignore_case && fold_case(ch) == fold_case(expected))
+    }
+
+    fn class_matches(&self, class: &Class, ch: char) -> bool {
+        class.matches(ch)
+            || (self.ignore_case
+                && (ch.to_lowercase().any(|ch| class.matches(ch))
+                    || ch.to_uppercase().any(|ch| class.matches(ch))))
+    }
+
+    // The match that starts trying at byte offset `start`, if there is one
+    pub fn match_at<H: Haystack + ?Sized>(&self, text: &H, start: usize) -> Option<Captures> {
+        self.run(text, start, &mut HashSet::new())
+    }
+
+    fn run<H: Haystack + ?Sized>(
+        &self,
+        text: &H,
+        start: usize,
+        failed: &mut HashSet<(usize, usize)>,
+    ) -> Option<Captures> {
+        enum Job {
+            Try(usize, usize),
+            Restore(usize, Option<usize>),
+        }
+        let mut slots = vec![None; self.slots];
+        let mut jobs = vec![Job::Try(0, start)];
+        while let Some(job) = jobs.pop() {
+            let (mut pc, mut offset) = match job {
+                Job::Try(pc, offset) => (pc, offset),
+                Job::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                }
+            };
+            // Nothing tried here can succeed once it has failed, whatever the captures are
+            while failed.insert((pc, offset)) {
+                match &self.program[pc] {
+                    Instruction::Char(expected) => match text.char_at(offset) {
+                        Some(ch) if self.same_char(ch, *expected) => offset += ch.len_utf8(),
+                        _ => break,
+                    },
+                    Instruction::Any => match text.char_at(offset) {
+                        Some(ch) if ch != '\n' => offset += ch.len_utf8(),
+                        _ => break,
+                    },
+                    Instruction::Class(class) => match text.char_at(offset) {
+                        Some(ch) if self.class_matches(class, ch) => offset += ch.len_utf8(),
+                        _ => break,
+                    },
+                    Instruction::Assert(assertion) => {
+                        if !assertion.holds(text, offset) {
+                            break;
+                        }
+                    }
+                    Instruction::Save(slot) => {
+                        jobs.push(Job::Restore(*slot, slots[*slot]));
+                        slots[*slot] = Some(offset);
+                    }
+                    Instruction::Split(first, second) => {
+                        jobs.push(Job::Try(*second, offset));
+                        pc = *first;
+                        continue;
+                    }
+                    Instruction::Jump(target) => {
+                        pc = *target;
+                        continue;
+                    }
+                    Instruction::Match => {
+                        if slots[1].is_none() {
+                            slots[1] = Some(offset);
+                        }
+                        return Some(Captures { slots });
+                    }
+                }
+                pc += 1;
+            }
+        }
+        None
+    }
+
+    // Tries each byte offset `offsets` gives along with the char before it, stopping at the first match
+    fn first_match<H: Haystack + ?Sized>(
+        &self,
+        text: &H,
+        offsets: impl Iterator<Item = (usize, Option<char>, Option<char>)>,
+        accept: impl Fn(&Captures) -> bool,
+    ) -> Option<Captures> {
+        let mut failed = HashSet::new();
+        for (offset, previous, ch) in offsets {
+            if !self.could_start(previous, ch) {
+                continue;
+            }
+            if failed.len() > MAX_VISITED {
+                failed.clear();
+            }
+            if let Some(captures) = self.run(text, offset, &mut failed) {
+                if accept(&captures) {
+                    return Some(captures);
+                }
+                failed.clear();
+            }
+        }
+        None
+    }
+
+    // The first match tried from a byte offset between `from` and `until`, inclusive
+    pub fn find_forward(
+        &self,
+        piece_table: &PieceTable,
+        from: usize,
+        until: usize,
+    ) -> Option<Captures> {
+        let length = piece_table.len();
+        let mut previous = piece_table.char_before(from);
+        let offsets = piece_table
+            .chars_at(from)
+            .map(|(offset, ch)| (offset, Some(ch)))
+            // A pattern like `$` can match at the very end
+            .chain(std::iter::once((length, None)))
+            .take_while(move |&(offset, _)| offset <= until && from <= length)
+            .map(move |(offset, ch)| {
+                let before = previous;
+                previous = ch;
+                (offset, before, ch)
+            });
+        self.first_match(piece_table, offsets, |_| true)
+    }
+
+    // The last match starting before byte offset `before`, tried from an offset no less than `limit`
+    pub fn find_backward(
+        &self,
+        piece_table: &PieceTable,
+        before: usize,
+        limit: usize,
+    ) -> Option<Captures> {
+        let length = piece_table.len();
+        // A pattern like `$` can match at the very end
+        let end = (before > length).then(|| (length, piece_table.char_before(length), None));
+        let mut chars = piece_table.chars_before(before.min(length)).peekable();
+        let offsets = end
+            .into_iter()
+            .chain(std::iter::from_fn(move || {
+                let (offset, ch) = chars.next()?;
+                let previous = chars.peek().map(|&(_, previous)| previous);
+                Some((offset, previous, Some(ch)))
+            }))
+            .take_while(move |&(offset, _, _)| offset >= limit);
+        self.first_match(piece_table, offsets, |captures| captures.start() < before)
+    }
+
+    // The first match in `line` tried from byte offset `from` on
+    pub fn find_in(&self, line: &str, from: usize) -> Option<Captures> {
+        let offsets = line
+            .get(from..)?
+            .char_indices()
+            .map(|(i, ch)| (from + i, Some(ch)))
+            .chain(std::iter::once((line.len(), None)))
+            .map(|(offset, ch)| (offset, line.char_before(offset), ch));
+        self.first_match(line, offsets, |_| true)
+    }
+}
+
+fn fold_case(ch: char) -> char {
+    ch.to_lowercase().next().unwrap_or(ch)
+}
+
+pub fn is_keyword(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where each match in `text` is, as the matched text
+    fn matches(pattern: &str, text: &str) -> Vec<String> {
+        let regex = Regex::new(pattern).unwrap();
+        let mut found = Vec::new();
+        let mut from = 0;
+        while let Some(captures) = regex.find_in(text, from) {
+            found.push(text[captures.start()..captures.end()].to_string());
+            from = captures.end().max(captures.start() + 1);
+            if from > text.len() {
+                break;
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn test_regex() {
+        assert_eq!(matches("a.c", "abc a-c ac"), vec!["abc", "a-c"]);
+        assert_eq!(matches("ab*", "a abbb"), vec!["a", "abbb"]);
+        assert_eq!(matches("x\\+", "xx y x"), vec!["xx", "x"]);
+        assert_eq!(
+            matches("colou\\=r", "color colour"),
+            vec!["color", "colour"]
+        );
+        assert_eq!(matches("\\d\\{2,3}", "1 22 4444"), vec!["22", "444"]);
+        assert_eq!(matches("a\\{-1,}", "aaa"), vec!["a", "a", "a"]);
+        assert_eq!(matches("[a-c]\\+", "xabcx cab"), vec!["abc", "cab"]);
+        assert_eq!(matches("[^ ]\\+", "one two"), vec!["one", "two"]);
+        assert_eq!(matches("[[:digit:]x]", "a1x"), vec!["1", "x"]);
+        assert_eq!(matches("cat\\|dog", "dog cat"), vec!["dog", "cat"]);
+        assert_eq!(matches("\\<the\\>", "then the other"), vec!["the"]);
+        assert_eq!(matches("^a", "aa"), vec!["a"]);
+        assert_eq!(matches("a$", "aa"), vec!["a"]);
+        assert_eq!(matches("a$b", "a$b"), vec!["a$b"]);
+        assert_eq!(matches("*", "a*"), vec!["*"]);
+        assert_eq!(matches("foo\\zsbar", "foobar bar"), vec!["bar"]);
+        assert_eq!(matches("foo\\zebar", "foobar foo"), vec!["foo"]);
+        assert_eq!(matches("\\cabc", "ABC abc"), vec!["ABC", "abc"]);
+        assert_eq!(matches("ABC\\C", "ABC abc"), vec!["ABC"]);
+        assert_eq!(matches("\\v(ab)+|<x>", "ababa x"), vec!["abab", "x"]);
+        assert_eq!(matches("\\Va.c", "abc a.c"), vec!["a.c"]);
+        assert_eq!(matches("\\(a*\\)*b", "aab"), vec!["aab"]);
+
+        let regex = Regex::new("\\(\\w\\+\\)=\\(\\d*\\)").unwrap();
+        let captures = regex.find_in("set x=12", 0).unwrap();
+        assert_eq!(captures.group(0), Some((4, 8)));
+        assert_eq!(captures.group(1), Some((4, 5)));
+        assert_eq!(captures.group(2), Some((6, 8)));
+        assert_eq!(captures.group(3), None);
+
+        assert_eq!(Regex::new("\\(a"), Err(RegexError::UnmatchedOpen));
+        assert_eq!(Regex::new("a\\)"), Err(RegexError::UnmatchedClose));
+        assert_eq!(Regex::new("\\+"), Err(RegexError::FollowsNothing('+')));
+        assert_eq!(Regex::new("a\\{2,1}"), Err(RegexError::BadCount));
+        assert!(Regex::new("[abc").is_ok());
+    }
+
+    #[test]
+    fn test_regex_in_piece_table() {
+        let mut piece_table = PieceTable::new("one two\nthree four\n");
+        piece_table.insert(5, "w");
+        piece_table.delete(5);
+        piece_table.insert(15, "x");
+        piece_table.delete(15);
+
+        let regex = Regex::new("o\\nth").unwrap();
+        let captures = regex
+            .find_forward(&piece_table, 0, piece_table.len())
+            .unwrap();
+        assert_eq!((captures.start(), captures.end()), (6, 10));
+
+        let regex = Regex::new("^\\a").unwrap();
+        assert_eq!(regex.find_forward(&piece_table, 1, 19).unwrap().start(), 8);
+        assert_eq!(regex.find_backward(&piece_table, 8, 0).unwrap().start(), 0);
+        assert_eq!(regex.find_backward(&piece_table, 20, 1).unwrap().start(), 8);
+        assert_eq!(regex.find_forward(&piece_table, 9, 19), None);
+
+        let regex = Regex::new("$").unwrap();
+        assert_eq!(regex.find_forward(&piece_table, 8, 19).unwrap().start(), 18);
+    }
+}
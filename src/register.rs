@@ -0,0 +1,341 @@
+use crate::operator::{first_non_blank, pad_line, RangeKind};
+use crate::piece_table::PieceTable;
+use crate::utils::{display_column, grapheme_at_column, grapheme_count};
+use std::collections::BTreeMap;
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Register {
+    pub text: String,
+    pub kind: RangeKind,
+}
+
+impl Register {
+    pub fn new(text: &str, kind: RangeKind) -> Self {
+        Self {
+            text: text.to_string(),
+            kind,
+        }
+    }
+
+    // `"Ayy` adds a line to a register holding a word, which turns it into lines
+    fn append(&mut self, other: Register) {
+        if self.kind == RangeKind::Linewise || other.kind == RangeKind::Linewise {
+            if self.kind != RangeKind::Linewise && !self.text.is_empty() {
+                self.text.push('\n');
+            }
+            self.text.push_str(&other.text);
+            if !self.text.ends_with('\n') {
+                self.text.push('\n');
+            }
+            self.kind = RangeKind::Linewise;
+        } else {
+            if self.kind == RangeKind::Blockwise {
+                self.text.push('\n');
+            }
+            self.text.push_str(&other.text);
+        }
+    }
+}
+
+// Names that can follow `"`
+pub fn is_register_name(name: char) -> bool {
+    name.is_ascii_alphanumeric() || matches!(name, '"' | '-' | '_' | '.' | '%' | ':')
+}
+
+// Text that was yanked or deleted, keyed by register name.
+// `""` holds whatever was stored last, `"0` the last yank, `"1`-`"9` the last
+// deletes of a line or more, `"-` the last smaller delete and `"a`-`"z` whatever they were asked to
+#[derive(Default)]
+pub struct Registers {
+    stored: BTreeMap<char, Register>,
+    // Text typed the last time insert mode was left, for `".`
+    pub last_inserted: String,
+    // The last command line run, for `":`
+    pub last_command: String,
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Stores yanked text in `name`, or in `"0` if no register was given
+    pub fn yank(&mut self, name: Option<char>, register: Register) {
+        match name {
+            None | Some('"') => {
+                self.stored.insert('0', register.clone());
+                self.stored.insert('"', register);
+            }
+            Some(name) => self.store_and_point(name, register),
+        }
+    }
+
+    // Stores the keys of a recorded macro, leaving the unnamed register alone
+    pub fn record(&mut self, name: char, text: &str) {
+        self.store(name, Register::new(text, RangeKind::Charwise));
+    }
+
+    // Stores deleted text in `name`, shifting the numbered registers along if it was a line or more
+    pub fn delete(&mut self, name: Option<char>, register: Register) {
+        if name == Some('_') {
+            return;
+        }
+        let multiline = register.kind == RangeKind::Linewise || register.text.contains('\n');
+        if multiline {
+            let digit = |number: u32| char::from_digit(number, 10).unwrap_or('1');
+            for number in (1..9).rev() {
+                if let Some(shifted) = self.stored.remove(&digit(number)) {
+                    self.stored.insert(digit(number + 1), shifted);
+                }
+            }
+        }
+        match name {
+            None | Some('"') => {
+                let key = if multiline { '1' } else { '-' };
+                self.stored.insert(key, register.clone());
+                self.stored.insert('"', register);
+            }
+            Some(name) => {
+                if multiline {
+                    self.stored.insert('1', register.clone());
+                }
+                self.store_and_point(name, register);
+            }
+        }
+    }
+
+    // Writes to a register the user named, appending for an uppercase name.
+    // Returns what the register holds afterwards
+    fn store(&mut self, name: char, register: Register) -> Option<Register> {
+        if matches!(name, '_' | '.' | '%' | ':') {
+            return None;
+        }
+        match self.stored.get_mut(&name.to_ascii_lowercase()) {
+            Some(existing) if name.is_ascii_uppercase() => {
+                existing.append(register);
+                Some(existing.clone())
+            }
+            _ => {
+                self.stored
+                    .insert(name.to_ascii_lowercase(), register.clone());
+                Some(register)
+            }
+        }
+    }
+
+    // Writes to a register the user named and makes the unnamed register hold the same
+    fn store_and_point(&mut self, name: char, register: Register) {
+        if let Some(register) = self.store(name, register) {
+            self.stored.insert('"', register);
+        }
+    }
+
+    // The contents of `name`, or of `""` if no register was given
+    pub fn get(&self, name: Option<char>, file_name: &str) -> Option<Register> {
+        let read_only = |text: &str| {
+            if text.is_empty() {
+                None
+            } else {
+                Some(Register::new(text, RangeKind::Charwise))
+            }
+        };
+        match name.unwrap_or('"') {
+            '_' => None,
+            '.' => read_only(&self.last_inserted),
+            ':' => read_only(&self.last_command),
+            '%' => read_only(file_name),
+            name => self.stored.get(&name.to_ascii_lowercase()).cloned(),
+        }
+    }
+
+    // `:registers`: a line for each register holding text, or only for those in `names` if given
+    pub fn list(&self, names: &str, file_name: &str) -> Vec<String> {
+        let mut lines = vec!["Type Name Content".to_string()];
+        let order = "\"0123456789-abcdefghijklmnopqrstuvwxyz.:%";
+        for name in order.chars() {
+            if !names.is_empty() && !names.contains(name) {
+                continue;
+            }
+            let Some(register) = self.get(Some(name), file_name) else {
+                continue;
+            };
+            let kind = match register.kind {
+                RangeKind::Charwise => 'c',
+                RangeKind::Linewise => 'l',
+                RangeKind::Blockwise => 'b',
+            };
+            let content: String = register
+                .text
+                .chars()
+                .flat_map(|ch| match ch {
+                    '\n' => vec!['^', 'J'],
+                    '\t' => vec!['^', 'I'],
+                    _ => vec![ch],
+                })
+                .take(Self::LISTED_CHARS)
+                .collect();
+            lines.push(format!("  {}  \"{}   {}", kind, name, content));
+        }
+        lines
+    }
+
+    const LISTED_CHARS: usize = 60;
+}
+
+// Puts `register` after the cursor `count` times, or before it for `P`.
+// Returns where the cursor goes
+pub fn put(
+    register: &Register,
+    piece_table: &mut PieceTable,
+    (x, y): (usize, usize),
+    before: bool,
+    count: usize,
+) -> (usize, usize) {
+    match register.kind {
+        RangeKind::Charwise => {
+            let line_length = piece_table.line_grapheme_len(y);
+            let x = if before || line_length == 0 {
+                x
+            } else {
+                (x + 1).min(line_length)
+            };
+            let Ok(position) = piece_table.find_index(x, y) else {
+                return (x, y);
+            };
+            let text = register.text.repeat(count);
+            piece_table.insert(position, &text);
+            // The cursor ends up on the last char put, unless that took more than one line
+            match piece_table.chars_before(position + text.len()).next() {
+                Some((last, _)) if !text.contains('\n') => piece_table.coordinates(last),
+                _ => (x, y),
+            }
+        }
+        RangeKind::Linewise => {
+            let text = register.text.repeat(count);
+            let target = if before { y } else { y + 1 };
+            match piece_table.line_start(target) {
+                Some(start) => piece_table.insert(start, &text),
+                None => {
+                    let end = piece_table.len();
+                    // A file without a newline at the end keeps going without one
+                    if end == 0 || piece_table.index(end - 1) == Some('\n') {
+                        piece_table.insert(end, &text);
+                    } else {
+                        let text = format!("\n{}", text.strip_suffix('\n').unwrap_or(&text));
+                        piece_table.insert(end, &text);
+                    }
+                }
+            }
+            let target = target.min(piece_table.line_count().saturating_sub(1));
+            (first_non_blank(piece_table, target), target)
+        }
+        RangeKind::Blockwise => put_block(register, piece_table, (x, y), before, count),
+    }
+}
+
+// Puts each line of a block on the lines from the cursor down, adding lines at the end if needed
+fn put_block(
+    register: &Register,
+    piece_table: &mut PieceTable,
+    (x, y): (usize, usize),
+    before: bool,
+    count: usize,
+) -> (usize, usize) {
+    let line = piece_table.line(y).unwrap_or_default();
+    let column = if before || line.is_empty() {
+        display_column(&line, x)
+    } else {
+        display_column(&line, x + 1)
+    };
+    let width = register
+        .text
+        .split('\n')
+        .map(|piece| display_column(piece, usize::MAX))
+        .max()
+        .unwrap_or(0);
+
+    for (i, piece) in register.text.split('\n').enumerate() {
+        let line_y = y + i;
+        if line_y >= piece_table.line_count() {
+            let new_line = " ".repeat(column) + &piece.repeat(count);
+            let end = piece_table.len();
+            if end == 0 || piece_table.index(end - 1) == Some('\n') {
+                piece_table.insert(end, &(new_line + "\n"));
+            } else {
+                piece_table.insert(end, &format!("\n{}", new_line));
+            }
+            continue;
+        }
+        pad_line(piece_table, line_y, column);
+
+        let line = piece_table.line(line_y).unwrap_or_default();
+        let line_x = grapheme_at_column(&line, column);
+        // Text after the block stays lined up
+        let piece = if line_x < grapheme_count(&line) {
+            let padding = width - display_column(piece, usize::MAX);
+            format!("{}{}", piece, " ".repeat(padding))
+        } else {
+            piece.to_string()
+        };
+        if let Ok(position) = piece_table.find_index(line_x, line_y) {
+            piece_table.insert(position, &piece.repeat(count));
+        }
+    }
+
+    let line = piece_table.line(y).unwrap_or_default();
+    (grapheme_at_column(&line, column), y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers() {
+        let mut registers = Registers::new();
+        registers.yank(None, Register::new("word", RangeKind::Charwise));
+        registers.delete(None, Register::new("line\n", RangeKind::Linewise));
+        registers.delete(None, Register::new("x", RangeKind::Charwise));
+        assert_eq!(registers.get(Some('0'), "").unwrap().text, "word");
+        assert_eq!(registers.get(Some('1'), "").unwrap().text, "line\n");
+        assert_eq!(registers.get(Some('-'), "").unwrap().text, "x");
+        assert_eq!(registers.get(None, "").unwrap().text, "x");
+
+        registers.delete(None, Register::new("other\n", RangeKind::Linewise));
+        assert_eq!(registers.get(Some('2'), "").unwrap().text, "line\n");
+
+        registers.yank(Some('a'), Register::new("one", RangeKind::Charwise));
+        registers.yank(Some('A'), Register::new("two\n", RangeKind::Linewise));
+        let appended = registers.get(Some('a'), "").unwrap();
+        assert_eq!(appended.text, "one\ntwo\n");
+        assert_eq!(appended.kind, RangeKind::Linewise);
+        assert_eq!(registers.get(None, "").unwrap(), appended);
+
+        registers.delete(Some('_'), Register::new("gone", RangeKind::Charwise));
+        assert_eq!(registers.get(None, "").unwrap(), appended);
+        assert_eq!(
+            registers.get(Some('%'), "notes.txt").unwrap().text,
+            "notes.txt"
+        );
+        assert_eq!(registers.get(Some('.'), ""), None);
+    }
+
+    #[test]
+    fn test_put() {
+        let mut piece_table = PieceTable::new("abc\ndef");
+        let word = Register::new("XY", RangeKind::Charwise);
+        assert_eq!(put(&word, &mut piece_table, (0, 0), false, 2), (4, 0));
+        assert_eq!(put(&word, &mut piece_table, (0, 1), true, 1), (1, 1));
+        assert_eq!(piece_table.to_string(), "aXYXYbc\nXYdef");
+
+        let lines = Register::new("  new\n", RangeKind::Linewise);
+        assert_eq!(put(&lines, &mut piece_table, (3, 1), false, 1), (2, 2));
+        assert_eq!(put(&lines, &mut piece_table, (0, 0), true, 1), (2, 0));
+        assert_eq!(piece_table.to_string(), "  new\naXYXYbc\nXYdef\n  new");
+
+        let mut piece_table = PieceTable::new("abcd\ne\n");
+        let block = Register::new("1\n23\n4", RangeKind::Blockwise);
+        assert_eq!(put(&block, &mut piece_table, (0, 0), false, 1), (1, 0));
+        assert_eq!(piece_table.to_string(), "a1 bcd\ne23\n 4\n");
+    }
+}
@@ -0,0 +1,205 @@
+use crate::piece_table::PieceTable;
+use crate::regex::{is_keyword, Captures, Regex, RegexError};
+use crate::utils::{byte_to_grapheme, grapheme_to_byte};
+
+// What `/`, `?`, `*` and `#` look for
+#[derive(PartialEq, Clone, Debug)]
+pub struct Pattern {
+    // The pattern as it was typed, for the status bar
+    source: String,
+    regex: Regex,
+}
+
+// Byte offsets of a match, and whether the search went past an end of the file to find it
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+    pub wrapped: bool,
+}
+
+impl Match {
+    fn new(captures: Captures, wrapped: bool) -> Self {
+        Self {
+            start: captures.start(),
+            end: captures.end(),
+            wrapped,
+        }
+    }
+}
+
+// The last search, for `n` and `N`
+#[derive(PartialEq, Clone, Debug)]
+pub struct LastSearch {
+    pub pattern: Pattern,
+    pub forward: bool,
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Self, RegexError> {
+        Ok(Self {
+            source: source.to_string(),
+            regex: Regex::new(source)?,
+        })
+    }
+
+    // Matches `word` only where it isn't part of a longer word
+    pub fn word(word: &str) -> Result<Self, RegexError> {
+        Self::new(&format!("\\<{}\\>", word))
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    // The next match after the byte offset `position`, or the one before it going backward
+    pub fn find_from(
+        &self,
+        piece_table: &PieceTable,
+        position: usize,
+        forward: bool,
+    ) -> Option<Match> {
+        if forward {
+            let from = piece_table
+                .chars_at(position)
+                .next()
+                .map_or(position + 1, |(i, ch)| i + ch.len_utf8());
+            self.find_forward(piece_table, from)
+        } else {
+            self.find_backward(piece_table, position)
+        }
+    }
+
+    // The first match starting at or after `from`, or else the first one in the file
+    pub fn find_forward(&self, piece_table: &PieceTable, from: usize) -> Option<Match> {
+        match self
+            .regex
+            .find_forward(piece_table, from, piece_table.len())
+        {
+            Some(captures) => Some(Match::new(captures, false)),
+            None => self
+                .regex
+                .find_forward(piece_table, 0, from)
+                .map(|captures| Match::new(captures, true)),
+        }
+    }
+
+    // The last match starting before `from`, or else the last one in the file
+    pub fn find_backward(&self, piece_table: &PieceTable, from: usize) -> Option<Match> {
+        match self.regex.find_backward(piece_table, from, 0) {
+            Some(captures) => Some(Match::new(captures, false)),
+            None => self
+                .regex
+                .find_backward(piece_table, piece_table.len() + 1, from)
+                .map(|captures| Match::new(captures, true)),
+        }
+    }
+
+    // Grapheme columns `start..end` of each match in `line`, for highlighting
+    pub fn line_matches(&self, line: &str) -> Vec<(usize, usize)> {
+        let mut matches = Vec::new();
+        let mut from = 0;
+        while let Some(captures) = self.regex.find_in(line, from) {
+            let (start, end) = (captures.start(), captures.end());
+            if end > start {
+                matches.push((byte_to_grapheme(line, start), byte_to_grapheme(line, end)));
+            }
+            // An empty match moves on a char so the next search doesn't find it again
+            from = if end > start {
+                end
+            } else {
+                line[start..]
+                    .chars()
+                    .next()
+                    .map_or(line.len() + 1, |ch| start + ch.len_utf8())
+            };
+            if from > line.len() {
+                break;
+            }
+        }
+        matches
+    }
+}
+
+// The word under or after the cursor on its line, for `*` and `#`, with the byte offset it starts at
+pub fn word_at(piece_table: &PieceTable, (x, y): (usize, usize)) -> Option<(usize, String)> {
+    let line = piece_table.line(y)?;
+    let cursor = grapheme_to_byte(&line, x);
+    let (first, _) = line[cursor..]
+        .char_indices()
+        .find(|&(_, ch)| is_keyword(ch))?;
+    let first = cursor + first;
+    let start = line[..first]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, ch)| is_keyword(ch))
+        .last()
+        .map_or(first, |(i, _)| i);
+    let end = line[first..]
+        .char_indices()
+        .find(|&(_, ch)| !is_keyword(ch))
+        .map_or(line.len(), |(i, _)| first + i);
+    let line_start = piece_table.line_start(y)?;
+    Some((line_start + start, line[start..end].to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let mut piece_table = PieceTable::new("abab cab\nabc");
+        // Splits the text over several pieces
+        piece_table.insert(2, "a");
+        piece_table.delete(2);
+        piece_table.insert(10, "x");
+        piece_table.delete(10);
+
+        let pattern = Pattern::new("ab").unwrap();
+        let found = pattern.find_forward(&piece_table, 1).unwrap();
+        assert_eq!((found.start, found.end, found.wrapped), (2, 4, false));
+        assert_eq!(pattern.find_forward(&piece_table, 10).unwrap().start, 0);
+        assert!(pattern.find_forward(&piece_table, 10).unwrap().wrapped);
+        assert_eq!(pattern.find_backward(&piece_table, 9).unwrap().start, 6);
+        assert_eq!(pattern.find_backward(&piece_table, 7).unwrap().start, 6);
+        let found = pattern.find_backward(&piece_table, 0).unwrap();
+        assert_eq!((found.start, found.wrapped), (9, true));
+        assert_eq!(
+            Pattern::new("ca b").unwrap().find_forward(&piece_table, 0),
+            None
+        );
+
+        let word = Pattern::word("ab").unwrap();
+        assert_eq!(word.find_forward(&piece_table, 0), None);
+        assert_eq!(
+            Pattern::word("abc")
+                .unwrap()
+                .find_backward(&piece_table, 3)
+                .unwrap()
+                .start,
+            9
+        );
+        assert_eq!(pattern.line_matches("xabab"), vec![(1, 3), (3, 5)]);
+        assert_eq!(word.line_matches("ab abc ab"), vec![(0, 2), (7, 9)]);
+    }
+
+    #[test]
+    fn test_word_at() {
+        let piece_table = PieceTable::new("one\n  foo_bar(baz)");
+        assert_eq!(
+            word_at(&piece_table, (0, 1)),
+            Some((6, "foo_bar".to_string()))
+        );
+        assert_eq!(
+            word_at(&piece_table, (5, 1)),
+            Some((6, "foo_bar".to_string()))
+        );
+        assert_eq!(word_at(&piece_table, (9, 1)), Some((14, "baz".to_string())));
+        assert_eq!(word_at(&piece_table, (13, 1)), None);
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+
+// What a shell command printed, with what it wrote to stderr after what it wrote to stdout
+pub struct ShellOutput {
+    pub text: String,
+    // The exit code, or None if a signal stopped it
+    pub code: Option<i32>,
+}
+
+impl ShellOutput {
+    // What `:w !cmd` shows: the output, and the exit code if the command failed
+    pub fn message(&self) -> String {
+        let mut lines: Vec<&str> = self.text.lines().collect();
+        let returned = self
+            .code
+            .filter(|&code| code != 0)
+            .map(|code| format!("shell returned {}", code));
+        lines.extend(returned.as_deref());
+        lines.join("\n")
+    }
+}
+
+// Runs `command` with `sh -c` for `:w !cmd` and `:r !cmd`, giving it `input` on stdin.
+// Nothing is read from the terminal, which the editor is using
+pub fn run(command: &str, input: Option<&str>) -> io::Result<ShellOutput> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Written from another thread so a command with a lot to say doesn't block on its output
+    // while the input is still going in
+    let writer = child.stdin.take().zip(input).map(|(mut stdin, input)| {
+        let input = input.to_string();
+        thread::spawn(move || {
+            // A command that stops reading early, like `head`, closes the pipe
+            let _ = stdin.write_all(input.as_bytes());
+        })
+    });
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(ShellOutput {
+        text,
+        code: output.status.code(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() -> io::Result<()> {
+        let output = run("tr a-z A-Z", Some("one\ntwo\n"))?;
+        assert_eq!(output.text, "ONE\nTWO\n");
+        assert_eq!(output.message(), "ONE\nTWO");
+
+        let output = run("echo out; echo err >&2; exit 3", None)?;
+        assert_eq!(output.text, "out\nerr\n");
+        assert_eq!(output.code, Some(3));
+        assert_eq!(output.message(), "out\nerr\nshell returned 3");
+        Ok(())
+    }
+}
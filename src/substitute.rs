@@ -0,0 +1,448 @@
+use crate::ex::{CommandError, LineRange};
+use crate::piece_table::PieceTable;
+use crate::regex::Captures;
+use crate::search::Pattern;
+
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct Flags {
+    // `g`: every match on a line rather than the first
+    pub global: bool,
+    // `c`: ask before each substitution
+    pub confirm: bool,
+    // `i` and `I`: ignore case or don't, whatever the pattern says
+    pub ignore_case: Option<bool>,
+    // `n`: only count the matches
+    pub count_only: bool,
+}
+
+// `:s/pattern/replacement/flags count`, with any punctuation in place of `/`.
+// `pattern` is empty to use the last search and `replacement` is `None` for a bare `:s`,
+// which repeats the last substitution
+#[derive(PartialEq, Clone, Debug)]
+pub struct SubstituteCommand {
+    pub pattern: String,
+    pub replacement: Option<String>,
+    pub flags: Flags,
+    pub count: Option<usize>,
+}
+
+pub fn parse_substitute(args: &str) -> Result<SubstituteCommand, CommandError> {
+    let delimiter = args
+        .chars()
+        .next()
+        .filter(|&ch| !ch.is_alphanumeric() && !matches!(ch, '\\' | '"' | '|' | ' '));
+    let (pattern, replacement, rest) = match delimiter {
+        Some(delimiter) => {
+            let (pattern, rest) = split_at_delimiter(&args[delimiter.len_utf8()..], delimiter);
+            let (replacement, rest) = match rest {
+                Some(rest) => split_at_delimiter(rest, delimiter),
+                None => (String::new(), None),
+            };
+            (pattern, Some(replacement), rest.unwrap_or_default())
+        }
+        None => (String::new(), None, args),
+    };
+
+    let mut flags = Flags::default();
+    let mut rest = rest.trim_start();
+    // `&` keeps the flags of the last substitution, which aren't remembered, so it does nothing
+    while let Some(flag) = rest.chars().next() {
+        match flag {
+            'g' => flags.global = true,
+            'c' => flags.confirm = true,
+            'i' => flags.ignore_case = Some(true),
+            'I' => flags.ignore_case = Some(false),
+            'n' => flags.count_only = true,
+            '&' => {}
+            _ => break,
+        }
+        rest = &rest[1..];
+    }
+    let rest = rest.trim();
+    let count = if rest.is_empty() {
+        None
+    } else {
+        match rest.parse::<usize>() {
+            Ok(count) if count > 0 => Some(count),
+            _ => return Err(CommandError::TrailingCharacters(rest.to_string())),
+        }
+    };
+    Ok(SubstituteCommand {
+        pattern,
+        replacement,
+        flags,
+        count,
+    })
+}
+
+// The text up to an unescaped `delimiter`, with `\` taken off escaped delimiters, and what
+// comes after the delimiter if there is one
+pub fn split_at_delimiter(text: &str, delimiter: char) -> (String, Option<&str>) {
+    let mut part = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        if ch == delimiter {
+            return (part, Some(&text[i + ch.len_utf8()..]));
+        }
+        if ch == '\\' {
+            match chars.next() {
+                Some((_, escaped)) if escaped == delimiter => part.push(escaped),
+                Some((_, escaped)) => {
+                    part.push('\\');
+                    part.push(escaped);
+                }
+                None => part.push('\\'),
+            }
+        } else {
+            part.push(ch);
+        }
+    }
+    (part, None)
+}
+
+// Replaces each unescaped `~` in a replacement with the previous replacement
+pub fn expand_tilde(replacement: &str, previous: &str) -> String {
+    let mut expanded = String::new();
+    let mut chars = replacement.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '~' => expanded.push_str(previous),
+            '\\' => {
+                expanded.push('\\');
+                expanded.extend(chars.next());
+            }
+            ch => expanded.push(ch),
+        }
+    }
+    expanded
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum CaseChange {
+    Upper,
+    Lower,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+enum Part {
+    Text(String),
+    // `&` and `\0` for the whole match, `\1` to `\9` for groups
+    Group(usize),
+    // `\u` and `\l`
+    NextChar(CaseChange),
+    // `\U` and `\L`, until `\E`
+    Following(Option<CaseChange>),
+}
+
+// A replacement split into text, groups and case changes
+#[derive(PartialEq, Clone, Debug)]
+pub struct Replacement {
+    parts: Vec<Part>,
+}
+
+impl Replacement {
+    pub fn new(replacement: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = replacement.chars();
+        let push = |parts: &mut Vec<Part>, text: &mut String, part| {
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(text)));
+            }
+            parts.push(part);
+        };
+        while let Some(ch) = chars.next() {
+            if ch == '&' {
+                push(&mut parts, &mut text, Part::Group(0));
+                continue;
+            }
+            if ch != '\\' {
+                text.push(ch);
+                continue;
+            }
+            let part = match chars.next() {
+                Some(digit @ '0'..='9') => Part::Group(digit as usize - '0' as usize),
+                Some('u') => Part::NextChar(CaseChange::Upper),
+                Some('l') => Part::NextChar(CaseChange::Lower),
+                Some('U') => Part::Following(Some(CaseChange::Upper)),
+                Some('L') => Part::Following(Some(CaseChange::Lower)),
+                Some('E' | 'e') => Part::Following(None),
+                // Vim puts a NUL in for `\n`, which is never what anyone wants in a text file
+                Some('r' | 'n') => {
+                    text.push('\n');
+                    continue;
+                }
+                Some('t') => {
+                    text.push('\t');
+                    continue;
+                }
+                Some(escaped) => {
+                    text.push(escaped);
+                    continue;
+                }
+                None => {
+                    text.push('\\');
+                    continue;
+                }
+            };
+            push(&mut parts, &mut text, part);
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Self { parts }
+    }
+
+    // The text that replaces a match
+    pub fn expand(&self, captures: &Captures, piece_table: &PieceTable) -> String {
+        let mut expanded = String::new();
+        let mut next_char = None;
+        let mut following = None;
+        for part in &self.parts {
+            let text = match part {
+                Part::Text(text) => text.clone(),
+                Part::Group(number) => captures
+                    .group(*number)
+                    .map(|(start, end)| piece_table.slice(start, end))
+                    .unwrap_or_default(),
+                Part::NextChar(change) => {
+                    next_char = Some(*change);
+                    continue;
+                }
+                Part::Following(change) => {
+                    following = *change;
+                    continue;
+                }
+            };
+            for ch in text.chars() {
+                match next_char.take().or(following) {
+                    Some(CaseChange::Upper) => expanded.extend(ch.to_uppercase()),
+                    Some(CaseChange::Lower) => expanded.extend(ch.to_lowercase()),
+                    None => expanded.push(ch),
+                }
+            }
+        }
+        expanded
+    }
+}
+
+// A `:s` under way, which for the `c` flag waits for an answer at each match
+pub struct Substitution {
+    pub pattern: Pattern,
+    replacement: Replacement,
+    flags: Flags,
+    // Byte offset to search from, and the offset of the end of the last line in the range,
+    // which moves as text before it is replaced
+    from: usize,
+    end: usize,
+    pub substitutions: usize,
+    lines: usize,
+    last_changed: Option<usize>,
+}
+
+impl Substitution {
+    pub fn new(
+        pattern: Pattern,
+        replacement: Replacement,
+        flags: Flags,
+        range: LineRange,
+        piece_table: &PieceTable,
+    ) -> Self {
+        let from = piece_table.line_start(range.start).unwrap_or(0);
+        let last = range.end.min(piece_table.line_count().saturating_sub(1));
+        let end = piece_table
+            .line_start(last)
+            .map_or(0, |start| start + piece_table.line_len(last));
+        Self {
+            pattern,
+            replacement,
+            flags,
+            from,
+            end,
+            substitutions: 0,
+            lines: 0,
+            last_changed: None,
+        }
+    }
+
+    pub fn confirm(&self) -> bool {
+        self.flags.confirm
+    }
+
+    pub fn count_only(&self) -> bool {
+        self.flags.count_only
+    }
+
+    // The next match that starts on a line in the range
+    pub fn next_match(&self, piece_table: &PieceTable) -> Option<Captures> {
+        if self.from > self.end {
+            return None;
+        }
+        self.pattern
+            .regex()
+            .find_forward(piece_table, self.from, self.end)
+    }
+
+    // Replaces the match, or just counts it for the `n` flag
+    pub fn substitute(&mut self, captures: &Captures, piece_table: &mut PieceTable) {
+        self.count(captures, piece_table);
+        if self.flags.count_only {
+            self.skip(captures, piece_table);
+            return;
+        }
+        let (start, end) = (captures.start(), captures.end());
+        let text = self.replacement.expand(captures, piece_table);
+        let joined_lines = piece_table.slice(start, end).contains('\n');
+        piece_table.delete_range(start, end);
+        piece_table.insert(start, &text);
+        self.end = if end > self.end {
+            start + text.len()
+        } else {
+            self.end - (end - start) + text.len()
+        };
+        // Replacing a line break brings the next line up, which still needs searching
+        if joined_lines {
+            self.from = start + text.len();
+        } else {
+            self.move_past(start, start + text.len(), end == start, piece_table);
+        }
+    }
+
+    // Leaves the match as it is
+    pub fn skip(&mut self, captures: &Captures, piece_table: &PieceTable) {
+        let (start, end) = (captures.start(), captures.end());
+        self.move_past(start, end, end == start, piece_table);
+    }
+
+    fn count(&mut self, captures: &Captures, piece_table: &PieceTable) {
+        let (_, y) = piece_table.coordinates(captures.start());
+        self.substitutions += 1;
+        if self.last_changed != Some(y) {
+            self.lines += 1;
+            self.last_changed = Some(y);
+        }
+    }
+
+    // Carries on searching after text that ends at `end`: on the same line for the `g` flag,
+    // otherwise on the next one. After an empty match the search skips a char so it doesn't
+    // find the same place again
+    fn move_past(&mut self, start: usize, end: usize, empty: bool, piece_table: &PieceTable) {
+        self.from = if !self.flags.global {
+            piece_table
+                .chars_at(end.max(start))
+                .find(|&(_, ch)| ch == '\n')
+                .map_or(usize::MAX, |(newline, _)| newline + 1)
+        } else if empty {
+            piece_table
+                .chars_at(end)
+                .next()
+                .map_or(usize::MAX, |(i, ch)| i + ch.len_utf8())
+        } else {
+            end
+        };
+    }
+
+    // The line the cursor goes to afterwards
+    pub fn last_changed(&self) -> Option<usize> {
+        self.last_changed
+    }
+
+    // "5 substitutions on 3 lines"
+    pub fn report(&self) -> String {
+        let plural = |count: usize, word: &str| {
+            if count == 1 {
+                format!("{} {}", count, word)
+            } else if word == "match" {
+                format!("{} matches", count)
+            } else {
+                format!("{} {}s", count, word)
+            }
+        };
+        let what = if self.flags.count_only {
+            "match"
+        } else {
+            "substitution"
+        };
+        format!(
+            "{} on {}",
+            plural(self.substitutions, what),
+            plural(self.lines, "line")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn substitute(text: &str, command: &str, range: LineRange) -> (String, String) {
+        let command = parse_substitute(command).unwrap();
+        let pattern = Pattern::new(&command.pattern).unwrap();
+        let replacement = Replacement::new(&command.replacement.unwrap());
+        let mut piece_table = PieceTable::new(text);
+        let mut substitution =
+            Substitution::new(pattern, replacement, command.flags, range, &piece_table);
+        while let Some(captures) = substitution.next_match(&piece_table) {
+            substitution.substitute(&captures, &mut piece_table);
+        }
+        (piece_table.to_string(), substitution.report())
+    }
+
+    #[test]
+    fn test_parse_substitute() {
+        let command = parse_substitute("#a\\#b#c/d#gc 3").unwrap();
+        assert_eq!(command.pattern, "a#b");
+        assert_eq!(command.replacement.as_deref(), Some("c/d"));
+        assert!(command.flags.global && command.flags.confirm);
+        assert_eq!(command.count, Some(3));
+        assert_eq!(
+            parse_substitute("/x").unwrap().replacement.as_deref(),
+            Some("")
+        );
+        assert_eq!(parse_substitute("").unwrap().replacement, None);
+        assert_eq!(
+            parse_substitute("/a/b/z"),
+            Err(CommandError::TrailingCharacters("z".to_string()))
+        );
+        assert_eq!(expand_tilde("<~>\\~", "x"), "<x>\\~");
+    }
+
+    #[test]
+    fn test_substitute() {
+        let all = LineRange { start: 0, end: 2 };
+        assert_eq!(
+            substitute("a a\nb\na", "/a/x/", all),
+            (
+                "x a\nb\nx".to_string(),
+                "2 substitutions on 2 lines".to_string()
+            )
+        );
+        assert_eq!(
+            substitute("a a\nb\na", "/a/x/g", LineRange::line(0)).0,
+            "x x\nb\na"
+        );
+        assert_eq!(
+            substitute(
+                "key=value",
+                "/\\(\\w\\+\\)=\\(\\w\\+\\)/\\2: \\u\\1 [&]/",
+                all
+            )
+            .0,
+            "value: Key [key=value]"
+        );
+        assert_eq!(
+            substitute("one two", "/\\w\\+/\\U&\\E!/g", all).0,
+            "ONE! TWO!"
+        );
+        assert_eq!(substitute("abc", "/x*/-/g", all).0, "-a-b-c-");
+        assert_eq!(
+            substitute("a,b\nc,d\ne", "/,/\\r/g", all).0,
+            "a\nb\nc\nd\ne"
+        );
+        assert_eq!(substitute("a\nb\nc\nd", "/\\n//", all).0, "abcd");
+        assert_eq!(
+            substitute("aa\na", "/a/x/gn", all),
+            ("aa\na".to_string(), "3 matches on 2 lines".to_string())
+        );
+    }
+}
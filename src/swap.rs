@@ -0,0 +1,386 @@
+use crate::metadata::FileMetadata;
+use crate::piece_table::{PieceOperation, PieceTable};
+use rustix::io::Errno;
+use rustix::process::{test_kill_process, Pid};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The first line of every swap file
+const HEADER: &str = "text-editor swap 2";
+// Changes are written to the swap file once typing stops for this long,
+// or straight away once this many have built up
+pub const SYNC_IDLE_TIME: Duration = Duration::from_secs(4);
+const SYNC_CHANGES: usize = 200;
+
+// A journal of the changes made to a file since it was last written, kept next to it so
+// they can be recovered if the editor dies. It starts from the file on disk, which it
+// notes the size, modification time and inode of:
+//   file <size> <seconds> <nanoseconds> <inode> <path>
+// or, when the text doesn't match a file on disk, from the text itself:
+//   text <length>\n<text>
+// followed by records of the text put in the added buffer and the pieces changed:
+//   a <length>\n<text>
+//   i <position> <start in the text> <length>\n
+//   d <position> <length>\n
+pub struct SwapFile {
+    path: PathBuf,
+    file: File,
+    // Where the added buffer was when the journal started, and how much more has been written
+    added_start: usize,
+    added_written: usize,
+}
+
+impl SwapFile {
+    pub fn create(
+        path: PathBuf,
+        piece_table: &mut PieceTable,
+        metadata: &FileMetadata,
+    ) -> io::Result<Self> {
+        // Only the owner gets to read what is being typed
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        let mut swap = Self {
+            path,
+            file,
+            added_start: 0,
+            added_written: 0,
+        };
+        swap.restart(piece_table, metadata)?;
+        Ok(swap)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Starts the journal again from the text as it is now, like after the file is written.
+    // Only text that hasn't been changed since the file was read or written is left on disk
+    pub fn restart(
+        &mut self,
+        piece_table: &mut PieceTable,
+        metadata: &FileMetadata,
+    ) -> io::Result<()> {
+        piece_table.start_journal();
+        self.added_start = piece_table.added_text(0).len();
+        self.added_written = 0;
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        write!(self.file, "{}\n{}\n", HEADER, process::id())?;
+        let original = Original::new(metadata)
+            .filter(|_| !piece_table.is_modified() && metadata.disk_change().is_none());
+        match original {
+            Some(original) => writeln!(self.file, "{}", original)?,
+            None => {
+                let text = piece_table.to_string();
+                write!(self.file, "text {}\n{}", text.len(), text)?
+            }
+        }
+        self.file.sync_data()
+    }
+
+    // Whether enough changes have built up to write them without waiting
+    pub fn is_due(&self, piece_table: &PieceTable) -> bool {
+        piece_table.journal_len() >= SYNC_CHANGES
+    }
+
+    // Writes the changes made since the last sync
+    pub fn sync(&mut self, piece_table: &mut PieceTable) -> io::Result<()> {
+        let operations = piece_table.take_journal();
+        if operations.is_empty() {
+            return Ok(());
+        }
+        let mut records = Vec::new();
+        let added = &piece_table.added_text(self.added_start)[self.added_written..];
+        if !added.is_empty() {
+            write!(records, "a {}\n{}", added.len(), added)?;
+            self.added_written += added.len();
+        }
+        for operation in operations {
+            match operation {
+                PieceOperation::Insert {
+                    position,
+                    start,
+                    length,
+                } => writeln!(
+                    records,
+                    "i {} {} {}",
+                    position,
+                    start - self.added_start,
+                    length
+                )?,
+                PieceOperation::Delete { position, length } => {
+                    writeln!(records, "d {} {}", position, length)?
+                }
+            }
+        }
+        self.file.write_all(&records)?;
+        self.file.sync_data()
+    }
+
+    // Done with when the editor quits normally
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+// Where the swap file for `file_path` goes
+pub fn swap_path(file_path: &str) -> PathBuf {
+    let path = Path::new(file_path);
+    let name = path
+        .file_name()
+        .map_or("".into(), |name| name.to_string_lossy());
+    path.with_file_name(format!(".{}.swap", name))
+}
+
+// The process that wrote the swap file at `path`
+pub fn swap_owner(path: &Path) -> io::Result<u32> {
+    let contents = fs::read(path)?;
+    let (pid, _) = read_header(&mut Records { rest: &contents })?;
+    Ok(pid)
+}
+
+// Whether process `pid` is still running, and so maybe still editing the file
+pub fn is_running(pid: u32) -> bool {
+    let Some(pid) = i32::try_from(pid).ok().and_then(Pid::from_raw) else {
+        return false;
+    };
+    matches!(test_kill_process(pid), Ok(()) | Err(Errno::PERM))
+}
+
+// The text as it was when the swap file at `path` was last written to, from the file the
+// journal started from and the changes in it. A record cut short by a crash ends the journal
+pub fn recover(path: &Path) -> io::Result<String> {
+    let contents = fs::read(path)?;
+    let mut records = Records { rest: &contents };
+    let (_, start) = read_header(&mut records)?;
+    let text = match start {
+        Start::File(original) => original.read()?,
+        Start::Text(text) => text.to_string(),
+    };
+    let mut piece_table = PieceTable::new(&text);
+    let mut added = String::new();
+    while let Some(line) = records.line() {
+        let mut fields = line.split(' ');
+        let kind = fields.next();
+        let numbers: Vec<usize> = fields.map_while(|field| field.parse().ok()).collect();
+        match (kind, numbers.as_slice()) {
+            (Some("a"), &[length]) => match records.text(length) {
+                Some(text) => added.push_str(text),
+                None => break,
+            },
+            (Some("i"), &[position, start, length]) if position <= piece_table.len() => {
+                let Some(text) = added.get(start..start + length) else {
+                    break;
+                };
+                piece_table.insert(position, text);
+            }
+            (Some("d"), &[position, length]) if position + length <= piece_table.len() => {
+                piece_table.delete_range(position, position + length);
+            }
+            _ => break,
+        }
+    }
+    Ok(piece_table.to_string())
+}
+
+// The file on disk a journal starts from, as it was when the journal started
+#[derive(PartialEq, Debug)]
+struct Original {
+    size: u64,
+    modified: SystemTime,
+    inode: u64,
+    path: PathBuf,
+}
+
+impl Original {
+    // The file as it was last read or written, if there is one
+    fn new(metadata: &FileMetadata) -> Option<Self> {
+        let path = fs::canonicalize(&metadata.file_path).ok()?;
+        Some(Self {
+            size: metadata.file_size? as u64,
+            modified: metadata.last_write_time?,
+            inode: metadata.inode?,
+            path,
+        })
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.strip_prefix("file ")?.splitn(5, ' ');
+        let mut number = || fields.next()?.parse::<u64>().ok();
+        let size = number()?;
+        let seconds = number()?;
+        let nanoseconds = number()?;
+        let inode = number()?;
+        let modified = UNIX_EPOCH + Duration::new(seconds, u32::try_from(nanoseconds).ok()?);
+        Some(Self {
+            size,
+            modified,
+            inode,
+            path: PathBuf::from(fields.next()?),
+        })
+    }
+
+    // The file's text, as long as nothing has written over it since
+    fn read(&self) -> io::Result<String> {
+        let disk = fs::metadata(&self.path)?;
+        if disk.len() != self.size || disk.modified()? != self.modified || disk.ino() != self.inode
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has changed since the swap file was written",
+                    self.path.display()
+                ),
+            ));
+        }
+        fs::read_to_string(&self.path)
+    }
+}
+
+impl std::fmt::Display for Original {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let modified = self.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(
+            f,
+            "file {} {} {} {} {}",
+            self.size,
+            modified.as_secs(),
+            modified.subsec_nanos(),
+            self.inode,
+            self.path.display()
+        )
+    }
+}
+
+// What the journal's changes are made to
+enum Start<'a> {
+    File(Original),
+    Text(&'a str),
+}
+
+fn read_header<'a>(records: &mut Records<'a>) -> io::Result<(u32, Start<'a>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a swap file");
+    if records.line() != Some(HEADER) {
+        return Err(invalid());
+    }
+    let pid = records
+        .line()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(invalid)?;
+    let line = records.line().ok_or_else(invalid)?;
+    let start = match line.strip_prefix("text ") {
+        Some(length) => {
+            let length = length.parse().map_err(|_| invalid())?;
+            Start::Text(records.text(length).ok_or_else(invalid)?)
+        }
+        None => Start::File(Original::parse(line).ok_or_else(invalid)?),
+    };
+    Ok((pid, start))
+}
+
+// Reads a swap file a line or a number of bytes at a time
+struct Records<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    // Only a line that was finished with a newline
+    fn line(&mut self) -> Option<&'a str> {
+        let end = self.rest.iter().position(|&byte| byte == b'\n')?;
+        let line = std::str::from_utf8(&self.rest[..end]).ok()?;
+        self.rest = &self.rest[end + 1..];
+        Some(line)
+    }
+
+    fn text(&mut self, length: usize) -> Option<&'a str> {
+        let text = std::str::from_utf8(self.rest.get(..length)?).ok()?;
+        self.rest = &self.rest[length..];
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_swap_file() -> io::Result<()> {
+        let directory = tempdir()?;
+        let file_path = directory.path().join("file.txt");
+        let path = directory.path().join(".file.txt.swap");
+        fs::write(&file_path, "one\ntwo\n")?;
+        let mut metadata = FileMetadata::new(file_path.to_str().unwrap().to_string());
+        metadata.update();
+        let mut piece_table = PieceTable::new("one\ntwo\n");
+        let mut swap = SwapFile::create(path.clone(), &mut piece_table, &metadata)?;
+        assert_eq!(swap_owner(&path)?, process::id());
+        // The text is left in the file rather than copied
+        assert!(!fs::read_to_string(&path)?.contains("two"));
+
+        piece_table.insert(0, "zero\n");
+        piece_table.insert(9, "and a half\n");
+        piece_table.delete_range(0, 5);
+        swap.sync(&mut piece_table)?;
+        piece_table.insert(0, "é");
+        piece_table.undo().unwrap();
+        swap.sync(&mut piece_table)?;
+        assert_eq!(recover(&path)?, piece_table.to_string());
+
+        // Half a record is left off
+        let whole = fs::read(&path)?;
+        piece_table.insert(0, "lost");
+        swap.sync(&mut piece_table)?;
+        let mut contents = fs::read(&path)?;
+        contents.truncate(contents.len() - 2);
+        fs::write(&path, contents)?;
+        assert_eq!(recover(&path)?, recover_text(&whole, &path)?);
+
+        // Writing the file starts the journal again from what was written
+        fs::write(&file_path, piece_table.to_string())?;
+        metadata.update();
+        piece_table.mark_saved();
+        swap.restart(&mut piece_table, &metadata)?;
+        assert!(!fs::read_to_string(&path)?.contains("two"));
+        piece_table.append("!");
+        swap.sync(&mut piece_table)?;
+        assert!(recover(&path)?.ends_with("two\n!"));
+        assert_eq!(recover(&path)?, piece_table.to_string());
+
+        // The changes can't be put back on a file something else has written over
+        fs::write(&file_path, "other\n")?;
+        assert!(recover(&path).is_err());
+
+        // Text that differs from the file on disk goes in the swap file itself
+        swap.restart(&mut piece_table, &metadata)?;
+        fs::remove_file(&file_path)?;
+        piece_table.append("?");
+        swap.sync(&mut piece_table)?;
+        assert_eq!(recover(&path)?, piece_table.to_string());
+
+        swap.remove()?;
+        assert!(!path.exists());
+        assert!(recover(&file_path).is_err());
+        Ok(())
+    }
+
+    fn recover_text(contents: &[u8], path: &Path) -> io::Result<String> {
+        let copy = path.with_extension("copy");
+        fs::write(&copy, contents)?;
+        recover(&copy)
+    }
+
+    #[test]
+    fn test_swap_path() {
+        assert_eq!(swap_path("notes.txt"), Path::new(".notes.txt.swap"));
+        assert_eq!(swap_path("/tmp/a/b.rs"), Path::new("/tmp/a/.b.rs.swap"));
+    }
+}
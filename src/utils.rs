@@ -1,4 +1,8 @@
 use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+pub const TAB_WIDTH: usize = 8;
 
 pub fn create_key_event(code: KeyCode) -> KeyEvent {
     KeyEvent {
@@ -26,23 +30,132 @@ pub fn string_to_key_events(text: String) -> Vec<KeyEvent> {
     key_events
 }
 
-pub fn find_index(lines: &[String], x: usize, y: usize) -> Option<usize> {
-    let mut char_count = 0;
-    for (cur_y, line) in lines.iter().enumerate() {
-        if line.is_empty() && cur_y == y {
-            return Some(char_count);
+// Keys as they are kept in a register by macro recording, with Esc, Enter and
+// Ctrl combinations as control characters the way vim does
+pub fn keys_to_text(key_events: &[KeyEvent]) -> String {
+    key_events
+        .iter()
+        .filter_map(|key_event| match key_event.code {
+            KeyCode::Char(ch)
+                if key_event.modifiers.contains(KeyModifiers::CONTROL)
+                    && ch.is_ascii_alphabetic() =>
+            {
+                Some(char::from(ch.to_ascii_lowercase() as u8 & 0x1f))
+            }
+            KeyCode::Char(ch) => Some(ch),
+            KeyCode::Esc => Some('\x1b'),
+            KeyCode::Enter => Some('\r'),
+            KeyCode::Tab => Some('\t'),
+            KeyCode::Backspace => Some('\x08'),
+            KeyCode::Delete => Some('\x7f'),
+            _ => None,
+        })
+        .collect()
+}
+
+// The keys `keys_to_text` turned into `text`. A newline, like the one on the end of a yanked line, is Enter
+pub fn text_to_keys(text: &str) -> Vec<KeyEvent> {
+    text.chars()
+        .map(|ch| match ch {
+            '\x1b' => create_key_event(KeyCode::Esc),
+            '\r' | '\n' => create_key_event(KeyCode::Enter),
+            '\t' => create_key_event(KeyCode::Tab),
+            '\x08' => create_key_event(KeyCode::Backspace),
+            '\x7f' => create_key_event(KeyCode::Delete),
+            '\x01'..='\x1a' => control_key_event(KeyCode::Char(char::from(ch as u8 + b'a' - 1))),
+            _ => create_key_event(KeyCode::Char(ch)),
+        })
+        .collect()
+}
+
+pub fn grapheme_count(text: &str) -> usize {
+    text.graphemes(true).count()
+}
+
+// Byte offset of the grapheme cluster at `grapheme_index`, or the length of `text` if it is past the end
+pub fn grapheme_to_byte(text: &str, grapheme_index: usize) -> usize {
+    text.grapheme_indices(true)
+        .nth(grapheme_index)
+        .map_or(text.len(), |(byte_index, _)| byte_index)
+}
+
+// Number of grapheme clusters that start before `byte_index`
+pub fn byte_to_grapheme(text: &str, byte_index: usize) -> usize {
+    text.grapheme_indices(true)
+        .take_while(|(i, _)| *i < byte_index)
+        .count()
+}
+
+// Terminal columns taken up by a grapheme cluster drawn at `column`
+fn grapheme_width(grapheme: &str, column: usize) -> usize {
+    match grapheme {
+        "\t" => TAB_WIDTH - column % TAB_WIDTH,
+        _ => grapheme.width(),
+    }
+}
+
+// Screen column that the grapheme cluster at `grapheme_index` is drawn at
+pub fn display_column(text: &str, grapheme_index: usize) -> usize {
+    text.graphemes(true)
+        .take(grapheme_index)
+        .fold(0, |column, grapheme| {
+            column + grapheme_width(grapheme, column)
+        })
+}
+
+// Index of the first grapheme cluster drawn at or after `column`,
+// or the number of grapheme clusters if the line is too short
+pub fn grapheme_at_column(text: &str, column: usize) -> usize {
+    let mut cur_column = 0;
+    for (i, grapheme) in text.graphemes(true).enumerate() {
+        if cur_column >= column {
+            return i;
         }
+        cur_column += grapheme_width(grapheme, cur_column);
+    }
+    grapheme_count(text)
+}
 
-        for (cur_x, _) in line.chars().enumerate() {
-            if cur_x == x && cur_y == y {
-                return Some(char_count);
-            }
-            char_count += 1;
-            if cur_x + 1 == x && cur_y == y {
-                return Some(char_count);
+// Each grapheme cluster as it is drawn, with tabs replaced by spaces
+pub fn display_graphemes(text: &str) -> Vec<String> {
+    let mut column = 0;
+    text.graphemes(true)
+        .map(|grapheme| {
+            let width = grapheme_width(grapheme, column);
+            column += width;
+            match grapheme {
+                "\t" => " ".repeat(width),
+                _ => grapheme.to_string(),
             }
-        }
-        char_count += 1;
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grapheme_columns() {
+        // e + combining acute, a family emoji joined with ZWJs, then CJK
+        let line = "e\u{301}x👨\u{200d}👩\u{200d}👧中\tz";
+        assert_eq!(grapheme_count(line), 6);
+        assert_eq!(grapheme_to_byte(line, 1), 3);
+        assert_eq!(byte_to_grapheme(line, grapheme_to_byte(line, 3)), 3);
+        assert_eq!(display_column(line, 1), 1);
+        assert_eq!(display_column(line, 3), 4);
+        assert_eq!(display_column(line, 4), 6);
+        assert_eq!(display_column(line, 5), 8);
+    }
+
+    #[test]
+    fn test_keys_as_text() {
+        let mut key_events = string_to_key_events(String::from("ix"));
+        key_events.push(create_key_event(KeyCode::Esc));
+        key_events.push(control_key_event(KeyCode::Char('r')));
+        key_events.push(create_key_event(KeyCode::Enter));
+        let text = keys_to_text(&key_events);
+        assert_eq!(text, "ix\x1b\x12\r");
+        assert_eq!(text_to_keys(&text), key_events);
     }
-    None
 }
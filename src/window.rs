@@ -0,0 +1,368 @@
+// Commands about windows, which the key handler leaves for the editor to carry out
+#[derive(PartialEq, Clone, Debug)]
+pub enum WindowCommand {
+    // `:split` and `:vsplit`, with a file to edit in the new window if one was given
+    Split { vertical: bool, file_path: String },
+    // `Ctrl-w h/j/k/l`
+    Focus(Direction),
+    // `Ctrl-w w` goes to the next window, going round from the last to the first
+    Next,
+    // `Ctrl-w =` makes the windows the same size
+    Equalize,
+    // `:only` and `Ctrl-w o`
+    Only,
+    // `:close` and `Ctrl-w c`
+    Close,
+    // `:tabnew`, with a file to edit in the new tab page if one was given
+    TabNew(String),
+    // `:tabnext` and `gt`, to the next tab page or the one numbered from 1
+    TabNext(Option<usize>),
+    // `:tabprevious` and `gT`, going back a number of tab pages
+    TabPrevious(usize),
+    // `:tabclose`
+    TabClose,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Direction {
+    Left,
+    Down,
+    Up,
+    Right,
+}
+
+// Where a window goes on the screen. Its height takes in its status line
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// The smallest window: one line of text and a status line, one column wide
+const MIN_HEIGHT: usize = 2;
+const MIN_WIDTH: usize = 1;
+
+// How the windows are arranged. A split holds windows or other splits side by side, with a
+// column between them for the separator, or one above the other, along with how many columns
+// or rows each one takes up
+#[derive(PartialEq, Clone, Debug)]
+pub enum Layout {
+    Window(usize),
+    Split {
+        vertical: bool,
+        children: Vec<(Layout, usize)>,
+    },
+}
+
+impl Layout {
+    // Where each window goes when the layout fills `area`, from the top left
+    pub fn rects(&self, area: Rect) -> Vec<(usize, Rect)> {
+        match self {
+            Layout::Window(id) => vec![(*id, area)],
+            Layout::Split { vertical, children } => children
+                .iter()
+                .zip(child_rects(*vertical, children, area))
+                .flat_map(|((child, _), rect)| child.rects(rect))
+                .collect(),
+        }
+    }
+
+    // Splits window `id` in two, putting window `new_id` above it or to the left of it
+    pub fn split(
+        &mut self,
+        id: usize,
+        new_id: usize,
+        vertical: bool,
+        area: Rect,
+    ) -> Result<(), LayoutError> {
+        let (_, rect) = self
+            .rects(area)
+            .into_iter()
+            .find(|&(window, _)| window == id)
+            .ok_or(LayoutError::NoSuchWindow)?;
+        // The separator column comes out of the space being split
+        let (space, min) = if vertical {
+            (rect.width.saturating_sub(1), MIN_WIDTH)
+        } else {
+            (rect.height, MIN_HEIGHT)
+        };
+        let first = space - space / 2;
+        let second = space / 2;
+        if second < min {
+            return Err(LayoutError::NotEnoughRoom);
+        }
+        self.split_window(id, new_id, vertical, (first, second));
+        Ok(())
+    }
+
+    fn split_window(
+        &mut self,
+        id: usize,
+        new_id: usize,
+        vertical: bool,
+        sizes: (usize, usize),
+    ) -> bool {
+        match self {
+            Layout::Window(window) if *window == id => {
+                *self = Layout::Split {
+                    vertical,
+                    children: vec![
+                        (Layout::Window(new_id), sizes.0),
+                        (Layout::Window(id), sizes.1),
+                    ],
+                };
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split {
+                vertical: split_vertical,
+                children,
+            } => {
+                // A split the same way as this one only adds to it
+                let index = children
+                    .iter()
+                    .position(|(child, _)| *child == Layout::Window(id));
+                if let (true, Some(index)) = (*split_vertical == vertical, index) {
+                    children[index].1 = sizes.1;
+                    children.insert(index, (Layout::Window(new_id), sizes.0));
+                    return true;
+                }
+                children
+                    .iter_mut()
+                    .any(|(child, _)| child.split_window(id, new_id, vertical, sizes))
+            }
+        }
+    }
+
+    // Takes window `id` out, giving its space to the window before it, or else the one after.
+    // The last window can't be closed
+    pub fn close(&mut self, id: usize) -> Result<(), LayoutError> {
+        if let Layout::Window(_) = self {
+            return Err(LayoutError::LastWindow);
+        }
+        if self.close_window(id) {
+            Ok(())
+        } else {
+            Err(LayoutError::NoSuchWindow)
+        }
+    }
+
+    fn close_window(&mut self, id: usize) -> bool {
+        let Layout::Split { vertical, children } = self else {
+            return false;
+        };
+        match children
+            .iter()
+            .position(|(child, _)| *child == Layout::Window(id))
+        {
+            Some(index) => {
+                let (_, size) = children.remove(index);
+                let separator = usize::from(*vertical);
+                children[index.saturating_sub(1)].1 += size + separator;
+            }
+            None if children.iter_mut().any(|(child, _)| child.close_window(id)) => {}
+            None => return false,
+        }
+
+        // A split inside one going the same way, like one left with a single window, is merged into it
+        let vertical = *vertical;
+        let mut merged = Vec::new();
+        for (child, size) in children.drain(..) {
+            match child {
+                Layout::Split {
+                    vertical: inner,
+                    children: inner_children,
+                } if inner == vertical => merged.extend(inner_children),
+                child => merged.push((child, size)),
+            }
+        }
+        *self = match merged.len() {
+            1 => merged.pop().map(|(child, _)| child).unwrap(),
+            _ => Layout::Split {
+                vertical,
+                children: merged,
+            },
+        };
+        true
+    }
+
+    // Shares the space in each split out evenly, for `Ctrl-w =`
+    pub fn equalize(&mut self, area: Rect) {
+        let Layout::Split { vertical, children } = self else {
+            return;
+        };
+        let count = children.len();
+        let space = if *vertical {
+            area.width.saturating_sub(count - 1)
+        } else {
+            area.height
+        };
+        for (i, (_, size)) in children.iter_mut().enumerate() {
+            *size = space / count + usize::from(i < space % count);
+        }
+        let rects = child_rects(*vertical, children, area);
+        for ((child, _), rect) in children.iter_mut().zip(rects) {
+            child.equalize(rect);
+        }
+    }
+
+    // The window next to window `id` in `direction`, preferring the one beside its top left corner
+    pub fn neighbour(&self, id: usize, direction: Direction, area: Rect) -> Option<usize> {
+        let rects = self.rects(area);
+        let (_, current) = rects.iter().find(|&&(window, _)| window == id)?;
+        let overlaps = |start: usize, length: usize, other_start: usize, other_length: usize| {
+            start < other_start + other_length && other_start < start + length
+        };
+        let candidates: Vec<&(usize, Rect)> = rects
+            .iter()
+            .filter(|(_, rect)| match direction {
+                Direction::Left => {
+                    rect.x + rect.width + 1 == current.x
+                        && overlaps(rect.y, rect.height, current.y, current.height)
+                }
+                Direction::Right => {
+                    current.x + current.width + 1 == rect.x
+                        && overlaps(rect.y, rect.height, current.y, current.height)
+                }
+                Direction::Up => {
+                    rect.y + rect.height == current.y
+                        && overlaps(rect.x, rect.width, current.x, current.width)
+                }
+                Direction::Down => {
+                    current.y + current.height == rect.y
+                        && overlaps(rect.x, rect.width, current.x, current.width)
+                }
+            })
+            .collect();
+        let beside = |(_, rect): &&&(usize, Rect)| match direction {
+            Direction::Left | Direction::Right => overlaps(rect.y, rect.height, current.y, 1),
+            Direction::Up | Direction::Down => overlaps(rect.x, rect.width, current.x, 1),
+        };
+        candidates
+            .iter()
+            .find(beside)
+            .or(candidates.first())
+            .map(|&&(window, _)| window)
+    }
+}
+
+// Where each child of a split goes in `area`. The last one takes what is left over, so the
+// split still fills an area that has changed size
+fn child_rects(vertical: bool, children: &[(Layout, usize)], area: Rect) -> Vec<Rect> {
+    let mut rects = Vec::new();
+    let mut position = if vertical { area.x } else { area.y };
+    let end = if vertical {
+        area.x + area.width
+    } else {
+        area.y + area.height
+    };
+    for (i, (_, size)) in children.iter().enumerate() {
+        let size = if i + 1 == children.len() {
+            end.saturating_sub(position)
+        } else {
+            (*size).min(end.saturating_sub(position))
+        };
+        rects.push(if vertical {
+            Rect {
+                x: position,
+                width: size,
+                ..area
+            }
+        } else {
+            Rect {
+                y: position,
+                height: size,
+                ..area
+            }
+        });
+        position += size + usize::from(vertical);
+    }
+    rects
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum LayoutError {
+    NoSuchWindow,
+    NotEnoughRoom,
+    LastWindow,
+}
+
+impl std::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LayoutError::NoSuchWindow => write!(f, "E957: Invalid window number"),
+            LayoutError::NotEnoughRoom => write!(f, "E36: Not enough room"),
+            LayoutError::LastWindow => write!(f, "E444: Cannot close last window"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: 80,
+        height: 23,
+    };
+
+    fn rect(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut layout = Layout::Window(0);
+        assert_eq!(layout.close(0), Err(LayoutError::LastWindow));
+        layout.split(0, 1, false, AREA).unwrap();
+        layout.split(1, 2, true, AREA).unwrap();
+        assert_eq!(
+            layout.rects(AREA),
+            [
+                (2, rect(0, 0, 40, 12)),
+                (1, rect(41, 0, 39, 12)),
+                (0, rect(0, 12, 80, 11))
+            ]
+        );
+        assert_eq!(layout.neighbour(0, Direction::Up, AREA), Some(2));
+        assert_eq!(layout.neighbour(1, Direction::Left, AREA), Some(2));
+        assert_eq!(layout.neighbour(1, Direction::Down, AREA), Some(0));
+        assert_eq!(layout.neighbour(2, Direction::Up, AREA), None);
+
+        // The space goes to the window on the left, and the split left with one window goes
+        layout.close(1).unwrap();
+        assert_eq!(
+            layout,
+            Layout::Split {
+                vertical: false,
+                children: vec![(Layout::Window(2), 12), (Layout::Window(0), 11)]
+            }
+        );
+        layout.split(0, 3, false, AREA).unwrap();
+        layout.equalize(AREA);
+        assert_eq!(
+            layout.rects(AREA),
+            [
+                (2, rect(0, 0, 80, 8)),
+                (3, rect(0, 8, 80, 8)),
+                (0, rect(0, 16, 80, 7))
+            ]
+        );
+
+        let small = rect(0, 0, 80, 4);
+        assert_eq!(
+            layout.split(3, 4, false, small),
+            Err(LayoutError::NotEnoughRoom)
+        );
+        assert_eq!(layout.close(5), Err(LayoutError::NoSuchWindow));
+    }
+}